@@ -0,0 +1,50 @@
+//! Records where a binary came from, so bug reports can say more than "dura 0.2.0". Anything
+//! that can't be found out is recorded as "unknown" rather than failing the build.
+use std::env;
+use std::path::Path;
+use std::process::Command;
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    // Only watch git files that exist, a missing one would rerun this on every build
+    for file in [".git/HEAD", ".git/index"] {
+        if Path::new(file).exists() {
+            println!("cargo:rerun-if-changed={file}");
+        }
+    }
+
+    let git_hash = output("git", &["rev-parse", "--short=12", "HEAD"]);
+    let rustc = output(
+        &env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string()),
+        &["--version"],
+    );
+    // Reproducible builds pin the date with SOURCE_DATE_EPOCH
+    let build_date = env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|secs| secs.parse::<i64>().ok())
+        .and_then(|secs| chrono::DateTime::from_timestamp(secs, 0))
+        .unwrap_or_else(chrono::Utc::now)
+        .format("%Y-%m-%d")
+        .to_string();
+
+    println!("cargo:rustc-env=DURA_GIT_HASH={git_hash}");
+    println!("cargo:rustc-env=DURA_BUILD_DATE={build_date}");
+    println!(
+        "cargo:rustc-env=DURA_TARGET={}",
+        env::var("TARGET").unwrap_or_else(|_| "unknown".to_string())
+    );
+    println!("cargo:rustc-env=DURA_RUSTC_VERSION={rustc}");
+}
+
+fn output(program: &str, args: &[&str]) -> String {
+    Command::new(program)
+        .args(args)
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .unwrap_or_else(|| "unknown".to_string())
+}
@@ -0,0 +1,30 @@
+//! Using dura from another program instead of running the binary: watch a repo in a config of
+//! its own, snapshot it and list what's there.
+//!
+//!     cargo run --example embed -- path/to/repo
+use std::path::PathBuf;
+
+use dura::api::{self, Config, WatchEdit};
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let path = PathBuf::from(std::env::args().nth(1).unwrap_or_else(|| ".".to_string()));
+
+    // Kept in memory. Config::load and Config::save work with dura's own file instead.
+    let mut config = Config::empty();
+    println!(
+        "{:?}",
+        api::watch(&mut config, &path, &WatchEdit::default())?
+    );
+
+    match api::capture_with_config(&path, &config)? {
+        Some(status) => println!("Snapshot: {status}"),
+        None => println!("Nothing changed since the last snapshot"),
+    }
+    for snapshot in api::list_snapshots(&path)? {
+        println!(
+            "{}  {}  on top of {}",
+            snapshot.timestamp, snapshot.commit, snapshot.base
+        );
+    }
+    Ok(())
+}
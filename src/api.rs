@@ -0,0 +1,120 @@
+//! The supported surface for embedding dura, e.g. in a GUI. Nothing here prints, and nothing
+//! loads dura's config file or runtime lock behind the caller's back: every function takes the
+//! `Config` to use, and failures come back as errors.
+//!
+//! ```
+//! use dura::api::{self, Config, WatchEdit, WatchResult};
+//! # let tmp = tempfile::tempdir().unwrap();
+//! # let repo = git2::Repository::init(tmp.path()).unwrap();
+//! # let sig = git2::Signature::now("a", "a@example.com").unwrap();
+//! # let tree = repo.find_tree(repo.index().unwrap().write_tree().unwrap()).unwrap();
+//! # repo.commit(Some("HEAD"), &sig, &sig, "initial", &tree, &[]).unwrap();
+//! # let path = tmp.path();
+//!
+//! let mut config = Config::empty();
+//! let result = api::watch(&mut config, path, &WatchEdit::default())?;
+//! assert_eq!(result, WatchResult::Created);
+//!
+//! std::fs::write(path.join("notes.txt"), "don't lose me")?;
+//! let status = api::capture_with_config(path, &config)?.expect("there was a change");
+//! let snapshots = api::list_snapshots(path)?;
+//! assert_eq!(snapshots[0].commit, status.commit_hash);
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+use std::collections::HashSet;
+use std::path::Path;
+
+use chrono::{DateTime, TimeZone, Utc};
+use git2::Oid;
+
+pub use crate::config::{
+    Config, ConfigError, UnwatchResult, WatchConfig, WatchEdit, WatchError, WatchResult,
+};
+pub use crate::snapshots::{CaptureConfig, CaptureStatus, LfsMode, RefNamespace};
+pub use git2::Error as GitError;
+
+use crate::snapshots::{self, PIN_PREFIX};
+
+/// A snapshot commit in a repo
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Snapshot {
+    pub commit: String,
+    /// The user's commit the snapshot was taken on top of
+    pub base: String,
+    /// The snapshot ref (or pin) it was found through
+    pub reference: String,
+    pub timestamp: DateTime<Utc>,
+}
+
+/// Snapshots the repo at `path` with the settings in `config`. `Ok(None)` means nothing changed
+/// since the last snapshot.
+pub fn capture_with_config(
+    path: &Path,
+    config: &Config,
+) -> Result<Option<CaptureStatus>, GitError> {
+    snapshots::capture_with(path, &CaptureConfig::from(config))
+}
+
+/// Starts watching `path`, or applies `edit` to its existing watch. Only changes `config`,
+/// saving it is up to the caller.
+pub fn watch(
+    config: &mut Config,
+    path: &Path,
+    edit: &WatchEdit,
+) -> Result<WatchResult, WatchError> {
+    config.set_watch(path_string(path)?, edit)
+}
+
+/// Stops watching `path`. Only changes `config`, saving it is up to the caller.
+pub fn unwatch(config: &mut Config, path: &Path) -> Result<UnwatchResult, WatchError> {
+    config.set_unwatch(path_string(path)?)
+}
+
+fn path_string(path: &Path) -> Result<String, WatchError> {
+    path.to_str()
+        .map(str::to_string)
+        .ok_or_else(|| WatchError::NotUnicode(path.display().to_string()))
+}
+
+/// Every snapshot in the repo at `path`, from both ref namespaces and pins, newest first
+pub fn list_snapshots(path: &Path) -> Result<Vec<Snapshot>, GitError> {
+    let repo = snapshots::open_repo(path)?;
+    let mut seen = HashSet::new();
+    let mut found = vec![];
+    let prefixes = [
+        RefNamespace::Heads.prefix(),
+        RefNamespace::Dura.prefix(),
+        PIN_PREFIX,
+    ];
+    for prefix in prefixes {
+        for reference in repo.references_glob(&format!("{prefix}*"))? {
+            let reference = reference?;
+            let name = reference.name().unwrap_or_default().to_string();
+            let mut commit = match reference.peel_to_commit() {
+                Ok(commit) => commit,
+                Err(_) => continue,
+            };
+            // Each snapshot's first parent is the one before it, down to the user's commit
+            let mut chain: Vec<(Oid, i64)> = vec![];
+            while snapshots::is_snapshot(&commit) {
+                if seen.insert(commit.id()) {
+                    chain.push((commit.id(), commit.time().seconds()));
+                }
+                commit = match commit.parent(0) {
+                    Ok(parent) => parent,
+                    Err(_) => break,
+                };
+            }
+            let base = commit.id().to_string();
+            found.extend(chain.into_iter().map(|(oid, seconds)| Snapshot {
+                commit: oid.to_string(),
+                base: base.clone(),
+                reference: name.clone(),
+                timestamp: Utc.timestamp_opt(seconds, 0).single().unwrap_or_default(),
+            }));
+        }
+    }
+    found.sort_by_key(|snapshot| std::cmp::Reverse(snapshot.timestamp));
+    Ok(found)
+}
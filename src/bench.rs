@@ -0,0 +1,133 @@
+//! `dura bench`: times the work a poll loop does, so CPU usage can be compared between machines
+//! and configs. Each step runs the same code the poller does, just several times over.
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::config::Config;
+use crate::poll_guard::PollGuard;
+use crate::snapshots::{self, CaptureConfig};
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BenchResult {
+    pub name: &'static str,
+    pub samples: usize,
+    pub median_ms: f64,
+    pub p95_ms: f64,
+}
+
+impl BenchResult {
+    pub fn from_samples(name: &'static str, samples: &[Duration]) -> Self {
+        let mut sorted = samples.to_vec();
+        sorted.sort();
+        Self {
+            name,
+            samples: sorted.len(),
+            median_ms: percentile(&sorted, 50.0),
+            p95_ms: percentile(&sorted, 95.0),
+        }
+    }
+}
+
+/// Nearest-rank percentile of already sorted samples, in milliseconds
+fn percentile(sorted: &[Duration], pct: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    let rank = (pct / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1].as_secs_f64() * 1000.0
+}
+
+fn time<T>(f: impl FnOnce() -> T) -> (Duration, T) {
+    let start = Instant::now();
+    let value = f();
+    (start.elapsed(), value)
+}
+
+/// Runs every benchmark `iterations` times. `repo` is the one the capture dry run is done on.
+///
+/// "cold" is the first run only, before the OS has cached the directories (unless something
+/// else already did) and, for PollGuard, with nothing remembered from a previous scan. "warm" is
+/// every run after that.
+pub fn run(
+    config: &Config,
+    repo: &Path,
+    iterations: usize,
+) -> Result<Vec<BenchResult>, git2::Error> {
+    let iterations = iterations.max(1);
+    let mut results = vec![];
+
+    let mut discovery = vec![];
+    let mut repos: Vec<PathBuf> = vec![];
+    for _ in 0..iterations {
+        let (elapsed, found) = time(|| config.git_repos().collect::<Vec<_>>());
+        discovery.push(elapsed);
+        repos = found;
+    }
+    results.push(BenchResult::from_samples("discovery_cold", &discovery[..1]));
+    if iterations > 1 {
+        results.push(BenchResult::from_samples("discovery_warm", &discovery[1..]));
+    }
+
+    let mut guard = PollGuard::new();
+    guard.set_fast(config.fast_poll_guard);
+    let mut scans = vec![];
+    for _ in 0..iterations {
+        let (elapsed, _) = time(|| {
+            for repo in &repos {
+                guard.dir_changed(repo);
+            }
+        });
+        scans.push(elapsed);
+    }
+    results.push(BenchResult::from_samples("poll_guard_cold", &scans[..1]));
+    if iterations > 1 {
+        results.push(BenchResult::from_samples("poll_guard_warm", &scans[1..]));
+
+        // The same, but reading HEAD and refs through git every time, to see what the
+        // precheck saves
+        guard.set_precheck(false);
+        let mut scans = vec![];
+        for _ in 1..iterations {
+            let (elapsed, _) = time(|| {
+                for repo in &repos {
+                    guard.dir_changed(repo);
+                }
+            });
+            scans.push(elapsed);
+        }
+        results.push(BenchResult::from_samples(
+            "poll_guard_warm_no_precheck",
+            &scans,
+        ));
+    }
+
+    let capture_config = CaptureConfig::from(config);
+    let mut captures = vec![];
+    for _ in 0..iterations {
+        let (elapsed, status) = time(|| snapshots::capture_dry_run(repo, &capture_config));
+        status?;
+        captures.push(elapsed);
+    }
+    results.push(BenchResult::from_samples("capture_dry_run", &captures));
+
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn medians_and_p95s() {
+        let samples: Vec<Duration> = (1..=20).rev().map(Duration::from_millis).collect();
+        let result = BenchResult::from_samples("x", &samples);
+        assert_eq!(result.samples, 20);
+        assert_eq!(result.median_ms, 10.0);
+        assert_eq!(result.p95_ms, 19.0);
+
+        let one = BenchResult::from_samples("x", &[Duration::from_millis(7)]);
+        assert_eq!((one.median_ms, one.p95_ms), (7.0, 7.0));
+    }
+}
@@ -0,0 +1,36 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+/// Where this binary came from, filled in by build.rs. Fields that couldn't be found out at build
+/// time are "unknown".
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct BuildInfo {
+    pub version: String,
+    pub git_hash: String,
+    pub build_date: String,
+    pub target: String,
+    pub rustc: String,
+}
+
+impl BuildInfo {
+    pub fn current() -> Self {
+        Self {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            git_hash: env!("DURA_GIT_HASH").to_string(),
+            build_date: env!("DURA_BUILD_DATE").to_string(),
+            target: env!("DURA_TARGET").to_string(),
+            rustc: env!("DURA_RUSTC_VERSION").to_string(),
+        }
+    }
+}
+
+/// The multi-line form shown by `dura --version --verbose`
+impl fmt::Display for BuildInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "commit:     {}", self.git_hash)?;
+        writeln!(f, "build date: {}", self.build_date)?;
+        writeln!(f, "target:     {}", self.target)?;
+        write!(f, "rustc:      {}", self.rustc)
+    }
+}
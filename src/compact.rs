@@ -0,0 +1,247 @@
+//! `dura compact`: the cleanup dura can do to a repo, run in one go. Old snapshot branches are
+//! pruned, snapshots that were since committed for real are dropped, then git gc (and
+//! optionally a repack) gets the space back.
+//!
+//! Every stage runs even when an earlier one failed, and reports on its own.
+use std::collections::HashSet;
+use std::fmt;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use git2::{Error, Oid};
+
+use crate::maintenance::{self, GcOutcome};
+use crate::size;
+use crate::snapshots::{self, RefNamespace};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompactOptions {
+    /// Snapshot branches whose newest snapshot is older than this are pruned
+    pub keep_days: u64,
+    /// The same for stashes kept by `snapshot_stashes`, by when they were stashed
+    pub keep_stash_days: u64,
+    /// Report what would be deleted without deleting anything. gc and repack are skipped.
+    pub dry_run: bool,
+    pub repack: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    Prune,
+    CleanMerged,
+    Gc,
+    Repack,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Stage::Prune => "prune",
+            Stage::CleanMerged => "clean-merged",
+            Stage::Gc => "gc",
+            Stage::Repack => "repack",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StageOutcome {
+    /// How many snapshot branches were deleted, or would be in a dry run
+    Deleted(usize),
+    Ran(GcOutcome),
+    /// gc and repack don't run in a dry run
+    DryRun,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StageReport {
+    pub stage: Stage,
+    pub result: Result<StageOutcome, String>,
+}
+
+/// How much of the repo is snapshots
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Footprint {
+    pub branches: usize,
+    /// Uncompressed bytes only snapshots refer to, as in `dura size`
+    pub bytes: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompactReport {
+    pub before: Footprint,
+    /// With `dry_run`, what it would be after deleting the refs. An error when it couldn't be
+    /// measured, which doesn't take the stages' reports with it.
+    pub after: Result<Footprint, String>,
+    pub stages: Vec<StageReport>,
+    /// Snapshot refs that don't point at a commit, e.g. at an object that's gone. Prune leaves
+    /// them alone.
+    pub unreadable: Vec<String>,
+}
+
+impl CompactReport {
+    pub fn reclaimed_bytes(&self) -> u64 {
+        match &self.after {
+            Ok(after) => self.before.bytes.saturating_sub(after.bytes),
+            Err(_) => 0,
+        }
+    }
+
+    pub fn failed(&self) -> bool {
+        self.after.is_err() || self.stages.iter().any(|stage| stage.result.is_err())
+    }
+}
+
+/// Compacts the repo at `path`. Only fails when the repo can't be measured beforehand, stage
+/// failures end up in the report.
+pub fn compact(path: &Path, options: &CompactOptions) -> Result<CompactReport, Error> {
+    let before = Footprint {
+        branches: snapshot_refs(path)?.len(),
+        bytes: size::dura_size(path)?.total_bytes,
+    };
+    let mut stages = vec![];
+    // Refs that are gone, or would be in a dry run
+    let mut deleted = HashSet::new();
+    let mut unreadable = vec![];
+
+    let pruned = prune(path, options);
+    stages.push(StageReport {
+        stage: Stage::Prune,
+        result: pruned
+            .map(|(refs, skipped)| {
+                let count = refs.len();
+                deleted.extend(refs);
+                unreadable = skipped;
+                StageOutcome::Deleted(count)
+            })
+            .map_err(|e| e.to_string()),
+    });
+
+    let merged = match options.dry_run {
+        true => snapshots::merged_snapshots(path),
+        false => snapshots::clean_merged(path),
+    };
+    stages.push(StageReport {
+        stage: Stage::CleanMerged,
+        result: merged
+            .map(|merged| {
+                // Already counted if prune would have deleted it anyway
+                let merged: Vec<String> = merged
+                    .into_iter()
+                    .map(|snapshot| snapshot.reference)
+                    .filter(|reference| !deleted.contains(reference))
+                    .collect();
+                let count = merged.len();
+                deleted.extend(merged);
+                StageOutcome::Deleted(count)
+            })
+            .map_err(|e| e.to_string()),
+    });
+
+    let mut git_stages = vec![Stage::Gc];
+    if options.repack {
+        git_stages.push(Stage::Repack);
+    }
+    for stage in git_stages {
+        let run = match stage {
+            Stage::Repack => maintenance::repack,
+            _ => maintenance::gc,
+        };
+        let result = match options.dry_run {
+            true => Ok(StageOutcome::DryRun),
+            false => run(path).map(StageOutcome::Ran),
+        };
+        stages.push(StageReport { stage, result });
+    }
+
+    let after = match options.dry_run {
+        true => size::dura_size_without(path, &deleted).map(|size| Footprint {
+            branches: before.branches.saturating_sub(deleted.len()),
+            bytes: size.total_bytes,
+        }),
+        false => snapshot_refs(path).and_then(|refs| {
+            Ok(Footprint {
+                branches: refs.len(),
+                bytes: size::dura_size(path)?.total_bytes,
+            })
+        }),
+    };
+    Ok(CompactReport {
+        before,
+        after: after.map_err(|e| e.to_string()),
+        stages,
+        unreadable,
+    })
+}
+
+/// Names of the snapshot branches, and refs to kept stashes, in either namespace. Only refs
+/// named after a commit hash count, so a user's own `dura/something` branch is left alone.
+fn snapshot_refs(path: &Path) -> Result<Vec<String>, Error> {
+    let repo = snapshots::open_repo(path)?;
+    let mut names = vec![];
+    for namespace in [RefNamespace::Heads, RefNamespace::Dura] {
+        for reference in repo.references_glob(&format!("{}*", namespace.prefix()))? {
+            let reference = reference?;
+            let name = match reference.name() {
+                Some(name) => name,
+                None => continue,
+            };
+            let is_snapshot_ref = name
+                .strip_prefix(namespace.prefix())
+                .map(|sha| Oid::from_str(sha).is_ok())
+                .unwrap_or(false);
+            if is_snapshot_ref || snapshots::is_stash_ref(name) {
+                names.push(name.to_string());
+            }
+        }
+    }
+    Ok(names)
+}
+
+/// Unix time `days` ago
+fn days_ago(days: u64) -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .saturating_sub(Duration::from_secs(days.saturating_mul(24 * 60 * 60)))
+        .as_secs() as i64
+}
+
+/// Deletes snapshot branches and kept stashes whose tip was committed before the cutoff, unless
+/// the tip is pinned or a snapshot branch's tip isn't a snapshot. Returns the refs deleted, or
+/// that would be with `dry_run`, and the ones that were skipped because they don't point at a
+/// commit.
+fn prune(path: &Path, options: &CompactOptions) -> Result<(Vec<String>, Vec<String>), Error> {
+    let repo = snapshots::open_repo(path)?;
+    let pinned = snapshots::pinned_commits(&repo)?;
+    let cutoff = days_ago(options.keep_days);
+    let stash_cutoff = days_ago(options.keep_stash_days);
+    let mut pruned = vec![];
+    let mut unreadable = vec![];
+    for name in snapshot_refs(path)? {
+        let tip = repo
+            .find_reference(&name)
+            .and_then(|reference| reference.peel_to_commit());
+        let tip = match tip {
+            Ok(tip) => tip,
+            Err(_) => {
+                unreadable.push(name);
+                continue;
+            }
+        };
+        let cutoff = match snapshots::is_stash_ref(&name) {
+            true => stash_cutoff,
+            // Named like a snapshot branch but somebody else's commit is on top
+            false if !snapshots::is_snapshot(&tip) => continue,
+            false => cutoff,
+        };
+        if pinned.contains(&tip.id()) || tip.time().seconds() >= cutoff {
+            continue;
+        }
+        if !options.dry_run {
+            repo.find_reference(&name)?.delete()?;
+        }
+        pruned.push(name);
+    }
+    Ok((pruned, unreadable))
+}
@@ -1,21 +1,110 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
 use std::fs::{create_dir_all, File};
-use std::io::{BufReader, Read};
-use std::path::{Path, PathBuf};
+use std::io::{self, BufReader, Read};
+use std::path::{Component, Path, PathBuf};
 use std::rc::Rc;
-use std::{env, fs};
+use std::sync::Mutex;
+use std::time::Duration;
 
 use serde::{Deserialize, Serialize};
 
 use crate::git_repo_iter::GitRepoIter;
+use crate::notify::NotificationConfig;
+use crate::path_case::PathCase;
+use crate::paths;
+use crate::redact::RedactPaths;
+use crate::schedule::Schedule;
+use crate::snapshots::{self, LfsMode, RefNamespace, SnapshotUntracked};
 
-type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+type Result<T> = std::result::Result<T, ConfigError>;
+
+/// How many times each config file was parsed by this process, see `Config::load_count`. Kept
+/// per file so tests parsing their own files at the same time don't throw each other's counts off.
+static LOAD_COUNTS: Mutex<BTreeMap<PathBuf, usize>> = Mutex::new(BTreeMap::new());
+
+/// Warnings already raised by this process, so a daemon reloading the config every loop
+/// doesn't repeat them
+static WARNED: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+
+/// Warnings not yet handed out by `Config::take_warnings`
+static WARNINGS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// The config format this build writes. Bump it along with a new entry in `MIGRATIONS` when a
+/// change to the format needs old files rewritten.
+pub const CONFIG_VERSION: u32 = 1;
+
+/// `MIGRATIONS[n]` upgrades a version `n` config to version `n + 1`
+const MIGRATIONS: [fn(&mut toml::value::Table); CONFIG_VERSION as usize] = [
+    // Files from before versioning. Nothing changed but the version itself.
+    |_| (),
+];
+
+/// Problems reading or writing dura's files, i.e. config.toml and the runtime lock
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("Couldn't read {}: {source}", path.display())]
+    Read { path: PathBuf, source: io::Error },
+    #[error("Couldn't parse {}: {message}", path.display())]
+    Parse { path: PathBuf, message: String },
+    #[error("Couldn't serialize {}: {message}", path.display())]
+    Serialize { path: PathBuf, message: String },
+    #[error("Couldn't write {}: {source}", path.display())]
+    Write { path: PathBuf, source: io::Error },
+    #[error("{} is config version {version}, but this dura only knows up to {CONFIG_VERSION}. Upgrade dura before changing it.", path.display())]
+    TooNew { path: PathBuf, version: u32 },
+    #[error("{} has unknown settings: {}", path.display(), keys.join(", "))]
+    UnknownKeys { path: PathBuf, keys: Vec<String> },
+    /// Includes and excludes that `normalize_watch_entry` rejects, with the watch they're in
+    #[error("{} has invalid includes/excludes: {}", path.display(), entries.join("; "))]
+    InvalidWatchEntries { path: PathBuf, entries: Vec<String> },
+}
+
+impl ConfigError {
+    /// True when the file just isn't there, as opposed to being broken
+    pub fn is_not_found(&self) -> bool {
+        matches!(self, ConfigError::Read { source, .. } if source.kind() == io::ErrorKind::NotFound)
+    }
+}
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
 pub struct WatchConfig {
     pub include: Vec<String>,
     pub exclude: Vec<String>,
     pub max_depth: u8,
+    /// Overrides `Config::min_snapshot_interval_secs` for repos under this watch
+    #[serde(default)]
+    pub min_snapshot_interval_secs: Option<u64>,
+    /// Turned off watches keep their settings, but nothing under them is snapshotted
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// Repos under this watch that are never snapshotted. Glob patterns, matched against the repo
+    /// path relative to the watch root, or against the full path if the pattern is absolute.
+    #[serde(default)]
+    pub deny_repos: Vec<String>,
+    /// The watch is a repo itself, so it goes straight to the poller without listing any
+    /// directories. Set by `dura watch` when the directory is a repo and has no includes or
+    /// excludes.
+    #[serde(default)]
+    pub single_repo: bool,
+    /// Overrides `Config::snapshot_sensitive_files` for repos under this watch
+    #[serde(default)]
+    pub snapshot_sensitive_files: Option<bool>,
+    /// No snapshots while the checked out branch matches one of these glob patterns, e.g.
+    /// `release/*`. `(detached)` matches a detached HEAD.
+    #[serde(default)]
+    pub skip_on_branches: Vec<String>,
+    /// Keep a ref to every stash, so stashes survive `git stash drop` and `git stash clear`
+    #[serde(default)]
+    pub snapshot_stashes: bool,
+    /// Whether snapshots include untracked files: `always`, `never`, or
+    /// `only_with_tracked_changes` so new files alone don't start a snapshot
+    #[serde(default)]
+    pub snapshot_untracked: SnapshotUntracked,
+}
+
+fn default_enabled() -> bool {
+    true
 }
 
 impl WatchConfig {
@@ -24,6 +113,14 @@ impl WatchConfig {
             include: vec![],
             exclude: vec![],
             max_depth: 255,
+            min_snapshot_interval_secs: None,
+            enabled: true,
+            deny_repos: vec![],
+            single_repo: false,
+            snapshot_sensitive_files: None,
+            skip_on_branches: vec![],
+            snapshot_stashes: false,
+            snapshot_untracked: SnapshotUntracked::Always,
         }
     }
 }
@@ -34,8 +131,282 @@ impl Default for WatchConfig {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+impl WatchConfig {
+    /// Does `deny_repos` rule out the repo at `repo`, found under the watch at `root`? A pattern
+    /// that isn't a valid glob only matches its exact path.
+    pub fn denies(&self, root: &Path, repo: &Path, case: PathCase) -> bool {
+        let relative = case.strip_prefix(repo, root);
+        self.deny_repos.iter().any(|deny| {
+            let candidate = match (Path::new(deny).is_absolute(), &relative) {
+                (false, Some(relative)) => relative.as_path(),
+                _ => repo,
+            };
+            match glob::Pattern::new(deny) {
+                Ok(pattern) => pattern.matches_path_with(candidate, case.glob_options()),
+                Err(_) => case.same(candidate, Path::new(deny)),
+            }
+        })
+    }
+
+    /// Includes and excludes that don't point to anything under `root` right now. Not an error,
+    /// since the directory may just not have been created yet.
+    pub fn missing_entries(&self, root: &Path) -> Vec<String> {
+        self.include
+            .iter()
+            .chain(self.exclude.iter())
+            .filter(|entry| !root.join(entry).exists())
+            .cloned()
+            .collect()
+    }
+
+    /// Human readable list of what's different in `other`, e.g. `+ exclude: target`
+    pub fn diff(&self, other: &WatchConfig) -> Vec<String> {
+        fn list_diff(name: &str, old: &[String], new: &[String], out: &mut Vec<String>) {
+            for item in old.iter().filter(|item| !new.contains(item)) {
+                out.push(format!("- {name}: {item}"));
+            }
+            for item in new.iter().filter(|item| !old.contains(item)) {
+                out.push(format!("+ {name}: {item}"));
+            }
+        }
+        /// Shown the way they'd be written in config.toml, like `Config::settings_diff`
+        fn value_diff<T: Serialize + PartialEq>(
+            name: &str,
+            old: &T,
+            new: &T,
+            out: &mut Vec<String>,
+        ) {
+            if old != new {
+                let show = |value: &T| match toml::Value::try_from(value) {
+                    Ok(value) => value.to_string(),
+                    Err(_) => "(unset)".to_string(),
+                };
+                out.push(format!("~ {name}: {} -> {}", show(old), show(new)));
+            }
+        }
+
+        // No `..`, so a new setting can't be left out of the diff
+        let WatchConfig {
+            include,
+            exclude,
+            max_depth,
+            min_snapshot_interval_secs,
+            enabled,
+            deny_repos,
+            single_repo,
+            snapshot_sensitive_files,
+            skip_on_branches,
+            snapshot_stashes,
+            snapshot_untracked,
+        } = self;
+        let mut changes = vec![];
+        list_diff("include", include, &other.include, &mut changes);
+        list_diff("exclude", exclude, &other.exclude, &mut changes);
+        value_diff("max_depth", max_depth, &other.max_depth, &mut changes);
+        value_diff(
+            "min_snapshot_interval_secs",
+            min_snapshot_interval_secs,
+            &other.min_snapshot_interval_secs,
+            &mut changes,
+        );
+        list_diff("deny_repos", deny_repos, &other.deny_repos, &mut changes);
+        value_diff("enabled", enabled, &other.enabled, &mut changes);
+        value_diff("single_repo", single_repo, &other.single_repo, &mut changes);
+        value_diff(
+            "snapshot_sensitive_files",
+            snapshot_sensitive_files,
+            &other.snapshot_sensitive_files,
+            &mut changes,
+        );
+        list_diff(
+            "skip_on_branches",
+            skip_on_branches,
+            &other.skip_on_branches,
+            &mut changes,
+        );
+        value_diff(
+            "snapshot_stashes",
+            snapshot_stashes,
+            &other.snapshot_stashes,
+            &mut changes,
+        );
+        value_diff(
+            "snapshot_untracked",
+            snapshot_untracked,
+            &other.snapshot_untracked,
+            &mut changes,
+        );
+        changes
+    }
+}
+
+/// Changes to a watch requested from the command line. Lists that are `None`, and a `max_depth`
+/// or `enabled` of `None`, leave the existing values alone.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct WatchEdit {
+    pub include: Option<Vec<String>>,
+    pub exclude: Option<Vec<String>>,
+    pub max_depth: Option<u8>,
+    pub enabled: Option<bool>,
+    pub add_include: Vec<String>,
+    pub remove_include: Vec<String>,
+    pub add_exclude: Vec<String>,
+    pub remove_exclude: Vec<String>,
+}
+
+/// An include or exclude that could never match anything
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidWatchEntry {
+    pub entry: String,
+    pub reason: &'static str,
+}
+
+impl std::fmt::Display for InvalidWatchEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Invalid include/exclude '{}': {}",
+            self.entry, self.reason
+        )
+    }
+}
+
+impl std::error::Error for InvalidWatchEntry {}
+
+/// Why `Config::set_watch` couldn't watch a path
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WatchError {
+    InvalidEntry(InvalidWatchEntry),
+    NotADirectory(String),
+    NotUnicode(String),
+    /// Imported watches have to be absolute or start with `~`
+    NotAbsolute(String),
+}
+
+impl std::fmt::Display for WatchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WatchError::InvalidEntry(e) => e.fmt(f),
+            WatchError::NotADirectory(path) => write!(f, "{path} is not a directory"),
+            WatchError::NotUnicode(path) => write!(f, "{path} is not valid unicode"),
+            WatchError::NotAbsolute(path) => {
+                write!(f, "{path} should be an absolute path or start with ~/")
+            }
+        }
+    }
+}
+
+impl std::error::Error for WatchError {}
+
+impl From<InvalidWatchEntry> for WatchError {
+    fn from(value: InvalidWatchEntry) -> Self {
+        WatchError::InvalidEntry(value)
+    }
+}
+
+/// Cleans up an include or exclude so it matches the way `GitRepoIter` compares paths, i.e. a
+/// plain path relative to the watch root. Trailing slashes and `.` are dropped.
+pub fn normalize_watch_entry(entry: &str) -> std::result::Result<String, InvalidWatchEntry> {
+    let invalid = |reason| InvalidWatchEntry {
+        entry: entry.to_string(),
+        reason,
+    };
+    let mut normalized = PathBuf::new();
+    for component in Path::new(entry).components() {
+        match component {
+            Component::Normal(part) => normalized.push(part),
+            Component::CurDir => (),
+            Component::ParentDir => return Err(invalid("'..' would leave the watch directory")),
+            Component::RootDir | Component::Prefix(_) => {
+                return Err(invalid("must be relative to the watch directory"))
+            }
+        }
+    }
+    match normalized.to_str() {
+        Some("") => Err(invalid("refers to the watch directory itself")),
+        Some(normalized) => Ok(normalized.to_string()),
+        None => Err(invalid("not valid unicode")),
+    }
+}
+
+impl WatchEdit {
+    /// Runs every entry through `normalize_watch_entry`
+    pub fn normalized(&self) -> std::result::Result<WatchEdit, InvalidWatchEntry> {
+        fn all(list: &[String]) -> std::result::Result<Vec<String>, InvalidWatchEntry> {
+            list.iter().map(|e| normalize_watch_entry(e)).collect()
+        }
+
+        Ok(WatchEdit {
+            include: self.include.as_deref().map(all).transpose()?,
+            exclude: self.exclude.as_deref().map(all).transpose()?,
+            max_depth: self.max_depth,
+            enabled: self.enabled,
+            add_include: all(&self.add_include)?,
+            remove_include: all(&self.remove_include)?,
+            add_exclude: all(&self.add_exclude)?,
+            remove_exclude: all(&self.remove_exclude)?,
+        })
+    }
+
+    pub fn apply(&self, cfg: &WatchConfig) -> WatchConfig {
+        fn edit_list(
+            list: &[String],
+            replace: &Option<Vec<String>>,
+            add: &[String],
+            remove: &[String],
+        ) -> Vec<String> {
+            let mut list = replace.clone().unwrap_or_else(|| list.to_vec());
+            for item in add {
+                if !list.contains(item) {
+                    list.push(item.clone());
+                }
+            }
+            list.retain(|item| !remove.contains(item));
+            list
+        }
+
+        WatchConfig {
+            include: edit_list(
+                &cfg.include,
+                &self.include,
+                &self.add_include,
+                &self.remove_include,
+            ),
+            exclude: edit_list(
+                &cfg.exclude,
+                &self.exclude,
+                &self.add_exclude,
+                &self.remove_exclude,
+            ),
+            max_depth: self.max_depth.unwrap_or(cfg.max_depth),
+            enabled: self.enabled.unwrap_or(cfg.enabled),
+            ..cfg.clone()
+        }
+    }
+}
+
+/// What `Config::set_watch` did
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WatchResult {
+    Created,
+    /// The watch already existed, these are the changes in `WatchConfig::diff` format
+    Updated(Vec<String>),
+    /// The watch already existed and was left as it was
+    Unchanged,
+}
+
+/// What `Config::set_unwatch` did, with the absolute path it looked for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UnwatchResult {
+    Removed(String),
+    NotWatched(String),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct Config {
+    /// Format of the file, see `CONFIG_VERSION`. Files without one are version 0.
+    #[serde(default)]
+    pub version: u32,
     // When commit_exclude_git_config is true,
     // never use any git configuration to sign dura's commits.
     // Defaults to false
@@ -43,123 +414,949 @@ pub struct Config {
     pub commit_exclude_git_config: bool,
     pub commit_author: Option<String>,
     pub commit_email: Option<String>,
+    /// After a snapshot, wait at least this long before taking another one of the same repo
+    #[serde(default)]
+    pub min_snapshot_interval_secs: Option<u64>,
+    /// Don't capture a repo while any of its files were modified less than this many
+    /// milliseconds ago, e.g. an editor is mid-save
+    #[serde(default)]
+    pub debounce_ms: Option<u64>,
+    /// Give up waiting for a repo to settle down after this many loops, and capture anyway
+    #[serde(default = "default_debounce_max_defer_loops")]
+    pub debounce_max_defer_loops: u32,
+    /// Give up on a repo for the current loop when looking at it takes longer than this, e.g.
+    /// because it's on a network mount that stalled. At least a second, see `per_repo_timeout`.
+    #[serde(default = "default_per_repo_timeout_secs")]
+    pub per_repo_timeout_secs: u64,
+    /// Stop looking for more repos under a watch once it has turned up this many, so a watch on
+    /// something huge like / doesn't bog the machine down. 0 for no limit.
+    #[serde(default = "default_max_repos_per_watch")]
+    pub max_repos_per_watch: usize,
+    /// Stop watching a directory once it's been missing for this many days, e.g. after the
+    /// project was deleted. Off by default, missing watch roots are only warned about.
+    #[serde(default)]
+    pub auto_unwatch_missing_after_days: Option<u32>,
+    /// How many snapshots per repo `dura history` remembers. 0 turns the history off.
+    #[serde(default = "default_history_size")]
+    pub history_size: usize,
+    /// Stop capturing while the filesystem holding a repo has less than this much space left
+    #[serde(default)]
+    pub min_free_space_mb: Option<u64>,
+    /// Look for changes with a cheaper heuristic based on directory mtimes. Some edits are only
+    /// noticed after a delay, see `PollGuard::set_fast`.
+    #[serde(default)]
+    pub fast_poll_guard: bool,
+    /// Whether snapshots are stored as branches ("heads", the default) or under refs/dura/
+    /// ("dura") where they stay out of `git branch`
+    #[serde(default)]
+    pub ref_namespace: RefNamespace,
+    /// Delete snapshot refs once their exact contents have been committed to a real branch
+    #[serde(default)]
+    pub auto_clean_merged: bool,
+    /// Start `dura serve` in the background from `dura watch` and `dura capture` when it isn't
+    /// running, as if `--ensure-daemon` was passed
+    #[serde(default)]
+    pub auto_start_daemon: bool,
+    /// How files tracked by Git LFS are snapshotted: "pointer", "skip" or "full" (the default)
+    #[serde(default)]
+    pub lfs_mode: LfsMode,
+    /// Run `git gc` after snapshot refs were cleaned up, so the disk space comes back
+    #[serde(default)]
+    pub run_gc_after_cleanup: bool,
+    /// Double check that no file content from outside a repo ends up in its snapshots through a
+    /// symlink. On by default.
+    #[serde(default = "default_forbid_external_symlink_content")]
+    pub forbid_external_symlink_content: bool,
+    /// Fail a repo's snapshots when neither dura's config nor git has a name and email for them,
+    /// instead of committing them as "dura"
+    #[serde(default)]
+    pub require_identity: bool,
+    /// Snapshot untracked files that usually hold secrets, like `.env` or `id_rsa`. Off by
+    /// default, so they're left out of snapshots (and listed in the snapshot's log line).
+    #[serde(default)]
+    pub snapshot_sensitive_files: bool,
+    /// Which files are sensitive. Glob patterns, matched against the file name, or the path
+    /// relative to the repo when they contain a `/`.
+    #[serde(default = "default_sensitive_files")]
+    pub sensitive_files: Vec<String>,
+    /// What `dura serve` logs when it's given no `-v`, `-q` or `--log-filter`, e.g. "debug" or a
+    /// full filter like "info,dura::git_repo_iter=trace". Defaults to "info".
+    #[serde(default)]
+    pub log_level: Option<String>,
+    /// How repo paths appear in logs: "off" (the default), "hash" or "basename"
+    #[serde(default)]
+    pub log_redact_paths: RedactPaths,
+    /// Whether paths that only differ in case are the same, for watches, excludes and
+    /// `deny_repos`: "auto" (the default) says they are on macOS and Windows, "sensitive" and
+    /// "insensitive" are for filesystems that don't do what their platform usually does
+    #[serde(default)]
+    pub path_case: PathCase,
+    /// Times of day when the poller shouldn't capture anything
+    #[serde(default)]
+    pub schedule: Schedule,
+    /// Desktop notifications when a repo starts or stops failing
+    #[serde(default)]
+    pub notifications: NotificationConfig,
     pub repos: BTreeMap<String, Rc<WatchConfig>>,
 }
 
+fn default_debounce_max_defer_loops() -> u32 {
+    12
+}
+
+fn default_per_repo_timeout_secs() -> u64 {
+    120
+}
+
+fn default_max_repos_per_watch() -> usize {
+    500
+}
+
+fn default_history_size() -> usize {
+    100
+}
+
+fn default_forbid_external_symlink_content() -> bool {
+    true
+}
+
+fn default_sensitive_files() -> Vec<String> {
+    [".env*", "*.pem", "*.key", "id_rsa*", "*.p12"]
+        .map(String::from)
+        .to_vec()
+}
+
 impl Config {
     pub fn empty() -> Self {
         Self {
+            version: CONFIG_VERSION,
             commit_exclude_git_config: false,
             commit_author: None,
             commit_email: None,
+            min_snapshot_interval_secs: None,
+            debounce_ms: None,
+            debounce_max_defer_loops: default_debounce_max_defer_loops(),
+            per_repo_timeout_secs: default_per_repo_timeout_secs(),
+            max_repos_per_watch: default_max_repos_per_watch(),
+            auto_unwatch_missing_after_days: None,
+            history_size: default_history_size(),
+            min_free_space_mb: None,
+            fast_poll_guard: false,
+            ref_namespace: RefNamespace::default(),
+            auto_clean_merged: false,
+            auto_start_daemon: false,
+            lfs_mode: LfsMode::default(),
+            run_gc_after_cleanup: false,
+            forbid_external_symlink_content: default_forbid_external_symlink_content(),
+            require_identity: false,
+            snapshot_sensitive_files: false,
+            sensitive_files: default_sensitive_files(),
+            log_level: None,
+            log_redact_paths: RedactPaths::default(),
+            path_case: PathCase::default(),
+            schedule: Schedule::default(),
+            notifications: NotificationConfig::default(),
             repos: BTreeMap::new(),
         }
     }
 
+    /// See `paths` for where it is and how to change it
     pub fn default_path() -> PathBuf {
-        Self::get_dura_config_home().join("config.toml")
-    }
-
-    /// Location of all config. By default
-    ///
-    /// Linux   :   $XDG_CONFIG_HOME/dura or $HOME/.config/dura
-    /// macOS   :   $HOME/Library/Application Support
-    /// Windows :   %AppData%\Roaming\dura
-    ///
-    /// This can be overridden by setting DURA_CONFIG_HOME environment variable.
-    fn get_dura_config_home() -> PathBuf {
-        // The environment variable lets us run tests independently, but I'm sure someone will come
-        // up with another reason to use it.
-        if let Ok(env_var) = env::var("DURA_CONFIG_HOME") {
-            if !env_var.is_empty() {
-                return env_var.into();
-            }
+        paths::current().config_file()
+    }
+
+    /// Load Config from default path. A missing file is the same as an empty config, but one that
+    /// can't be read or parsed is an error.
+    pub fn load() -> Result<Self> {
+        match Self::load_file(Self::default_path().as_path()) {
+            Err(e) if e.is_not_found() => Ok(Self::empty()),
+            res => res,
         }
+    }
+
+    /// Like `load`, but falls back to an empty config on any error. Only for callers that can't
+    /// do anything better, since it hides a broken config file.
+    pub fn load_or_default() -> Self {
+        Self::load().unwrap_or_else(|_| Self::empty())
+    }
 
-        dirs::config_dir()
-            .expect("Could not find your config directory. The default is ~/.config/dura but it can also \
-                be controlled by setting the DURA_CONFIG_HOME environment variable.")
-            .join("dura")
+    /// Warnings about config files read since the last call, each one only the first time it
+    /// comes up in this process. It's up to the caller to show them.
+    pub fn take_warnings() -> Vec<String> {
+        std::mem::take(&mut *WARNINGS.lock().unwrap_or_else(|e| e.into_inner()))
     }
 
-    /// Load Config from default path
-    pub fn load() -> Self {
-        Self::load_file(Self::default_path().as_path()).unwrap_or_else(|_| Self::empty())
+    /// How many times the config file at `path` has been parsed by this process. Lets tests
+    /// check that the config isn't reloaded more than it has to be.
+    pub fn load_count(path: &Path) -> usize {
+        let counts = LOAD_COUNTS.lock().unwrap_or_else(|e| e.into_inner());
+        counts.get(path).copied().unwrap_or(0)
     }
 
+    /// Reads the config at `path`, upgrading it from older versions. Settings dura doesn't know,
+    /// usually typos, and files written by a newer dura are warned about, see
+    /// `Config::take_warnings`.
     pub fn load_file(path: &Path) -> Result<Self> {
-        let mut reader = BufReader::new(File::open(path)?);
+        Self::load_bundle(path).map(|(config, _)| config)
+    }
+
+    /// Like `load_file`, but also returns the top-level settings the file sets, e.g. for
+    /// `dura config import` to tell them from the ones that are only defaults.
+    pub fn load_bundle(path: &Path) -> Result<(Self, BTreeSet<String>)> {
+        let (config, unknown, set) = Self::parse_file(path)?;
+        if config.version > CONFIG_VERSION {
+            warn_once(format!(
+                "{} was written by a newer dura (config version {}, this one knows up to {CONFIG_VERSION}). \
+                 Settings it doesn't know are ignored and it won't be saved.",
+                path.display(),
+                config.version
+            ));
+        } else if !unknown.is_empty() {
+            warn_once(format!(
+                "ignoring unknown settings in {}: {}",
+                path.display(),
+                unknown.join(", ")
+            ));
+        }
+        Ok((config, set))
+    }
+
+    /// Like `load_file`, but unknown settings, newer versions and includes or excludes that could
+    /// never match are errors. For `dura config check`.
+    pub fn check_file(path: &Path) -> Result<Self> {
+        let (config, keys, _) = Self::parse_file(path)?;
+        if config.version > CONFIG_VERSION {
+            return Err(ConfigError::TooNew {
+                path: path.to_path_buf(),
+                version: config.version,
+            });
+        }
+        if !keys.is_empty() {
+            return Err(ConfigError::UnknownKeys {
+                path: path.to_path_buf(),
+                keys,
+            });
+        }
+        let entries: Vec<String> = config
+            .repos
+            .iter()
+            .flat_map(|(root, watch)| {
+                watch
+                    .include
+                    .iter()
+                    .chain(&watch.exclude)
+                    .filter_map(|entry| normalize_watch_entry(entry).err())
+                    .map(move |invalid| format!("{root}: '{}' {}", invalid.entry, invalid.reason))
+            })
+            .collect();
+        if !entries.is_empty() {
+            return Err(ConfigError::InvalidWatchEntries {
+                path: path.to_path_buf(),
+                entries,
+            });
+        }
+        Ok(config)
+    }
+
+    /// The migrated config, the keys in the file that didn't make it into it, and the top-level
+    /// keys it has after migrating
+    fn parse_file(path: &Path) -> Result<(Self, Vec<String>, BTreeSet<String>)> {
+        *LOAD_COUNTS
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(path.to_path_buf())
+            .or_default() += 1;
+        let read_err = |source| ConfigError::Read {
+            path: path.to_path_buf(),
+            source,
+        };
+        let mut reader = BufReader::new(File::open(path).map_err(read_err)?);
 
         let mut buffer = Vec::new();
-        reader.read_to_end(&mut buffer)?;
+        reader.read_to_end(&mut buffer).map_err(read_err)?;
 
-        let res = toml::from_slice(buffer.as_slice())?;
-        Ok(res)
+        let parse_err = |e: toml::de::Error| ConfigError::Parse {
+            path: path.to_path_buf(),
+            message: e.to_string(),
+        };
+        let mut value: toml::Value = toml::from_slice(buffer.as_slice()).map_err(parse_err)?;
+        if let Some(table) = value.as_table_mut() {
+            migrate(table);
+        }
+        let config: Config = value.clone().try_into().map_err(parse_err)?;
+
+        let mut unknown = vec![];
+        if let Ok(known) = toml::Value::try_from(&config) {
+            unknown_keys(&value, &known, "", &mut unknown);
+        }
+        let set = value
+            .as_table()
+            .map(|table| table.keys().cloned().collect())
+            .unwrap_or_default();
+        Ok((config, unknown, set))
     }
 
     /// Save config to disk in ~/.config/dura/config.toml
-    pub fn save(&self) {
+    pub fn save(&self) -> Result<()> {
         self.save_to_path(Self::default_path().as_path())
     }
 
-    pub fn create_dir(path: &Path) {
+    pub fn create_dir(path: &Path) -> Result<()> {
         if let Some(dir) = path.parent() {
-            create_dir_all(dir)
-                .unwrap_or_else(|_| panic!("Failed to create directory at `{}`.\
-                    Dura stores its configuration in `{}/config.toml`, \
-                    where you can instruct dura to watch patterns of Git repositories, among other things. \
-                    See https://github.com/tkellogg/dura for more information.", dir.display(), path.display()))
+            create_dir_all(dir).map_err(|source| ConfigError::Write {
+                path: dir.to_path_buf(),
+                source,
+            })?;
         }
+        Ok(())
     }
 
     /// Attempts to create parent dirs, serialize `self` as TOML and write to disk.
-    pub fn save_to_path(&self, path: &Path) {
-        Self::create_dir(path);
-
-        let config_string = match toml::to_string(self) {
-            Ok(v) => v,
-            Err(e) => {
-                println!("Unexpected error when deserializing config: {e}");
-                return;
-            }
+    /// Refuses to overwrite a config from a newer dura, since that would lose whatever it added.
+    pub fn save_to_path(&self, path: &Path) -> Result<()> {
+        if self.version > CONFIG_VERSION {
+            return Err(ConfigError::TooNew {
+                path: path.to_path_buf(),
+                version: self.version,
+            });
+        }
+        Self::create_dir(path)?;
+
+        let current = Config {
+            version: CONFIG_VERSION,
+            ..self.clone()
         };
+        let config_string = toml::to_string(&current).map_err(|e| ConfigError::Serialize {
+            path: path.to_path_buf(),
+            message: e.to_string(),
+        })?;
 
-        match fs::write(path, config_string) {
-            Ok(_) => (),
-            Err(e) => println!("Unable to initialize dura config file: {e}"),
-        }
+        fs::write(path, config_string).map_err(|source| ConfigError::Write {
+            path: path.to_path_buf(),
+            source,
+        })
     }
 
-    pub fn set_watch(&mut self, path: String, cfg: WatchConfig) {
-        let abs_path = fs::canonicalize(path).expect("The provided path is not a directory");
-        let abs_path = abs_path
-            .to_str()
-            .expect("The provided path is not valid unicode");
+    /// Starts watching `path`, or applies `edit` to the existing watch if there is one. Fails if
+    /// any include or exclude in `edit` is invalid, see `normalize_watch_entry`.
+    pub fn set_watch(
+        &mut self,
+        path: String,
+        edit: &WatchEdit,
+    ) -> std::result::Result<WatchResult, WatchError> {
+        let edit = edit.normalized()?;
+        let abs_path = match fs::canonicalize(&path) {
+            Ok(abs_path) if abs_path.is_dir() => abs_path,
+            _ => return Err(WatchError::NotADirectory(path)),
+        };
+        let is_repo = snapshots::is_repo(&abs_path);
+        let abs_path = abs_path.to_str().ok_or(WatchError::NotUnicode(path))?;
+        // Spelled the way it was first watched
+        let abs_path = self
+            .path_case
+            .find_key(&self.repos, abs_path)
+            .map_or(abs_path, String::as_str)
+            .to_string();
+        let apply = |cfg: &WatchConfig| {
+            let mut cfg = edit.apply(cfg);
+            cfg.single_repo = is_repo && cfg.include.is_empty() && cfg.exclude.is_empty();
+            cfg
+        };
 
-        if self.repos.contains_key(abs_path) {
-            println!("{abs_path} is already being watched")
-        } else {
-            self.repos.insert(abs_path.to_string(), Rc::new(cfg));
-            println!("Started watching {abs_path}")
+        match self.repos.get(&abs_path) {
+            Some(existing) => {
+                let updated = apply(existing);
+                let changes = existing.diff(&updated);
+                if changes.is_empty() {
+                    Ok(WatchResult::Unchanged)
+                } else {
+                    self.repos.insert(abs_path, Rc::new(updated));
+                    Ok(WatchResult::Updated(changes))
+                }
+            }
+            None => {
+                let cfg = apply(&WatchConfig::new());
+                self.repos.insert(abs_path, Rc::new(cfg));
+                Ok(WatchResult::Created)
+            }
         }
     }
 
-    pub fn set_unwatch(&mut self, path: String) {
-        let abs_path = fs::canonicalize(path).expect("The provided path is not a directory");
+    /// Stops watching `path`. A path that no longer exists can still be unwatched by the
+    /// absolute path it was watched under.
+    pub fn set_unwatch(&mut self, path: String) -> std::result::Result<UnwatchResult, WatchError> {
+        let abs_path = match fs::canonicalize(&path) {
+            Ok(abs_path) => abs_path,
+            Err(_) if Path::new(&path).is_absolute() => PathBuf::from(&path),
+            Err(_) => return Err(WatchError::NotADirectory(path)),
+        };
         let abs_path = abs_path
             .to_str()
-            .expect("The provided path is not valid unicode")
+            .ok_or_else(|| WatchError::NotUnicode(path.clone()))?
             .to_string();
+        let abs_path = self
+            .path_case
+            .find_key(&self.repos, &abs_path)
+            .cloned()
+            .unwrap_or(abs_path);
+
+        Ok(match self.repos.remove(&abs_path) {
+            Some(_) => UnwatchResult::Removed(abs_path),
+            None => UnwatchResult::NotWatched(abs_path),
+        })
+    }
+
+    /// `per_repo_timeout_secs`. 0 would time out every repo on every loop, so it counts as 1.
+    pub fn per_repo_timeout(&self) -> Duration {
+        Duration::from_secs(self.per_repo_timeout_secs.max(1))
+    }
+
+    pub fn git_repos(&self) -> GitRepoIter<'_> {
+        GitRepoIter::new(self)
+    }
 
-        match self.repos.remove(&abs_path) {
-            Some(_) => {
-                println!("Stopped watching {abs_path}");
+    /// The enabled watch that the repo at `repo` (an absolute path) falls under, if its
+    /// `deny_repos` doesn't rule it out. With nested watches, the innermost one.
+    pub fn watch_root_of(&self, repo: &Path) -> Option<PathBuf> {
+        self.repos
+            .iter()
+            .map(|(root, watch)| (Path::new(root), watch))
+            .filter(|(root, watch)| {
+                watch.enabled
+                    && self.path_case.starts_with(repo, root)
+                    && !watch.denies(root, repo, self.path_case)
+            })
+            .max_by_key(|(root, _)| root.components().count())
+            .map(|(root, _)| root.to_path_buf())
+    }
+
+    /// A copy that can be carried to another machine. Watches under `home` are written as `~/...`
+    /// and `min_free_space_mb` is left out, since it depends on the disk.
+    pub fn export(&self, home: Option<&Path>) -> Config {
+        let mut portable = self.clone();
+        portable.min_free_space_mb = None;
+        portable.repos = self
+            .repos
+            .iter()
+            .map(|(path, watch)| {
+                let relative = home.and_then(|home| Path::new(path).strip_prefix(home).ok());
+                let path = match relative {
+                    Some(rest) if rest.as_os_str().is_empty() => "~".to_string(),
+                    Some(rest) => format!("~/{}", rest.display()),
+                    None => path.clone(),
+                };
+                (path, Rc::clone(watch))
+            })
+            .collect();
+        portable
+    }
+
+    /// Undoes `export` on this machine: expands `~` to `home` and checks every watch the way
+    /// `set_watch` would, except that the directory doesn't have to exist yet.
+    pub fn localize(mut self, home: Option<&Path>) -> std::result::Result<Config, WatchError> {
+        let mut repos = BTreeMap::new();
+        for (path, watch) in std::mem::take(&mut self.repos) {
+            let expanded = match path.strip_prefix('~') {
+                Some(rest) if rest.is_empty() || rest.starts_with('/') => match home {
+                    Some(home) => home.join(rest.trim_start_matches('/')),
+                    None => return Err(WatchError::NotAbsolute(path)),
+                },
+                _ => PathBuf::from(&path),
+            };
+            if !expanded.is_absolute() {
+                return Err(WatchError::NotAbsolute(path));
             }
-            None => println!("{abs_path} is not being watched"),
+            let key = expanded
+                .to_str()
+                .ok_or_else(|| WatchError::NotUnicode(path.clone()))?
+                .to_string();
+            let edit = WatchEdit {
+                include: Some(watch.include.clone()),
+                exclude: Some(watch.exclude.clone()),
+                ..Default::default()
+            }
+            .normalized()?;
+            let watch = WatchConfig {
+                include: edit.include.unwrap_or_default(),
+                exclude: edit.exclude.unwrap_or_default(),
+                ..watch.as_ref().clone()
+            };
+            repos.insert(key, Rc::new(watch));
         }
+        self.repos = repos;
+        Ok(self)
     }
 
-    pub fn git_repos(&self) -> GitRepoIter {
-        GitRepoIter::new(self)
+    /// Takes on `imported`, returning what changed in `WatchConfig::diff` format. Imported values
+    /// win wherever both configs have something. Without `replace`, watches that are only in
+    /// `self` are kept, and so are settings not in `set`, the ones the imported file actually
+    /// sets (see `load_bundle`). Settings that `export` leaves out keep their current values.
+    pub fn import(
+        &mut self,
+        imported: Config,
+        set: &BTreeSet<String>,
+        replace: bool,
+    ) -> Vec<String> {
+        let imported = match replace {
+            true => imported,
+            false => self.overlay(imported, set),
+        };
+        let mut changes = self.settings_diff(&imported);
+        let mut repos = match replace {
+            true => BTreeMap::new(),
+            false => self.repos.clone(),
+        };
+        for (path, watch) in imported.repos.iter() {
+            match self.repos.get(path) {
+                Some(existing) => {
+                    let diff = existing.diff(watch);
+                    if !diff.is_empty() {
+                        changes.push(format!("~ watch {path}"));
+                        changes.extend(diff.into_iter().map(|line| format!("    {line}")));
+                    }
+                }
+                None => changes.push(format!("+ watch {path}")),
+            }
+            repos.insert(path.clone(), Rc::clone(watch));
+        }
+        for path in self.repos.keys() {
+            if !repos.contains_key(path) {
+                changes.push(format!("- watch {path}"));
+            }
+        }
+
+        let min_free_space_mb = self.min_free_space_mb;
+        *self = Config {
+            repos,
+            min_free_space_mb,
+            ..imported
+        };
+        changes
+    }
+
+    /// `self`'s settings, with the top-level ones in `keys` taken from `other`. The watches are
+    /// `other`'s.
+    fn overlay(&self, other: Config, keys: &BTreeSet<String>) -> Config {
+        let table = |cfg: &Config| -> toml::value::Table {
+            toml::Value::try_from(cfg)
+                .ok()
+                .and_then(|value| value.try_into().ok())
+                .unwrap_or_default()
+        };
+        let (mut merged, theirs) = (table(self), table(&other));
+        for key in keys.iter().filter(|key| key.as_str() != "repos") {
+            match theirs.get(key) {
+                Some(value) => merged.insert(key.clone(), value.clone()),
+                None => merged.remove(key),
+            };
+        }
+        match toml::Value::Table(merged).try_into::<Config>() {
+            Ok(merged) => Config {
+                repos: other.repos,
+                ..merged
+            },
+            Err(_) => other,
+        }
+    }
+
+    /// Differences in everything but the watches, as `~ name: old -> new`
+    fn settings_diff(&self, other: &Config) -> Vec<String> {
+        let table = |cfg: &Config| -> BTreeMap<String, toml::Value> {
+            let cfg = Config {
+                repos: BTreeMap::new(),
+                min_free_space_mb: None,
+                ..cfg.clone()
+            };
+            toml::Value::try_from(cfg)
+                .ok()
+                .and_then(|value| value.try_into().ok())
+                .unwrap_or_default()
+        };
+        let (old, new) = (table(self), table(other));
+        let mut keys: Vec<&String> = old.keys().chain(new.keys()).collect();
+        keys.sort();
+        keys.dedup();
+        keys.into_iter()
+            .filter(|key| key.as_str() != "repos" && old.get(*key) != new.get(*key))
+            .map(|key| {
+                let show = |value: Option<&toml::Value>| match value {
+                    Some(value) => value.to_string(),
+                    None => "(unset)".to_string(),
+                };
+                format!("~ {key}: {} -> {}", show(old.get(key)), show(new.get(key)))
+            })
+            .collect()
+    }
+}
+
+/// Brings `table` up to `CONFIG_VERSION`. Newer versions are left alone.
+fn migrate(table: &mut toml::value::Table) {
+    let version = match table.get("version").and_then(|v| v.as_integer()) {
+        Some(version) => u32::try_from(version).unwrap_or(u32::MAX),
+        None => 0,
+    };
+    if version >= CONFIG_VERSION {
+        return;
+    }
+    for step in &MIGRATIONS[version as usize..] {
+        step(table);
+    }
+    table.insert(
+        "version".into(),
+        toml::Value::Integer(CONFIG_VERSION.into()),
+    );
+}
+
+/// Collects the keys of `raw` that aren't in `known`, as dotted paths
+fn unknown_keys(raw: &toml::Value, known: &toml::Value, prefix: &str, out: &mut Vec<String>) {
+    let (Some(raw), Some(known)) = (raw.as_table(), known.as_table()) else {
+        return;
+    };
+    for (key, value) in raw {
+        let path = match prefix {
+            "" => key.clone(),
+            _ => format!("{prefix}.{key}"),
+        };
+        match known.get(key) {
+            Some(known) => unknown_keys(value, known, &path, out),
+            None => out.push(path),
+        }
+    }
+}
+
+fn warn_once(message: String) {
+    let mut warned = WARNED.lock().unwrap_or_else(|e| e.into_inner());
+    if warned.insert(message.clone()) {
+        WARNINGS
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(message);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(items: &[&str]) -> Vec<String> {
+        items.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn edit_leaves_unmentioned_fields_alone() {
+        let cfg = WatchConfig {
+            include: strings(&["a/keep"]),
+            exclude: strings(&["a"]),
+            max_depth: 3,
+            ..Default::default()
+        };
+        let edit = WatchEdit {
+            add_exclude: strings(&["b"]),
+            ..Default::default()
+        };
+        let updated = edit.apply(&cfg);
+        assert_eq!(updated.include, strings(&["a/keep"]));
+        assert_eq!(updated.exclude, strings(&["a", "b"]));
+        assert_eq!(updated.max_depth, 3);
+        assert_eq!(cfg.diff(&updated), strings(&["+ exclude: b"]));
+    }
+
+    #[test]
+    fn diff_covers_every_setting() {
+        let cfg = WatchConfig::default();
+        let updated = WatchConfig {
+            snapshot_untracked: SnapshotUntracked::OnlyWithTrackedChanges,
+            ..Default::default()
+        };
+        assert_eq!(
+            cfg.diff(&updated),
+            strings(&["~ snapshot_untracked: \"always\" -> \"only_with_tracked_changes\""])
+        );
+        let updated = WatchConfig {
+            snapshot_sensitive_files: Some(true),
+            skip_on_branches: strings(&["release/*"]),
+            snapshot_stashes: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            cfg.diff(&updated),
+            strings(&[
+                "~ snapshot_sensitive_files: (unset) -> true",
+                "+ skip_on_branches: release/*",
+                "~ snapshot_stashes: false -> true",
+            ])
+        );
+    }
+
+    #[test]
+    fn edit_replaces_and_removes() {
+        let cfg = WatchConfig {
+            include: strings(&["a/keep"]),
+            exclude: strings(&["a", "b"]),
+            ..Default::default()
+        };
+        let edit = WatchEdit {
+            exclude: Some(strings(&["c"])),
+            remove_include: strings(&["a/keep"]),
+            max_depth: Some(2),
+            ..Default::default()
+        };
+        let updated = edit.apply(&cfg);
+        assert_eq!(updated.include, Vec::<String>::new());
+        assert_eq!(updated.exclude, strings(&["c"]));
+        assert_eq!(
+            cfg.diff(&updated),
+            strings(&[
+                "- include: a/keep",
+                "- exclude: a",
+                "- exclude: b",
+                "+ exclude: c",
+                "~ max_depth: 255 -> 2",
+            ])
+        );
+    }
+
+    #[test]
+    fn normalize_cleans_up() {
+        assert_eq!(normalize_watch_entry("build/").unwrap(), "build");
+        assert_eq!(
+            normalize_watch_entry("./build//keep/").unwrap(),
+            "build/keep"
+        );
+        assert_eq!(normalize_watch_entry("a/./b").unwrap(), "a/b");
+    }
+
+    #[test]
+    fn normalize_rejects_absolute() {
+        let err = normalize_watch_entry("/etc").unwrap_err();
+        assert_eq!(err.reason, "must be relative to the watch directory");
+    }
+
+    #[test]
+    fn normalize_rejects_parent_dir() {
+        assert!(normalize_watch_entry("../sibling").is_err());
+        assert!(normalize_watch_entry("a/../../b").is_err());
+    }
+
+    #[test]
+    fn normalize_rejects_watch_root() {
+        assert!(normalize_watch_entry(".").is_err());
+        assert!(normalize_watch_entry("").is_err());
+    }
+
+    #[test]
+    fn normalized_edit_matches_existing_entries() {
+        let cfg = WatchConfig {
+            exclude: strings(&["build"]),
+            ..Default::default()
+        };
+        let edit = WatchEdit {
+            remove_exclude: strings(&["build/"]),
+            ..Default::default()
+        };
+        let updated = edit.normalized().unwrap().apply(&cfg);
+        assert!(updated.exclude.is_empty());
+    }
+
+    #[test]
+    fn missing_entries_are_reported() {
+        let tmp = tempfile::tempdir().unwrap();
+        fs::create_dir(tmp.path().join("build")).unwrap();
+        let cfg = WatchConfig {
+            include: strings(&["build/keep"]),
+            exclude: strings(&["build"]),
+            ..Default::default()
+        };
+        assert_eq!(cfg.missing_entries(tmp.path()), strings(&["build/keep"]));
+    }
+
+    #[test]
+    fn add_is_idempotent() {
+        let cfg = WatchConfig {
+            exclude: strings(&["a"]),
+            ..Default::default()
+        };
+        let edit = WatchEdit {
+            add_exclude: strings(&["a"]),
+            ..Default::default()
+        };
+        assert!(cfg.diff(&edit.apply(&cfg)).is_empty());
+    }
+
+    #[test]
+    fn old_watches_are_enabled() {
+        let cfg: Config = toml::from_str(
+            r#"
+            [repos."/a"]
+            include = []
+            exclude = []
+            max_depth = 255
+            "#,
+        )
+        .unwrap();
+        assert!(cfg.repos["/a"].enabled);
+    }
+
+    #[test]
+    fn zero_per_repo_timeout_is_a_second() {
+        let mut config = Config::empty();
+        assert_eq!(config.per_repo_timeout(), Duration::from_secs(120));
+        config.per_repo_timeout_secs = 0;
+        assert_eq!(config.per_repo_timeout(), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn watches_differing_in_case() {
+        let tmp = tempfile::tempdir().unwrap();
+        let upper = tmp.path().join("Code");
+        let lower = tmp.path().join("code");
+        fs::create_dir(&upper).unwrap();
+        // Only possible on a case-sensitive filesystem, otherwise it's the same directory
+        let distinct = fs::create_dir(&lower).is_ok();
+        let upper = upper.to_str().unwrap().to_string();
+        let lower = lower.to_str().unwrap().to_string();
+
+        let mut cfg = Config::empty();
+        cfg.path_case = PathCase::Insensitive;
+        let edit = WatchEdit::default();
+        assert_eq!(
+            cfg.set_watch(upper.clone(), &edit),
+            Ok(WatchResult::Created)
+        );
+        assert_eq!(
+            cfg.set_watch(lower.clone(), &edit),
+            Ok(WatchResult::Unchanged)
+        );
+        let keys: Vec<&String> = cfg.repos.keys().collect();
+        assert_eq!(
+            keys,
+            vec![&fs::canonicalize(&upper).unwrap().display().to_string()]
+        );
+        assert!(matches!(
+            cfg.set_unwatch(lower.clone()),
+            Ok(UnwatchResult::Removed(_))
+        ));
+        assert!(cfg.repos.is_empty());
+
+        if distinct {
+            cfg.path_case = PathCase::Sensitive;
+            cfg.set_watch(upper.clone(), &edit).unwrap();
+            assert_eq!(
+                cfg.set_watch(lower.clone(), &edit),
+                Ok(WatchResult::Created)
+            );
+            assert_eq!(cfg.repos.len(), 2);
+        }
+    }
+
+    #[test]
+    fn deny_repos_globs() {
+        let cfg = WatchConfig {
+            deny_repos: strings(&["secrets-*", "/elsewhere/exact", "[bad"]),
+            ..Default::default()
+        };
+        let root = Path::new("/code");
+        let case = PathCase::Sensitive;
+        assert!(cfg.denies(root, Path::new("/code/secrets-repo"), case));
+        assert!(!cfg.denies(root, Path::new("/code/app"), case));
+        assert!(cfg.denies(root, Path::new("/elsewhere/exact"), case));
+        assert!(cfg.denies(root, Path::new("/code/[bad"), case));
+        assert!(!cfg.denies(root, Path::new("/code/Secrets-repo"), case));
+        assert!(!cfg.denies(root, Path::new("/Code/secrets-repo"), case));
+
+        let case = PathCase::Insensitive;
+        assert!(cfg.denies(root, Path::new("/code/Secrets-repo"), case));
+        assert!(cfg.denies(root, Path::new("/Code/secrets-repo"), case));
+        assert!(cfg.denies(root, Path::new("/Elsewhere/Exact"), case));
+    }
+
+    #[test]
+    fn unknown_keys_are_reported() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("config.toml");
+        fs::write(
+            &path,
+            r#"
+            debounce_ms = 500
+            [repos."/a"]
+            include = []
+            exclude = []
+            max_depth = 255
+            max_dpeth = 3
+            [schedule]
+            qiuet = ["22:00-06:00"]
+            "#,
+        )
+        .unwrap();
+
+        let cfg = Config::load_file(&path).unwrap();
+        assert_eq!(cfg.debounce_ms, Some(500));
+        assert_eq!(cfg.repos["/a"].max_depth, 255);
+        match Config::check_file(&path) {
+            Err(ConfigError::UnknownKeys { keys, .. }) => {
+                assert_eq!(keys, strings(&["repos./a.max_dpeth", "schedule.qiuet"]))
+            }
+            other => panic!("expected unknown keys, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn version_round_trip() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("config.toml");
+        // Written before versioning
+        fs::write(&path, "debounce_ms = 500\n[repos]\n").unwrap();
+        let cfg = Config::check_file(&path).unwrap();
+        assert_eq!(cfg.version, CONFIG_VERSION);
+
+        cfg.save_to_path(&path).unwrap();
+        let saved = fs::read_to_string(&path).unwrap();
+        assert!(saved.starts_with(&format!("version = {CONFIG_VERSION}\n")));
+        assert_eq!(Config::check_file(&path).unwrap(), cfg);
+    }
+
+    #[test]
+    fn newer_version_isnt_overwritten() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("config.toml");
+        let contents = "version = 99\nshiny_new_setting = true\n[repos]\n";
+        fs::write(&path, contents).unwrap();
+
+        let mut cfg = Config::load_file(&path).unwrap();
+        assert_eq!(cfg.version, 99);
+        cfg.debounce_ms = Some(500);
+        assert!(matches!(
+            cfg.save_to_path(&path),
+            Err(ConfigError::TooNew { version: 99, .. })
+        ));
+        assert_eq!(fs::read_to_string(&path).unwrap(), contents);
+        assert!(matches!(
+            Config::check_file(&path),
+            Err(ConfigError::TooNew { .. })
+        ));
+    }
+
+    #[test]
+    fn merge_import_keeps_settings_the_bundle_leaves_out() {
+        let tmp = tempfile::tempdir().unwrap();
+        let bundle = tmp.path().join("bundle.toml");
+        fs::write(&bundle, "fast_poll_guard = true\n\n[repos]\n").unwrap();
+        let mut local = Config::empty();
+        local.commit_author = Some("me".to_string());
+        local.auto_clean_merged = true;
+
+        let (imported, set) = Config::load_bundle(&bundle).unwrap();
+        let mut merged = local.clone();
+        let changes = merged.import(imported.clone(), &set, false);
+        assert_eq!(changes, strings(&["~ fast_poll_guard: false -> true"]));
+        assert!(merged.fast_poll_guard);
+        assert_eq!(merged.commit_author.as_deref(), Some("me"));
+        assert!(merged.auto_clean_merged);
+
+        // Replacing takes the bundle as it is
+        let mut replaced = local;
+        replaced.import(imported, &set, true);
+        assert_eq!(replaced.commit_author, None);
+        assert!(!replaced.auto_clean_merged);
     }
 }
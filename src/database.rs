@@ -1,81 +1,420 @@
-use std::fs::{create_dir_all, File};
-use std::io::Result;
+use std::collections::BTreeMap;
+use std::fs::File;
 use std::path::{Path, PathBuf};
-use std::{env, fs, io};
+use std::{fs, io};
 
+use chrono::{DateTime, Duration, NaiveTime, Utc};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 
+use crate::build_info::BuildInfo;
+use crate::config::{Config, ConfigError};
+use crate::paths;
+
+type Result<T> = std::result::Result<T, ConfigError>;
+
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub struct RuntimeLock {
     pub pid: Option<u32>,
+    /// The build of the process holding the lock
+    #[serde(default)]
+    pub build: Option<BuildInfo>,
+    /// With `dura serve --supervise`, the process that restarts the worker in `pid` when it
+    /// crashes
+    #[serde(default)]
+    pub supervisor_pid: Option<u32>,
+    /// The config file the process holding the lock reads, see `config_mismatch`
+    #[serde(default)]
+    pub config_path: Option<PathBuf>,
+    /// Set by a new `dura serve` while it waits for the one it's replacing to stop, so two
+    /// pollers never snapshot at the same time
+    #[serde(default)]
+    pub taking_over_from: Option<u32>,
 }
 
 impl RuntimeLock {
     pub fn empty() -> Self {
-        Self { pid: None }
+        Self {
+            pid: None,
+            build: None,
+            supervisor_pid: None,
+            config_path: None,
+            taking_over_from: None,
+        }
     }
 
+    /// See `paths` for where it is and how to change it
     pub fn default_path() -> PathBuf {
-        Self::get_dura_cache_home().join("runtime.db")
-    }
-
-    /// Location of all database files. By default
-    ///
-    /// Linux   :   $XDG_CACHE_HOME/dura or $HOME/.cache/dura
-    /// macOS   :   $HOME/Library/Caches
-    /// Windows :   %AppData%\Local\dura
-    ///
-    /// This can be overridden by setting DURA_CACHE_HOME environment variable.
-    fn get_dura_cache_home() -> PathBuf {
-        // The environment variable lets us run tests independently, but I'm sure someone will come
-        // up with another reason to use it.
-        if let Ok(env_var) = env::var("DURA_CACHE_HOME") {
-            if !env_var.is_empty() {
-                return env_var.into();
-            }
+        paths::current().runtime_lock()
+    }
+
+    /// Where `dura serve` logs to when it's started in the background by another command
+    pub fn default_log_path() -> PathBuf {
+        paths::current().cache_dir.join("dura.log")
+    }
+
+    /// Is the process holding the lock, or its supervisor, still running?
+    pub fn is_alive(&self) -> bool {
+        self.pid
+            .into_iter()
+            .chain(self.supervisor_pid)
+            .any(pid_alive)
+    }
+
+    /// The config file of a running daemon, when it isn't `ours`. Changes to `ours` won't reach
+    /// it, which usually means --config-dir or DURA_CONFIG_HOME is set differently for the daemon
+    /// and the CLI.
+    pub fn config_mismatch(&self, ours: &Path) -> Option<&Path> {
+        let theirs = self.config_path.as_deref()?;
+        if !self.is_alive() || same_file(theirs, ours) {
+            return None;
         }
+        Some(theirs)
+    }
 
-        dirs::cache_dir()
-            .expect("Could not find your cache directory. The default is ~/.cache/dura but it can also \
-                be controlled by setting the DURA_CACHE_HOME environment variable.")
-            .join("dura")
+    /// Load the lock from its default path. A missing file means nobody holds the lock.
+    pub fn load() -> Result<Self> {
+        match Self::load_file(Self::default_path().as_path()) {
+            Err(e) if e.is_not_found() => Ok(Self::empty()),
+            res => res,
+        }
     }
 
-    /// Load Config from default path
-    pub fn load() -> Self {
-        Self::load_file(Self::default_path().as_path()).unwrap_or_else(|_| Self::empty())
+    /// Like `load`, but treats an unreadable lock as if nobody holds it
+    pub fn load_or_default() -> Self {
+        Self::load().unwrap_or_else(|_| Self::empty())
     }
 
     pub fn load_file(path: &Path) -> Result<Self> {
-        let reader = io::BufReader::new(File::open(path)?);
-        let res = serde_json::from_reader(reader)?;
-        Ok(res)
+        let file = File::open(path).map_err(|source| ConfigError::Read {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        serde_json::from_reader(io::BufReader::new(file)).map_err(|e| ConfigError::Parse {
+            path: path.to_path_buf(),
+            message: e.to_string(),
+        })
     }
 
     /// Save config to disk in ~/.cache/dura/runtime.db
-    pub fn save(&self) {
+    pub fn save(&self) -> Result<()> {
         self.save_to_path(Self::default_path().as_path())
     }
 
-    pub fn create_dir(path: &Path) {
-        if let Some(dir) = path.parent() {
-            create_dir_all(dir).unwrap_or_else(|_| {
-                panic!(
-                    "Failed to create directory at `{}`.\
-                    Dura stores its runtime cache in `{}/runtime.db`. \
-                    See https://github.com/tkellogg/dura for more information.",
-                    dir.display(),
-                    path.display()
-                )
-            })
+    /// Attempts to create parent dirs, serialize `self` as JSON and write to disk.
+    pub fn save_to_path(&self, path: &Path) -> Result<()> {
+        Config::create_dir(path)?;
+
+        let json = serde_json::to_string(self).map_err(|e| ConfigError::Serialize {
+            path: path.to_path_buf(),
+            message: e.to_string(),
+        })?;
+        fs::write(path, json).map_err(|source| ConfigError::Write {
+            path: path.to_path_buf(),
+            source,
+        })
+    }
+}
+
+/// Watch roots that don't exist anymore, and since when. Kept next to the runtime lock so that
+/// `auto_unwatch_missing_after_days` counts across restarts.
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct MissingRoots {
+    pub since: BTreeMap<PathBuf, DateTime<Utc>>,
+}
+
+impl MissingRoots {
+    pub fn default_path() -> PathBuf {
+        RuntimeLock::default_path().with_file_name("missing_roots.json")
+    }
+
+    /// A missing or unreadable file means nothing was missing
+    pub fn load_or_default() -> Self {
+        load_json(&Self::default_path())
+    }
+
+    pub fn save(&self) -> Result<()> {
+        save_json(&Self::default_path(), self)
+    }
+
+    /// Brings the record up to date with the roots found missing just now. Returns the ones that
+    /// went missing and the ones that came back.
+    pub fn update(
+        &mut self,
+        missing: &[PathBuf],
+        now: DateTime<Utc>,
+    ) -> (Vec<PathBuf>, Vec<PathBuf>) {
+        let gone: Vec<PathBuf> = missing
+            .iter()
+            .filter(|root| !self.since.contains_key(*root))
+            .cloned()
+            .collect();
+        let back: Vec<PathBuf> = self
+            .since
+            .keys()
+            .filter(|root| !missing.contains(root))
+            .cloned()
+            .collect();
+        for root in &gone {
+            self.since.insert(root.clone(), now);
+        }
+        for root in &back {
+            self.since.remove(root);
         }
+        (gone, back)
     }
 
-    /// Attempts to create parent dirs, serialize `self` as JSON and write to disk.
-    pub fn save_to_path(&self, path: &Path) {
-        Self::create_dir(path);
+    /// Drops roots that aren't in `watched` anymore, e.g. after `dura unwatch`. They didn't come
+    /// back, so `update` mustn't report them as such. Returns whether any were dropped.
+    pub fn forget_unwatched(&mut self, watched: &[PathBuf]) -> bool {
+        let before = self.since.len();
+        self.since.retain(|root, _| watched.contains(root));
+        self.since.len() != before
+    }
+
+    /// Roots that have been missing for at least `days`
+    pub fn expired(&self, days: u32, now: DateTime<Utc>) -> Vec<PathBuf> {
+        self.since
+            .iter()
+            .filter(|(_, since)| now - **since >= Duration::days(days.into()))
+            .map(|(root, _)| root.clone())
+            .collect()
+    }
+}
+
+/// What the running poller is holding back on, for `dura doctor`. Kept next to the runtime lock
+/// and rewritten by the poller whenever it changes.
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PollerStatus {
+    /// Repos whose captures are suspended because their filesystem is below
+    /// `min_free_space_mb`
+    #[serde(default)]
+    pub low_disk: BTreeMap<PathBuf, LowDisk>,
+    /// Repos whose last capture took longer than `per_repo_timeout_secs`, and that the poller is
+    /// backing off from
+    #[serde(default)]
+    pub timeouts: BTreeMap<PathBuf, RepoTimeout>,
+    /// When the current quiet hours end, in local time, if we're in them
+    #[serde(default)]
+    pub quiet_until: Option<NaiveTime>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct LowDisk {
+    pub since: DateTime<Utc>,
+    /// As of the last time the poller looked
+    pub available_mb: u64,
+    pub min_free_space_mb: u64,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RepoTimeout {
+    /// Timeouts in a row
+    pub count: u32,
+    /// The repo isn't tried again before this
+    pub retry_after: DateTime<Utc>,
+}
+
+impl PollerStatus {
+    pub fn default_path() -> PathBuf {
+        RuntimeLock::default_path().with_file_name("poller_status.json")
+    }
+
+    /// A missing or unreadable file means nothing is held back
+    pub fn load_or_default() -> Self {
+        load_json(&Self::default_path())
+    }
+
+    pub fn save(&self) -> Result<()> {
+        save_json(&Self::default_path(), self)
+    }
+}
+
+/// Repos that `dura boost` asked to be snapshotted more often for a while, by canonical path.
+/// Kept next to the runtime lock, where both `dura boost` and the poller can get at it.
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Boosts {
+    pub repos: BTreeMap<PathBuf, Boost>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Boost {
+    pub until: DateTime<Utc>,
+    /// How often to look at the repo while boosted
+    pub interval_secs: u64,
+}
+
+impl Boosts {
+    pub fn default_path() -> PathBuf {
+        RuntimeLock::default_path().with_file_name("boosts.json")
+    }
+
+    /// A missing or unreadable file means nothing is boosted
+    pub fn load_or_default() -> Self {
+        load_json(&Self::default_path())
+    }
+
+    pub fn save(&self) -> Result<()> {
+        save_json(&Self::default_path(), self)
+    }
+
+    /// Boosts that haven't run out at `now`
+    pub fn active(&self, now: DateTime<Utc>) -> impl Iterator<Item = (&PathBuf, &Boost)> {
+        self.repos
+            .iter()
+            .filter(move |(_, boost)| boost.until > now)
+    }
+
+    /// Forgets boosts that ran out before `now`. Returns whether there were any.
+    pub fn remove_expired(&mut self, now: DateTime<Utc>) -> bool {
+        let before = self.repos.len();
+        self.repos.retain(|_, boost| boost.until > now);
+        self.repos.len() != before
+    }
+}
+
+/// The JSON files the daemon keeps next to the runtime lock. A missing or unreadable file reads
+/// as the default.
+fn load_json<T: DeserializeOwned + Default>(path: &Path) -> T {
+    File::open(path)
+        .ok()
+        .and_then(|file| serde_json::from_reader(io::BufReader::new(file)).ok())
+        .unwrap_or_default()
+}
+
+/// Writes to a temporary file first and renames it into place, so another process reading at
+/// the same time never sees half a file
+fn save_json<T: Serialize>(path: &Path, value: &T) -> Result<()> {
+    Config::create_dir(path)?;
+    let json = serde_json::to_string(value).map_err(|e| ConfigError::Serialize {
+        path: path.to_path_buf(),
+        message: e.to_string(),
+    })?;
+    // Per process, since `dura boost` and the poller may both be writing the same file
+    let tmp = path.with_extension(format!("{}.tmp", std::process::id()));
+    fs::write(&tmp, json)
+        .and_then(|_| fs::rename(&tmp, path))
+        .map_err(|source| {
+            let _ = fs::remove_file(&tmp);
+            ConfigError::Write {
+                path: path.to_path_buf(),
+                source,
+            }
+        })
+}
+
+/// Compares canonical paths where they exist, since a config home may be reached through a
+/// symlink. Only the parent has to exist, the file may not have been written yet.
+fn same_file(a: &Path, b: &Path) -> bool {
+    let canonical = |path: &Path| match (path.parent(), path.file_name()) {
+        (Some(dir), Some(name)) => dir
+            .canonicalize()
+            .map(|dir| dir.join(name))
+            .unwrap_or_else(|_| path.to_path_buf()),
+        _ => path.to_path_buf(),
+    };
+    canonical(a) == canonical(b)
+}
+
+/// Is there a running process with this pid?
+#[cfg(unix)]
+pub fn pid_alive(pid: u32) -> bool {
+    let raw_pid = match libc::pid_t::try_from(pid) {
+        Ok(pid) => pid,
+        Err(_) => return false,
+    };
+    // Signal 0 only checks whether the process could be signalled. EPERM means it exists but
+    // belongs to someone else.
+    let signalled = unsafe { libc::kill(raw_pid, 0) } == 0;
+    let exists = signalled || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM);
+    exists && !is_zombie(pid)
+}
+
+#[cfg(windows)]
+pub fn pid_alive(pid: u32) -> bool {
+    use windows_sys::Win32::Foundation::{
+        CloseHandle, GetLastError, ERROR_ACCESS_DENIED, STILL_ACTIVE,
+    };
+    use windows_sys::Win32::System::Threading::{
+        GetExitCodeProcess, OpenProcess, PROCESS_QUERY_LIMITED_INFORMATION,
+    };
+
+    let handle = unsafe { OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, 0, pid) };
+    if handle.is_null() {
+        // Exists, but e.g. elevated. No such process is ERROR_INVALID_PARAMETER.
+        return unsafe { GetLastError() } == ERROR_ACCESS_DENIED;
+    }
+    let mut code = 0u32;
+    let ok = unsafe { GetExitCodeProcess(handle, &mut code) };
+    unsafe { CloseHandle(handle) };
+    // A process that exited with 259 looks like it's still running, which errs on the safe side
+    ok == 0 || code == STILL_ACTIVE as u32
+}
+
+/// A process that exited but wasn't reaped by its parent yet can still be signalled. Only Linux
+/// can tell, from /proc, elsewhere this is always false.
+#[cfg(unix)]
+fn is_zombie(pid: u32) -> bool {
+    match fs::read_to_string(format!("/proc/{pid}/stat")) {
+        // The state comes right after the command, which is in parens and may contain spaces
+        Ok(stat) => stat
+            .rsplit_once(')')
+            .is_some_and(|(_, rest)| rest.trim_start().starts_with('Z')),
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn missing_roots_come_and_go() {
+        let mut missing = MissingRoots::default();
+        let start = Utc.timestamp_opt(1_000_000, 0).unwrap();
+        let a = PathBuf::from("/code/a");
+        let b = PathBuf::from("/code/b");
+
+        let (gone, back) = missing.update(&[a.clone(), b.clone()], start);
+        assert_eq!(gone, vec![a.clone(), b.clone()]);
+        assert!(back.is_empty());
+
+        let later = start + Duration::days(3);
+        let (gone, back) = missing.update(std::slice::from_ref(&a), later);
+        assert!(gone.is_empty());
+        assert_eq!(back, vec![b.clone()]);
+        // Still counted from when it first went missing
+        assert_eq!(missing.expired(3, later), vec![a.clone()]);
+        assert!(missing.expired(4, later).is_empty());
+
+        // Unwatched while it was missing, so it didn't come back
+        assert!(missing.forget_unwatched(std::slice::from_ref(&b)));
+        let (gone, back) = missing.update(&[], later);
+        assert!(gone.is_empty());
+        assert!(back.is_empty());
+        assert!(!missing.forget_unwatched(&[b]));
+    }
+
+    #[test]
+    fn json_saved_whole() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("boosts.json");
+        let mut boosts = Boosts::default();
+        boosts.repos.insert(
+            PathBuf::from("/code/a"),
+            Boost {
+                until: Utc.timestamp_opt(1_000_000, 0).unwrap(),
+                interval_secs: 5,
+            },
+        );
+
+        save_json(&path, &boosts).unwrap();
+        assert_eq!(load_json::<Boosts>(&path), boosts);
+        // Nothing left behind from writing it
+        assert_eq!(fs::read_dir(tmp.path()).unwrap().count(), 1);
 
-        let json = serde_json::to_string(self).unwrap();
-        fs::write(path, json).unwrap()
+        fs::write(&path, "{\"repos\":").unwrap();
+        assert_eq!(load_json::<Boosts>(&path), Boosts::default());
     }
 }
@@ -0,0 +1,220 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use tracing::{debug, warn};
+
+use crate::redact;
+
+/// How often to repeat the low disk warning for the same filesystem
+const WARN_INTERVAL: Duration = Duration::from_secs(600);
+
+/// Where `DiskGuard` gets its numbers from. Exists so the decision logic can be tested without
+/// filling up a disk. `Send` so the poller can ask from a blocking thread.
+pub trait SpaceProvider: Send {
+    /// Identifies the filesystem that `path` lives on, so we only ask once per filesystem.
+    fn volume(&self, path: &Path) -> io::Result<u64>;
+
+    /// Bytes available to this user on the filesystem that `path` lives on.
+    fn available(&self, path: &Path) -> io::Result<u64>;
+}
+
+/// Asks the OS (statvfs / GetDiskFreeSpaceEx)
+#[derive(Debug, Default)]
+pub struct SystemSpace;
+
+impl SpaceProvider for SystemSpace {
+    #[cfg(unix)]
+    fn volume(&self, path: &Path) -> io::Result<u64> {
+        use std::os::unix::fs::MetadataExt;
+        Ok(path.metadata()?.dev())
+    }
+
+    #[cfg(not(unix))]
+    fn volume(&self, path: &Path) -> io::Result<u64> {
+        use std::collections::hash_map::DefaultHasher;
+        use std::hash::{Hash, Hasher};
+        // The drive letter or UNC share is good enough
+        let mut hasher = DefaultHasher::new();
+        path.components().next().hash(&mut hasher);
+        Ok(hasher.finish())
+    }
+
+    fn available(&self, path: &Path) -> io::Result<u64> {
+        fs2::available_space(path)
+    }
+}
+
+/// Keeps dura from being the thing that fills up the disk. Free space is looked up once per
+/// filesystem per loop.
+pub struct DiskGuard {
+    provider: Box<dyn SpaceProvider>,
+    /// Free bytes per volume, for the current loop
+    cache: HashMap<u64, u64>,
+    last_warning: HashMap<u64, Instant>,
+}
+
+impl DiskGuard {
+    pub fn new(provider: Box<dyn SpaceProvider>) -> Self {
+        Self {
+            provider,
+            cache: HashMap::new(),
+            last_warning: HashMap::new(),
+        }
+    }
+
+    /// Forget free space numbers from the last loop
+    pub fn new_loop(&mut self) {
+        self.cache.clear();
+    }
+
+    /// Is there at least `min_free_bytes` available where `path` lives? Errors finding out are
+    /// logged and treated as "yes", since skipping snapshots is worse than the risk.
+    pub fn has_room(&mut self, path: &Path, min_free_bytes: u64) -> bool {
+        let (volume, available) = match self.available(path) {
+            Some(found) => found,
+            None => return true,
+        };
+
+        if available >= min_free_bytes {
+            return true;
+        }
+
+        let should_warn = match self.last_warning.get(&volume) {
+            Some(last) => last.elapsed() > WARN_INTERVAL,
+            None => true,
+        };
+        if should_warn {
+            warn!(
+                repo = redact::path(path).as_str(),
+                available_mb = available / 1024 / 1024,
+                min_free_space_mb = min_free_bytes / 1024 / 1024,
+                "captures suspended: low disk"
+            );
+            self.last_warning.insert(volume, Instant::now());
+        }
+        false
+    }
+
+    /// Bytes available where `path` lives, as of this loop. None when it couldn't be found out.
+    pub fn available_bytes(&mut self, path: &Path) -> Option<u64> {
+        self.available(path).map(|(_, available)| available)
+    }
+
+    fn available(&mut self, path: &Path) -> Option<(u64, u64)> {
+        let volume = match self.provider.volume(path) {
+            Ok(volume) => volume,
+            Err(e) => {
+                debug!(
+                    "Couldn't identify filesystem of {}: {e}",
+                    redact::path(path)
+                );
+                return None;
+            }
+        };
+        let available = match self.cache.get(&volume) {
+            Some(available) => *available,
+            None => match self.provider.available(path) {
+                Ok(available) => {
+                    self.cache.insert(volume, available);
+                    available
+                }
+                Err(e) => {
+                    debug!("Couldn't get free space of {}: {e}", redact::path(path));
+                    return None;
+                }
+            },
+        };
+        Some((volume, available))
+    }
+}
+
+impl Default for DiskGuard {
+    fn default() -> Self {
+        Self::new(Box::new(SystemSpace))
+    }
+}
+
+/// Implemented manually because the provider is a trait object
+impl std::fmt::Debug for DiskGuard {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DiskGuard")
+            .field("cache", &self.cache)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+
+    /// Everything under /a is on volume 1, everything else on volume 2
+    struct FakeSpace {
+        available: u64,
+        calls: Arc<AtomicU32>,
+    }
+
+    impl SpaceProvider for FakeSpace {
+        fn volume(&self, path: &Path) -> io::Result<u64> {
+            Ok(if path.starts_with("/a") { 1 } else { 2 })
+        }
+
+        fn available(&self, _path: &Path) -> io::Result<u64> {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+            Ok(self.available)
+        }
+    }
+
+    fn guard(available: u64) -> (DiskGuard, Arc<AtomicU32>) {
+        let calls = Arc::new(AtomicU32::new(0));
+        let provider = FakeSpace {
+            available,
+            calls: Arc::clone(&calls),
+        };
+        (DiskGuard::new(Box::new(provider)), calls)
+    }
+
+    #[test]
+    fn plenty_of_room() {
+        let (mut guard, _) = guard(1000);
+        assert!(guard.has_room(Path::new("/a/repo"), 500));
+    }
+
+    #[test]
+    fn below_threshold() {
+        let (mut guard, _) = guard(100);
+        assert!(!guard.has_room(Path::new("/a/repo"), 500));
+    }
+
+    #[test]
+    fn cached_per_volume_per_loop() {
+        let (mut guard, calls) = guard(1000);
+        guard.has_room(Path::new("/a/one"), 500);
+        guard.has_room(Path::new("/a/two"), 500);
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+        guard.has_room(Path::new("/b/three"), 500);
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+
+        guard.new_loop();
+        guard.has_room(Path::new("/a/one"), 500);
+        assert_eq!(calls.load(Ordering::Relaxed), 3);
+    }
+
+    #[test]
+    fn errors_allow_capture() {
+        struct Broken;
+        impl SpaceProvider for Broken {
+            fn volume(&self, _path: &Path) -> io::Result<u64> {
+                Ok(1)
+            }
+            fn available(&self, _path: &Path) -> io::Result<u64> {
+                Err(io::Error::other("nope"))
+            }
+        }
+        let mut guard = DiskGuard::new(Box::new(Broken));
+        assert!(guard.has_room(Path::new("/a/repo"), u64::MAX));
+    }
+}
@@ -0,0 +1,301 @@
+//! The settings that apply to one repo. They come in layers: dura's builtin defaults, the config
+//! file, the watch the repo falls under, and the command line, each one winning over the ones
+//! before it. Captures and the poller resolve settings through here, and `dura config show` prints
+//! the result along with where each value came from.
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::config::{Config, WatchConfig};
+use crate::snapshots::{LfsMode, RefNamespace, SnapshotUntracked};
+
+/// How long `dura serve` sleeps between loops, unless it's given `--interval`
+pub const DEFAULT_POLL_INTERVAL_SECS: u64 = 5;
+
+/// Where a setting's value came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Source {
+    Default,
+    /// The config file
+    Global,
+    /// The watch the repo falls under
+    Watch,
+    Cli,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Source::Default => "default",
+            Source::Global => "config file",
+            Source::Watch => "watch",
+            Source::Cli => "command line",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Setting<T> {
+    pub value: T,
+    pub source: Source,
+}
+
+impl<T> Setting<T> {
+    fn default(value: T) -> Self {
+        Self {
+            value,
+            source: Source::Default,
+        }
+    }
+
+    /// Replaced by `value`, when a higher layer has one
+    fn or_from(self, value: Option<T>, source: Source) -> Self {
+        match value {
+            Some(value) => Self { value, source },
+            None => self,
+        }
+    }
+}
+
+/// Settings given on the command line, e.g. to `dura serve`
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CliOverrides {
+    pub interval_secs: Option<u64>,
+}
+
+/// Everything that can set something for a repo
+#[derive(Debug, Clone)]
+pub struct Layers<'a> {
+    pub config: &'a Config,
+    /// The watch the repo falls under, and its root
+    pub watch: Option<(&'a Path, &'a WatchConfig)>,
+    pub cli: CliOverrides,
+}
+
+impl<'a> Layers<'a> {
+    pub fn new(config: &'a Config, watch: Option<(&'a Path, &'a WatchConfig)>) -> Self {
+        Self {
+            config,
+            watch,
+            cli: CliOverrides::default(),
+        }
+    }
+
+    /// With the watch that `repo` falls under, see `Config::watch_root_of`
+    pub fn for_repo(config: &'a Config, repo: &Path) -> Self {
+        let repo = fs::canonicalize(repo).unwrap_or_else(|_| repo.to_path_buf());
+        Self::for_canonical_repo(config, &repo)
+    }
+
+    /// Like `for_repo`, for a path that's already canonical. Doesn't touch the filesystem.
+    pub fn for_canonical_repo(config: &'a Config, repo: &Path) -> Self {
+        let watch = config
+            .watch_root_of(repo)
+            .and_then(|root| config.repos.get_key_value(root.to_str()?))
+            .map(|(root, watch)| (Path::new(root.as_str()), watch.as_ref()));
+        Self::new(config, watch)
+    }
+
+    /// A setting only watches have. Values other than the default count as set by the watch.
+    fn watch_only<T: Clone + Default + PartialEq>(
+        &self,
+        get: impl Fn(&WatchConfig) -> &T,
+    ) -> Setting<T> {
+        let value = self
+            .watch
+            .map(|(_, watch)| get(watch).clone())
+            .filter(|value| *value != T::default());
+        Setting::default(T::default()).or_from(value, Source::Watch)
+    }
+}
+
+/// The settings for one repo, with where each one came from
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct EffectiveConfig {
+    /// The watch the repo falls under. Without one, it's only snapshotted by `dura capture`.
+    pub watch_root: Option<PathBuf>,
+    pub commit_author: Setting<Option<String>>,
+    pub commit_email: Setting<Option<String>>,
+    pub commit_exclude_git_config: Setting<bool>,
+    pub require_identity: Setting<bool>,
+    pub exclude: Setting<Vec<String>>,
+    pub deny_repos: Setting<Vec<String>>,
+    pub snapshot_sensitive_files: Setting<bool>,
+    pub sensitive_files: Setting<Vec<String>>,
+    pub skip_on_branches: Setting<Vec<String>>,
+    pub snapshot_stashes: Setting<bool>,
+    pub snapshot_untracked: Setting<SnapshotUntracked>,
+    pub poll_interval_secs: Setting<u64>,
+    pub min_snapshot_interval_secs: Setting<Option<u64>>,
+    pub debounce_ms: Setting<Option<u64>>,
+    pub fast_poll_guard: Setting<bool>,
+    pub history_size: Setting<usize>,
+    pub auto_clean_merged: Setting<bool>,
+    pub ref_namespace: Setting<RefNamespace>,
+    pub lfs_mode: Setting<LfsMode>,
+    pub forbid_external_symlink_content: Setting<bool>,
+}
+
+impl EffectiveConfig {
+    pub fn resolve(layers: &Layers) -> Self {
+        let config = layers.config;
+        let defaults = Config::empty();
+        let watch = layers.watch.map(|(_, watch)| watch);
+        Self {
+            watch_root: layers.watch.map(|(root, _)| root.to_path_buf()),
+            commit_author: global(config.commit_author.clone(), defaults.commit_author),
+            commit_email: global(config.commit_email.clone(), defaults.commit_email),
+            commit_exclude_git_config: global(
+                config.commit_exclude_git_config,
+                defaults.commit_exclude_git_config,
+            ),
+            require_identity: global(config.require_identity, defaults.require_identity),
+            exclude: layers.watch_only(|w| &w.exclude),
+            deny_repos: layers.watch_only(|w| &w.deny_repos),
+            snapshot_sensitive_files: global(
+                config.snapshot_sensitive_files,
+                defaults.snapshot_sensitive_files,
+            )
+            .or_from(
+                watch.and_then(|w| w.snapshot_sensitive_files),
+                Source::Watch,
+            ),
+            sensitive_files: global(config.sensitive_files.clone(), defaults.sensitive_files),
+            skip_on_branches: layers.watch_only(|w| &w.skip_on_branches),
+            snapshot_stashes: layers.watch_only(|w| &w.snapshot_stashes),
+            snapshot_untracked: layers.watch_only(|w| &w.snapshot_untracked),
+            poll_interval_secs: Setting::default(DEFAULT_POLL_INTERVAL_SECS)
+                .or_from(layers.cli.interval_secs, Source::Cli),
+            min_snapshot_interval_secs: global(
+                config.min_snapshot_interval_secs,
+                defaults.min_snapshot_interval_secs,
+            )
+            .or_from(
+                watch.and_then(|w| w.min_snapshot_interval_secs.map(Some)),
+                Source::Watch,
+            ),
+            debounce_ms: global(config.debounce_ms, defaults.debounce_ms),
+            fast_poll_guard: global(config.fast_poll_guard, defaults.fast_poll_guard),
+            history_size: global(config.history_size, defaults.history_size),
+            auto_clean_merged: global(config.auto_clean_merged, defaults.auto_clean_merged),
+            ref_namespace: global(config.ref_namespace, defaults.ref_namespace),
+            lfs_mode: global(config.lfs_mode, defaults.lfs_mode),
+            forbid_external_symlink_content: global(
+                config.forbid_external_symlink_content,
+                defaults.forbid_external_symlink_content,
+            ),
+        }
+    }
+
+    /// Minimum time between snapshots of the repo, if any
+    pub fn min_snapshot_interval(&self) -> Option<Duration> {
+        self.min_snapshot_interval_secs
+            .value
+            .map(Duration::from_secs)
+    }
+
+    /// Each setting as `(name, value, source)`, in the order they're declared, for printing.
+    /// Unset values are "unset".
+    pub fn rows(&self) -> Vec<(&'static str, String, Source)> {
+        macro_rules! rows {
+            ($($field:ident),*) => {
+                vec![$((stringify!($field), display(&self.$field.value), self.$field.source)),*]
+            };
+        }
+        rows!(
+            commit_author,
+            commit_email,
+            commit_exclude_git_config,
+            require_identity,
+            exclude,
+            deny_repos,
+            snapshot_sensitive_files,
+            sensitive_files,
+            skip_on_branches,
+            snapshot_stashes,
+            snapshot_untracked,
+            poll_interval_secs,
+            min_snapshot_interval_secs,
+            debounce_ms,
+            fast_poll_guard,
+            history_size,
+            auto_clean_merged,
+            ref_namespace,
+            lfs_mode,
+            forbid_external_symlink_content
+        )
+    }
+}
+
+/// A setting from the config file. There's no telling whether the file has it when it's the
+/// same as the default, since saving the config writes out every setting, so it counts as the
+/// default then.
+fn global<T: PartialEq>(value: T, default: T) -> Setting<T> {
+    let source = match value == default {
+        true => Source::Default,
+        false => Source::Global,
+    };
+    Setting { value, source }
+}
+
+fn display<T: Serialize>(value: &T) -> String {
+    match serde_json::to_value(value) {
+        Ok(serde_json::Value::Null) => "unset".to_string(),
+        Ok(serde_json::Value::String(s)) => s,
+        Ok(value) => value.to_string(),
+        Err(_) => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+
+    #[test]
+    fn sources_follow_layers() {
+        let mut config = Config::empty();
+        config.commit_author = Some("Me".to_string());
+        config.min_snapshot_interval_secs = Some(60);
+        let watch = WatchConfig {
+            min_snapshot_interval_secs: Some(300),
+            exclude: vec!["vendor".to_string()],
+            ..WatchConfig::new()
+        };
+        config
+            .repos
+            .insert("/work".to_string(), Rc::new(watch.clone()));
+        let layers = Layers {
+            config: &config,
+            watch: Some((Path::new("/work"), &watch)),
+            cli: CliOverrides {
+                interval_secs: Some(2),
+            },
+        };
+
+        let effective = EffectiveConfig::resolve(&layers);
+        assert_eq!(effective.watch_root, Some(PathBuf::from("/work")));
+        assert_eq!(effective.commit_author.source, Source::Global);
+        assert_eq!(effective.commit_email.source, Source::Default);
+        assert_eq!(effective.min_snapshot_interval_secs.value, Some(300));
+        assert_eq!(effective.min_snapshot_interval_secs.source, Source::Watch);
+        assert_eq!(effective.exclude.source, Source::Watch);
+        assert_eq!(effective.deny_repos.source, Source::Default);
+        assert_eq!(effective.poll_interval_secs.value, 2);
+        assert_eq!(effective.poll_interval_secs.source, Source::Cli);
+
+        let rows = effective.rows();
+        assert_eq!(
+            rows.iter().find(|row| row.0 == "commit_email"),
+            Some(&("commit_email", "unset".to_string(), Source::Default))
+        );
+        assert_eq!(
+            rows.iter().find(|row| row.0 == "exclude"),
+            Some(&("exclude", "[\"vendor\"]".to_string(), Source::Watch))
+        );
+    }
+}
@@ -0,0 +1,205 @@
+//! `dura serve --events-fd`: a stream of what the daemon did, for editor plugins and other tools
+//! that would otherwise have to pick through the logs.
+//!
+//! Each line is one JSON `Event`. Unlike the logs, the format is part of dura's interface:
+//! fields are only ever added, and anything that would break a reader bumps `SCHEMA`. Paths are
+//! never redacted, the stream is meant for tools on the same machine.
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::snapshots::CaptureStatus;
+
+/// The version of the event format, in every event as `schema`
+pub const SCHEMA: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Event {
+    pub schema: u32,
+    pub time: DateTime<Utc>,
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+/// Which event it is, in the `event` field, e.g. `"event":"snapshot_created"`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum EventKind {
+    /// The poller took the runtime lock and is about to start its first loop
+    DaemonStarted { pid: u32, version: String },
+    /// A repo was seen for the first time since the daemon started
+    RepoDiscovered { repo: PathBuf },
+    SnapshotCreated {
+        repo: PathBuf,
+        /// The dura branch the snapshot was committed to
+        branch: String,
+        commit: String,
+        /// The commit HEAD pointed to
+        base: String,
+        files_changed: usize,
+        insertions: usize,
+        deletions: usize,
+    },
+    /// Snapshotting a repo failed or timed out
+    SnapshotError { repo: PathBuf, error: String },
+}
+
+impl EventKind {
+    pub fn snapshot_created(repo: PathBuf, status: &CaptureStatus) -> Self {
+        Self::SnapshotCreated {
+            repo,
+            branch: status.dura_branch.clone(),
+            commit: status.commit_hash.clone(),
+            base: status.base_hash.clone(),
+            files_changed: status.files_changed,
+            insertions: status.insertions,
+            deletions: status.deletions,
+        }
+    }
+}
+
+/// Where `--events-fd` sends the events: `stdout`, or any other value as a file to append to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EventTarget {
+    Stdout,
+    File(PathBuf),
+}
+
+impl FromStr for EventTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "" => Err("expected stdout or a file".to_string()),
+            "stdout" | "-" => Ok(Self::Stdout),
+            path => Ok(Self::File(PathBuf::from(path))),
+        }
+    }
+}
+
+impl fmt::Display for EventTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Stdout => write!(f, "stdout"),
+            Self::File(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+/// Writes events as they happen. The default one has nowhere to write, so emitting is a no-op.
+#[derive(Default)]
+pub struct EventSink {
+    out: Option<Box<dyn Write + Send>>,
+}
+
+impl fmt::Debug for EventSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EventSink")
+            .field("enabled", &self.out.is_some())
+            .finish()
+    }
+}
+
+impl EventSink {
+    pub fn open(target: &EventTarget) -> io::Result<Self> {
+        let out: Box<dyn Write + Send> = match target {
+            EventTarget::Stdout => Box::new(io::stdout()),
+            EventTarget::File(path) => {
+                Box::new(OpenOptions::new().append(true).create(true).open(path)?)
+            }
+        };
+        Ok(Self::to_writer(out))
+    }
+
+    pub fn to_writer(out: Box<dyn Write + Send>) -> Self {
+        Self { out: Some(out) }
+    }
+
+    /// Writes one line for `kind`. If that fails, e.g. because the tool reading stdout went away,
+    /// it's logged and nothing more is written.
+    pub fn emit(&mut self, kind: EventKind) {
+        let out = match &mut self.out {
+            Some(out) => out,
+            None => return,
+        };
+        let event = Event {
+            schema: SCHEMA,
+            time: Utc::now(),
+            kind,
+        };
+        let result = serde_json::to_vec(&event)
+            .map_err(io::Error::from)
+            .and_then(|mut line| {
+                line.push(b'\n');
+                out.write_all(&line)?;
+                out.flush()
+            });
+        if let Err(e) = result {
+            warn!("Couldn't write event, no more will be sent: {e}");
+            self.out = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn one_line_per_event() {
+        let buffer = Shared::default();
+        let mut sink = EventSink::to_writer(Box::new(buffer.clone()));
+        sink.emit(EventKind::RepoDiscovered {
+            repo: PathBuf::from("/src/dura"),
+        });
+        sink.emit(EventKind::SnapshotError {
+            repo: PathBuf::from("/src/dura"),
+            error: "locked".to_string(),
+        });
+
+        let written = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = written.lines().collect();
+        assert_eq!(lines.len(), 2);
+        let json: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(json["schema"], 1);
+        assert_eq!(json["event"], "repo_discovered");
+        assert_eq!(json["repo"], "/src/dura");
+        let event: Event = serde_json::from_str(lines[1]).unwrap();
+        assert_eq!(
+            event.kind,
+            EventKind::SnapshotError {
+                repo: PathBuf::from("/src/dura"),
+                error: "locked".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn target_from_str() {
+        assert_eq!("stdout".parse(), Ok(EventTarget::Stdout));
+        assert_eq!(
+            "/tmp/events".parse(),
+            Ok(EventTarget::File(PathBuf::from("/tmp/events")))
+        );
+    }
+}
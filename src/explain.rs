@@ -0,0 +1,222 @@
+//! `dura explain`: walks the same decisions `GitRepoIter` makes on the way down to one path, so
+//! it's possible to tell why dura does or doesn't snapshot it.
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use crate::config::Config;
+use crate::git_repo_iter::{check_directory, DirDecision};
+use crate::snapshots;
+
+/// Where the walk from the watch root down to the path ended
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    NoWatch,
+    /// The last step's decision kept dura out
+    Blocked,
+    /// Under a watch that's turned off
+    TurnedOff(PathBuf),
+    /// dura stops at the first repo it finds, so anything inside it is part of that repo
+    InsideRepo(PathBuf),
+    /// The path is this many directories below the watch root, more than `max_depth`
+    TooDeep(usize),
+    /// A repo, but ruled out by the watch's `deny_repos`
+    Denied,
+    Repo,
+    /// Not a repo, but dura looks for repos in it
+    Searched,
+}
+
+#[derive(Debug)]
+pub struct Explanation {
+    pub path: PathBuf,
+    /// The innermost watch covering `path`, and its max_depth
+    pub watch: Option<(PathBuf, u8)>,
+    /// Each directory from the watch root down, with the decision made about it
+    pub steps: Vec<(PathBuf, DirDecision)>,
+    pub outcome: Outcome,
+    /// Whether a full walk of the config, as the poller does it, turns up `path` as a repo
+    pub discovered: bool,
+}
+
+/// `path` should be absolute and canonical, like the watch roots in the config
+pub fn explain(config: &Config, path: &Path) -> Explanation {
+    let mut explanation = Explanation {
+        path: path.to_path_buf(),
+        watch: None,
+        steps: vec![],
+        outcome: Outcome::NoWatch,
+        discovered: config.git_repos().any(|repo| repo == path),
+    };
+    let watch = config
+        .repos
+        .iter()
+        .filter(|(root, _)| config.path_case.starts_with(path, Path::new(root)))
+        .max_by_key(|(root, _)| Path::new(root).components().count());
+    let (root, watch) = match watch {
+        Some((root, watch)) => (PathBuf::from(root), watch),
+        None => return explanation,
+    };
+    explanation.watch = Some((root.clone(), watch.max_depth));
+
+    let relative = config
+        .path_case
+        .strip_prefix(path, &root)
+        .unwrap_or_default();
+    let mut dir = root.clone();
+    let mut components = relative.components();
+    let mut depth = 0;
+    explanation.outcome = loop {
+        let decision = check_directory(&root, &dir, watch, config.path_case);
+        let allowed = decision.allowed();
+        explanation.steps.push((dir.clone(), decision));
+        if !allowed {
+            break Outcome::Blocked;
+        }
+        let disabled = config.repos.iter().find(|(other, cfg)| {
+            !cfg.enabled && config.path_case.starts_with(&dir, Path::new(other))
+        });
+        if let Some((other, _)) = disabled {
+            break Outcome::TurnedOff(PathBuf::from(other));
+        }
+        let is_repo = snapshots::is_repo(&dir);
+        match components.next() {
+            None if is_repo && watch.denies(&root, &dir, config.path_case) => {
+                break Outcome::Denied
+            }
+            None if is_repo => break Outcome::Repo,
+            None => break Outcome::Searched,
+            Some(_) if is_repo => break Outcome::InsideRepo(dir),
+            Some(component) => {
+                dir.push(component);
+                depth += 1;
+                if depth > usize::from(watch.max_depth) {
+                    break Outcome::TooDeep(depth);
+                }
+            }
+        }
+    };
+    explanation
+}
+
+impl fmt::Display for Explanation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", self.path.display())?;
+        match &self.watch {
+            Some((root, max_depth)) => {
+                writeln!(f, "watch: {} (max_depth {max_depth})", root.display())?
+            }
+            None => writeln!(f, "watch: none")?,
+        }
+        for (dir, decision) in &self.steps {
+            writeln!(f, "  {}: {decision}", dir.display())?;
+        }
+        let max_depth = self.watch.as_ref().map(|(_, depth)| *depth).unwrap_or(0);
+        match &self.outcome {
+            Outcome::NoWatch => writeln!(f, "not under any watch")?,
+            Outcome::Blocked => writeln!(f, "dura doesn't look here")?,
+            Outcome::TurnedOff(root) => {
+                writeln!(f, "the watch at {} is turned off", root.display())?
+            }
+            Outcome::InsideRepo(repo) => writeln!(
+                f,
+                "inside the repo {}, which dura snapshots as a whole",
+                repo.display()
+            )?,
+            Outcome::TooDeep(depth) => writeln!(
+                f,
+                "too deep: {depth} directories below the watch, max_depth is {max_depth}"
+            )?,
+            Outcome::Denied => writeln!(f, "a repo, but deny_repos rules it out")?,
+            Outcome::Repo => writeln!(f, "a repo, dura snapshots it")?,
+            Outcome::Searched => writeln!(f, "not a repo, dura looks for repos inside it")?,
+        }
+        let discovered = match self.discovered {
+            true => "yes",
+            false => "no",
+        };
+        write!(f, "currently discovered as a repo: {discovered}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::WatchConfig;
+    use std::fs;
+    use std::rc::Rc;
+
+    #[test]
+    fn exclude_then_include() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().canonicalize().unwrap();
+        let repo = root.join("vendor/ours");
+        fs::create_dir_all(&repo).unwrap();
+        git2::Repository::init(&repo).unwrap();
+        git2::Repository::init(root.join("vendor/theirs")).unwrap();
+
+        let mut config = Config::empty();
+        let watch = WatchConfig {
+            include: vec!["vendor/ours".to_string()],
+            exclude: vec!["vendor".to_string()],
+            ..WatchConfig::new()
+        };
+        let key = root.to_str().unwrap().to_string();
+        config.repos.insert(key, Rc::new(watch));
+
+        let ours = explain(&config, &repo);
+        assert_eq!(ours.outcome, Outcome::Repo);
+        assert!(ours.discovered);
+        let expected = format!(
+            "{root}/vendor/ours\n\
+             watch: {root} (max_depth 255)\n  \
+             {root}: ok\n  \
+             {root}/vendor: excluded by \"vendor\", let back in by include \"vendor/ours\"\n  \
+             {root}/vendor/ours: excluded by \"vendor\", let back in by include \"vendor/ours\"\n\
+             a repo, dura snapshots it\n\
+             currently discovered as a repo: yes",
+            root = root.display()
+        );
+        assert_eq!(ours.to_string(), expected);
+
+        let theirs = explain(&config, &root.join("vendor/theirs"));
+        assert_eq!(theirs.outcome, Outcome::Blocked);
+        assert!(!theirs.discovered);
+        assert_eq!(
+            theirs.steps.last().unwrap().1,
+            DirDecision::Excluded {
+                exclude: "vendor".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn inside_repo_and_too_deep() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().canonicalize().unwrap();
+        fs::create_dir_all(root.join("a/b/c")).unwrap();
+        git2::Repository::init(root.join("a")).unwrap();
+
+        let mut config = Config::empty();
+        let watch = WatchConfig {
+            max_depth: 1,
+            ..WatchConfig::new()
+        };
+        config
+            .repos
+            .insert(root.to_str().unwrap().to_string(), Rc::new(watch));
+
+        assert_eq!(
+            explain(&config, &root.join("a/b")).outcome,
+            Outcome::InsideRepo(root.join("a"))
+        );
+        fs::remove_dir_all(root.join("a/.git")).unwrap();
+        assert_eq!(
+            explain(&config, &root.join("a/b/c")).outcome,
+            Outcome::TooDeep(2)
+        );
+        assert_eq!(
+            explain(&config, Path::new("/elsewhere")).outcome,
+            Outcome::NoWatch
+        );
+    }
+}
@@ -0,0 +1,123 @@
+//! `dura find-file`: which snapshots have a copy of a file, e.g. untracked files that were
+//! deleted by accident. Snapshots only record what was there, so once a file is gone the newest
+//! snapshot that still has it is otherwise hard to find.
+use std::collections::HashMap;
+use std::path::Path;
+use std::rc::Rc;
+
+use chrono::{DateTime, TimeZone, Utc};
+use git2::{Error, ObjectType, Oid, Repository, Tree};
+use serde::Serialize;
+
+use crate::snapshots;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FoundFile {
+    pub commit: String,
+    pub timestamp: DateTime<Utc>,
+    /// Relative to the repo root
+    pub path: String,
+    pub blob: String,
+    pub size: usize,
+}
+
+/// Whether a path in a snapshot is one we're looking for. A pattern without a `/` also matches
+/// the file name alone, so `notes.txt` finds it in any directory.
+fn matches(pattern: &glob::Pattern, path: &str) -> bool {
+    if pattern.matches(path) {
+        return true;
+    }
+    !pattern.as_str().contains('/')
+        && path
+            .rsplit_once('/')
+            .is_some_and(|(_, name)| pattern.matches(name))
+}
+
+/// A blob that matched, as (path, blob, size)
+type Match = (String, Oid, usize);
+
+/// Walks trees, remembering what each one held at each path. Consecutive snapshots share most
+/// of their trees, so on a long history most lookups are answered from memory.
+struct TreeSearch<'r> {
+    repo: &'r Repository,
+    pattern: glob::Pattern,
+    seen: HashMap<(Oid, String), Rc<Vec<Match>>>,
+}
+
+impl TreeSearch<'_> {
+    fn search(&mut self, tree: &Tree, prefix: &str) -> Result<Rc<Vec<Match>>, Error> {
+        let key = (tree.id(), prefix.to_string());
+        if let Some(found) = self.seen.get(&key) {
+            return Ok(Rc::clone(found));
+        }
+        let mut found = vec![];
+        for entry in tree.iter() {
+            let name = match entry.name() {
+                Some(name) => name,
+                None => continue,
+            };
+            let path = format!("{prefix}{name}");
+            match entry.kind() {
+                Some(ObjectType::Tree) => {
+                    let subtree = self.repo.find_tree(entry.id())?;
+                    found.extend(self.search(&subtree, &format!("{path}/"))?.iter().cloned());
+                }
+                Some(ObjectType::Blob) if matches(&self.pattern, &path) => {
+                    let size = self.repo.find_blob(entry.id())?.size();
+                    found.push((path, entry.id(), size));
+                }
+                _ => (),
+            }
+        }
+        let found = Rc::new(found);
+        self.seen.insert(key, Rc::clone(&found));
+        Ok(found)
+    }
+}
+
+/// Every snapshot (on a dura branch or pinned) in the repo at `repo_path` with a file matching
+/// `pattern`, newest first. Commits on the user's own branches aren't looked at.
+pub fn find_file(repo_path: &Path, pattern: glob::Pattern) -> Result<Vec<FoundFile>, Error> {
+    let repo = snapshots::open_repo(repo_path)?;
+    let mut search = TreeSearch {
+        repo: &repo,
+        pattern,
+        seen: HashMap::new(),
+    };
+    let mut found = vec![];
+    for snapshot in snapshots::snapshot_walk(&repo)? {
+        let (_, commit) = snapshot?;
+        let commit = commit?;
+        let timestamp = Utc
+            .timestamp_opt(commit.time().seconds(), 0)
+            .single()
+            .unwrap_or_default();
+        for (path, blob, size) in search.search(&commit.tree()?, "")?.iter() {
+            found.push(FoundFile {
+                commit: commit.id().to_string(),
+                timestamp,
+                path: path.clone(),
+                blob: blob.to_string(),
+                size: *size,
+            });
+        }
+    }
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bare_names_match_in_any_directory() {
+        let pattern = glob::Pattern::new("notes.txt").unwrap();
+        assert!(matches(&pattern, "notes.txt"));
+        assert!(matches(&pattern, "src/notes.txt"));
+        assert!(!matches(&pattern, "src/notes.txt.bak"));
+
+        let pattern = glob::Pattern::new("src/*.rs").unwrap();
+        assert!(matches(&pattern, "src/main.rs"));
+        assert!(!matches(&pattern, "main.rs"));
+    }
+}
@@ -0,0 +1,202 @@
+//! `dura fsck`: whether snapshots can still be read back in full, e.g. after disk trouble.
+//!
+//! A snapshot is intact when every tree and blob its tree points at is in the object database
+//! and hashes to its id. Unlike `git fsck` this only looks at what snapshots refer to, and says
+//! which snapshots are affected rather than which objects.
+use std::collections::HashSet;
+use std::path::Path;
+
+use git2::{Error, ObjectType, Odb, Oid, Repository};
+use serde::Serialize;
+
+use crate::snapshots;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DamagedSnapshot {
+    pub commit: String,
+    /// What couldn't be read, e.g. the path of a blob that's missing
+    pub problem: String,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct FsckReport {
+    /// How many snapshots were checked
+    pub snapshots: usize,
+    pub damaged: Vec<DamagedSnapshot>,
+}
+
+/// Checks every snapshot (on a dura branch or pinned) in the repo at `path`
+pub fn check_repo(path: &Path) -> Result<FsckReport, Error> {
+    let repo = snapshots::open_repo(path)?;
+    let mut report = FsckReport::default();
+    let mut walk = snapshots::snapshot_walk(&repo)?;
+    for (name, target, e) in walk.broken.drain(..) {
+        report.snapshots += 1;
+        report.damaged.push(DamagedSnapshot {
+            commit: target.map(|oid| oid.to_string()).unwrap_or_default(),
+            problem: format!("{name}: {}", e.message()),
+        });
+    }
+
+    let mut verifier = Verifier::new(&repo)?;
+    for found in walk {
+        let (oid, commit) = found?;
+        let problem = match commit {
+            Ok(commit) => verifier.tree(commit.tree_id(), "").err(),
+            Err(e) => Some(format!("commit is unreadable: {}", e.message())),
+        };
+        report.snapshots += 1;
+        if let Some(problem) = problem {
+            report.damaged.push(DamagedSnapshot {
+                commit: oid.to_string(),
+                problem,
+            });
+        }
+    }
+    Ok(report)
+}
+
+/// Checks that `paths` (files or directories, relative to the repo) can be read back from the
+/// snapshot `commit`, before anything is written from it. Paths the snapshot doesn't have are
+/// fine, there's nothing to read.
+pub fn verify_paths(repo: &Repository, commit: Oid, paths: &[&Path]) -> Result<(), String> {
+    let damaged =
+        |problem: String| format!("snapshot {commit} is damaged, try an older one: {problem}");
+    let tree = repo
+        .find_commit(commit)
+        .and_then(|commit| commit.tree())
+        .map_err(|e| damaged(e.message().to_string()))?;
+    let mut verifier = Verifier::new(repo).map_err(|e| e.message().to_string())?;
+    for path in paths {
+        let entry = match tree.get_path(path) {
+            Ok(entry) => entry,
+            Err(e) if e.code() == git2::ErrorCode::NotFound => continue,
+            Err(e) => return Err(damaged(e.message().to_string())),
+        };
+        let display = path.to_string_lossy();
+        match entry.kind() {
+            Some(ObjectType::Tree) => verifier.tree(entry.id(), &format!("{display}/")),
+            Some(ObjectType::Blob) => verifier.blob(entry.id(), &display),
+            _ => Ok(()),
+        }
+        .map_err(damaged)?;
+    }
+    Ok(())
+}
+
+/// Reads objects back, remembering the ones that were fine. Consecutive snapshots share most of
+/// their trees, so each is only read once.
+struct Verifier<'r> {
+    repo: &'r Repository,
+    odb: Odb<'r>,
+    intact: HashSet<Oid>,
+}
+
+impl<'r> Verifier<'r> {
+    fn new(repo: &'r Repository) -> Result<Self, Error> {
+        Ok(Self {
+            repo,
+            odb: repo.odb()?,
+            intact: HashSet::new(),
+        })
+    }
+
+    /// `prefix` is where the tree is in the snapshot, for the problem's description
+    fn tree(&mut self, id: Oid, prefix: &str) -> Result<(), String> {
+        if self.intact.contains(&id) {
+            return Ok(());
+        }
+        let tree = self
+            .repo
+            .find_tree(id)
+            .map_err(|e| format!("{prefix}: tree {id} is unreadable: {}", e.message()))?;
+        for entry in tree.iter() {
+            let path = format!("{prefix}{}", entry.name().unwrap_or_default());
+            match entry.kind() {
+                Some(ObjectType::Tree) => self.tree(entry.id(), &format!("{path}/"))?,
+                Some(ObjectType::Blob) => self.blob(entry.id(), &path)?,
+                // Submodules live in their own repo
+                _ => (),
+            }
+        }
+        self.intact.insert(id);
+        Ok(())
+    }
+
+    fn blob(&mut self, id: Oid, path: &str) -> Result<(), String> {
+        if self.intact.contains(&id) {
+            return Ok(());
+        }
+        let object = self
+            .odb
+            .read(id)
+            .map_err(|e| format!("{path}: blob {id} is unreadable: {}", e.message()))?;
+        let actual = Oid::hash_object(ObjectType::Blob, object.data())
+            .map_err(|e| format!("{path}: {}", e.message()))?;
+        if object.kind() != ObjectType::Blob || actual != id {
+            return Err(format!("{path}: blob {id} doesn't match its contents"));
+        }
+        self.intact.insert(id);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshots::CaptureConfig;
+    use git2::Signature;
+    use std::fs;
+
+    #[test]
+    fn damaged_blob_is_found() {
+        let tmp = tempfile::tempdir().unwrap();
+        let repo = Repository::init(tmp.path()).unwrap();
+        let sig = Signature::now("a", "a@example.com").unwrap();
+        let tree = repo
+            .find_tree(repo.index().unwrap().write_tree().unwrap())
+            .unwrap();
+        repo.commit(Some("HEAD"), &sig, &sig, "initial", &tree, &[])
+            .unwrap();
+        fs::write(tmp.path().join("notes.txt"), "only in the snapshot").unwrap();
+        let status = snapshots::capture_with(tmp.path(), &CaptureConfig::default())
+            .unwrap()
+            .unwrap();
+        let snapshot = Oid::from_str(&status.commit_hash).unwrap();
+        let notes = Path::new("notes.txt");
+
+        let report = check_repo(tmp.path()).unwrap();
+        assert_eq!(report.snapshots, 1);
+        assert!(report.damaged.is_empty(), "{report:?}");
+        assert_eq!(verify_paths(&repo, snapshot, &[notes]), Ok(()));
+
+        let blob = repo
+            .find_commit(snapshot)
+            .unwrap()
+            .tree()
+            .unwrap()
+            .get_path(notes)
+            .unwrap()
+            .id()
+            .to_string();
+        let object = tmp
+            .path()
+            .join(".git/objects")
+            .join(&blob[..2])
+            .join(&blob[2..]);
+        let mut permissions = fs::metadata(&object).unwrap().permissions();
+        #[allow(clippy::permissions_set_readonly_false)]
+        permissions.set_readonly(false);
+        fs::set_permissions(&object, permissions).unwrap();
+        fs::write(&object, b"not zlib").unwrap();
+
+        // Needs a fresh handle, the old one may have cached the blob
+        let repo = Repository::open(tmp.path()).unwrap();
+        let err = verify_paths(&repo, snapshot, &[notes]).unwrap_err();
+        assert!(err.contains("damaged, try an older one"), "{err}");
+        assert!(err.contains("notes.txt"), "{err}");
+        let report = check_repo(tmp.path()).unwrap();
+        assert_eq!(report.damaged.len(), 1, "{report:?}");
+        assert_eq!(report.damaged[0].commit, status.commit_hash);
+    }
+}
@@ -1,9 +1,14 @@
-use std::collections::btree_map;
+use std::collections::{btree_map, BTreeMap, HashMap};
 use std::fs;
 use std::path::{Path, PathBuf};
 use std::rc::Rc;
 
+use serde::{Deserialize, Serialize};
+use tracing::{trace, warn};
+
 use crate::config::{Config, WatchConfig};
+use crate::path_case::PathCase;
+use crate::redact;
 use crate::snapshots;
 
 /// Internal structure to facilitate "recursion" without blowing up the stack. Without this, we
@@ -11,11 +16,24 @@ use crate::snapshots;
 /// of a directory listing. There's no stack space used because we just mutate GitRepoIter, so
 /// might as well turn it into a loop.
 enum CallState {
-    Yield(PathBuf),
+    Yield(Rc<PathBuf>, Rc<WatchConfig>, PathBuf),
     Recurse,
     Done,
 }
 
+/// What discovery did under one watch root, for telling why a repo wasn't found: it's usually
+/// just past `max_depth` or under an exclude.
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct DiscoveryStats {
+    pub dirs_visited: usize,
+    /// Directories not looked inside because they're deeper than `max_depth`
+    pub pruned_by_depth: usize,
+    pub pruned_by_exclude: usize,
+    /// Under an exclude, but looked inside anyway on the way to an include
+    pub readmitted_by_include: usize,
+    pub repos_found: usize,
+}
+
 /// Iterator over all Git repos covered by a config.
 ///
 /// The process is naturally recursive, traversing a directory structure, which made it a poor fit
@@ -29,6 +47,23 @@ pub struct GitRepoIter<'a> {
     config_iter: btree_map::Iter<'a, String, Rc<WatchConfig>>,
     /// A stack, because we can't use recursion with an iterator (at least not between elements)
     sub_iter: Vec<(Rc<PathBuf>, Rc<WatchConfig>, fs::ReadDir)>,
+    /// The watch root & config of the most recently yielded repo
+    current: Option<(Rc<PathBuf>, Rc<WatchConfig>)>,
+    /// Every watch root and whether it's turned on. Repos whose innermost watch is turned off
+    /// are skipped even when another watch would find them.
+    roots: Vec<(PathBuf, bool)>,
+    /// Repos that were found but not yielded because of `WatchConfig::deny_repos`, with the watch
+    /// root they were found under
+    denied: HashMap<PathBuf, PathBuf>,
+    dirs_listed: usize,
+    /// `Config::max_repos_per_watch`, 0 for no limit
+    max_repos: usize,
+    /// Repos yielded so far from the watch being walked
+    repos_in_watch: usize,
+    /// Watch roots that had more repos than `max_repos`
+    capped: Vec<PathBuf>,
+    stats: BTreeMap<PathBuf, DiscoveryStats>,
+    case: PathCase,
 }
 
 impl<'a> GitRepoIter<'a> {
@@ -36,9 +71,83 @@ impl<'a> GitRepoIter<'a> {
         Self {
             config_iter: config.repos.iter(),
             sub_iter: Vec::new(),
+            current: None,
+            roots: config
+                .repos
+                .iter()
+                .map(|(root, watch)| (PathBuf::from(root), watch.enabled))
+                .collect(),
+            denied: HashMap::new(),
+            dirs_listed: 0,
+            max_repos: config.max_repos_per_watch,
+            repos_in_watch: 0,
+            capped: vec![],
+            stats: BTreeMap::new(),
+            case: config.path_case,
         }
     }
 
+    /// How many directories have been listed so far
+    pub fn dirs_listed(&self) -> usize {
+        self.dirs_listed
+    }
+
+    fn read_dir(&mut self, path: &Path) -> Option<fs::ReadDir> {
+        self.dirs_listed += 1;
+        fs::read_dir(path).ok()
+    }
+
+    /// Watch roots that turned up more than `max_repos_per_watch` repos. Only the first ones
+    /// found were yielded.
+    pub fn capped(&self) -> &[PathBuf] {
+        &self.capped
+    }
+
+    /// What was visited and pruned under each watch root walked so far
+    pub fn stats(&self) -> &BTreeMap<PathBuf, DiscoveryStats> {
+        &self.stats
+    }
+
+    fn stats_for(&mut self, root: &Path) -> &mut DiscoveryStats {
+        self.stats.entry(root.to_path_buf()).or_default()
+    }
+
+    /// Repos skipped so far because they matched a watch's `deny_repos`
+    pub fn denied(&self) -> &HashMap<PathBuf, PathBuf> {
+        &self.denied
+    }
+
+    /// The watch root (a key of `Config::repos`) that the most recently yielded repo was
+    /// discovered under.
+    pub fn watch_root(&self) -> Option<&Path> {
+        self.current.as_ref().map(|(root, _)| root.as_path())
+    }
+
+    /// The watch config that the most recently yielded repo was discovered under.
+    pub fn watch_config(&self) -> Option<&WatchConfig> {
+        self.current.as_ref().map(|(_, cfg)| cfg.as_ref())
+    }
+
+    /// Whether the innermost watch `path` is under is turned off, as in `Config::watch_root_of`.
+    /// A watch that's turned on inside one that's off still counts.
+    fn in_disabled_watch(&self, path: &Path) -> bool {
+        self.roots
+            .iter()
+            .filter(|(root, _)| self.case.starts_with(path, root))
+            .max_by_key(|(root, _)| root.components().count())
+            .is_some_and(|(_, enabled)| !enabled)
+    }
+
+    fn cap_watch(&mut self, root: &Path) {
+        self.sub_iter.clear();
+        warn!(
+            watch = redact::path(root).as_str(),
+            max_repos_per_watch = self.max_repos,
+            "Watch has more repos than max_repos_per_watch, skipping the rest"
+        );
+        self.capped.push(root.to_path_buf());
+    }
+
     fn get_next(&mut self) -> CallState {
         // pop
         //
@@ -52,18 +161,51 @@ impl<'a> GitRepoIter<'a> {
                 let max_depth: usize = watch_config.max_depth.into();
                 if let Some(Ok(entry)) = dir_iter.next() {
                     let child_path = entry.path();
-                    if is_valid_directory(base_path.as_path(), child_path.as_path(), &watch_config)
-                    {
-                        if snapshots::is_repo(child_path.as_path()) {
-                            ret_val = CallState::Yield(child_path);
+                    let decision = check_directory(
+                        base_path.as_path(),
+                        child_path.as_path(),
+                        &watch_config,
+                        self.case,
+                    );
+                    match decision {
+                        DirDecision::Excluded { .. } => {
+                            self.stats_for(&base_path).pruned_by_exclude += 1
+                        }
+                        DirDecision::Readmitted { .. } => {
+                            self.stats_for(&base_path).readmitted_by_include += 1
+                        }
+                        _ => (),
+                    }
+                    if decision.allowed() {
+                        if self.in_disabled_watch(&child_path) {
+                            // Part of a watch that's turned off
+                        } else if snapshots::is_repo(child_path.as_path()) {
+                            if watch_config.denies(&base_path, &child_path, self.case) {
+                                self.denied.insert(child_path, base_path.to_path_buf());
+                            } else if self.max_repos > 0 && self.repos_in_watch >= self.max_repos {
+                                // Leave the rest of this watch alone, dir_iter isn't put back
+                                self.cap_watch(&base_path);
+                                return CallState::Recurse;
+                            } else {
+                                self.repos_in_watch += 1;
+                                self.stats_for(&base_path).repos_found += 1;
+                                ret_val = CallState::Yield(
+                                    Rc::clone(&base_path),
+                                    Rc::clone(&watch_config),
+                                    child_path,
+                                );
+                            }
                         } else if self.sub_iter.len() < max_depth {
-                            if let Ok(child_dir_iter) = fs::read_dir(child_path.as_path()) {
+                            if let Some(child_dir_iter) = self.read_dir(child_path.as_path()) {
+                                self.stats_for(&base_path).dirs_visited += 1;
                                 next_next = Some((
                                     Rc::clone(&base_path),
                                     Rc::clone(&watch_config),
                                     child_dir_iter,
                                 ))
                             }
+                        } else {
+                            self.stats_for(&base_path).pruned_by_depth += 1;
                         }
                     }
                     // un-pop
@@ -78,10 +220,29 @@ impl<'a> GitRepoIter<'a> {
             }
             None => {
                 // Finished dir, queue up next hashmap pair
+                self.repos_in_watch = 0;
                 match self.config_iter.next() {
+                    Some((_, watch_config)) if !watch_config.enabled => CallState::Recurse,
+                    // Only falls back to discovery if the directory stopped being a repo
+                    Some((base_path, watch_config))
+                        if watch_config.single_repo && snapshots::is_repo(Path::new(base_path)) =>
+                    {
+                        let path = PathBuf::from(base_path);
+                        if self.in_disabled_watch(&path) {
+                            CallState::Recurse
+                        } else if watch_config.denies(&path, &path, self.case) {
+                            self.denied.insert(path.clone(), path);
+                            CallState::Recurse
+                        } else {
+                            self.stats_for(&path).repos_found += 1;
+                            CallState::Yield(Rc::new(path.clone()), Rc::clone(watch_config), path)
+                        }
+                    }
                     Some((base_path, watch_config)) => {
                         let path = PathBuf::from(base_path);
-                        let dir_iter_opt = path.parent().and_then(|p| fs::read_dir(p).ok());
+                        // So a watch that turns up nothing at all still shows up
+                        self.stats_for(&path);
+                        let dir_iter_opt = path.parent().and_then(|p| self.read_dir(p));
                         if let Some(dir_iter) = dir_iter_opt {
                             // clone because we're going from more global to less global scope
                             self.sub_iter
@@ -103,7 +264,15 @@ impl<'a> Iterator for GitRepoIter<'a> {
     fn next(&mut self) -> Option<Self::Item> {
         loop {
             match self.get_next() {
-                CallState::Yield(path) => return Some(path),
+                CallState::Yield(root, watch_config, path) => {
+                    trace!(
+                        repo = redact::path(&path).as_str(),
+                        watch = redact::path(&root).as_str(),
+                        "repo_found"
+                    );
+                    self.current = Some((root, watch_config));
+                    return Some(path);
+                }
                 CallState::Recurse => continue,
                 CallState::Done => return None,
             }
@@ -111,34 +280,106 @@ impl<'a> Iterator for GitRepoIter<'a> {
     }
 }
 
+/// Whether the poller looks inside a directory under a watch, and which rule decided it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DirDecision {
+    NotADirectory,
+    OutsideWatch,
+    Allowed,
+    Excluded {
+        exclude: String,
+    },
+    /// Under an exclude, but on the way to an include
+    Readmitted {
+        exclude: String,
+        include: String,
+    },
+}
+
+impl DirDecision {
+    pub fn allowed(&self) -> bool {
+        matches!(self, DirDecision::Allowed | DirDecision::Readmitted { .. })
+    }
+}
+
+impl std::fmt::Display for DirDecision {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DirDecision::NotADirectory => write!(f, "not a directory"),
+            DirDecision::OutsideWatch => write!(f, "outside the watch"),
+            DirDecision::Allowed => write!(f, "ok"),
+            DirDecision::Excluded { exclude } => write!(f, "excluded by {exclude:?}"),
+            DirDecision::Readmitted { exclude, include } => write!(
+                f,
+                "excluded by {exclude:?}, let back in by include {include:?}"
+            ),
+        }
+    }
+}
+
 /// Checks the provided `child_path` is a directory.
 /// If either `includes` or `excludes` are set,
 /// checks whether the path is included/excluded respectively.
-fn is_valid_directory(base_path: &Path, child_path: &Path, value: &WatchConfig) -> bool {
+pub fn check_directory(
+    base_path: &Path,
+    child_path: &Path,
+    value: &WatchConfig,
+    case: PathCase,
+) -> DirDecision {
     if !child_path.is_dir() {
-        return false;
+        return DirDecision::NotADirectory;
     }
 
-    if !child_path.starts_with(base_path) {
-        return false;
+    if !case.starts_with(child_path, base_path) {
+        return DirDecision::OutsideWatch;
     }
 
-    let includes = &value.include;
-    let excludes = &value.exclude;
-
-    let mut include = true;
+    let exclude = value
+        .exclude
+        .iter()
+        .find(|exclude| case.starts_with(child_path, &base_path.join(exclude)));
+    let exclude = match exclude {
+        Some(exclude) => exclude.clone(),
+        None => return DirDecision::Allowed,
+    };
 
-    if !excludes.is_empty() {
-        include = !excludes
-            .iter()
-            .any(|exclude| child_path.starts_with(base_path.join(exclude)));
+    // An include further down keeps the way to it open
+    match value
+        .include
+        .iter()
+        .find(|include| case.starts_with(&base_path.join(include), child_path))
+    {
+        Some(include) => DirDecision::Readmitted {
+            exclude,
+            include: include.clone(),
+        },
+        None => DirDecision::Excluded { exclude },
     }
+}
 
-    if !include && !includes.is_empty() {
-        include = includes
-            .iter()
-            .any(|include| base_path.join(include).starts_with(child_path));
-    }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
 
-    include
+    #[test]
+    fn excludes_follow_path_case() {
+        let tmp = tempfile::tempdir().unwrap();
+        let target = tmp.path().join("target");
+        fs::create_dir(&target).unwrap();
+        let watch = WatchConfig {
+            exclude: vec!["Target".to_string()],
+            ..WatchConfig::new()
+        };
+        assert_eq!(
+            check_directory(tmp.path(), &target, &watch, PathCase::Sensitive),
+            DirDecision::Allowed
+        );
+        assert_eq!(
+            check_directory(tmp.path(), &target, &watch, PathCase::Insensitive),
+            DirDecision::Excluded {
+                exclude: "Target".to_string()
+            }
+        );
+    }
 }
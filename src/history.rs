@@ -0,0 +1,152 @@
+//! The last few snapshots of each repo, kept in SQLite under the cache directory so `dura history`
+//! works after the logs have rotated away. The poller adds a row after every snapshot it takes and
+//! drops the oldest rows beyond `history_size` in the same transaction.
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Connection};
+use serde::Serialize;
+
+use crate::database::RuntimeLock;
+use crate::snapshots::CaptureStatus;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS history (
+        id INTEGER PRIMARY KEY,
+        repo TEXT NOT NULL,
+        time TEXT NOT NULL,
+        dura_branch TEXT NOT NULL,
+        commit_hash TEXT NOT NULL,
+        files_changed INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS history_repo ON history (repo, id);
+";
+
+/// How long to wait for `dura serve` to finish writing before giving up on a read or write
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HistoryEntry {
+    pub repo: PathBuf,
+    pub timestamp: DateTime<Utc>,
+    pub dura_branch: String,
+    pub commit_hash: String,
+    pub files_changed: usize,
+}
+
+pub struct History {
+    conn: Connection,
+}
+
+impl History {
+    pub fn default_path() -> PathBuf {
+        RuntimeLock::default_path().with_file_name("history.db")
+    }
+
+    /// Opens (or creates) the history database at `path`
+    pub fn open(path: &Path) -> rusqlite::Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        // A crash mid-write loses at most that write
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(Self { conn })
+    }
+
+    /// Adds a snapshot of `repo`, keeping only its newest `keep` entries
+    pub fn record(
+        &mut self,
+        repo: &Path,
+        status: &CaptureStatus,
+        keep: usize,
+    ) -> rusqlite::Result<()> {
+        let repo = repo.to_string_lossy();
+        let tx = self.conn.transaction()?;
+        tx.execute(
+            "INSERT INTO history (repo, time, dura_branch, commit_hash, files_changed)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                repo,
+                status.timestamp.to_rfc3339(),
+                status.dura_branch,
+                status.commit_hash,
+                status.files_changed as i64,
+            ],
+        )?;
+        tx.execute(
+            "DELETE FROM history WHERE repo = ?1 AND id NOT IN
+                (SELECT id FROM history WHERE repo = ?1 ORDER BY id DESC LIMIT ?2)",
+            params![repo, keep as i64],
+        )?;
+        tx.commit()
+    }
+
+    /// Newest first
+    pub fn recent(&self, repo: &Path, limit: usize) -> rusqlite::Result<Vec<HistoryEntry>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT time, dura_branch, commit_hash, files_changed FROM history
+             WHERE repo = ?1 ORDER BY id DESC LIMIT ?2",
+        )?;
+        let rows = stmt.query_map(params![repo.to_string_lossy(), limit as i64], |row| {
+            let time: String = row.get(0)?;
+            let files_changed: i64 = row.get(3)?;
+            Ok(HistoryEntry {
+                repo: repo.to_path_buf(),
+                timestamp: DateTime::parse_from_rfc3339(&time)
+                    .map(|t| t.with_timezone(&Utc))
+                    .unwrap_or_default(),
+                dura_branch: row.get(1)?,
+                commit_hash: row.get(2)?,
+                files_changed: files_changed.try_into().unwrap_or_default(),
+            })
+        })?;
+        rows.collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(commit_hash: &str) -> CaptureStatus {
+        CaptureStatus {
+            dura_branch: "dura/base".to_string(),
+            commit_hash: commit_hash.to_string(),
+            base_hash: "base".to_string(),
+            phases: Default::default(),
+            files_changed: 1,
+            insertions: 1,
+            deletions: 0,
+            timestamp: Utc::now(),
+            identity: None,
+            commit_time: None,
+            withheld: vec![],
+            lock_retries: 0,
+            tracked_changes: 1,
+            untracked_changes: 0,
+        }
+    }
+
+    #[test]
+    fn newest_first_and_capped() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut history = History::open(&tmp.path().join("history.db")).unwrap();
+        let repo = Path::new("/code/dura");
+        let other = Path::new("/code/other");
+        for hash in ["a", "b", "c", "d"] {
+            history.record(repo, &status(hash), 3).unwrap();
+        }
+        history.record(other, &status("x"), 3).unwrap();
+
+        let hashes: Vec<String> = history
+            .recent(repo, 10)
+            .unwrap()
+            .into_iter()
+            .map(|e| e.commit_hash)
+            .collect();
+        assert_eq!(hashes, vec!["d", "c", "b"]);
+        assert_eq!(history.recent(repo, 1).unwrap()[0].commit_hash, "d");
+        assert_eq!(history.recent(other, 10).unwrap().len(), 1);
+    }
+}
@@ -1,9 +1,33 @@
+pub mod api;
+pub mod bench;
+pub mod build_info;
+pub mod compact;
 pub mod config;
 pub mod database;
+pub mod disk_space;
+pub mod effective_config;
+pub mod events;
+pub mod explain;
+pub mod find_file;
+pub mod fsck;
 pub mod git_repo_iter;
+pub mod history;
 pub mod log;
 pub mod logger;
+pub mod maintenance;
 pub mod metrics;
+pub mod metrics_db;
+pub mod notify;
+pub mod path_case;
+pub mod paths;
 pub mod poll_guard;
 pub mod poller;
+pub mod privileges;
+pub mod redact;
+pub mod schedule;
+pub mod size;
 pub mod snapshots;
+pub mod summary;
+pub mod supervisor;
+pub mod timeline;
+pub mod trigger;
@@ -1,10 +1,14 @@
+use std::collections::BTreeMap;
 use std::fmt::Debug;
+use std::path::PathBuf;
 use std::time::{Duration, Instant};
 
 use hdrhistogram::Histogram;
 use serde::{Deserialize, Serialize};
 use tracing::trace;
 
+use crate::git_repo_iter::DiscoveryStats;
+use crate::redact;
 use crate::snapshots::CaptureStatus;
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -18,7 +22,128 @@ pub enum Operation {
     CollectStats {
         per_dir_stats: Histo,
         loop_stats: Histo,
+        /// How many repos were found on the most recent loop
+        #[serde(default)]
+        repo_count: usize,
+        #[serde(flatten, default)]
+        outcomes: OutcomeCounts,
+        #[serde(flatten, default)]
+        loops: LoopCounts,
+        /// Seconds since the poller started. Missing from older logs.
+        #[serde(default)]
+        uptime_secs: u64,
+        /// Per watch root, from the most recent loop
+        #[serde(default)]
+        discovery: BTreeMap<String, DiscoveryStats>,
     },
+    /// Background upkeep of a repo that isn't a snapshot, e.g. folding old dura branches
+    /// together or running git gc.
+    Maintenance {
+        repo: String,
+        action: MaintenanceAction,
+        #[serde(default)]
+        details: BTreeMap<String, String>,
+        error: Option<String>,
+        latency: f32,
+    },
+    /// The daemon starting, stopping or handing over to another one, so gaps in the snapshots
+    /// can be explained afterwards
+    Lifecycle {
+        event: LifecycleEvent,
+        pid: u32,
+        version: String,
+        #[serde(default)]
+        details: BTreeMap<String, String>,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum LifecycleEvent {
+    Started,
+    /// This daemon took the runtime lock from another one that was still running
+    TakeoverStarted,
+    /// Another daemon took the runtime lock, so this one is stepping down
+    TakeoverCompleted,
+    /// `dura kill` cleared the runtime lock
+    KillReceived,
+    Stopped,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum MaintenanceAction {
+    Consolidate,
+    Prune,
+    Gc,
+    RemotePush,
+    /// A snapshot ref was deleted because its contents were committed for real
+    CleanMerged,
+}
+
+/// What happened when the poller looked at a single repo
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CaptureOutcome {
+    Succeeded,
+    /// Either PollGuard or the capture itself found nothing to snapshot
+    NoChange,
+    /// Files were still being written, so the capture was put off to the next loop
+    Deferred,
+    /// Not attempted because the disk is nearly full
+    LowDisk,
+    /// Took longer than `per_repo_timeout_secs` and was abandoned
+    TimedOut,
+    /// Not attempted because of `skip_on_branches`, with the reason
+    Skipped(String),
+    Failed(String),
+}
+
+/// Tallies of `CaptureOutcome`s since stats were last logged
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct OutcomeCounts {
+    #[serde(default)]
+    pub snapshot_errors: u64,
+    #[serde(default)]
+    pub captures_succeeded: u64,
+    #[serde(default)]
+    pub captures_skipped_no_change: u64,
+    #[serde(default)]
+    pub captures_deferred: u64,
+    #[serde(default)]
+    pub captures_suspended_low_disk: u64,
+    #[serde(default)]
+    pub captures_timed_out: u64,
+    #[serde(default)]
+    pub captures_skipped_on_branch: u64,
+    #[serde(default)]
+    pub last_error: Option<String>,
+}
+
+/// How much work the poller did since stats were last logged
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct LoopCounts {
+    #[serde(default)]
+    pub loops_completed: u64,
+    /// Loops that didn't look at any repos, e.g. during quiet hours
+    #[serde(default)]
+    pub loops_skipped: u64,
+    #[serde(default)]
+    pub repos_processed: u64,
+}
+
+impl OutcomeCounts {
+    pub fn record(&mut self, outcome: &CaptureOutcome) {
+        match outcome {
+            CaptureOutcome::Succeeded => self.captures_succeeded += 1,
+            CaptureOutcome::NoChange => self.captures_skipped_no_change += 1,
+            CaptureOutcome::Deferred => self.captures_deferred += 1,
+            CaptureOutcome::LowDisk => self.captures_suspended_low_disk += 1,
+            CaptureOutcome::TimedOut => self.captures_timed_out += 1,
+            CaptureOutcome::Skipped(_) => self.captures_skipped_on_branch += 1,
+            CaptureOutcome::Failed(error) => {
+                self.snapshot_errors += 1;
+                self.last_error = Some(error.clone());
+            }
+        }
+    }
 }
 
 impl Operation {
@@ -33,6 +158,8 @@ impl Operation {
             Operation::CollectStats { .. } => {
                 true // logic punted to StatCollector
             }
+            Operation::Maintenance { .. } => true,
+            Operation::Lifecycle { .. } => true,
         }
     }
 
@@ -42,12 +169,6 @@ impl Operation {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct Stats {
-    dir_stats: Histo,
-    loop_stats: Histo,
-}
-
 /// A serializable form of a hdrhistogram, mainly just for logging out
 /// in a way we want to read it
 #[derive(Debug, Serialize, Deserialize)]
@@ -91,9 +212,20 @@ impl Histo {
 
 #[derive(Debug)]
 pub struct StatCollector {
+    /// When the poller started, for `uptime_secs`
+    started: Instant,
     start: Instant,
     per_dir_stats: Histogram<u64>,
     loop_stats: Histogram<u64>,
+    repo_count: usize,
+    outcomes: OutcomeCounts,
+    loops: LoopCounts,
+    discovery: BTreeMap<String, DiscoveryStats>,
+    /// Sum of per-dir latencies in the loop that's currently running
+    current_loop_dir_total: Duration,
+    current_loop_dir_count: u32,
+    /// Mean per-dir latency of the last complete loop
+    last_loop_dir_mean: Duration,
 }
 
 /// 5 minutes in milliseconds
@@ -105,9 +237,17 @@ const STAT_LOG_INTERVAL: f32 = 600.0;
 impl StatCollector {
     pub fn new() -> Self {
         Self {
+            started: Instant::now(),
             start: Instant::now(),
             per_dir_stats: Histogram::<u64>::new_with_max(MAX_LATENCY_IMAGINABLE, 3).unwrap(),
             loop_stats: Histogram::<u64>::new_with_max(MAX_LATENCY_IMAGINABLE, 3).unwrap(),
+            repo_count: 0,
+            outcomes: OutcomeCounts::default(),
+            loops: LoopCounts::default(),
+            discovery: BTreeMap::new(),
+            current_loop_dir_total: Duration::ZERO,
+            current_loop_dir_count: 0,
+            last_loop_dir_mean: Duration::ZERO,
         }
     }
 
@@ -115,17 +255,25 @@ impl StatCollector {
         Operation::CollectStats {
             per_dir_stats: Histo::from_histogram(&self.per_dir_stats),
             loop_stats: Histo::from_histogram(&self.loop_stats),
+            repo_count: self.repo_count,
+            outcomes: self.outcomes.clone(),
+            loops: self.loops.clone(),
+            uptime_secs: self.started.elapsed().as_secs(),
+            discovery: self.discovery.clone(),
         }
     }
 
+    /// Whether it's time to log stats. Never while no loop has completed since they were last
+    /// logged, e.g. all through quiet hours, since there'd be nothing to say.
     pub fn should_log(&self) -> bool {
         let elapsed = (Instant::now() - self.start).as_secs_f32();
         trace!(
             elapsed = elapsed,
             target = STAT_LOG_INTERVAL,
+            loops = self.loops.loops_completed,
             "Should we log metrics?"
         );
-        elapsed > STAT_LOG_INTERVAL
+        elapsed > STAT_LOG_INTERVAL && self.loops.loops_completed > 0
     }
 
     pub fn log_str(&mut self) -> String {
@@ -139,11 +287,21 @@ impl StatCollector {
         self.start = Instant::now();
         self.per_dir_stats.clear();
         self.loop_stats.clear();
+        self.outcomes = OutcomeCounts::default();
+        self.loops = LoopCounts::default();
+    }
+
+    /// Count the result of processing a single directory
+    pub fn record_outcome(&mut self, outcome: &CaptureOutcome) {
+        self.outcomes.record(outcome);
     }
 
     /// Record the time it takes to process a single directory. Mainly interested to see if
     /// there's any outliers, the histogram should be interesting.
     pub fn record_dir(&mut self, latency: Duration) {
+        self.current_loop_dir_total += latency;
+        self.current_loop_dir_count += 1;
+        self.loops.repos_processed += 1;
         let value = latency.as_millis().try_into().unwrap();
         self.per_dir_stats.saturating_record(value);
     }
@@ -151,8 +309,52 @@ impl StatCollector {
     /// Record the time it takes to go through all directories. I expect mean will be the
     /// most interesting datum. Mainly for projecting CPU usage.
     pub fn record_loop(&mut self, latency: Duration) {
+        self.loops.loops_completed += 1;
         let value = latency.as_millis().try_into().unwrap();
         self.loop_stats.saturating_record(value);
+
+        if self.current_loop_dir_count > 0 {
+            self.last_loop_dir_mean = self.current_loop_dir_total / self.current_loop_dir_count;
+        }
+        self.current_loop_dir_total = Duration::ZERO;
+        self.current_loop_dir_count = 0;
+    }
+
+    /// Forget how long the loop that's currently running took, e.g. because the system was
+    /// suspended in the middle of it. It still counts as completed.
+    pub fn discard_loop(&mut self) {
+        self.loops.loops_completed += 1;
+        self.current_loop_dir_total = Duration::ZERO;
+        self.current_loop_dir_count = 0;
+    }
+
+    /// Mean time spent per directory during the last complete loop. Unlike the histograms, this
+    /// survives `reset()`, since the poller uses it to pace the next loop.
+    pub fn last_loop_dir_latency(&self) -> Duration {
+        self.last_loop_dir_mean
+    }
+
+    /// Count a loop that ended before looking at any repos
+    pub fn record_skipped_loop(&mut self) {
+        self.loops.loops_skipped += 1;
+    }
+
+    pub fn repo_count(&self) -> usize {
+        self.repo_count
+    }
+
+    /// Record how many repos were discovered in the latest loop. Not reset, since it's a gauge
+    /// rather than a distribution.
+    pub fn record_repo_count(&mut self, count: usize) {
+        self.repo_count = count;
+    }
+
+    /// Record what discovery did under each watch root in the latest loop. Also a gauge.
+    pub fn record_discovery(&mut self, stats: &BTreeMap<PathBuf, DiscoveryStats>) {
+        self.discovery = stats
+            .iter()
+            .map(|(root, stats)| (redact::path(root), *stats))
+            .collect();
     }
 }
 
@@ -161,3 +363,84 @@ impl Default for StatCollector {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn outcomes_serialize_with_stats() {
+        let mut stats = StatCollector::new();
+        stats.record_outcome(&CaptureOutcome::Succeeded);
+        stats.record_outcome(&CaptureOutcome::Succeeded);
+        stats.record_outcome(&CaptureOutcome::NoChange);
+        stats.record_outcome(&CaptureOutcome::Failed("first".to_string()));
+        stats.record_outcome(&CaptureOutcome::Failed("second".to_string()));
+        let discovery = DiscoveryStats {
+            pruned_by_depth: 3,
+            ..DiscoveryStats::default()
+        };
+        stats.record_discovery(&BTreeMap::from([(PathBuf::from("/watched"), discovery)]));
+
+        let json: serde_json::Value = serde_json::from_str(&stats.log_str()).unwrap();
+        let fields = &json["CollectStats"];
+        assert_eq!(fields["discovery"]["/watched"]["pruned_by_depth"], 3);
+        assert_eq!(fields["captures_succeeded"], 2);
+        assert_eq!(fields["captures_skipped_no_change"], 1);
+        assert_eq!(fields["snapshot_errors"], 2);
+        assert_eq!(fields["last_error"], "second");
+    }
+
+    #[test]
+    fn outcomes_reset_after_logging() {
+        let mut stats = StatCollector::new();
+        stats.record_outcome(&CaptureOutcome::Failed("oops".to_string()));
+        stats.log_str();
+
+        let json: serde_json::Value = serde_json::from_str(&stats.log_str()).unwrap();
+        let fields = &json["CollectStats"];
+        assert_eq!(fields["snapshot_errors"], 0);
+        assert_eq!(fields["last_error"], serde_json::Value::Null);
+    }
+
+    #[test]
+    fn loops_counted_until_logged() {
+        let mut stats = StatCollector::new();
+        stats.start = Instant::now() - Duration::from_secs(STAT_LOG_INTERVAL as u64 + 1);
+        stats.record_skipped_loop();
+        stats.record_skipped_loop();
+        // Nothing ran, so a whole window of quiet hours isn't worth a line
+        assert!(!stats.should_log());
+
+        for _ in 0..3 {
+            stats.record_dir(Duration::from_millis(5));
+            stats.record_dir(Duration::from_millis(5));
+            stats.record_loop(Duration::from_millis(10));
+        }
+        assert!(stats.should_log());
+        let json: serde_json::Value = serde_json::from_str(&stats.log_str()).unwrap();
+        let fields = &json["CollectStats"];
+        assert_eq!(fields["loops_completed"], 3);
+        assert_eq!(fields["loops_skipped"], 2);
+        assert_eq!(fields["repos_processed"], 6);
+        assert!(fields["uptime_secs"].is_u64());
+
+        let json: serde_json::Value = serde_json::from_str(&stats.log_str()).unwrap();
+        assert_eq!(json["CollectStats"]["loops_completed"], 0);
+        assert!(!stats.should_log());
+    }
+
+    #[test]
+    fn old_stats_still_parse() {
+        let line = r#"{"CollectStats":{
+            "per_dir_stats":{"mean":1.0,"count":1,"min":1,"max":1,"percentiles":[]},
+            "loop_stats":{"mean":1.0,"count":1,"min":1,"max":1,"percentiles":[]}}}"#;
+        let op: Operation = serde_json::from_str(line).unwrap();
+        match op {
+            Operation::CollectStats { outcomes, .. } => {
+                assert_eq!(outcomes, OutcomeCounts::default())
+            }
+            _ => panic!("wrong variant"),
+        }
+    }
+}
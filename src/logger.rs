@@ -32,7 +32,10 @@ impl<W: for<'a> MakeWriter<'a> + 'static> NestedJsonLayer<W> {
         ser_map.serialize_entry("name", event.metadata().name())?;
         ser_map.serialize_entry("level", &format!("{:?}", event.metadata().level()))?;
         ser_map.serialize_entry("fields", &hm)?;
-        ser_map.serialize_entry("time", &Utc::now().to_rfc3339())?;
+        let now = Utc::now();
+        ser_map.serialize_entry("time", &now.to_rfc3339())?;
+        // Same instant, for tools that would rather not parse dates
+        ser_map.serialize_entry("time_unix_ms", &now.timestamp_millis())?;
         ser_map.end()?;
         Ok(buffer)
     }
@@ -107,3 +110,51 @@ impl Visit for JsonVisitor {
         }
     }
 }
+
+/// The env filter directive `dura serve` logs with. `RUST_LOG` wins if it's set, then
+/// `--log-filter`, then `-v`/`-q`, then `log_level` from the config. Verbosity only applies to
+/// dura's own logs, since trace from every dependency would drown them out.
+pub fn filter_directive(
+    env: Option<&str>,
+    log_filter: Option<&str>,
+    verbosity: i8,
+    config_level: Option<&str>,
+) -> String {
+    if let Some(env) = env.filter(|env| !env.is_empty()) {
+        return env.to_string();
+    }
+    if let Some(filter) = log_filter {
+        return filter.to_string();
+    }
+    match verbosity {
+        i8::MIN..=-1 => "warn".to_string(),
+        1 => "info,dura=debug".to_string(),
+        2..=i8::MAX => "info,dura=trace".to_string(),
+        0 => config_level.unwrap_or("info").to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn directive_precedence() {
+        assert_eq!(filter_directive(None, None, 0, None), "info");
+        assert_eq!(filter_directive(None, None, 0, Some("debug")), "debug");
+        assert_eq!(
+            filter_directive(None, None, 2, Some("debug")),
+            "info,dura=trace"
+        );
+        assert_eq!(filter_directive(None, None, -1, None), "warn");
+        assert_eq!(
+            filter_directive(None, Some("dura::poller=trace"), 1, None),
+            "dura::poller=trace"
+        );
+        assert_eq!(
+            filter_directive(Some("error"), Some("trace"), 2, None),
+            "error"
+        );
+        assert_eq!(filter_directive(Some(""), None, 1, None), "info,dura=debug");
+    }
+}
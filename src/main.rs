@@ -1,45 +1,172 @@
 use std::fs::{File, OpenOptions};
-use std::io::{stdin, stdout, BufReader, BufWriter, Read, Write};
-use std::path::Path;
+use std::io::{stdin, stdout, BufReader, Write};
+use std::path::{Path, PathBuf};
 use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 
 use clap::builder::IntoResettable;
 use clap::{
-    arg, crate_authors, crate_description, crate_name, crate_version, value_parser, Arg, Command,
+    arg, crate_authors, crate_description, crate_name, crate_version, value_parser, Arg,
+    ArgMatches, ColorChoice, Command,
 };
-use dura::config::{Config, WatchConfig};
-use dura::database::RuntimeLock;
-use dura::logger::NestedJsonLayer;
-use dura::metrics;
+use dura::bench;
+use dura::build_info::BuildInfo;
+use dura::compact::{self, CompactOptions, StageOutcome};
+use dura::config::{Config, ConfigError, UnwatchResult, WatchEdit, WatchResult};
+use dura::database::{self, Boost, Boosts, PollerStatus, RuntimeLock};
+use dura::effective_config::{CliOverrides, EffectiveConfig, Layers};
+use dura::events::{EventSink, EventTarget};
+use dura::explain;
+use dura::find_file;
+use dura::fsck;
+use dura::history::History;
+use dura::logger::{self, NestedJsonLayer};
+use dura::maintenance::GcOutcome;
+use dura::metrics::{self, JsonLines, MetricsSink, WorkAtRisk};
+use dura::metrics_db::SqliteSink;
+use dura::paths::{self, Paths};
+use dura::poll_guard::PollGuard;
 use dura::poller;
-use dura::snapshots;
-use tracing::info;
+use dura::privileges;
+use dura::redact;
+use dura::size;
+use dura::snapshots::{self, RefNamespace};
+use dura::summary;
+use dura::supervisor;
+use dura::timeline;
+use dura::trigger::{self, CaptureResponse};
+use tracing::{info, warn};
 use tracing_subscriber::prelude::__tracing_subscriber_SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
 use tracing_subscriber::{EnvFilter, Registry};
 
+/// `--quiet`, see `note!`
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+/// Prints a diagnostic to stderr, unless `--quiet`. Results, what the command was asked for, go
+/// to stdout so scripts can read them. Everything else a command has to say, like hints,
+/// warnings and progress, goes through here. Errors always go to stderr.
+macro_rules! note {
+    ($($arg:tt)*) => {
+        if !QUIET.load(Ordering::Relaxed) {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+/// Something went wrong in a subcommand. These are printed as a single line on stderr, and the
+/// variant decides the exit code:
+///
+/// 1 : the command ran but failed, e.g. a capture error or a directory that couldn't be watched
+/// 2 : invalid arguments (same as clap uses for its own argument errors)
+/// 3 : dura's config or runtime lock couldn't be read or written
+#[derive(Debug, thiserror::Error)]
+enum CliError {
+    #[error("{0}")]
+    Failed(String),
+    #[error("{0}")]
+    Usage(String),
+    #[error(transparent)]
+    Config(#[from] ConfigError),
+}
+
+impl CliError {
+    fn exit_code(&self) -> i32 {
+        match self {
+            CliError::Failed(_) => 1,
+            CliError::Usage(_) => 2,
+            CliError::Config(_) => 3,
+        }
+    }
+}
+
+type CliResult = Result<(), CliError>;
+
 #[tokio::main]
 async fn main() {
-    if !check_if_user() {
-        eprintln!("Dura cannot be run as root, to avoid data corruption");
-        process::exit(1);
+    let result = run().await;
+    for warning in Config::take_warnings() {
+        note!("dura: warning: {warning}");
+    }
+    if let Err(e) = result {
+        eprintln!("dura: {e}");
+        process::exit(e.exit_code());
     }
+}
 
-    let cwd = std::env::current_dir().expect("Failed to get current directory");
+async fn run() -> CliResult {
+    let cwd = std::env::current_dir()
+        .map_err(|e| CliError::Failed(format!("Couldn't get the current directory: {e}")))?;
 
     let suffix = option_env!("DURA_VERSION_SUFFIX")
         .map(|v| format!(" @ {}", v))
         .unwrap_or_else(|| String::from(""));
 
-    let version = format!("{}{}", crate_version!(), suffix);
+    let build = BuildInfo::current();
+    let version = format!("{}{} ({})", crate_version!(), suffix, build.git_hash);
+
+    // clap stops at --version, so the verbose form has to be spotted before it gets a look
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.iter().any(|a| a == "--verbose") && args.iter().any(|a| a == "--version" || a == "-V") {
+        println!("{} {version}\n{build}", crate_name!());
+        return Ok(());
+    }
+
+    let no_color = args.iter().any(|a| a == "--no-color")
+        || std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty());
 
     let arg_directory = Arg::new("directory")
-        .default_value(cwd.into_os_string().into_resettable())
+        .default_value(cwd.clone().into_os_string().into_resettable())
         .help("The directory to watch. Defaults to current directory");
 
+    let arg_ensure_daemon = arg!(--"ensure-daemon")
+        .required(false)
+        .action(clap::builder::ArgAction::SetTrue)
+        .help("Start `dura serve` in the background if it isn't running. Also turned on by auto_start_daemon in the config");
+
     let matches = Command::new(crate_name!())
         .about(crate_description!())
         .version(version.into_resettable())
+        .disable_version_flag(true)
+        .arg(arg!(-V --version)
+            .action(clap::builder::ArgAction::Version)
+            .help("Print version. Add --verbose for the commit, build date, target and rustc")
+        )
+        .arg(arg!(--verbose)
+            .action(clap::builder::ArgAction::SetTrue)
+            .help("With --version, print build details")
+        )
+        .arg(Arg::new(privileges::ALLOW_FLAG)
+            .long(privileges::ALLOW_FLAG)
+            .global(true)
+            .action(clap::builder::ArgAction::SetTrue)
+            .help("Run even with root or Administrator privileges. Git objects dura writes will be owned by that user")
+        )
+        .arg(arg!(--"config-dir" <DIR>)
+            .global(true)
+            .value_parser(value_parser!(PathBuf))
+            .help("Where config.toml is. Overrides DURA_CONFIG_HOME")
+        )
+        .arg(arg!(--"cache-dir" <DIR>)
+            .global(true)
+            .value_parser(value_parser!(PathBuf))
+            .help("Where the runtime lock, history and logs are. Overrides DURA_CACHE_HOME. A separate config and cache dir makes a separate dura, e.g. for work and personal repos")
+        )
+        .arg(arg!(-q --quiet)
+            .global(true)
+            .action(clap::builder::ArgAction::SetTrue)
+            .help("Only print results and errors. With serve, only log warnings and errors")
+        )
+        .arg(arg!(--"no-color")
+            .global(true)
+            .action(clap::builder::ArgAction::SetTrue)
+            .help("Don't color the output. Also turned off by setting NO_COLOR")
+        )
+        .color(match no_color {
+            true => ColorChoice::Never,
+            false => ColorChoice::Auto,
+        })
         .subcommand_required(true)
         .arg_required_else_help(true)
         .author(crate_authors!())
@@ -49,6 +176,49 @@ async fn main() {
                 .long_flag("capture")
                 .about("Run a single backup of an entire repository. This is the one single iteration of the `serve` control loop.")
                 .arg(arg_directory.clone())
+                .arg(arg_ensure_daemon.clone())
+                .arg(arg!(--"via-daemon")
+                    .action(clap::builder::ArgAction::SetTrue)
+                    .help("Ask the running `dura serve` to take the snapshot. Captures here if it isn't running or doesn't answer")
+                )
+                .arg(arg!(--force)
+                    .action(clap::builder::ArgAction::SetTrue)
+                    .conflicts_with("via-daemon")
+                    .help("Snapshot even if nothing changed, e.g. to mark a point to come back to")
+                )
+        )
+        .subcommand(
+            Command::new("boost")
+                .about("Snapshot a repo as often as possible for a while, e.g. during a big rename or a messy merge")
+                .arg(arg_directory.clone().help("The repository. Defaults to current directory"))
+                .arg(arg!(--for <AGE>)
+                    .required(false)
+                    .default_value("30m")
+                    .value_parser(summary::parse_age)
+                    .help("How long to boost for, e.g. 90s, 30m or 2h")
+                )
+                .arg(arg!(--interval <SECS>)
+                    .required(false)
+                    .default_value("2")
+                    .value_parser(value_parser!(u64).range(1..))
+                    .help("Seconds between looks at the repo while boosted. Quiet hours and min_snapshot_interval_secs don't apply")
+                )
+                .arg(arg!(--clear)
+                    .action(clap::builder::ArgAction::SetTrue)
+                    .conflicts_with_all(["for", "interval"])
+                    .help("End the boost now")
+                )
+        )
+        .subcommand(
+            Command::new("guard")
+                .about("Snapshot the repo in the current directory, run a command (e.g. `git rebase -i main`) and snapshot again after it")
+                .arg(Arg::new("command")
+                    .required(true)
+                    .num_args(1..)
+                    .trailing_var_arg(true)
+                    .allow_hyphen_values(true)
+                    .help("The command to run, after --")
+                )
         )
         .subcommand(
             Command::new("serve")
@@ -59,13 +229,72 @@ async fn main() {
                     arg!(--logfile <FILE>)
                     .required(false)
                     .help("Sets custom logfile. Default is logging to stdout")
-        ))
+                )
+                .arg(
+                    arg!(--once)
+                    .required(false)
+                    .action(clap::builder::ArgAction::SetTrue)
+                    .help("Run a single pass over all watched repos and exit, e.g. from cron")
+                )
+                .arg(
+                    arg!(--interval <SECS>)
+                    .required(false)
+                    .value_parser(value_parser!(u64).range(1..))
+                    .help("Seconds to sleep between passes. Defaults to 5")
+                )
+                .arg(
+                    arg!(--supervise)
+                    .required(false)
+                    .action(clap::builder::ArgAction::SetTrue)
+                    .conflicts_with("once")
+                    .help("Run the poller in a child process, and restart it if it crashes")
+                )
+                .arg(
+                    arg!(--"supervised-by" <PID>)
+                    .required(false)
+                    .value_parser(value_parser!(u32))
+                    .hide(true)
+                )
+                .arg(
+                    arg!(-v --verbose)
+                    .required(false)
+                    .action(clap::builder::ArgAction::Count)
+                    .conflicts_with("quiet")
+                    .help("Log more: -v for debug, -vv for trace")
+                )
+                .arg(
+                    arg!(--"log-filter" <DIRECTIVE>)
+                    .required(false)
+                    .conflicts_with_all(["verbose", "quiet"])
+                    .help("Log with this filter, e.g. dura::git_repo_iter=trace. RUST_LOG still wins if it's set")
+                )
+                .arg(
+                    arg!(--"events-fd" <TARGET>)
+                    .required(false)
+                    .value_parser(value_parser!(EventTarget))
+                    .help("Write snapshots and other events as JSON lines to stdout or a file, for other tools to follow. With stdout, logs go to stderr unless --logfile is given")
+                )
+        )
         .subcommand(
             Command::new("watch")
                 .short_flag('W')
                 .long_flag("watch")
                 .about("Add the current working directory as a repository to watch.")
-                .arg(arg_directory.clone())
+                .arg(Arg::new("directory")
+                    .num_args(0..)
+                    .help("The directories to watch. Defaults to current directory")
+                )
+                .arg(arg!(--stdin)
+                    .required(false)
+                    .action(clap::builder::ArgAction::SetTrue)
+                    .help("Also read directories to watch from stdin, one per line")
+                )
+                .arg(arg_ensure_daemon)
+                .arg(arg!(--strict)
+                    .required(false)
+                    .action(clap::builder::ArgAction::SetTrue)
+                    .help("Stop without changing anything if any directory can't be watched")
+                )
                 .arg(arg!(-i --include)
                     .required(false)
                     .action(clap::builder::ArgAction::Set)
@@ -85,10 +314,60 @@ async fn main() {
                 .arg(arg!(-d --maxdepth)
                     .required(false)
                     .action(clap::builder::ArgAction::Set)
-                    .value_parser(value_parser!(String))
-                    .default_value(&"255".to_string())
+                    .value_parser(value_parser!(u8))
                     .num_args(0..=1)
-                    .help("Determines the depth to recurse into when scanning directories")
+                    .help("Determines the depth to recurse into when scanning directories, 0-255. Defaults to 255")
+                )
+                .arg(arg!(--disable)
+                    .required(false)
+                    .action(clap::builder::ArgAction::SetTrue)
+                    .conflicts_with("enable")
+                    .help("Stop snapshotting under this watch, but keep its settings")
+                )
+                .arg(arg!(--enable)
+                    .required(false)
+                    .action(clap::builder::ArgAction::SetTrue)
+                    .help("Resume snapshotting under a watch turned off with --disable")
+                )
+                .arg(arg!(--"add-include" <DIR>)
+                    .required(false)
+                    .action(clap::builder::ArgAction::Append)
+                    .value_delimiter(',')
+                    .help("Add to the includes of an existing watch")
+                )
+                .arg(arg!(--"remove-include" <DIR>)
+                    .required(false)
+                    .action(clap::builder::ArgAction::Append)
+                    .value_delimiter(',')
+                    .help("Remove from the includes of an existing watch")
+                )
+                .arg(arg!(--"add-exclude" <DIR>)
+                    .required(false)
+                    .action(clap::builder::ArgAction::Append)
+                    .value_delimiter(',')
+                    .help("Add to the excludes of an existing watch")
+                )
+                .arg(arg!(--"remove-exclude" <DIR>)
+                    .required(false)
+                    .action(clap::builder::ArgAction::Append)
+                    .value_delimiter(',')
+                    .help("Remove from the excludes of an existing watch")
+                )
+                .arg(arg!(--foreground)
+                    .required(false)
+                    .action(clap::builder::ArgAction::SetTrue)
+                    .conflicts_with_all([
+                        "stdin", "ensure-daemon", "strict", "include", "exclude", "maxdepth",
+                        "disable", "enable", "add-include", "remove-include", "add-exclude",
+                        "remove-exclude",
+                    ])
+                    .help("Snapshot one repo from this terminal until Ctrl-C, printing each snapshot. Doesn't touch the config or need dura serve.")
+                )
+                .arg(arg!(--interval <SECS>)
+                    .required(false)
+                    .requires("foreground")
+                    .value_parser(value_parser!(u64).range(1..))
+                    .help("With --foreground, seconds to sleep between checks. Defaults to 5")
                 )
         )
         .subcommand(
@@ -96,7 +375,244 @@ async fn main() {
                 .short_flag('U')
                 .long_flag("unwatch")
                 .about("Remove the current working directory as a repository to watch.")
-                .arg(arg_directory)
+                .arg(arg_directory.clone())
+        )
+        .subcommand(
+            Command::new("init")
+                .about("Set dura up in one go: create the config, watch a directory, start the daemon and take a first snapshot. Safe to run again.")
+                .arg(arg_directory.clone())
+                .arg(arg!(--"no-service")
+                    .required(false)
+                    .action(clap::builder::ArgAction::SetTrue)
+                    .help("Don't start `dura serve` in the background")
+                )
+        )
+        .subcommand(
+            Command::new("config")
+                .about("Check dura's settings or copy them between machines")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("check")
+                        .about("Check the config for settings dura doesn't know, e.g. typos")
+                        .arg(arg!([FILE]).help("The config to check. Defaults to dura's own"))
+                )
+                .subcommand(
+                    Command::new("show")
+                        .about("Show the settings that apply to a repo, and where each one comes from")
+                        .arg(arg_directory.clone().help("The repository. Defaults to current directory"))
+                        .arg(arg!(--interval <SECS>)
+                            .required(false)
+                            .value_parser(value_parser!(u64))
+                            .help("As given to `dura serve`")
+                        )
+                        .arg(arg!(--json)
+                            .required(false)
+                            .action(clap::builder::ArgAction::SetTrue)
+                            .help("Print a JSON object instead of a table")
+                        )
+                )
+                .subcommand(
+                    Command::new("export")
+                        .about("Print the config as portable TOML, with watches under your home directory written as ~/...")
+                )
+                .subcommand(
+                    Command::new("import")
+                        .about("Apply a config printed by `dura config export`")
+                        .arg(arg!(<FILE>).help("The exported config"))
+                        .arg(arg!(--merge)
+                            .required(false)
+                            .action(clap::builder::ArgAction::SetTrue)
+                            .conflicts_with("replace")
+                            .help("Keep watches that aren't in FILE. This is the default")
+                        )
+                        .arg(arg!(--replace)
+                            .required(false)
+                            .action(clap::builder::ArgAction::SetTrue)
+                            .help("Drop watches that aren't in FILE")
+                        )
+                )
+        )
+        .subcommand(
+            Command::new("migrate-refs")
+                .about("Move a repository's existing snapshot refs into the configured ref_namespace.")
+                .arg(arg_directory.clone())
+                .arg(arg!(--to <NAMESPACE>)
+                    .required(false)
+                    .value_parser(["heads", "dura"])
+                    .help("Namespace to move refs into. Defaults to ref_namespace from the config")
+                )
+        )
+        .subcommand(
+            Command::new("pin")
+                .about("Keep a snapshot forever by tagging it as refs/tags/dura/pin/<name>. Pinned snapshots are never cleaned up.")
+                .arg(Arg::new("target")
+                    .required(false)
+                    .help("The snapshot commit or dura branch to pin. With --list or --delete, this is the directory instead")
+                )
+                .arg(arg_directory.clone())
+                .arg(arg!(--name <NAME>)
+                    .required(false)
+                    .help("Name of the pin")
+                )
+                .arg(arg!(--list)
+                    .required(false)
+                    .action(clap::builder::ArgAction::SetTrue)
+                    .conflicts_with_all(["name", "delete"])
+                    .help("List the repository's pins")
+                )
+                .arg(arg!(--delete <NAME>)
+                    .required(false)
+                    .conflicts_with("name")
+                    .help("Remove a pin. The snapshot is then cleaned up like any other")
+                )
+        )
+        .subcommand(
+            Command::new("size")
+                .about("Report how much of a repository's .git only exists because of dura snapshots.")
+                .arg(arg_directory.clone())
+                .arg(arg!(--all)
+                    .required(false)
+                    .action(clap::builder::ArgAction::SetTrue)
+                    .help("Report on every watched repository instead")
+                )
+                .arg(arg!(--top <N>)
+                    .required(false)
+                    .value_parser(value_parser!(usize))
+                    .default_value("10")
+                    .help("How many of the largest files to list per repository")
+                )
+        )
+        .subcommand(
+            Command::new("compact")
+                .about("Prune old snapshot branches, drop ones that were committed for real and run git gc, reporting each step")
+                .arg(arg_directory.clone())
+                .arg(arg!(--all)
+                    .required(false)
+                    .action(clap::builder::ArgAction::SetTrue)
+                    .help("Compact every watched repository instead")
+                )
+                .arg(arg!(--"keep-days" <DAYS>)
+                    .required(false)
+                    .value_parser(value_parser!(u64))
+                    .default_value("30")
+                    .help("Keep snapshot branches with a snapshot from the last DAYS days. Pinned snapshots are always kept")
+                )
+                .arg(arg!(--"keep-stash-days" <DAYS>)
+                    .required(false)
+                    .value_parser(value_parser!(u64))
+                    .help("Keep stashes saved by snapshot_stashes for DAYS days after they were made. Defaults to --keep-days")
+                )
+                .arg(arg!(--repack)
+                    .required(false)
+                    .action(clap::builder::ArgAction::SetTrue)
+                    .help("Also repack the repository after gc")
+                )
+                .arg(arg!(--"dry-run")
+                    .required(false)
+                    .action(clap::builder::ArgAction::SetTrue)
+                    .help("Report what would be deleted without deleting anything")
+                )
+        )
+        .subcommand(
+            Command::new("summary")
+                .about("Show when each watched repo was last snapshotted, most stale first. Reads the snapshot refs, not the logs.")
+                .arg(arg!(--json)
+                    .required(false)
+                    .action(clap::builder::ArgAction::SetTrue)
+                    .help("Print a JSON array instead of a table")
+                )
+                .arg(arg!(--"max-age" <AGE>)
+                    .required(false)
+                    .value_parser(summary::parse_age)
+                    .help("Exit with an error if any repo has changes older than this that aren't snapshotted, e.g. 1d or 12h")
+                )
+                .arg(arg!(--verbose)
+                    .action(clap::builder::ArgAction::SetTrue)
+                    .help("Also print how many directories each watch looked in and skipped, to work out why a repo is missing")
+                )
+        )
+        .subcommand(
+            Command::new("identity")
+                .about("Show who each watched repo's snapshots are committed as, and whether that came from dura's config, git's config or a fallback")
+                .arg(arg!(--json)
+                    .required(false)
+                    .action(clap::builder::ArgAction::SetTrue)
+                    .help("Print a JSON array instead of a table")
+                )
+        )
+        .subcommand(
+            Command::new("history")
+                .about("List the latest snapshots dura serve took of a repository, newest first. Kept even after logs are rotated.")
+                .arg(arg_directory.clone().help("The repository. Defaults to current directory"))
+                .arg(arg!(-n --limit <N>)
+                    .required(false)
+                    .value_parser(value_parser!(usize))
+                    .default_value("20")
+                    .help("How many snapshots to list")
+                )
+                .arg(arg!(--json)
+                    .required(false)
+                    .action(clap::builder::ArgAction::SetTrue)
+                    .help("Print a JSON array instead of a table")
+                )
+        )
+        .subcommand(
+            Command::new("find-file")
+                .about("List the snapshots that have a copy of a file, newest first. For finding files that were deleted before they were ever committed.")
+                .arg(Arg::new("pattern")
+                    .required(true)
+                    .help("Path of the file in the repo, or a glob like 'src/**/*.rs'. Without a '/' it also matches file names in any directory")
+                )
+                .arg(arg_directory.clone().help("The repository. Defaults to current directory"))
+                .arg(arg!(--json)
+                    .required(false)
+                    .action(clap::builder::ArgAction::SetTrue)
+                    .help("Print a JSON array instead of a table")
+                )
+        )
+        .subcommand(
+            Command::new("doctor")
+                .about("Check where dura keeps its files, and whether it can write there")
+        )
+        .subcommand(
+            Command::new("fsck")
+                .about("Check that every snapshot can still be read back in full, e.g. after disk trouble")
+                .arg(arg_directory.clone().help("The repository. Defaults to current directory"))
+        )
+        .subcommand(
+            Command::new("timeline")
+                .about("List a repo's snapshots grouped by the commit they were taken on top of, newest first")
+                .arg(arg_directory.clone().help("The repository. Defaults to current directory"))
+                .arg(arg!(--json)
+                    .required(false)
+                    .action(clap::builder::ArgAction::SetTrue)
+                    .help("Print a JSON array instead of a table")
+                )
+        )
+        .subcommand(
+            Command::new("explain")
+                .about("Show how dura's watches decide whether a directory gets snapshotted")
+                .arg(arg_directory.clone().help("The directory to explain. Defaults to current directory"))
+        )
+        .subcommand(
+            Command::new("bench")
+                .about("Time repo discovery, change detection and a capture (without committing) to compare machines or configs.")
+                .arg(arg_directory.clone().help("The repository to time captures on. Defaults to current directory"))
+                .arg(arg!(--iterations <N>)
+                    .required(false)
+                    .value_parser(value_parser!(usize))
+                    .default_value("10")
+                    .help("How many times to run each step")
+                )
+                .arg(arg!(--json)
+                    .required(false)
+                    .action(clap::builder::ArgAction::SetTrue)
+                    .help("Print a JSON array instead of a table")
+                )
+        )
+        .subcommand(
+            Command::new("redact-map")
+                .about("Print the hash that log_redact_paths = \"hash\" uses for each watched repo, next to its path")
         )
         .subcommand(
             Command::new("kill")
@@ -109,163 +625,1415 @@ async fn main() {
                 .short_flag('M')
                 .long_flag("metrics")
                 .about("Convert logs into richer metrics about snapshots.")
-                .arg(arg!(-i --input)
+                .arg(arg!(-i --input <FILE>)
                      .required(false)
                      .num_args(1)
-                     .help("The log file to read. Defaults to stdin.")
+                     .action(clap::builder::ArgAction::Append)
+                     .help("A log file to read, plain or .gz. Can be given more than once, and globs are expanded. Defaults to stdin.")
+                 )
+                .arg(arg!(--format <FORMAT>)
+                     .required(false)
+                     .value_parser(["json", "sqlite", "risk"])
+                     .default_value("json")
+                     .help("json writes one object per line. sqlite writes snapshots into the database given by --output, updating rows that are already there. risk reports, per repo, how long work sat uncommitted and how many lines were at risk.")
                  )
-                .arg(arg!(-o --output)
+                .arg(arg!(-o --output <FILE>)
                      .required(false)
                      .num_args(1)
                      .help("The json file to write. Defaults to stdout.")
                  )
+                .arg(arg!(--"include-maintenance")
+                     .required(false)
+                     .action(clap::builder::ArgAction::SetTrue)
+                     .help("Also output maintenance operations (consolidate, prune, gc).")
+                 )
+                .arg(arg!(--"include-lifecycle")
+                     .required(false)
+                     .action(clap::builder::ArgAction::SetTrue)
+                     .help("Also output when the daemon started, stopped or was taken over.")
+                 )
+                .arg(arg!(-j --jobs <N>)
+                     .required(false)
+                     .value_parser(value_parser!(usize))
+                     .help("How many threads read snapshots from git, one repo per thread. Defaults to the number of CPUs.")
+                 )
         )
         .get_matches();
+    QUIET.store(matches.get_flag("quiet"), Ordering::Relaxed);
+
+    match privileges::Check::current(matches.get_flag(privileges::ALLOW_FLAG)) {
+        privileges::Check::User => (),
+        // So that background processes we start are allowed too
+        privileges::Check::Allowed => std::env::set_var(privileges::ALLOW_ENV, "1"),
+        privileges::Check::Refused => {
+            eprintln!("{}", privileges::REFUSAL);
+            process::exit(1);
+        }
+    }
+
+    let mut resolved = Paths::resolve(
+        matches.get_one::<PathBuf>("config-dir").cloned(),
+        matches.get_one::<PathBuf>("cache-dir").cloned(),
+    );
+    if resolved.from_legacy_home() {
+        note!(
+            "dura: {} is deprecated, use {} and {} (or --config-dir and --cache-dir) instead",
+            paths::LEGACY_ENV,
+            paths::CONFIG_ENV,
+            paths::CACHE_ENV
+        );
+    }
+    // So that background processes we start use the same ones
+    std::env::set_var(paths::CONFIG_ENV, &resolved.config_dir);
+    std::env::set_var(paths::CACHE_ENV, &resolved.cache_dir);
+    // ...which then make the same choice, and warn for themselves
+    if resolved.fall_back_if_unwritable() {
+        eprintln!(
+            "dura: WARNING: {} can't be written to, so the runtime lock, history and logs are in {} instead and may not survive a reboot. Point --cache-dir or {} somewhere writable.",
+            resolved.cache_fallback_from.as_deref().unwrap_or(Path::new("")).display(),
+            resolved.cache_dir.display(),
+            paths::CACHE_ENV
+        );
+    }
+    paths::set(resolved);
 
     match matches.subcommand() {
-        Some(("capture", arg_matches)) => {
-            let dir = Path::new(arg_matches.get_one::<String>("directory").unwrap());
-            match snapshots::capture(dir) {
-                Ok(oid_opt) => {
-                    if let Some(oid) = oid_opt {
-                        println!("{oid}");
+        Some(("capture", arg_matches)) => capture(arg_matches),
+        Some(("guard", arg_matches)) => guard(arg_matches, &cwd),
+        Some(("serve", arg_matches)) => serve(arg_matches).await,
+        Some(("watch", arg_matches)) => watch(arg_matches, &cwd),
+        Some(("unwatch", arg_matches)) => unwatch(arg_matches),
+        Some(("init", arg_matches)) => init(arg_matches),
+        Some(("config", arg_matches)) => match arg_matches.subcommand() {
+            Some(("check", arg_matches)) => config_check(arg_matches),
+            Some(("show", arg_matches)) => config_show(arg_matches),
+            Some(("export", _)) => config_export(),
+            Some(("import", arg_matches)) => config_import(arg_matches),
+            _ => unreachable!(),
+        },
+        Some(("migrate-refs", arg_matches)) => migrate_refs(arg_matches),
+        Some(("pin", arg_matches)) => pin(arg_matches),
+        Some(("size", arg_matches)) => size(arg_matches),
+        Some(("compact", arg_matches)) => compact(arg_matches),
+        Some(("summary", arg_matches)) => summary(arg_matches),
+        Some(("identity", arg_matches)) => identity(arg_matches),
+        Some(("history", arg_matches)) => history(arg_matches),
+        Some(("find-file", arg_matches)) => find_file(arg_matches),
+        Some(("doctor", _)) => doctor(),
+        Some(("fsck", arg_matches)) => fsck(arg_matches),
+        Some(("timeline", arg_matches)) => timeline(arg_matches),
+        Some(("explain", arg_matches)) => explain(arg_matches, &cwd),
+        Some(("bench", arg_matches)) => bench(arg_matches),
+        Some(("redact-map", _)) => redact_map(),
+        Some(("boost", arg_matches)) => boost(arg_matches),
+        Some(("kill", _)) => kill(),
+        Some(("metrics", arg_matches)) => metrics(arg_matches),
+        _ => unreachable!(),
+    }
+}
+
+fn capture(arg_matches: &ArgMatches) -> CliResult {
+    let dir = Path::new(arg_matches.get_one::<String>("directory").unwrap());
+    let via_daemon = match arg_matches.get_flag("via-daemon") {
+        true => capture_via_daemon(dir)?,
+        false => None,
+    };
+    let result = match via_daemon {
+        Some(response) => match response.error {
+            Some(e) => Err(e),
+            None => Ok(response.status),
+        },
+        None if arg_matches.get_flag("force") => {
+            let capture_config =
+                snapshots::CaptureConfig::for_repo(&Config::load_or_default(), dir);
+            snapshots::capture_forced(dir, &capture_config).map_err(|e| e.to_string())
+        }
+        None => snapshots::capture(dir).map_err(|e| e.to_string()),
+    };
+    match result {
+        Ok(Some(status)) => println!("{status}"),
+        Ok(None) => (),
+        Err(e) => return Err(CliError::Failed(format!("capture failed: {e}"))),
+    }
+    maybe_ensure_daemon(arg_matches)
+}
+
+fn guard(arg_matches: &ArgMatches, cwd: &Path) -> CliResult {
+    let mut command = arg_matches.get_many::<String>("command").unwrap();
+    let program = command.next().unwrap();
+    let repo = git2::Repository::discover(cwd)
+        .map_err(|e| CliError::Failed(format!("Not in a git repository: {e}")))?;
+    let dir = repo
+        .workdir()
+        .ok_or_else(|| CliError::Failed("Can't guard a bare repository".to_string()))?
+        .to_path_buf();
+
+    // Not running the command at all is better than running it without a way back
+    let before = recovery_point(&dir).map_err(|e| {
+        CliError::Failed(format!("Couldn't snapshot before running {program}: {e}"))
+    })?;
+    eprintln!("dura: before: {before}");
+
+    let status = process::Command::new(program)
+        .args(command)
+        .status()
+        .map_err(|e| CliError::Failed(format!("Couldn't run {program}: {e}")))?;
+
+    match recovery_point(&dir) {
+        Ok(after) => eprintln!("dura: after: {after}"),
+        Err(e) => eprintln!("dura: Couldn't snapshot after running {program}: {e}"),
+    }
+    if !status.success() {
+        process::exit(exit_code(status));
+    }
+    Ok(())
+}
+
+/// Snapshots the repo and returns the new snapshot. Forced, so it holds the worktree as it is
+/// even when nothing changed, `skip_on_branches` matches, or only untracked files are new.
+fn recovery_point(dir: &Path) -> Result<String, git2::Error> {
+    let capture_config = snapshots::CaptureConfig {
+        skip_on_branches: vec![],
+        ..snapshots::CaptureConfig::for_repo(&Config::load_or_default(), dir)
+    };
+    match snapshots::capture_forced(dir, &capture_config)? {
+        Some(status) => Ok(status.commit_hash),
+        None => Err(git2::Error::from_str("nothing was snapshotted")),
+    }
+}
+
+/// The exit code a shell would report for `status`, e.g. 130 after Ctrl-C
+fn exit_code(status: process::ExitStatus) -> i32 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(signal) = status.signal() {
+            return 128 + signal;
+        }
+    }
+    status.code().unwrap_or(1)
+}
+
+/// How long `capture --via-daemon` waits for the daemon to take the request. It only looks
+/// between repos, so a loop busy with a slow one can hold it up for a while.
+const DAEMON_PICKUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// None when there's no daemon to ask, or it didn't answer in time
+fn capture_via_daemon(dir: &Path) -> Result<Option<CaptureResponse>, CliError> {
+    let lock = RuntimeLock::load_or_default();
+    if !lock.pid.is_some_and(database::pid_alive) {
+        return Ok(None);
+    }
+    let dir = dir
+        .canonicalize()
+        .map_err(|e| CliError::Failed(format!("{}: {e}", dir.display())))?;
+    let capture_timeout = Config::load_or_default().per_repo_timeout();
+    match trigger::request_capture(
+        &trigger::default_dir(),
+        &dir,
+        DAEMON_PICKUP_TIMEOUT,
+        capture_timeout,
+    ) {
+        Ok(Some(response)) => Ok(Some(response)),
+        Ok(None) => {
+            note!("dura: dura serve didn't answer, capturing here instead");
+            Ok(None)
+        }
+        Err(e) => {
+            note!("dura: couldn't ask dura serve ({e}), capturing here instead");
+            Ok(None)
+        }
+    }
+}
+
+/// How long to wait for a daemon started by `ensure_daemon` to take the runtime lock
+const DAEMON_START_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn maybe_ensure_daemon(arg_matches: &ArgMatches) -> CliResult {
+    if arg_matches.get_flag("ensure-daemon") || Config::load_or_default().auto_start_daemon {
+        ensure_daemon()?;
+    }
+    Ok(())
+}
+
+fn boost(arg_matches: &ArgMatches) -> CliResult {
+    let dir = Path::new(arg_matches.get_one::<String>("directory").unwrap());
+    let repo = dir
+        .canonicalize()
+        .map_err(|e| CliError::Failed(format!("{}: {e}", dir.display())))?;
+    let mut boosts = Boosts::load_or_default();
+    let now = chrono::Utc::now();
+    boosts.remove_expired(now);
+
+    if arg_matches.get_flag("clear") {
+        match boosts.repos.remove(&repo) {
+            Some(_) => println!("cleared the boost on {}", repo.display()),
+            None => println!("{} isn't boosted", repo.display()),
+        }
+        boosts.save()?;
+        return Ok(());
+    }
+    if Config::load()?.watch_root_of(&repo).is_none() {
+        return Err(CliError::Failed(format!(
+            "{} isn't under a watched directory, so dura serve won't look at it",
+            repo.display()
+        )));
+    }
+    let length = *arg_matches.get_one::<Duration>("for").unwrap();
+    let interval_secs = *arg_matches.get_one::<u64>("interval").unwrap();
+    let until = chrono::Duration::from_std(length)
+        .ok()
+        .and_then(|length| now.checked_add_signed(length))
+        .ok_or_else(|| CliError::Usage("--for is too long".to_string()))?;
+    boosts.repos.insert(
+        repo.clone(),
+        Boost {
+            until,
+            interval_secs,
+        },
+    );
+    boosts.save()?;
+    println!(
+        "boosted {} until {}, every {interval_secs}s",
+        repo.display(),
+        until.with_timezone(&chrono::Local).format("%H:%M:%S")
+    );
+    if !RuntimeLock::load_or_default().is_alive() {
+        note!("dura: dura serve isn't running, the boost starts once it is");
+    }
+    Ok(())
+}
+
+/// Starts `dura serve` in the background, unless a live process already holds the runtime lock.
+/// The child gets its own process group and no terminal, so it outlives this command.
+fn ensure_daemon() -> CliResult {
+    if RuntimeLock::load_or_default().is_alive() {
+        return Ok(());
+    }
+
+    let exe = std::env::current_exe()
+        .map_err(|e| CliError::Failed(format!("Couldn't find the dura executable: {e}")))?;
+    let logfile = RuntimeLock::default_log_path();
+    Config::create_dir(&logfile)?;
+    let mut command = process::Command::new(exe);
+    command
+        .arg("serve")
+        .arg("--logfile")
+        .arg(&logfile)
+        .stdin(process::Stdio::null())
+        .stdout(process::Stdio::null())
+        .stderr(process::Stdio::null());
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        command.process_group(0);
+    }
+    let mut child = command
+        .spawn()
+        .map_err(|e| CliError::Failed(format!("Couldn't start dura serve: {e}")))?;
+
+    let started = std::time::Instant::now();
+    while started.elapsed() < DAEMON_START_TIMEOUT {
+        if let Ok(Some(status)) = child.try_wait() {
+            return Err(CliError::Failed(format!(
+                "dura serve exited right away ({status}), see {}",
+                logfile.display()
+            )));
+        }
+        if RuntimeLock::load_or_default().pid == Some(child.id()) {
+            note!(
+                "dura: started dura serve (pid {}), logging to {}",
+                child.id(),
+                logfile.display()
+            );
+            return Ok(());
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    Err(CliError::Failed(format!(
+        "Started dura serve (pid {}) but it didn't take the runtime lock, see {}",
+        child.id(),
+        logfile.display()
+    )))
+}
+
+async fn serve(arg_matches: &ArgMatches) -> CliResult {
+    // Refuse to start rather than run with nothing to watch
+    let config = Config::load()?;
+
+    let verbosity = match arg_matches.get_flag("quiet") {
+        true => -1,
+        false => arg_matches.get_count("verbose").min(2) as i8,
+    };
+    let directive = logger::filter_directive(
+        std::env::var(EnvFilter::DEFAULT_ENV).ok().as_deref(),
+        arg_matches
+            .get_one::<String>("log-filter")
+            .map(String::as_str),
+        verbosity,
+        config.log_level.as_deref(),
+    );
+    let env_filter = EnvFilter::try_new(&directive)
+        .map_err(|e| CliError::Usage(format!("Invalid log filter '{directive}': {e}")))?;
+    let events = arg_matches.get_one::<EventTarget>("events-fd");
+    if let Some(target) = events {
+        // Fail now rather than in the worker, where it would look like a crash
+        EventSink::open(target)
+            .map_err(|e| CliError::Failed(format!("Unable to open {target} for events: {e}")))?;
+    }
+
+    match arg_matches.get_one::<String>("logfile") {
+        Some(logfile) => {
+            let file = logfile.to_string();
+            // Fail now rather than on the first log line
+            OpenOptions::new()
+                .append(true)
+                .create(true)
+                .open(&file)
+                .map_err(|e| CliError::Failed(format!("Unable to open log file {file}: {e}")))?;
+            Registry::default()
+                .with(env_filter)
+                .with(NestedJsonLayer::new(move || {
+                    let result_open_file = OpenOptions::new().append(true).create(true).open(&file);
+                    match result_open_file {
+                        Ok(f) => f,
+                        Err(e) => {
+                            eprintln!("Unable to open file {file} for logging due to {e}");
+                            std::process::exit(1);
+                        }
                     }
-                }
-                Err(e) => {
-                    println!("Dura capture failed: {e}");
-                    process::exit(1);
-                }
+                }))
+                .init();
+        }
+        // Keep stdout for the events
+        None if events == Some(&EventTarget::Stdout) => {
+            Registry::default()
+                .with(env_filter)
+                .with(NestedJsonLayer::new(std::io::stderr))
+                .init();
+        }
+        None => {
+            Registry::default()
+                .with(env_filter)
+                .with(NestedJsonLayer::new(std::io::stdout))
+                .init();
+        }
+    }
+
+    let mut options = poller::PollerOptions {
+        once: arg_matches.get_flag("once"),
+        events: events.cloned(),
+        ..Default::default()
+    };
+    if let Some(secs) = arg_matches.get_one::<u64>("interval") {
+        options.interval = Duration::from_secs(*secs);
+    }
+
+    if privileges::Check::current(false) == privileges::Check::Allowed {
+        warn!("Running with root privileges. Git objects dura writes will be owned by root, which can break git for anyone else using those repos");
+    }
+
+    let build = serde_json::to_string(&BuildInfo::current()).unwrap_or_default();
+    info!(
+        build = build.as_str(),
+        "Started serving with dura v{}",
+        crate_version!()
+    );
+    let resolved = paths::current();
+    if let Some(from) = &resolved.cache_fallback_from {
+        warn!(
+            unwritable = from.to_string_lossy().as_ref(),
+            cache_dir = resolved.cache_dir.to_string_lossy().as_ref(),
+            "cache_dir_fallback"
+        );
+    }
+    if arg_matches.get_flag("supervise") {
+        let exe = std::env::current_exe()
+            .map_err(|e| CliError::Failed(format!("Couldn't find the dura executable: {e}")))?;
+        let worker_args: Vec<_> = std::env::args_os()
+            .skip(1)
+            .filter(|arg| arg != "--supervise")
+            .collect();
+        return supervisor::supervise(&exe, &worker_args)
+            .await
+            .map_err(CliError::Failed);
+    }
+    options.supervisor = arg_matches.get_one::<u32>("supervised-by").copied();
+    if let Some(supervisor) = options.supervisor {
+        supervisor::die_with_supervisor(supervisor);
+    }
+    poller::start(options).await;
+    Ok(())
+}
+
+fn watch(arg_matches: &ArgMatches, cwd: &Path) -> CliResult {
+    let mut dirs = arg_matches
+        .get_many::<String>("directory")
+        .unwrap_or_default()
+        .map(|s| s.to_string())
+        .collect::<Vec<String>>();
+    if arg_matches.get_flag("stdin") {
+        for line in stdin().lines() {
+            let line =
+                line.map_err(|e| CliError::Failed(format!("Couldn't read from stdin: {e}")))?;
+            if !line.trim().is_empty() {
+                dirs.push(line.trim().to_string());
             }
         }
-        Some(("serve", arg_matches)) => {
-            let env_filter =
-                EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
-
-            match arg_matches.get_one::<String>("logfile") {
-                Some(logfile) => {
-                    let file = logfile.to_string();
-                    Registry::default()
-                        .with(env_filter)
-                        .with(NestedJsonLayer::new(move || {
-                            let result_open_file =
-                                OpenOptions::new().append(true).create(true).open(&file);
-                            match result_open_file {
-                                Ok(f) => f,
-                                Err(e) => {
-                                    eprintln!("Unable to open file {file} for logging due to {e}");
-                                    std::process::exit(1);
-                                }
-                            }
-                        }))
-                        .init();
-                }
-                None => {
-                    Registry::default()
-                        .with(env_filter)
-                        .with(NestedJsonLayer::new(std::io::stdout))
-                        .init();
-                }
+    }
+    if dirs.is_empty() && !arg_matches.get_flag("stdin") {
+        dirs.push(path_string(cwd)?);
+    }
+    if arg_matches.get_flag("foreground") {
+        let interval = arg_matches.get_one::<u64>("interval").copied().unwrap_or(5);
+        return match dirs.as_slice() {
+            [dir] => watch_foreground(Path::new(dir), Duration::from_secs(interval)),
+            _ => Err(CliError::Usage(
+                "--foreground watches a single repository".to_string(),
+            )),
+        };
+    }
+
+    let strings = |name: &str| {
+        arg_matches
+            .get_many::<String>(name)
+            .map(|values| values.map(|s| s.to_string()).collect::<Vec<String>>())
+    };
+    let edit = WatchEdit {
+        include: strings("include"),
+        exclude: strings("exclude"),
+        max_depth: arg_matches.get_one::<u8>("maxdepth").copied(),
+        enabled: match (
+            arg_matches.get_flag("enable"),
+            arg_matches.get_flag("disable"),
+        ) {
+            (true, _) => Some(true),
+            (_, true) => Some(false),
+            _ => None,
+        },
+        add_include: strings("add-include").unwrap_or_default(),
+        remove_include: strings("remove-include").unwrap_or_default(),
+        add_exclude: strings("add-exclude").unwrap_or_default(),
+        remove_exclude: strings("remove-exclude").unwrap_or_default(),
+    };
+
+    watch_dirs(&dirs, &edit, arg_matches.get_flag("strict"))?;
+    maybe_ensure_daemon(arg_matches)
+}
+
+/// Snapshots one repo until the process is stopped, without the config's watches or the runtime
+/// lock. A `dura serve` snapshotting the same repo at the same time is harmless: both commit on
+/// top of the same snapshot ref, and the commit of whichever loses the race fails because the ref
+/// moved. That one is tried again from the new tip, where there's usually nothing left to capture.
+fn watch_foreground(dir: &Path, interval: Duration) -> CliResult {
+    let dir = dir
+        .canonicalize()
+        .map_err(|e| CliError::Failed(format!("{}: {e}", dir.display())))?;
+    if !snapshots::is_repo(&dir) {
+        return Err(CliError::Failed(format!(
+            "{} is not a git repository",
+            dir.display()
+        )));
+    }
+    // Still honors the identity and ref settings, if there is a config
+    let capture_config = snapshots::CaptureConfig::for_repo(&Config::load_or_default(), &dir);
+    let mut guard = PollGuard::new();
+    note!(
+        "dura: watching {} every {}s, Ctrl-C to stop",
+        dir.display(),
+        interval.as_secs()
+    );
+    loop {
+        if guard.dir_changed(&dir) {
+            let mut captured = snapshots::capture_with(&dir, &capture_config);
+            if matches!(&captured, Err(e) if e.code() == git2::ErrorCode::Modified) {
+                captured = snapshots::capture_with(&dir, &capture_config);
             }
+            match captured {
+                Ok(Some(status)) => println!("{status}"),
+                Ok(None) => (),
+                Err(e) => eprintln!("dura: capture failed: {e}"),
+            }
+        }
+        std::thread::sleep(interval);
+    }
+}
 
-            info!("Started serving with dura v{}", crate_version!());
-            poller::start().await;
-        }
-        Some(("watch", arg_matches)) => {
-            let dir = Path::new(arg_matches.get_one::<String>("directory").unwrap());
-
-            let include = arg_matches
-                .get_many::<String>("include")
-                .unwrap_or_default()
-                .map(|s| s.to_string())
-                .collect::<Vec<String>>();
-            let exclude = arg_matches
-                .get_many::<String>("exclude")
-                .unwrap_or_default()
-                .map(|s| s.to_string())
-                .collect::<Vec<String>>();
-            let max_depth = arg_matches
-                .get_one::<String>("maxdepth")
-                .unwrap_or(&"255".to_string())
-                .parse::<u8>()
-                .expect("Max depth must be between 0-255");
-
-            let watch_config = WatchConfig {
-                include,
-                exclude,
-                max_depth,
-            };
+fn unwatch(arg_matches: &ArgMatches) -> CliResult {
+    let path = arg_matches.get_one::<String>("directory").unwrap();
+    let mut config = Config::load()?;
+    match config
+        .set_unwatch(path.to_string())
+        .map_err(|e| CliError::Failed(format!("Can't unwatch {path}: {e}")))?
+    {
+        UnwatchResult::Removed(abs_path) => println!("Stopped watching {abs_path}"),
+        UnwatchResult::NotWatched(abs_path) => println!("{abs_path} is not being watched"),
+    }
+    config.save()?;
+    warn_config_mismatch();
+    Ok(())
+}
+
+/// Each step checks before it changes anything, so running init again only fills in what's
+/// missing.
+fn init(arg_matches: &ArgMatches) -> CliResult {
+    let dir = arg_matches.get_one::<String>("directory").unwrap();
+    let config_path = Config::default_path();
+    if config_path.exists() {
+        // Still load it, so a broken config stops init here
+        Config::load()?;
+    } else {
+        Config::empty().save()?;
+        println!("Created {}", config_path.display());
+    }
+
+    watch_dirs(std::slice::from_ref(dir), &WatchEdit::default(), true)?;
+
+    if !arg_matches.get_flag("no-service") {
+        ensure_daemon()?;
+    }
 
-            watch_dir(dir, watch_config);
+    // The directory might be a parent of many repos, so capture each one it covers
+    let root = std::fs::canonicalize(dir).unwrap_or_else(|_| dir.into());
+    let config = Config::load()?;
+    let repos: Vec<PathBuf> = config
+        .git_repos()
+        .filter(|repo| config.path_case.starts_with(repo, &root))
+        .collect();
+    for repo in &repos {
+        match snapshots::capture(repo) {
+            Ok(Some(status)) => println!("Took a first snapshot of {}: {status}", repo.display()),
+            Ok(None) => println!("{} has no changes to snapshot yet", repo.display()),
+            Err(e) => {
+                return Err(CliError::Failed(format!(
+                    "capture of {} failed: {e}",
+                    repo.display()
+                )))
+            }
         }
-        Some(("unwatch", arg_matches)) => {
-            let dir = Path::new(arg_matches.get_one::<String>("directory").unwrap());
-            unwatch_dir(dir)
+    }
+
+    println!();
+    println!("dura is set up for {} repositories", repos.len());
+    println!("  config: {}", config_path.display());
+    println!("  log:    {}", RuntimeLock::default_log_path().display());
+    println!(
+        "List a repo's snapshots with `git for-each-ref {}`, or summarize the log with `dura metrics -i <log>`",
+        config.ref_namespace.prefix()
+    );
+    Ok(())
+}
+
+fn config_check(arg_matches: &ArgMatches) -> CliResult {
+    let path = match arg_matches.get_one::<String>("FILE") {
+        Some(file) => PathBuf::from(file),
+        None => Config::default_path(),
+    };
+    let config = Config::check_file(&path)?;
+    println!(
+        "{}: ok, {} watches (config version {})",
+        path.display(),
+        config.repos.len(),
+        config.version
+    );
+    Ok(())
+}
+
+fn config_show(arg_matches: &ArgMatches) -> CliResult {
+    let dir = Path::new(arg_matches.get_one::<String>("directory").unwrap());
+    let config = Config::load()?;
+    let layers = Layers {
+        cli: CliOverrides {
+            interval_secs: arg_matches.get_one::<u64>("interval").copied(),
+        },
+        ..Layers::for_repo(&config, dir)
+    };
+    let effective = EffectiveConfig::resolve(&layers);
+
+    if arg_matches.get_flag("json") {
+        println!("{}", serde_json::to_string(&effective).unwrap_or_default());
+        return Ok(());
+    }
+    match &effective.watch_root {
+        Some(root) => println!("watch: {}", root.display()),
+        None => println!("watch: none, so only `dura capture` snapshots it"),
+    }
+    let rows = effective.rows();
+    let width = rows.iter().map(|(name, ..)| name.len()).max().unwrap_or(0);
+    for (name, value, source) in rows {
+        println!("{name:width$} = {value}  ({source})");
+    }
+    Ok(())
+}
+
+fn config_export() -> CliResult {
+    let home = dirs::home_dir();
+    let portable = Config::load()?.export(home.as_deref());
+    let toml = toml::to_string(&portable)
+        .map_err(|e| CliError::Failed(format!("Couldn't serialize the config: {e}")))?;
+    print!("{toml}");
+    Ok(())
+}
+
+fn config_import(arg_matches: &ArgMatches) -> CliResult {
+    let file = Path::new(arg_matches.get_one::<String>("FILE").unwrap());
+    let home = dirs::home_dir();
+    let (imported, set) = Config::load_bundle(file).map_err(|e| CliError::Failed(e.to_string()))?;
+    let imported = imported
+        .localize(home.as_deref())
+        .map_err(|e| CliError::Failed(format!("Can't import {}: {e}", file.display())))?;
+
+    let mut config = Config::load()?;
+    let changes = config.import(imported, &set, arg_matches.get_flag("replace"));
+    for path in config.repos.keys() {
+        if !Path::new(path).is_dir() {
+            note!("dura: warning: {path} doesn't exist on this machine (yet)");
+        }
+    }
+    config.save()?;
+
+    match changes.is_empty() {
+        true => println!("Nothing changed"),
+        false => {
+            println!("Imported {}", file.display());
+            for change in changes {
+                println!("  {change}");
+            }
+        }
+    }
+    Ok(())
+}
+
+fn migrate_refs(arg_matches: &ArgMatches) -> CliResult {
+    let dir = Path::new(arg_matches.get_one::<String>("directory").unwrap());
+    let to = match arg_matches.get_one::<String>("to").map(|s| s.as_str()) {
+        Some("heads") => RefNamespace::Heads,
+        Some("dura") => RefNamespace::Dura,
+        _ => Config::load()?.ref_namespace,
+    };
+    let moved = snapshots::migrate_refs(dir, to)
+        .map_err(|e| CliError::Failed(format!("migrate-refs failed: {e}")))?;
+    println!("Moved {moved} snapshot refs to {}", to.prefix());
+    Ok(())
+}
+
+fn pin(arg_matches: &ArgMatches) -> CliResult {
+    let target = arg_matches.get_one::<String>("target");
+    let directory = arg_matches.get_one::<String>("directory").unwrap();
+    let fail = |e: git2::Error| CliError::Failed(format!("pin failed: {e}"));
+
+    if arg_matches.get_flag("list") {
+        let dir = Path::new(target.unwrap_or(directory));
+        for (name, oid) in snapshots::list_pins(dir).map_err(fail)? {
+            println!("{name}\t{oid}");
         }
-        Some(("kill", _)) => {
-            kill();
+        return Ok(());
+    }
+    if let Some(name) = arg_matches.get_one::<String>("delete") {
+        let dir = Path::new(target.unwrap_or(directory));
+        snapshots::unpin(dir, name).map_err(fail)?;
+        println!("Removed pin {name}");
+        return Ok(());
+    }
+
+    let (target, name) = match (target, arg_matches.get_one::<String>("name")) {
+        (Some(target), Some(name)) => (target, name),
+        _ => {
+            return Err(CliError::Usage(
+                "pin needs a commit and --name, or --list or --delete".to_string(),
+            ))
         }
-        Some(("metrics", arg_matches)) => {
-            let mut input: Box<dyn Read> = match arg_matches.get_one::<String>("input") {
-                Some(input) => Box::new(
-                    File::open(input).unwrap_or_else(|_| panic!("Couldn't open '{}'", input)),
+    };
+    let oid = snapshots::pin(Path::new(directory), target, name).map_err(fail)?;
+    println!("Pinned {oid} as {}{name}", snapshots::PIN_PREFIX);
+    Ok(())
+}
+
+fn size(arg_matches: &ArgMatches) -> CliResult {
+    let top = *arg_matches.get_one::<usize>("top").unwrap();
+    let repos: Vec<PathBuf> = if arg_matches.get_flag("all") {
+        Config::load()?.git_repos().collect()
+    } else {
+        vec![PathBuf::from(
+            arg_matches.get_one::<String>("directory").unwrap(),
+        )]
+    };
+
+    let mut failed = false;
+    for repo in repos {
+        match size::dura_size(&repo) {
+            Ok(report) => {
+                println!(
+                    "{}: {} in {} objects from {} snapshot commits",
+                    repo.display(),
+                    human_bytes(report.total_bytes),
+                    report.objects,
+                    report.commits
+                );
+                for (file, bytes) in report.files.iter().take(top) {
+                    println!("  {:>10}  {file}", human_bytes(*bytes));
+                }
+            }
+            Err(e) => {
+                eprintln!("dura: {}: {e}", repo.display());
+                failed = true;
+            }
+        }
+    }
+    match failed {
+        true => Err(CliError::Failed(
+            "Couldn't measure some repositories".to_string(),
+        )),
+        false => Ok(()),
+    }
+}
+
+fn compact(arg_matches: &ArgMatches) -> CliResult {
+    let keep_days = *arg_matches.get_one::<u64>("keep-days").unwrap();
+    let options = CompactOptions {
+        keep_days,
+        keep_stash_days: arg_matches
+            .get_one::<u64>("keep-stash-days")
+            .copied()
+            .unwrap_or(keep_days),
+        dry_run: arg_matches.get_flag("dry-run"),
+        repack: arg_matches.get_flag("repack"),
+    };
+    let repos: Vec<PathBuf> = if arg_matches.get_flag("all") {
+        Config::load()?.git_repos().collect()
+    } else {
+        vec![PathBuf::from(
+            arg_matches.get_one::<String>("directory").unwrap(),
+        )]
+    };
+
+    let mut failed = false;
+    for repo in repos {
+        let report = match compact::compact(&repo, &options) {
+            Ok(report) => report,
+            Err(e) => {
+                eprintln!("dura: {}: {e}", repo.display());
+                failed = true;
+                continue;
+            }
+        };
+        println!("{}:", repo.display());
+        let deleted = match options.dry_run {
+            true => "would delete",
+            false => "deleted",
+        };
+        for stage in &report.stages {
+            let result = match &stage.result {
+                Ok(StageOutcome::Deleted(n)) => format!("{deleted} {n} snapshot branches"),
+                Ok(StageOutcome::Ran(outcome @ GcOutcome::Ran { after, .. })) => format!(
+                    "reclaimed {}, .git/objects is now {}",
+                    human_bytes(outcome.reclaimed()),
+                    human_bytes(*after)
                 ),
-                None => Box::new(BufReader::new(stdin())),
+                Ok(StageOutcome::Ran(GcOutcome::Skipped(reason))) => {
+                    format!("skipped, {reason} exists")
+                }
+                Ok(StageOutcome::DryRun) => "skipped, dry run".to_string(),
+                Err(e) => format!("failed: {e}"),
             };
-            let mut output: Box<dyn Write> = match arg_matches.get_one::<String>("output") {
-                Some(output) => Box::new(
-                    File::open(output).unwrap_or_else(|_| panic!("Couldn't open '{}'", output)),
-                ),
-                None => Box::new(BufWriter::new(stdout())),
+            println!("  {:<13} {result}", format!("{}:", stage.stage));
+        }
+        println!(
+            "  before:       {} snapshot branches, {} only in snapshots",
+            report.before.branches,
+            human_bytes(report.before.bytes)
+        );
+        for name in &report.unreadable {
+            println!("  skipped:      {name}, it doesn't point at a commit");
+        }
+        match &report.after {
+            Ok(after) => println!(
+                "  after:        {} snapshot branches, {} only in snapshots (about {} reclaimed)",
+                after.branches,
+                human_bytes(after.bytes),
+                human_bytes(report.reclaimed_bytes())
+            ),
+            Err(e) => println!("  after:        couldn't measure: {e}"),
+        }
+        failed |= report.failed();
+    }
+    match failed {
+        true => Err(CliError::Failed(
+            "Couldn't compact some repositories".to_string(),
+        )),
+        false => Ok(()),
+    }
+}
+
+fn summary(arg_matches: &ArgMatches) -> CliResult {
+    let max_age = arg_matches.get_one::<Duration>("max-age").copied();
+    warn_config_mismatch();
+    let mut guard = PollGuard::new();
+    let mut summaries = vec![];
+    let mut failed = false;
+    let config = Config::load()?;
+    let mut repos = config.git_repos();
+    for repo in repos.by_ref() {
+        match summary::summarize(&repo, &mut guard) {
+            Ok(summary) => summaries.push(summary),
+            Err(e) => {
+                eprintln!("dura: {}: {e}", repo.display());
+                failed = true;
+            }
+        }
+    }
+    if arg_matches.get_flag("verbose") {
+        for (root, stats) in repos.stats() {
+            note!(
+                "dura: {}: {} dirs visited, {} pruned by max_depth, {} pruned by exclude, {} let back in by include, {} repos found",
+                root.display(),
+                stats.dirs_visited,
+                stats.pruned_by_depth,
+                stats.pruned_by_exclude,
+                stats.readmitted_by_include,
+                stats.repos_found
+            );
+        }
+    }
+    for root in repos.capped() {
+        note!(
+            "dura: {} has more than {} repos, the rest aren't snapshotted. Narrow the watch down or raise max_repos_per_watch.",
+            root.display(),
+            config.max_repos_per_watch
+        );
+    }
+    for root in config
+        .repos
+        .keys()
+        .filter(|root| matches!(Path::new(root).try_exists(), Ok(false)))
+    {
+        note!("dura: {root} doesn't exist anymore. Run `dura unwatch {root}` to stop watching it.");
+    }
+    summary::sort_by_staleness(&mut summaries);
+
+    let now = chrono::Utc::now();
+    let is_stale = |s: &summary::RepoSummary| max_age.is_some_and(|max| s.is_stale(now, max));
+    if arg_matches.get_flag("json") {
+        let json: Vec<serde_json::Value> = summaries
+            .iter()
+            .map(|s| {
+                let mut value = serde_json::to_value(s).unwrap_or_default();
+                value["age_secs"] = s.age(now).map(|age| age.as_secs()).into();
+                value["stale"] = is_stale(s).into();
+                value
+            })
+            .collect();
+        println!("{}", serde_json::Value::from(json));
+    } else {
+        for s in &summaries {
+            let age = match s.age(now) {
+                Some(age) => summary::format_age(age),
+                None => "never".to_string(),
             };
-            if let Err(e) = metrics::get_snapshot_metrics(&mut input, &mut output) {
-                eprintln!("Failed: {}", e);
-                process::exit(1);
+            let note = match (&s.unsnapshotted_change, is_stale(s)) {
+                (Some(_), true) => "  changes not snapshotted, STALE",
+                (Some(_), false) => "  changes not snapshotted",
+                (None, _) => "",
+            };
+            println!("{age:>8}  {}{note}", s.repo.display());
+        }
+    }
+
+    let stale = summaries.iter().filter(|s| is_stale(s)).count();
+    if stale > 0 {
+        return Err(CliError::Failed(format!(
+            "{stale} repositories have changes that haven't been snapshotted for too long"
+        )));
+    }
+    match failed {
+        true => Err(CliError::Failed(
+            "Couldn't summarize some repositories".to_string(),
+        )),
+        false => Ok(()),
+    }
+}
+
+fn identity(arg_matches: &ArgMatches) -> CliResult {
+    let config = Config::load()?;
+    let capture_config = snapshots::CaptureConfig::from(&config);
+    let mut identities = vec![];
+    let mut failed = false;
+    for repo in config.git_repos() {
+        match snapshots::open_repo(&repo) {
+            Ok(git_repo) => identities.push((
+                repo,
+                snapshots::resolve_identity(&git_repo, &capture_config),
+            )),
+            Err(e) => {
+                eprintln!("dura: {}: {e}", repo.display());
+                failed = true;
             }
         }
-        _ => unreachable!(),
     }
+
+    if arg_matches.get_flag("json") {
+        let json: Vec<serde_json::Value> = identities
+            .iter()
+            .map(|(repo, identity)| {
+                let mut value = serde_json::to_value(identity).unwrap_or_default();
+                value["repo"] = repo.display().to_string().into();
+                value
+            })
+            .collect();
+        println!("{}", serde_json::Value::from(json));
+    } else {
+        for (repo, identity) in &identities {
+            println!("{}  {identity}", repo.display());
+        }
+    }
+
+    let missing = identities
+        .iter()
+        .filter(|(_, identity)| identity.is_fallback())
+        .count();
+    if config.require_identity && missing > 0 {
+        return Err(CliError::Failed(format!(
+            "{missing} repositories have no identity and won't be snapshotted while require_identity is set"
+        )));
+    }
+    match failed {
+        true => Err(CliError::Failed(
+            "Couldn't open some repositories".to_string(),
+        )),
+        false => Ok(()),
+    }
+}
+
+fn history(arg_matches: &ArgMatches) -> CliResult {
+    let dir = Path::new(arg_matches.get_one::<String>("directory").unwrap());
+    let limit = *arg_matches.get_one::<usize>("limit").unwrap();
+    // The poller records repos by the path it found them at, which is canonical
+    let repo = dir
+        .canonicalize()
+        .map_err(|e| CliError::Failed(format!("{}: {e}", dir.display())))?;
+    let db = History::default_path();
+    let entries = match db.exists() {
+        true => History::open(&db)
+            .and_then(|history| history.recent(&repo, limit))
+            .map_err(|e| CliError::Failed(format!("Couldn't read {}: {e}", db.display())))?,
+        false => vec![],
+    };
+
+    if arg_matches.get_flag("json") {
+        println!("{}", serde_json::to_string(&entries).unwrap_or_default());
+    } else {
+        for entry in &entries {
+            println!(
+                "{}  {}  {} files  {}",
+                entry
+                    .timestamp
+                    .with_timezone(&chrono::Local)
+                    .format("%Y-%m-%d %H:%M:%S"),
+                entry.commit_hash,
+                entry.files_changed,
+                entry.dura_branch
+            );
+        }
+    }
+    Ok(())
+}
+
+fn find_file(arg_matches: &ArgMatches) -> CliResult {
+    let dir = Path::new(arg_matches.get_one::<String>("directory").unwrap());
+    let pattern = arg_matches.get_one::<String>("pattern").unwrap();
+    let pattern = glob::Pattern::new(pattern)
+        .map_err(|e| CliError::Usage(format!("Invalid pattern '{pattern}': {e}")))?;
+    let found = find_file::find_file(dir, pattern)
+        .map_err(|e| CliError::Failed(format!("{}: {e}", dir.display())))?;
+
+    if arg_matches.get_flag("json") {
+        println!("{}", serde_json::to_string(&found).unwrap_or_default());
+    } else {
+        for file in &found {
+            println!(
+                "{}  {}  {:>10}  {}",
+                file.timestamp
+                    .with_timezone(&chrono::Local)
+                    .format("%Y-%m-%d %H:%M:%S"),
+                file.commit,
+                human_bytes(file.size as u64),
+                file.path
+            );
+        }
+    }
+    Ok(())
+}
+
+fn doctor() -> CliResult {
+    let resolved = paths::current();
+    let access = |dir: &Path| match paths::is_writable(dir) {
+        true => "writable",
+        false => "read-only",
+    };
+    println!(
+        "config dir: {} ({})",
+        resolved.config_dir.display(),
+        access(&resolved.config_dir)
+    );
+    if !paths::is_writable(&resolved.config_dir) {
+        println!("  watch, unwatch and anything else that changes the config will fail");
+    }
+    println!(
+        "cache dir:  {} ({})",
+        resolved.cache_dir.display(),
+        access(&resolved.cache_dir)
+    );
+    if let Some(from) = &resolved.cache_fallback_from {
+        println!(
+            "  degraded: {} can't be written to, so this is a fallback that may not survive a reboot",
+            from.display()
+        );
+    }
+    let lock = RuntimeLock::load_or_default();
+    let alive = lock.is_alive();
+    match lock.pid {
+        Some(pid) if alive => println!("daemon:     running (pid {pid})"),
+        _ => println!("daemon:     not running"),
+    }
+    let status = PollerStatus::load_or_default();
+    // Only means anything while the daemon is there to be quiet
+    if let Some(until) = status.quiet_until.filter(|_| alive) {
+        println!("  quiet until {}", until.format("%H:%M"));
+    }
+    let as_of = match alive {
+        true => "",
+        false => ", as of when it last ran",
+    };
+    for (repo, low_disk) in &status.low_disk {
+        println!(
+            "  captures suspended: low disk: {} ({} MB free, min_free_space_mb is {}, since {}{as_of})",
+            repo.display(),
+            low_disk.available_mb,
+            low_disk.min_free_space_mb,
+            low_disk
+                .since
+                .with_timezone(&chrono::Local)
+                .format("%Y-%m-%d %H:%M:%S")
+        );
+    }
+    for (repo, timeout) in &status.timeouts {
+        println!(
+            "  captures timing out: {} ({} in a row, retrying after {}{as_of})",
+            repo.display(),
+            timeout.count,
+            timeout
+                .retry_after
+                .with_timezone(&chrono::Local)
+                .format("%Y-%m-%d %H:%M:%S")
+        );
+    }
+    Ok(())
 }
 
-fn watch_dir(path: &std::path::Path, watch_config: WatchConfig) {
-    let mut config = Config::load();
-    let path = path
-        .to_str()
-        .expect("The provided path is not valid unicode")
-        .to_string();
+fn fsck(arg_matches: &ArgMatches) -> CliResult {
+    let dir = Path::new(arg_matches.get_one::<String>("directory").unwrap());
+    let report =
+        fsck::check_repo(dir).map_err(|e| CliError::Failed(format!("{}: {e}", dir.display())))?;
+    for damaged in &report.damaged {
+        println!("{}  {}", damaged.commit, damaged.problem);
+    }
+    match report.damaged.len() {
+        0 => {
+            println!("{} snapshots, all intact", report.snapshots);
+            Ok(())
+        }
+        n => Err(CliError::Failed(format!(
+            "{n} of {} snapshots are damaged, older ones may still be fine",
+            report.snapshots
+        ))),
+    }
+}
+
+fn timeline(arg_matches: &ArgMatches) -> CliResult {
+    let dir = Path::new(arg_matches.get_one::<String>("directory").unwrap());
+    let bases =
+        timeline::timeline(dir).map_err(|e| CliError::Failed(format!("{}: {e}", dir.display())))?;
+
+    if arg_matches.get_flag("json") {
+        println!("{}", serde_json::to_string(&bases).unwrap_or_default());
+        return Ok(());
+    }
+    let now = chrono::Utc::now();
+    let ago = |timestamp: chrono::DateTime<chrono::Utc>| {
+        let age = (now - timestamp).to_std().unwrap_or_default();
+        format!("{} ago", summary::format_age(age))
+    };
+    for base in &bases {
+        println!(
+            "{}  {}  ({})",
+            &base.commit[..base.commit.len().min(10)],
+            base.subject,
+            ago(base.timestamp)
+        );
+        for snapshot in &base.snapshots {
+            println!(
+                "  {:>10}  {}  {} files (+{} -{})",
+                ago(snapshot.timestamp),
+                snapshot.commit,
+                snapshot.files_changed,
+                snapshot.insertions,
+                snapshot.deletions
+            );
+        }
+    }
+    Ok(())
+}
 
-    config.set_watch(path, watch_config);
-    config.save();
+fn explain(arg_matches: &ArgMatches, cwd: &Path) -> CliResult {
+    let dir = Path::new(arg_matches.get_one::<String>("directory").unwrap());
+    // Doesn't have to exist, e.g. to check where a clone would end up
+    let dir = dir.canonicalize().unwrap_or_else(|_| cwd.join(dir));
+    println!("{}", explain::explain(&Config::load()?, &dir));
+    Ok(())
 }
 
-fn unwatch_dir(path: &std::path::Path) {
-    let mut config = Config::load();
-    let path = path
-        .to_str()
-        .expect("The provided path is not valid unicode")
-        .to_string();
+fn bench(arg_matches: &ArgMatches) -> CliResult {
+    let dir = Path::new(arg_matches.get_one::<String>("directory").unwrap());
+    let iterations = *arg_matches.get_one::<usize>("iterations").unwrap();
+    let results = bench::run(&Config::load_or_default(), dir, iterations)
+        .map_err(|e| CliError::Failed(format!("{}: {e}", dir.display())))?;
 
-    config.set_unwatch(path);
-    config.save();
+    if arg_matches.get_flag("json") {
+        println!("{}", serde_json::to_string(&results).unwrap_or_default());
+    } else {
+        println!(
+            "{:<16} {:>7} {:>11} {:>11}",
+            "step", "runs", "median ms", "p95 ms"
+        );
+        for result in &results {
+            println!(
+                "{:<16} {:>7} {:>11.2} {:>11.2}",
+                result.name, result.samples, result.median_ms, result.p95_ms
+            );
+        }
+    }
+    Ok(())
 }
 
-#[cfg(all(unix))]
-fn check_if_user() -> bool {
-    sudo::check() != sudo::RunningAs::Root
+/// Watch roots are included too, since discovery events name them
+fn redact_map() -> CliResult {
+    let config = Config::load()?;
+    let mut paths: Vec<PathBuf> = config.repos.keys().map(PathBuf::from).collect();
+    paths.extend(config.git_repos());
+    paths.sort();
+    paths.dedup();
+    for path in paths {
+        println!("{}\t{}", redact::hash(&path), path.display());
+    }
+    Ok(())
 }
 
-#[cfg(target_os = "windows")]
-fn check_if_user() -> bool {
-    true
+fn human_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{value:.1} {}", UNITS[unit])
 }
 
+fn metrics(arg_matches: &ArgMatches) -> CliResult {
+    let inputs = match arg_matches.get_many::<String>("input") {
+        Some(patterns) => {
+            let patterns = patterns.cloned().collect::<Vec<String>>();
+            let inputs = metrics::expand_inputs(&patterns)
+                .map_err(|e| CliError::Usage(format!("Bad --input: {e}")))?;
+            // Fail before creating the output file, rather than halfway through
+            for input in &inputs {
+                File::open(input).map_err(|e| {
+                    CliError::Failed(format!("Couldn't open '{}': {e}", input.display()))
+                })?;
+            }
+            Some(inputs)
+        }
+        None => None,
+    };
+    let output = arg_matches.get_one::<String>("output");
+    let format = arg_matches
+        .get_one::<String>("format")
+        .map(String::as_str)
+        .unwrap_or("json");
+    let writer: Box<dyn Write> = match output {
+        Some(output) if format != "sqlite" => Box::new(
+            File::create(output)
+                .map_err(|e| CliError::Failed(format!("Couldn't create '{output}': {e}")))?,
+        ),
+        _ => Box::new(stdout()),
+    };
+    let mut sink: Box<dyn MetricsSink> =
+        match format {
+            "sqlite" => {
+                let output = output
+                    .ok_or_else(|| CliError::Usage("--format sqlite needs --output".to_string()))?;
+                Box::new(SqliteSink::open(Path::new(output)).map_err(|e| {
+                    CliError::Failed(format!("Couldn't open database '{output}': {e}"))
+                })?)
+            }
+            "risk" => Box::new(WorkAtRisk::new(writer)),
+            _ => Box::new(JsonLines::new(writer)),
+        };
+    let options = metrics::MetricsOptions {
+        include_maintenance: arg_matches.get_flag("include-maintenance"),
+        include_lifecycle: arg_matches.get_flag("include-lifecycle"),
+        work_at_risk: format == "risk",
+        jobs: match arg_matches.get_one::<usize>("jobs") {
+            Some(jobs) => *jobs,
+            None => std::thread::available_parallelism().map_or(1, |n| n.get()),
+        },
+        progress: !QUIET.load(Ordering::Relaxed),
+    };
+    match inputs {
+        Some(inputs) => metrics::get_snapshot_metrics_from_files(&inputs, sink.as_mut(), &options),
+        None => {
+            let mut input = BufReader::new(stdin());
+            metrics::get_snapshot_metrics(&mut input, sink.as_mut(), &options)
+        }
+    }
+    .map_err(|e| CliError::Failed(format!("metrics failed: {e}")))
+}
+
+/// Watches every directory in `dirs` and saves the config once at the end. With `strict`, the
+/// first failure stops everything and nothing is saved.
+fn watch_dirs(dirs: &[String], edit: &WatchEdit, strict: bool) -> CliResult {
+    edit.normalized()
+        .map_err(|e| CliError::Usage(e.to_string()))?;
+
+    let mut config = Config::load()?;
+    let (mut created, mut updated, mut unchanged, mut failed) = (0, 0, 0, 0);
+    for path in dirs {
+        let abs_path = std::fs::canonicalize(path).unwrap_or_else(|_| path.into());
+        let abs_path = abs_path.display();
+        match config.set_watch(path.clone(), edit) {
+            Ok(WatchResult::Created) => {
+                created += 1;
+                println!("Started watching {abs_path}");
+            }
+            Ok(WatchResult::Updated(changes)) => {
+                updated += 1;
+                println!("Updated watch of {abs_path}");
+                for change in changes {
+                    println!("  {change}");
+                }
+            }
+            Ok(WatchResult::Unchanged) => {
+                unchanged += 1;
+                match config.repos.get(&abs_path.to_string()) {
+                    Some(watch) if !watch.enabled => {
+                        println!("{abs_path} is already being watched, but disabled")
+                    }
+                    _ => println!("{abs_path} is already being watched"),
+                }
+            }
+            Err(e) if strict => {
+                return Err(CliError::Failed(format!(
+                    "Can't watch {path}: {e}. Nothing was changed because of --strict"
+                )));
+            }
+            Err(e) => {
+                failed += 1;
+                eprintln!("Can't watch {path}: {e}");
+                continue;
+            }
+        }
+        if let Some(watch) = config.repos.get(&abs_path.to_string()) {
+            for entry in watch.missing_entries(Path::new(path)) {
+                note!("dura: warning: '{entry}' doesn't exist under {abs_path} (yet)");
+            }
+        }
+    }
+    config.save()?;
+    warn_config_mismatch();
+
+    if dirs.len() > 1 {
+        println!(
+            "{} directories: {created} started, {updated} updated, {unchanged} unchanged, {failed} failed",
+            dirs.len()
+        );
+    }
+    match failed {
+        0 => Ok(()),
+        _ => Err(CliError::Failed(format!(
+            "{failed} of {} directories couldn't be watched",
+            dirs.len()
+        ))),
+    }
+}
+
+/// Says so when the running daemon reads another config file than this command, since it then
+/// won't see what was just changed
+fn warn_config_mismatch() {
+    let ours = Config::default_path();
+    let lock = RuntimeLock::load_or_default();
+    if let Some(theirs) = lock.config_mismatch(&ours) {
+        note!(
+            "\nWARNING: the running dura serve (pid {}) reads {}, but this command used {}.\n\
+             It won't pick up this change. Is --config-dir or DURA_CONFIG_HOME set differently for it?\n",
+            lock.pid.map(|pid| pid.to_string()).unwrap_or_default(),
+            theirs.display(),
+            ours.display()
+        );
+    }
+}
+
+fn path_string(path: &Path) -> Result<String, CliError> {
+    path.to_str()
+        .map(|s| s.to_string())
+        .ok_or_else(|| CliError::Usage(format!("{} is not valid unicode", path.display())))
+}
+
+/// How long `dura kill` waits for the daemon to notice it lost the lock and exit
+const KILL_TIMEOUT: Duration = Duration::from_secs(15);
+
 /// kills running dura poller
 ///
 /// poller's check to make sure that their pid is the same as the pid
 /// found in config, and if they are not the same they exit. This
 /// function does not actually kill a poller but instead indicates
-/// that any living poller should exit during their next check.
-fn kill() {
-    let mut runtime_lock = RuntimeLock::load();
+/// that any living poller should exit during their next check, then
+/// waits for it to.
+fn kill() -> CliResult {
+    let mut runtime_lock = RuntimeLock::load_or_default();
+    let recorded = runtime_lock.pid.or(runtime_lock.supervisor_pid);
+    let supervisor = runtime_lock.supervisor_pid;
+    let alive = runtime_lock.is_alive();
+    // Whatever is in there, the point is to clear it
     runtime_lock.pid = None;
-    runtime_lock.save();
+    runtime_lock.build = None;
+    runtime_lock.supervisor_pid = None;
+    runtime_lock.save()?;
+
+    let pid = match (recorded, alive) {
+        (None, _) => return Err(CliError::Failed("no daemon recorded".to_string())),
+        (Some(pid), false) => {
+            println!("no running daemon (cleared stale pid {pid})");
+            return Ok(());
+        }
+        (Some(pid), true) => pid,
+    };
+
+    // The daemon checks the lock at the start of every loop, and exits when it's no longer
+    // the holder. A supervisor might be waiting to restart it though, so that's told directly.
+    if let Some(supervisor) = supervisor {
+        supervisor::terminate(supervisor);
+    }
+    let deadline = Instant::now() + KILL_TIMEOUT;
+    while Instant::now() < deadline {
+        if !database::pid_alive(pid) && supervisor.is_none_or(|pid| !database::pid_alive(pid)) {
+            println!("stopped pid {pid}");
+            return Ok(());
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    println!("asked pid {pid} to stop, it will at the start of its next loop");
+    Ok(())
 }
@@ -0,0 +1,84 @@
+//! Housekeeping that runs after dura deletes refs, so the space actually comes back.
+use std::path::{Path, PathBuf};
+
+use git2::Repository;
+use walkdir::WalkDir;
+
+use crate::snapshots;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GcOutcome {
+    /// gc wasn't run, e.g. because git is busy with the repo
+    Skipped(&'static str),
+    /// Sizes of `.git/objects` before and after
+    Ran { before: u64, after: u64 },
+}
+
+impl GcOutcome {
+    pub fn reclaimed(&self) -> u64 {
+        match self {
+            GcOutcome::Ran { before, after } => before.saturating_sub(*after),
+            GcOutcome::Skipped(_) => 0,
+        }
+    }
+}
+
+/// Runs `git gc --quiet`. Unreachable objects are only pruned once they're older than the
+/// repo's `gc.pruneExpire` (two weeks by default), the same as when the user runs it. Skipped
+/// while another git process holds the index lock.
+pub fn gc(path: &Path) -> Result<GcOutcome, String> {
+    run_git(path, &["gc", "--quiet"])
+}
+
+/// Runs `git repack -a -d --quiet`, packing everything reachable into a single pack. Skipped
+/// while another git process holds the index lock, like [`gc`].
+pub fn repack(path: &Path) -> Result<GcOutcome, String> {
+    run_git(path, &["repack", "-a", "-d", "--quiet"])
+}
+
+fn run_git(path: &Path, args: &[&str]) -> Result<GcOutcome, String> {
+    let repo = snapshots::open_repo(path).map_err(|e| e.to_string())?;
+    let git_dir = repo.path().to_path_buf();
+    if git_dir.join("index.lock").exists() {
+        return Ok(GcOutcome::Skipped("index.lock"));
+    }
+    let objects = objects_dir(&repo);
+
+    let before = dir_size(&objects);
+    let output = snapshots::git_command()
+        .arg("-C")
+        .arg(path)
+        .args(args)
+        .output()
+        .map_err(|e| format!("Couldn't run git {}: {e}", args[0]))?;
+    if !output.status.success() {
+        return Err(format!(
+            "git {} failed: {}",
+            args[0],
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(GcOutcome::Ran {
+        before,
+        after: dir_size(&objects),
+    })
+}
+
+/// Worktrees share the main repo's objects. Their git dir has a `commondir` file pointing at it.
+fn objects_dir(repo: &Repository) -> PathBuf {
+    let git_dir = repo.path();
+    match std::fs::read_to_string(git_dir.join("commondir")) {
+        Ok(common) => git_dir.join(common.trim()).join("objects"),
+        Err(_) => git_dir.join("objects"),
+    }
+}
+
+fn dir_size(path: &Path) -> u64 {
+    WalkDir::new(path)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.metadata().ok())
+        .filter(|metadata| metadata.is_file())
+        .map(|metadata| metadata.len())
+        .sum()
+}
@@ -1,52 +1,340 @@
 use crate::log::Operation;
+use crate::snapshots;
+use chrono::{DateTime, FixedOffset};
+use flate2::read::MultiGzDecoder;
 use git2::{Oid, Repository};
+use hdrhistogram::Histogram;
 use serde_json::map::Map;
 use serde_json::value::from_value;
 use serde_json::{json, Number, Value};
-use std::collections::HashMap;
-use std::io::{self, BufRead, Write};
-use std::rc::Rc;
+use std::collections::hash_map::{DefaultHasher, Entry};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
 
-type FlexResult<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+pub(crate) type FlexResult<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+/// Controls which log entries, besides snapshots, make it into the metrics output.
+#[derive(Debug, Default, Clone)]
+pub struct MetricsOptions {
+    /// Pass `Operation::Maintenance` entries through (consolidate, prune, gc, ...)
+    pub include_maintenance: bool,
+    /// Pass `Operation::Lifecycle` entries through (daemon started, stopped, taken over, ...)
+    pub include_lifecycle: bool,
+    /// Also diff each snapshot against its base commit, for `WorkAtRisk`
+    pub work_at_risk: bool,
+    /// How many threads look up snapshots in git. Each repo is handled by a single thread, so
+    /// this only helps with logs from several repos. 0 and 1 do it all on the calling thread.
+    pub jobs: usize,
+    /// Print how many entries are done to stderr every now and then, if it's a terminal
+    pub progress: bool,
+}
+
+/// Where enriched log entries end up
+pub trait MetricsSink {
+    fn record(&mut self, value: &Value) -> FlexResult<()>;
+
+    /// Called once all inputs have been read
+    fn finish(&mut self) -> FlexResult<()> {
+        Ok(())
+    }
+}
+
+/// Newline delimited JSON, one object per entry
+pub struct JsonLines<W: Write> {
+    writer: io::BufWriter<W>,
+}
+
+impl<W: Write> JsonLines<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer: io::BufWriter::new(writer),
+        }
+    }
+}
+
+impl<W: Write> MetricsSink for JsonLines<W> {
+    fn record(&mut self, value: &Value) -> FlexResult<()> {
+        writeln!(self.writer, "{value}")?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> FlexResult<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
 
 /// Reads an input stream that contains dura logs and enriches them with more analytics-ready info
-/// like number of insertions & deletions. The result is written back out to an output stream.
+/// like number of insertions & deletions. The result is handed to `sink`.
 pub fn get_snapshot_metrics(
     input: &mut dyn io::Read,
-    output: &mut dyn io::Write,
+    sink: &mut dyn MetricsSink,
+    options: &MetricsOptions,
 ) -> FlexResult<()> {
     let mut reader = io::BufReader::new(input);
-    let mut writer = io::BufWriter::new(output);
+    let mut enricher = Enricher::new(sink, options);
+    process_stream(&mut reader, None, &mut enricher, options)?;
+    enricher.finish()
+}
+
+/// Like `get_snapshot_metrics`, but reads several log files, e.g. rotated logs. Files ending in
+/// `.gz` are decompressed. Files are processed in order of the time of their first record, so
+/// the output stays in chronological order no matter how they were passed in.
+pub fn get_snapshot_metrics_from_files(
+    inputs: &[PathBuf],
+    sink: &mut dyn MetricsSink,
+    options: &MetricsOptions,
+) -> FlexResult<()> {
+    let mut ordered = Vec::with_capacity(inputs.len());
+    for path in inputs {
+        ordered.push((first_record_time(path)?, path));
+    }
+    // Files without any timestamp go last. The sort is stable, so ties keep the given order.
+    ordered.sort_by_key(|(time, _)| (time.is_none(), *time));
+
+    let mut enricher = Enricher::new(sink, options);
+    for (_, path) in ordered {
+        let mut reader = open_log(path)?;
+        process_stream(&mut reader, Some(path), &mut enricher, options)?;
+    }
+    enricher.finish()
+}
+
+/// At most this many entries are read ahead of the oldest one still being looked up in git
+const MAX_IN_FLIGHT: usize = 10_000;
+
+/// How often progress is printed
+const PROGRESS_EVERY: u64 = 1_000;
+
+type Enriched = (u64, Result<Value, git2::Error>);
+
+/// Where to send a worker entries, and the thread itself
+type Worker = (mpsc::Sender<(u64, Value)>, thread::JoinHandle<()>);
+
+/// Adds git details to entries and hands them to the sink in the order they were read, see
+/// `MetricsOptions::jobs`. Each worker thread owns the repos routed to it, since a `Repository`
+/// can't be shared between threads.
+struct Enricher<'a> {
+    sink: &'a mut dyn MetricsSink,
+    options: MetricsOptions,
+    /// For looking things up on this thread, when there are no workers
+    repo_cache: HashMap<String, Repository>,
+    workers: Vec<Worker>,
+    results: mpsc::Receiver<Enriched>,
+    /// Sequence number of the next entry read
+    next_in: u64,
+    /// Sequence number of the next entry to go to the sink
+    next_out: u64,
+    /// Entries that are done, but wait for an earlier one
+    done: BTreeMap<u64, Result<Value, git2::Error>>,
+    in_flight: usize,
+    show_progress: bool,
+}
+
+impl<'a> Enricher<'a> {
+    fn new(sink: &'a mut dyn MetricsSink, options: &MetricsOptions) -> Self {
+        let (results_sender, results) = mpsc::channel();
+        let workers = match options.jobs {
+            0 | 1 => vec![],
+            jobs => (0..jobs)
+                .map(|_| {
+                    let (sender, receiver) = mpsc::channel::<(u64, Value)>();
+                    let results = results_sender.clone();
+                    let options = options.clone();
+                    let handle = thread::spawn(move || {
+                        let mut repo_cache = HashMap::new();
+                        for (seq, mut value) in receiver {
+                            let result = scrape_git(&mut value, &mut repo_cache, &options);
+                            if results.send((seq, result.map(|_| value))).is_err() {
+                                return;
+                            }
+                        }
+                    });
+                    (sender, handle)
+                })
+                .collect(),
+        };
+        Self {
+            sink,
+            options: options.clone(),
+            repo_cache: HashMap::new(),
+            workers,
+            results,
+            next_in: 0,
+            next_out: 0,
+            done: BTreeMap::new(),
+            in_flight: 0,
+            show_progress: options.progress && io::stderr().is_terminal(),
+        }
+    }
+
+    fn push(&mut self, mut value: Value) -> FlexResult<()> {
+        let seq = self.next_in;
+        self.next_in += 1;
+        if value.get("commit_hash").is_none() {
+            self.done.insert(seq, Ok(value));
+        } else if self.workers.is_empty() {
+            let result = scrape_git(&mut value, &mut self.repo_cache, &self.options);
+            self.done.insert(seq, result.map(|_| value));
+        } else {
+            // The same repo always goes to the same worker, which keeps it open
+            let mut hasher = DefaultHasher::new();
+            value.get("repo").and_then(Value::as_str).hash(&mut hasher);
+            let worker = (hasher.finish() % self.workers.len() as u64) as usize;
+            self.workers[worker].0.send((seq, value))?;
+            self.in_flight += 1;
+        }
+
+        while let Ok((seq, result)) = self.results.try_recv() {
+            self.received(seq, result);
+        }
+        while self.in_flight >= MAX_IN_FLIGHT {
+            let (seq, result) = self.results.recv()?;
+            self.received(seq, result);
+        }
+        self.flush()
+    }
+
+    fn received(&mut self, seq: u64, result: Result<Value, git2::Error>) {
+        self.in_flight -= 1;
+        self.done.insert(seq, result);
+    }
+
+    /// Passes on everything that's done and isn't waiting for an earlier entry
+    fn flush(&mut self) -> FlexResult<()> {
+        while let Some(result) = self.done.remove(&self.next_out) {
+            self.sink.record(&result?)?;
+            self.next_out += 1;
+            if self.show_progress && self.next_out.is_multiple_of(PROGRESS_EVERY) {
+                eprint!("\r{} entries", self.next_out);
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> FlexResult<()> {
+        let workers = std::mem::take(&mut self.workers);
+        let mut handles = vec![];
+        for (sender, handle) in workers {
+            // Hanging up lets the worker finish what it has and exit
+            drop(sender);
+            handles.push(handle);
+        }
+        while self.in_flight > 0 {
+            let (seq, result) = self.results.recv()?;
+            self.received(seq, result);
+        }
+        for handle in handles {
+            let _ = handle.join();
+        }
+        let flushed = self.flush();
+        if self.show_progress && self.next_out >= PROGRESS_EVERY {
+            eprintln!("\r{} entries", self.next_out);
+        }
+        flushed?;
+        self.sink.finish()
+    }
+}
+
+/// Expands glob patterns, for shells that didn't already (or when the pattern was quoted).
+/// Patterns that match nothing are passed through unchanged, so opening them reports a sensible
+/// error.
+pub fn expand_inputs(patterns: &[String]) -> FlexResult<Vec<PathBuf>> {
+    let mut paths = vec![];
+    for pattern in patterns {
+        let mut matches = glob::glob(pattern)?.collect::<Result<Vec<_>, _>>()?;
+        if matches.is_empty() {
+            paths.push(PathBuf::from(pattern));
+        } else {
+            matches.sort();
+            paths.append(&mut matches);
+        }
+    }
+    Ok(paths)
+}
+
+fn open_log(path: &Path) -> FlexResult<Box<dyn BufRead>> {
+    let file = File::open(path).map_err(|e| format!("Couldn't open '{}': {e}", path.display()))?;
+    if path.extension().map(|ext| ext == "gz").unwrap_or(false) {
+        Ok(Box::new(io::BufReader::new(MultiGzDecoder::new(file))))
+    } else {
+        Ok(Box::new(io::BufReader::new(file)))
+    }
+}
+
+/// The `time` of the first log line that has one
+fn first_record_time(path: &Path) -> FlexResult<Option<DateTime<FixedOffset>>> {
+    for line in open_log(path)?.lines() {
+        let time = serde_json::from_str::<Value>(&line?)
+            .ok()
+            .and_then(|value| value.get("time").and_then(|t| t.as_str()).map(String::from))
+            .and_then(|time| DateTime::parse_from_rfc3339(&time).ok());
+        if time.is_some() {
+            return Ok(time);
+        }
+    }
+    Ok(None)
+}
+
+/// Enriches every line of one input. `source` is only used to say where bad lines came from.
+fn process_stream(
+    reader: &mut dyn BufRead,
+    source: Option<&Path>,
+    enricher: &mut Enricher,
+    options: &MetricsOptions,
+) -> FlexResult<()> {
     let mut line: u64 = 0; // for printing better error messages
-    let mut repo_cache: HashMap<String, Rc<Repository>> = HashMap::new();
     loop {
         line += 1;
         let mut input_line = String::new();
         if reader.read_line(&mut input_line)? == 0 {
             return Ok(());
         }
-        match scrape_log(input_line) {
-            Ok(Some(mut output)) => {
-                scrape_git(&mut output, &mut repo_cache)?;
-                writeln!(&mut writer, "{output}")?;
-            }
+        match scrape_log(input_line, options) {
+            Ok(Some(output)) => enricher.push(output)?,
             Ok(None) => {}
             // Seems like a good way to report errors, idk...
-            Err(e) => eprintln!("line {line}: {e}"),
+            Err(e) => match source {
+                Some(source) => eprintln!("{}:{line}: {e}", source.display()),
+                None => eprintln!("line {line}: {e}"),
+            },
         }
     }
 }
 
+/// When a log line was written, in milliseconds since the epoch. Lines from before
+/// `time_unix_ms` was logged only have `time`.
+fn log_time_ms(line: &Value) -> Option<i64> {
+    if let Some(ms) = line.get("time_unix_ms").and_then(Value::as_i64) {
+        return Some(ms);
+    }
+    line.get("time")
+        .and_then(Value::as_str)
+        .and_then(|time| DateTime::parse_from_rfc3339(time).ok())
+        .map(|time| time.timestamp_millis())
+}
+
 /// Scrape information out of the snapshot log.
-fn scrape_log(line: String) -> serde_json::Result<Option<Value>> {
+fn scrape_log(line: String, options: &MetricsOptions) -> serde_json::Result<Option<Value>> {
     let input_val: Value = serde_json::from_str(line.as_str())?;
     let mut output_val = Value::Object(Map::new());
 
     if let Some(t) = input_val.get("time") {
         output_val["time"] = t.clone();
     }
+    let logged_ms = log_time_ms(&input_val);
+    if let Some(ms) = logged_ms {
+        output_val["time_unix_ms"] = json!(ms);
+    }
 
     if let Some(op_value) = input_val.get("fields").and_then(|f| f.get("operation")) {
+        // Logs from before CaptureStatus carried diff stats
+        let has_stats = op_value.pointer("/Snapshot/op/insertions").is_some();
         match from_value(op_value.clone())? {
             Operation::Snapshot {
                 repo,
@@ -61,6 +349,50 @@ fn scrape_log(line: String) -> serde_json::Result<Option<Value>> {
                 output_val["dura_branch"] = Value::String(op.dura_branch);
                 output_val["commit_hash"] = Value::String(op.commit_hash);
                 output_val["base_hash"] = Value::String(op.base_hash);
+                output_val["statuses_ms"] = json!(op.phases.statuses_ms);
+                output_val["index_add_ms"] = json!(op.phases.index_add_ms);
+                output_val["diff_ms"] = json!(op.phases.diff_ms);
+                output_val["tree_write_ms"] = json!(op.phases.tree_write_ms);
+                output_val["commit_ms"] = json!(op.phases.commit_ms);
+                if has_stats {
+                    output_val["num_files_changed"] = json!(op.files_changed);
+                    output_val["insertions"] = json!(op.insertions);
+                    output_val["deletions"] = json!(op.deletions);
+                }
+                if let Some(commit_time) = op.commit_time {
+                    output_val["commit_time"] = json!(commit_time.to_rfc3339());
+                    // The line is logged after the commit, and the commit time is rounded down to
+                    // the second, so anything outside 0 to a few seconds means the clock moved
+                    if let Some(ms) = logged_ms {
+                        output_val["clock_skew_ms"] = json!(ms - commit_time.timestamp_millis());
+                    }
+                }
+            }
+            Operation::Maintenance {
+                repo,
+                action,
+                details,
+                error,
+                latency,
+            } if options.include_maintenance => {
+                output_val["repo"] = Value::String(repo);
+                if let Some(latency) = Number::from_f64(latency as f64) {
+                    output_val["latency"] = Value::Number(latency);
+                }
+                output_val["maintenance"] = json!(action);
+                output_val["details"] = json!(details);
+                output_val["error"] = json!(error);
+            }
+            Operation::Lifecycle {
+                event,
+                pid,
+                version,
+                details,
+            } if options.include_lifecycle => {
+                output_val["lifecycle"] = json!(event);
+                output_val["pid"] = json!(pid);
+                output_val["version"] = json!(version);
+                output_val["details"] = json!(details);
             }
             _ => return Ok(None),
         }
@@ -79,34 +411,40 @@ fn scrape_log(line: String) -> serde_json::Result<Option<Value>> {
 /// is inherently not cache-able.
 fn scrape_git(
     value: &mut Value,
-    repo_cache: &mut HashMap<String, Rc<Repository>>,
+    repo_cache: &mut HashMap<String, Repository>,
+    options: &MetricsOptions,
 ) -> Result<(), git2::Error> {
     if let Some(repo_path_value) = value.get("repo") {
         let repo_path = match repo_path_value.as_str() {
             Some(x) => Ok(x),
             None => Err(git2::Error::from_str("Couldn't find 'repo' in JSON")),
         }?;
-        let repo = match repo_cache.get(repo_path) {
-            Some(repo) => Rc::clone(repo),
-            None => {
-                let repo = Rc::new(Repository::open(repo_path)?);
-                repo_cache.insert(repo_path.to_string(), Rc::clone(&repo));
-                repo
-            }
+        // Logs written with log_redact_paths name repos by a token rather than a path, so
+        // there's nothing to look at
+        if !Path::new(repo_path).is_absolute() {
+            return Ok(());
+        }
+        let repo = match repo_cache.entry(repo_path.to_string()) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(snapshots::open_repo(Path::new(repo_path))?),
         };
         let commit_opt = value
             .get("commit_hash")
             .and_then(|c| c.as_str())
             .and_then(|c| Oid::from_str(c).ok())
             .and_then(|c| repo.find_commit(c).ok());
-        let parent_commit = commit_opt.as_ref().and_then(|c| c.parents().last());
+        let parent_commit = commit_opt.as_ref().and_then(|c| c.parents().next_back());
         if let (Some(commit), Some(parent)) = (commit_opt, parent_commit) {
             let diff =
                 repo.diff_tree_to_tree(Some(&parent.tree()?), Some(&commit.tree()?), None)?;
-            let stats = diff.stats()?;
-            value["num_files_changed"] = json!(stats.files_changed());
-            value["insertions"] = json!(stats.insertions());
-            value["deletions"] = json!(stats.deletions());
+            // Newer logs already have the stats. Listing paths only compares trees, it's the
+            // line counts that need every blob loaded.
+            if value.get("insertions").is_none() {
+                let stats = diff.stats()?;
+                value["num_files_changed"] = json!(stats.files_changed());
+                value["insertions"] = json!(stats.insertions());
+                value["deletions"] = json!(stats.deletions());
+            }
 
             let files: Vec<_> = diff
                 .deltas()
@@ -114,14 +452,122 @@ fn scrape_git(
                 .map(|p| p.to_str())
                 .collect();
             value["files_changed"] = json!(files);
+
+            if options.work_at_risk {
+                scrape_base(value, repo, &commit)?;
+            }
         };
     }
     Ok(())
 }
 
+/// How far the snapshot has drifted from the commit it hangs off. Unlike the diff in
+/// `scrape_git`, this is everything that's uncommitted, not just what changed since the previous
+/// snapshot.
+fn scrape_base(
+    value: &mut Value,
+    repo: &Repository,
+    commit: &git2::Commit,
+) -> Result<(), git2::Error> {
+    let base = match value
+        .get("base_hash")
+        .and_then(|c| c.as_str())
+        .and_then(|c| Oid::from_str(c).ok())
+        .and_then(|c| repo.find_commit(c).ok())
+    {
+        Some(base) => base,
+        None => return Ok(()),
+    };
+    let diff = repo.diff_tree_to_tree(Some(&base.tree()?), Some(&commit.tree()?), None)?;
+    let stats = diff.stats()?;
+    let seconds = (commit.time().seconds() - base.time().seconds()).max(0);
+    value["minutes_since_base"] = json!(seconds as f64 / 60.0);
+    value["insertions_since_base"] = json!(stats.insertions());
+    value["deletions_since_base"] = json!(stats.deletions());
+    Ok(())
+}
+
+/// Distributions of uncommitted work per repo: how long it had been since the last real commit
+/// when each snapshot was taken, and how many lines had been added since then.
+pub struct WorkAtRisk<W: Write> {
+    writer: io::BufWriter<W>,
+    repos: BTreeMap<String, RiskHistograms>,
+}
+
+struct RiskHistograms {
+    minutes: Histogram<u64>,
+    lines: Histogram<u64>,
+}
+
+impl<W: Write> WorkAtRisk<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer: io::BufWriter::new(writer),
+            repos: BTreeMap::new(),
+        }
+    }
+}
+
+impl<W: Write> MetricsSink for WorkAtRisk<W> {
+    fn record(&mut self, value: &Value) -> FlexResult<()> {
+        let (repo, minutes, lines) = match (
+            value["repo"].as_str(),
+            value["minutes_since_base"].as_f64(),
+            value["insertions_since_base"].as_u64(),
+        ) {
+            (Some(repo), Some(minutes), Some(lines)) => (repo, minutes, lines),
+            _ => return Ok(()),
+        };
+        if !self.repos.contains_key(repo) {
+            self.repos.insert(
+                repo.to_string(),
+                RiskHistograms {
+                    minutes: Histogram::new(3)?,
+                    lines: Histogram::new(3)?,
+                },
+            );
+        }
+        let histos = self.repos.get_mut(repo).unwrap();
+        histos.minutes.saturating_record(minutes.round() as u64);
+        histos.lines.saturating_record(lines);
+        Ok(())
+    }
+
+    fn finish(&mut self) -> FlexResult<()> {
+        for (repo, histos) in &self.repos {
+            let report = json!({
+                "repo": repo,
+                "snapshots": histos.minutes.len(),
+                "minutes_at_risk": {
+                    "median": histos.minutes.value_at_quantile(0.5),
+                    "p95": histos.minutes.value_at_quantile(0.95),
+                },
+                "lines_at_risk": {
+                    "median": histos.lines.value_at_quantile(0.5),
+                    "p95": histos.lines.value_at_quantile(0.95),
+                },
+            });
+            writeln!(self.writer, "{report}")?;
+        }
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::metrics::scrape_log;
+    use crate::metrics::{
+        get_snapshot_metrics, get_snapshot_metrics_from_files, scrape_git, scrape_log, JsonLines,
+        MetricsOptions, MetricsSink, WorkAtRisk,
+    };
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use git2::{Repository, Signature, Time};
+    use serde_json::json;
+    use std::collections::HashMap;
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::path::Path;
 
     #[test]
     fn scrape_log_happy_path() {
@@ -141,7 +587,9 @@ mod tests {
             },"time":"2022-01-14T01:49:51.638031+00:00"
         }"#;
 
-        let output = scrape_log(line.to_string()).unwrap().unwrap();
+        let output = scrape_log(line.to_string(), &MetricsOptions::default())
+            .unwrap()
+            .unwrap();
 
         assert_eq!(
             output["time"].as_str(),
@@ -165,6 +613,99 @@ mod tests {
         assert!(latency > (0.00988253 - f32::EPSILON).into());
     }
 
+    #[test]
+    fn scrape_log_phases() {
+        let line = r#"{"target":"dura::poller","file":"src/poller.rs",
+            "name":"event src/poller.rs:70","level":"Level(Info)",
+            "fields":{
+                "message":"info_operation","operation":{"Snapshot":{
+                    "error":null,"latency":0.00988253,"op":{
+                        "base_hash":"3e8e8c99b5434e726b13f56ba00d139bab57d5eb",
+                        "commit_hash":"3423d21a2937d95119982395bc1281d3d8ebe3b6",
+                        "dura_branch":"dura/3e8e8c99b5434e726b13f56ba00d139bab57d5eb",
+                        "phases":{"statuses_ms":1.5,"index_add_ms":2.0,"diff_ms":0.5,
+                            "tree_write_ms":0.25,"commit_ms":3.0}
+                    },
+                    "repo":"/Users/timkellogg/code/dura"}
+                }
+            },"time":"2022-01-14T01:49:51.638031+00:00"
+        }"#;
+
+        let output = scrape_log(line.to_string(), &MetricsOptions::default())
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(output["statuses_ms"].as_f64(), Some(1.5));
+        assert_eq!(output["index_add_ms"].as_f64(), Some(2.0));
+        assert_eq!(output["diff_ms"].as_f64(), Some(0.5));
+        assert_eq!(output["tree_write_ms"].as_f64(), Some(0.25));
+        assert_eq!(output["commit_ms"].as_f64(), Some(3.0));
+    }
+
+    #[test]
+    fn scrape_log_stats_from_capture() {
+        let line = r#"{"fields":{"message":"info_operation","operation":{"Snapshot":{
+                "error":null,"latency":0.01,"op":{
+                    "base_hash":"3e8e8c99b5434e726b13f56ba00d139bab57d5eb",
+                    "commit_hash":"3423d21a2937d95119982395bc1281d3d8ebe3b6",
+                    "dura_branch":"dura/3e8e8c99b5434e726b13f56ba00d139bab57d5eb",
+                    "files_changed":2,"insertions":7,"deletions":3,
+                    "timestamp":"2022-01-14T01:49:51.638031Z"
+                },
+                "repo":"/Users/timkellogg/code/dura"}}},
+            "time":"2022-01-14T01:49:51.638031+00:00"}"#;
+
+        let output = scrape_log(line.to_string(), &MetricsOptions::default())
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(output["num_files_changed"], 2);
+        assert_eq!(output["insertions"], 7);
+        assert_eq!(output["deletions"], 3);
+    }
+
+    #[test]
+    fn scrape_log_clock_skew() {
+        let line = r#"{"fields":{"message":"info_operation","operation":{"Snapshot":{
+                "error":null,"latency":0.01,"op":{
+                    "base_hash":"3e8e8c99b5434e726b13f56ba00d139bab57d5eb",
+                    "commit_hash":"3423d21a2937d95119982395bc1281d3d8ebe3b6",
+                    "dura_branch":"dura/3e8e8c99b5434e726b13f56ba00d139bab57d5eb",
+                    "commit_time":"2022-01-13T20:49:51-05:00"
+                },
+                "repo":"/Users/timkellogg/code/dura"}}},
+            "time":"2022-01-14T01:49:51.638031+00:00","time_unix_ms":1642124991638}"#;
+
+        let output = scrape_log(line.to_string(), &MetricsOptions::default())
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(output["time_unix_ms"], 1642124991638_i64);
+        assert_eq!(output["commit_time"], "2022-01-13T20:49:51-05:00");
+        assert_eq!(output["clock_skew_ms"], 638);
+    }
+
+    #[test]
+    fn scrape_log_time_from_old_lines() {
+        // No time_unix_ms and no commit_time
+        let line = r#"{"fields":{"message":"info_operation","operation":{"Snapshot":{
+                "error":null,"latency":0.01,"op":{
+                    "base_hash":"3e8e8c99b5434e726b13f56ba00d139bab57d5eb",
+                    "commit_hash":"3423d21a2937d95119982395bc1281d3d8ebe3b6",
+                    "dura_branch":"dura/3e8e8c99b5434e726b13f56ba00d139bab57d5eb"
+                },
+                "repo":"/Users/timkellogg/code/dura"}}},
+            "time":"2022-01-14T01:49:51.638031+00:00"}"#;
+
+        let output = scrape_log(line.to_string(), &MetricsOptions::default())
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(output["time_unix_ms"], 1642124991638_i64);
+        assert!(output.get("commit_time").is_none());
+        assert!(output.get("clock_skew_ms").is_none());
+    }
+
     #[test]
     fn scrape_log_no_snapshot() {
         // broken up into multiple lines to satisfy style checker, but serde_json will handle it
@@ -173,8 +714,229 @@ mod tests {
             "level":"Level(Info)","fields":{"pid":5416},
             "time":"2022-01-14T01:45:37.469819+00:00"}"#;
 
-        let output = scrape_log(line.to_string()).unwrap();
+        let output = scrape_log(line.to_string(), &MetricsOptions::default()).unwrap();
 
         assert_eq!(output, None);
     }
+
+    #[test]
+    fn scrape_log_maintenance() {
+        let line = r#"{"target":"dura::poller","file":"src/poller.rs",
+            "name":"event src/poller.rs:70","level":"Level(Info)",
+            "fields":{
+                "message":"info_operation","operation":{"Maintenance":{
+                    "action":"Consolidate","details":{"branches":"12"},
+                    "error":null,"latency":0.5,
+                    "repo":"/Users/timkellogg/code/dura"}
+                }
+            },"time":"2022-01-14T01:49:51.638031+00:00"
+        }"#;
+
+        let skipped = scrape_log(line.to_string(), &MetricsOptions::default()).unwrap();
+        assert_eq!(skipped, None);
+
+        let options = MetricsOptions {
+            include_maintenance: true,
+            ..Default::default()
+        };
+        let output = scrape_log(line.to_string(), &options).unwrap().unwrap();
+        assert_eq!(output["repo"].as_str(), Some("/Users/timkellogg/code/dura"));
+        assert_eq!(output["maintenance"].as_str(), Some("Consolidate"));
+        assert_eq!(output["details"]["branches"].as_str(), Some("12"));
+        assert_eq!(output.get("commit_hash"), None);
+    }
+
+    #[test]
+    fn scrape_log_lifecycle() {
+        let line = r#"{"fields":{"message":"info_operation","operation":{"Lifecycle":{
+            "event":"TakeoverCompleted","pid":4242,"version":"0.2.0",
+            "details":{"new_pid":"4343"}}}},
+            "time":"2022-01-14T01:49:51.638031+00:00"}"#;
+
+        let skipped = scrape_log(line.to_string(), &MetricsOptions::default()).unwrap();
+        assert_eq!(skipped, None);
+
+        let options = MetricsOptions {
+            include_lifecycle: true,
+            ..Default::default()
+        };
+        let output = scrape_log(line.to_string(), &options).unwrap().unwrap();
+        assert_eq!(output["lifecycle"].as_str(), Some("TakeoverCompleted"));
+        assert_eq!(output["pid"].as_u64(), Some(4242));
+        assert_eq!(output["details"]["new_pid"].as_str(), Some("4343"));
+    }
+
+    fn maintenance_line(repo: &str, time: &str) -> String {
+        format!(
+            r#"{{"fields":{{"message":"info_operation","operation":{{"Maintenance":{{
+                "action":"Gc","error":null,"latency":0.5,"repo":"{repo}"}}}}}},
+                "time":"{time}"}}"#
+        )
+        .replace('\n', "")
+    }
+
+    #[test]
+    fn plain_and_gzipped_inputs_in_time_order() {
+        let tmp = tempfile::tempdir().unwrap();
+        let plain = tmp.path().join("dura.log");
+        let gzipped = tmp.path().join("dura.log.1.gz");
+
+        fs::write(
+            &plain,
+            format!(
+                "{}\n{}\n",
+                maintenance_line("/c", "2022-01-15T10:00:00+00:00"),
+                maintenance_line("/d", "2022-01-15T11:00:00+00:00")
+            ),
+        )
+        .unwrap();
+        let mut encoder = GzEncoder::new(File::create(&gzipped).unwrap(), Compression::default());
+        writeln!(
+            encoder,
+            "{}",
+            maintenance_line("/a", "2022-01-14T10:00:00+00:00")
+        )
+        .unwrap();
+        writeln!(encoder, "not json").unwrap();
+        writeln!(
+            encoder,
+            "{}",
+            maintenance_line("/b", "2022-01-14T11:00:00+00:00")
+        )
+        .unwrap();
+        encoder.finish().unwrap();
+
+        let options = MetricsOptions {
+            include_maintenance: true,
+            ..Default::default()
+        };
+        let mut output = JsonLines::new(Vec::new());
+        get_snapshot_metrics_from_files(&[plain, gzipped], &mut output, &options).unwrap();
+        let output = output.writer.into_inner().unwrap();
+
+        let repos: Vec<String> = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|line| {
+                let value: serde_json::Value = serde_json::from_str(line).unwrap();
+                value["repo"].as_str().unwrap().to_string()
+            })
+            .collect();
+        assert_eq!(repos, vec!["/a", "/b", "/c", "/d"]);
+    }
+
+    /// Commits `contents` as foo.txt at `minutes` past some fixed time
+    fn commit_at(repo: &Repository, contents: &str, minutes: i64, refname: &str) -> git2::Oid {
+        let workdir = repo.workdir().unwrap();
+        fs::write(workdir.join("foo.txt"), contents).unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new("foo.txt")).unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let sig = Signature::new("test", "test@example.com", &Time::new(minutes * 60, 0)).unwrap();
+        let parents = match repo.head() {
+            Ok(head) => vec![head.peel_to_commit().unwrap()],
+            Err(_) => vec![],
+        };
+        let parents: Vec<_> = parents.iter().collect();
+        repo.commit(Some(refname), &sig, &sig, "msg", &tree, &parents)
+            .unwrap()
+    }
+
+    #[test]
+    fn work_at_risk_diffs_against_base() {
+        let tmp = tempfile::tempdir().unwrap();
+        let repo = Repository::init(tmp.path()).unwrap();
+        let base = commit_at(&repo, "a\n", 0, "HEAD");
+        // First snapshot adds a line, the second another one on top of it
+        commit_at(&repo, "a\nb\n", 10, "refs/heads/dura/snap");
+        repo.set_head("refs/heads/dura/snap").unwrap();
+        let second = commit_at(&repo, "a\nb\nc\n", 30, "HEAD");
+
+        let mut value = json!({
+            "repo": tmp.path().to_str().unwrap(),
+            "commit_hash": second.to_string(),
+            "base_hash": base.to_string(),
+        });
+        let options = MetricsOptions {
+            work_at_risk: true,
+            ..Default::default()
+        };
+        scrape_git(&mut value, &mut HashMap::new(), &options).unwrap();
+
+        assert_eq!(value["insertions"], 1);
+        assert_eq!(value["insertions_since_base"], 2);
+        assert_eq!(value["minutes_since_base"].as_f64(), Some(30.0));
+    }
+
+    #[test]
+    fn parallel_enrichment_keeps_order() {
+        let tmps: Vec<_> = (0..3).map(|_| tempfile::tempdir().unwrap()).collect();
+        let mut log = String::new();
+        for i in 0..6 {
+            for tmp in &tmps {
+                let repo = Repository::init(tmp.path()).unwrap();
+                let contents = "line\n".repeat(i + 1);
+                let commit = commit_at(&repo, &contents, i as i64, "HEAD");
+                let line = json!({
+                    "fields": {"message": "info_operation", "operation": {"Snapshot": {
+                        "error": null, "latency": 0.01, "repo": tmp.path().to_str().unwrap(),
+                        "op": {
+                            "base_hash": commit.to_string(),
+                            "commit_hash": commit.to_string(),
+                            "dura_branch": format!("dura/{commit}"),
+                        },
+                    }}},
+                    "time": format!("2022-01-14T01:{i:02}:00+00:00"),
+                });
+                log.push_str(&format!("{line}\n"));
+            }
+        }
+
+        let run = |jobs| {
+            let mut sink = JsonLines::new(Vec::new());
+            let options = MetricsOptions {
+                jobs,
+                ..Default::default()
+            };
+            get_snapshot_metrics(&mut log.as_bytes(), &mut sink, &options).unwrap();
+            String::from_utf8(sink.writer.into_inner().unwrap()).unwrap()
+        };
+        let serial = run(1);
+        assert_eq!(serial.lines().count(), 18);
+        // Every commit but the first in each repo adds one line on top of the last
+        assert_eq!(serial.matches("\"insertions\":1,").count(), 15);
+        assert_eq!(run(3), serial);
+        assert_eq!(run(8), serial);
+    }
+
+    #[test]
+    fn work_at_risk_per_repo_distributions() {
+        let mut report = WorkAtRisk::new(Vec::new());
+        for (repo, minutes, lines) in [("/a", 10.0, 5), ("/a", 20.0, 50), ("/a", 30.0, 500)] {
+            report
+                .record(&json!({
+                    "repo": repo,
+                    "minutes_since_base": minutes,
+                    "insertions_since_base": lines,
+                }))
+                .unwrap();
+        }
+        report
+            .record(&json!({"repo": "/b", "minutes_since_base": 1.0, "insertions_since_base": 1}))
+            .unwrap();
+        report.finish().unwrap();
+
+        let output = String::from_utf8(report.writer.into_inner().unwrap()).unwrap();
+        let lines: Vec<serde_json::Value> = output
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["repo"], "/a");
+        assert_eq!(lines[0]["snapshots"], 3);
+        assert_eq!(lines[0]["minutes_at_risk"]["median"], 20);
+        assert_eq!(lines[0]["minutes_at_risk"]["p95"], 30);
+        assert_eq!(lines[0]["lines_at_risk"]["median"], 50);
+        assert_eq!(lines[1]["repo"], "/b");
+    }
 }
@@ -0,0 +1,167 @@
+//! Writes `dura metrics` output into SQLite, for questions that are painful to answer by
+//! grepping NDJSON.
+//!
+//! Only snapshots are stored. Rows are keyed on `commit_hash`, so running over the same logs
+//! twice (e.g. overlapping rotated files) updates rows rather than duplicating them.
+use std::path::Path;
+
+use rusqlite::{params, Connection};
+use serde_json::Value;
+
+use crate::metrics::{FlexResult, MetricsSink};
+
+/// How many entries to insert per transaction
+const BATCH_SIZE: usize = 500;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS snapshots (
+        id INTEGER PRIMARY KEY,
+        time TEXT,
+        repo TEXT NOT NULL,
+        dura_branch TEXT,
+        commit_hash TEXT NOT NULL UNIQUE,
+        base_hash TEXT,
+        latency REAL,
+        files_changed INTEGER,
+        insertions INTEGER,
+        deletions INTEGER
+    );
+    CREATE TABLE IF NOT EXISTS snapshot_files (
+        snapshot_id INTEGER NOT NULL REFERENCES snapshots(id),
+        path TEXT NOT NULL,
+        PRIMARY KEY (snapshot_id, path)
+    );
+";
+
+pub struct SqliteSink {
+    conn: Connection,
+    pending: Vec<Value>,
+}
+
+impl SqliteSink {
+    /// Opens (or creates) the database at `path` and makes sure the tables exist
+    pub fn open(path: &Path) -> FlexResult<Self> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(Self {
+            conn,
+            pending: Vec::with_capacity(BATCH_SIZE),
+        })
+    }
+
+    fn flush(&mut self) -> FlexResult<()> {
+        let tx = self.conn.transaction()?;
+        for value in self.pending.drain(..) {
+            let commit_hash = match value["commit_hash"].as_str() {
+                Some(commit_hash) => commit_hash,
+                None => continue,
+            };
+            let snapshot_id: i64 = tx.query_row(
+                "INSERT INTO snapshots (time, repo, dura_branch, commit_hash, base_hash, latency,
+                    files_changed, insertions, deletions)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
+                 ON CONFLICT (commit_hash) DO UPDATE SET
+                    time = excluded.time,
+                    repo = excluded.repo,
+                    dura_branch = excluded.dura_branch,
+                    base_hash = excluded.base_hash,
+                    latency = excluded.latency,
+                    files_changed = excluded.files_changed,
+                    insertions = excluded.insertions,
+                    deletions = excluded.deletions
+                 RETURNING id",
+                params![
+                    value["time"].as_str(),
+                    value["repo"].as_str().unwrap_or_default(),
+                    value["dura_branch"].as_str(),
+                    commit_hash,
+                    value["base_hash"].as_str(),
+                    value["latency"].as_f64(),
+                    value["num_files_changed"].as_i64(),
+                    value["insertions"].as_i64(),
+                    value["deletions"].as_i64(),
+                ],
+                |row| row.get(0),
+            )?;
+            tx.execute(
+                "DELETE FROM snapshot_files WHERE snapshot_id = ?1",
+                params![snapshot_id],
+            )?;
+            let files = value["files_changed"].as_array().into_iter().flatten();
+            for file in files.filter_map(|f| f.as_str()) {
+                tx.execute(
+                    "INSERT OR IGNORE INTO snapshot_files (snapshot_id, path) VALUES (?1, ?2)",
+                    params![snapshot_id, file],
+                )?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+impl MetricsSink for SqliteSink {
+    fn record(&mut self, value: &Value) -> FlexResult<()> {
+        if value.get("commit_hash").is_none() {
+            // Maintenance entries and the like don't fit the schema
+            return Ok(());
+        }
+        self.pending.push(value.clone());
+        if self.pending.len() >= BATCH_SIZE {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> FlexResult<()> {
+        self.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn snapshot(commit_hash: &str, files: &[&str]) -> Value {
+        json!({
+            "time": "2022-01-14T01:49:51.638031+00:00",
+            "repo": "/code/dura",
+            "dura_branch": "dura/3e8e8c99b5434e726b13f56ba00d139bab57d5eb",
+            "commit_hash": commit_hash,
+            "base_hash": "3e8e8c99b5434e726b13f56ba00d139bab57d5eb",
+            "latency": 0.01,
+            "num_files_changed": files.len(),
+            "insertions": 3,
+            "deletions": 1,
+            "files_changed": files,
+        })
+    }
+
+    fn count(path: &Path, table: &str) -> i64 {
+        let conn = Connection::open(path).unwrap();
+        conn.query_row(&format!("SELECT count(*) FROM {table}"), [], |row| {
+            row.get(0)
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn rerun_does_not_duplicate() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db = tmp.path().join("metrics.db");
+
+        for _ in 0..2 {
+            let mut sink = SqliteSink::open(&db).unwrap();
+            sink.record(&snapshot("aaaa", &["src/main.rs", "README.md"]))
+                .unwrap();
+            sink.record(&snapshot("bbbb", &["src/lib.rs"])).unwrap();
+            sink.record(&json!({"repo": "/code/dura", "maintenance": "Gc"}))
+                .unwrap();
+            sink.finish().unwrap();
+        }
+
+        assert_eq!(count(&db, "snapshots"), 2);
+        assert_eq!(count(&db, "snapshot_files"), 3);
+    }
+}
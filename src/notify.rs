@@ -0,0 +1,183 @@
+//! Desktop notifications when a repo starts failing to snapshot, and when it works again. Only
+//! changes are announced, so a repo that keeps failing notifies once rather than every loop.
+//!
+//! Sending them needs the `notifications` cargo feature. Without it, enabling them in the config
+//! only leaves a debug line in the logs.
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::log::CaptureOutcome;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NotificationConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// When a repo that was fine fails to snapshot
+    #[serde(default = "default_true")]
+    pub on_error: bool,
+    /// When a failing repo is snapshotted (or found unchanged) again
+    #[serde(default = "default_true")]
+    pub on_recovery: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl Default for NotificationConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            on_error: true,
+            on_recovery: true,
+        }
+    }
+}
+
+pub trait Notifier {
+    fn notify(&mut self, summary: &str, body: &str);
+}
+
+/// Shows a desktop notification from a separate thread, so a slow notification daemon can't hold
+/// up the poller
+pub struct DesktopNotifier;
+
+impl Notifier for DesktopNotifier {
+    fn notify(&mut self, summary: &str, body: &str) {
+        let (summary, body) = (summary.to_string(), body.to_string());
+        std::thread::spawn(move || {
+            if let Err(e) = send(&summary, &body) {
+                debug!("Couldn't show notification: {e}");
+            }
+        });
+    }
+}
+
+#[cfg(feature = "notifications")]
+fn send(summary: &str, body: &str) -> Result<(), String> {
+    notify_rust::Notification::new()
+        .appname("dura")
+        .summary(summary)
+        .body(body)
+        .show()
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(not(feature = "notifications"))]
+fn send(_summary: &str, _body: &str) -> Result<(), String> {
+    Err("dura was built without the notifications feature".to_string())
+}
+
+/// Which repos are currently failing, to tell when one starts or stops
+#[derive(Debug, Default)]
+pub struct NotifyState {
+    failing: HashSet<PathBuf>,
+}
+
+impl NotifyState {
+    pub fn update(
+        &mut self,
+        config: &NotificationConfig,
+        notifier: &mut dyn Notifier,
+        repo: &Path,
+        outcome: &CaptureOutcome,
+    ) {
+        let body = repo.display().to_string();
+        match outcome {
+            CaptureOutcome::Failed(_) | CaptureOutcome::TimedOut => {
+                if self.failing.insert(repo.to_path_buf()) && config.enabled && config.on_error {
+                    let reason = match outcome {
+                        CaptureOutcome::Failed(error) => error.as_str(),
+                        _ => "took too long, maybe a network drive stopped responding",
+                    };
+                    notifier.notify("dura can't snapshot a repo", &format!("{body}: {reason}"));
+                }
+            }
+            CaptureOutcome::Succeeded | CaptureOutcome::NoChange => {
+                if self.failing.remove(repo) && config.enabled && config.on_recovery {
+                    notifier.notify("dura is snapshotting a repo again", &body);
+                }
+            }
+            // Says nothing about whether capturing works
+            CaptureOutcome::Deferred | CaptureOutcome::LowDisk | CaptureOutcome::Skipped(_) => (),
+        }
+    }
+
+    /// Forgets repos that aren't watched any more
+    pub fn retain(&mut self, keep: impl Fn(&Path) -> bool) {
+        self.failing.retain(|repo| keep(repo));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct Recorder(Vec<String>);
+
+    impl Notifier for Recorder {
+        fn notify(&mut self, summary: &str, _body: &str) {
+            self.0.push(summary.to_string());
+        }
+    }
+
+    fn enabled() -> NotificationConfig {
+        NotificationConfig {
+            enabled: true,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn only_changes_notify() {
+        let mut state = NotifyState::default();
+        let mut recorder = Recorder::default();
+        let repo = Path::new("/code/dura");
+        let failed = CaptureOutcome::Failed("locked".to_string());
+        for outcome in [
+            CaptureOutcome::Succeeded,
+            failed.clone(),
+            failed.clone(),
+            CaptureOutcome::TimedOut,
+            CaptureOutcome::Deferred,
+            CaptureOutcome::NoChange,
+            CaptureOutcome::Succeeded,
+            failed,
+        ] {
+            state.update(&enabled(), &mut recorder, repo, &outcome);
+        }
+        assert_eq!(
+            recorder.0,
+            vec![
+                "dura can't snapshot a repo",
+                "dura is snapshotting a repo again",
+                "dura can't snapshot a repo"
+            ]
+        );
+    }
+
+    #[test]
+    fn respects_config() {
+        let repo = Path::new("/code/dura");
+        let failed = CaptureOutcome::Failed("locked".to_string());
+
+        let mut recorder = Recorder::default();
+        let mut state = NotifyState::default();
+        state.update(&Default::default(), &mut recorder, repo, &failed);
+        assert!(recorder.0.is_empty());
+
+        let config = NotificationConfig {
+            on_error: false,
+            ..enabled()
+        };
+        let mut state = NotifyState::default();
+        state.update(&config, &mut recorder, repo, &failed);
+        state.update(&config, &mut recorder, repo, &CaptureOutcome::NoChange);
+        assert_eq!(recorder.0, vec!["dura is snapshotting a repo again"]);
+    }
+}
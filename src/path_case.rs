@@ -0,0 +1,109 @@
+//! Comparing paths the way the filesystem does. The default filesystems on macOS and Windows
+//! ignore case, so `~/Code` and `~/code` are the same directory there and an exclude of `Target`
+//! covers `target`. On Linux they're different. Paths are stored as they were given either way,
+//! only comparisons change.
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+/// `path_case` in the config
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PathCase {
+    /// Ignore case on macOS and Windows, not elsewhere
+    #[default]
+    Auto,
+    Sensitive,
+    /// E.g. for a case-insensitive filesystem mounted on Linux
+    Insensitive,
+}
+
+impl PathCase {
+    pub fn ignores_case(self) -> bool {
+        match self {
+            PathCase::Auto => cfg!(any(windows, target_os = "macos")),
+            PathCase::Sensitive => false,
+            PathCase::Insensitive => true,
+        }
+    }
+
+    /// `path` with case folded away when case doesn't matter, for sets and maps of paths
+    pub fn key(self, path: &Path) -> PathBuf {
+        match self.ignores_case() {
+            true => PathBuf::from(path.to_string_lossy().to_lowercase()),
+            false => path.to_path_buf(),
+        }
+    }
+
+    pub fn same(self, a: &Path, b: &Path) -> bool {
+        self.key(a) == self.key(b)
+    }
+
+    /// Like `Path::starts_with`, whole components only
+    pub fn starts_with(self, path: &Path, base: &Path) -> bool {
+        self.key(path).starts_with(self.key(base))
+    }
+
+    /// Like `Path::strip_prefix`. The rest keeps its case.
+    pub fn strip_prefix(self, path: &Path, base: &Path) -> Option<PathBuf> {
+        if !self.starts_with(path, base) {
+            return None;
+        }
+        Some(path.components().skip(base.components().count()).collect())
+    }
+
+    /// For matching `glob::Pattern`s against paths
+    pub fn glob_options(self) -> glob::MatchOptions {
+        glob::MatchOptions {
+            case_sensitive: !self.ignores_case(),
+            ..Default::default()
+        }
+    }
+
+    /// The key in `map` that's the same path as `path`, as it's spelled there
+    pub fn find_key<'m, V>(self, map: &'m BTreeMap<String, V>, path: &str) -> Option<&'m String> {
+        match map.get_key_value(path) {
+            Some((key, _)) => Some(key),
+            None => map
+                .keys()
+                .find(|key| self.same(Path::new(key.as_str()), Path::new(path))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sensitive_and_insensitive() {
+        let code = Path::new("/home/me/Code");
+        let lower = Path::new("/home/me/code/dura");
+
+        let case = PathCase::Sensitive;
+        assert!(!case.same(code, Path::new("/home/me/code")));
+        assert!(!case.starts_with(lower, code));
+        assert_eq!(case.strip_prefix(lower, code), None);
+        assert!(!glob::Pattern::new("Target")
+            .unwrap()
+            .matches_with("target", case.glob_options()));
+
+        let case = PathCase::Insensitive;
+        assert!(case.same(code, Path::new("/home/me/code")));
+        assert!(case.starts_with(lower, code));
+        // Components, not characters
+        assert!(!case.starts_with(Path::new("/home/me/codebase"), code));
+        assert_eq!(case.strip_prefix(lower, code), Some(PathBuf::from("dura")));
+        assert!(glob::Pattern::new("Target")
+            .unwrap()
+            .matches_with("target", case.glob_options()));
+
+        let map = BTreeMap::from([("/home/me/Code".to_string(), ())]);
+        assert_eq!(
+            case.find_key(&map, "/home/me/code"),
+            Some(&"/home/me/Code".to_string())
+        );
+        assert_eq!(PathCase::Sensitive.find_key(&map, "/home/me/code"), None);
+    }
+}
@@ -0,0 +1,240 @@
+//! Where dura keeps its files. `dura` resolves this once at startup, from `--config-dir` and
+//! `--cache-dir`, then DURA_CONFIG_HOME and DURA_CACHE_HOME, then the legacy DURA_HOME, and then
+//! the platform's defaults. Everything else asks `current()` instead of reading the environment.
+//!
+//! A cache dir that can't be written to, e.g. a read-only `~/.cache` on a locked-down machine,
+//! is swapped for one that can by `fall_back_if_unwritable`, so the daemon can still hold its
+//! runtime lock.
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+pub const CONFIG_ENV: &str = "DURA_CONFIG_HOME";
+pub const CACHE_ENV: &str = "DURA_CACHE_HOME";
+/// Used to be the one place for everything. Still honored, as both dirs, but deprecated.
+pub const LEGACY_ENV: &str = "DURA_HOME";
+
+static CURRENT: RwLock<Option<Paths>> = RwLock::new(None);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Paths {
+    /// Holds config.toml
+    pub config_dir: PathBuf,
+    /// Holds the runtime lock, history, logs and anything else dura can rebuild
+    pub cache_dir: PathBuf,
+    from_legacy_home: bool,
+    /// The cache dir asked for, when it couldn't be written to and `cache_dir` is a fallback
+    pub cache_fallback_from: Option<PathBuf>,
+}
+
+impl Paths {
+    /// Directories given on the command line win over the environment
+    pub fn resolve(config_dir: Option<PathBuf>, cache_dir: Option<PathBuf>) -> Self {
+        let config_dir = config_dir.or_else(|| env_dir(CONFIG_ENV));
+        let cache_dir = cache_dir.or_else(|| env_dir(CACHE_ENV));
+        let legacy = env_dir(LEGACY_ENV).filter(|_| config_dir.is_none() || cache_dir.is_none());
+        Self {
+            from_legacy_home: legacy.is_some(),
+            config_dir: config_dir
+                .or_else(|| legacy.clone())
+                .unwrap_or_else(default_config_dir),
+            cache_dir: cache_dir.or(legacy).unwrap_or_else(default_cache_dir),
+            cache_fallback_from: None,
+        }
+    }
+
+    /// Swaps a cache dir that can't be written to for `$XDG_RUNTIME_DIR/dura`, or failing that
+    /// `dura-<uid>` in the temp dir. Every dura process picks the same one, so they still find
+    /// each other's runtime lock. Returns whether it swapped.
+    ///
+    /// Anyone can create `dura-<uid>` in a shared temp dir before dura does, and whoever controls
+    /// the runtime lock decides what `dura kill` signals, so that one is only used when it's a
+    /// directory of our own that nobody else has access to.
+    pub fn fall_back_if_unwritable(&mut self) -> bool {
+        if is_writable(&self.cache_dir) {
+            return false;
+        }
+        let fallback = env_dir("XDG_RUNTIME_DIR")
+            .map(|dir| dir.join("dura"))
+            .filter(|dir| is_writable(dir))
+            .unwrap_or_else(|| env::temp_dir().join(format!("dura-{}", user_id())));
+        if !is_private(&fallback) || !is_writable(&fallback) {
+            return false;
+        }
+        self.cache_fallback_from = Some(std::mem::replace(&mut self.cache_dir, fallback));
+        true
+    }
+
+    /// Whether the cache dir is somewhere that may not survive a reboot
+    pub fn is_degraded(&self) -> bool {
+        self.cache_fallback_from.is_some()
+    }
+
+    /// Whether either directory came from DURA_HOME, which should be replaced
+    pub fn from_legacy_home(&self) -> bool {
+        self.from_legacy_home
+    }
+
+    pub fn config_file(&self) -> PathBuf {
+        self.config_dir.join("config.toml")
+    }
+
+    pub fn runtime_lock(&self) -> PathBuf {
+        self.cache_dir.join("runtime.db")
+    }
+}
+
+/// Makes `paths` what `current()` returns from now on
+pub fn set(paths: Paths) {
+    *CURRENT.write().unwrap_or_else(|e| e.into_inner()) = Some(paths);
+}
+
+/// The paths `set` by `dura`, or for library users that never called it, whatever the
+/// environment says right now
+pub fn current() -> Paths {
+    match &*CURRENT.read().unwrap_or_else(|e| e.into_inner()) {
+        Some(paths) => paths.clone(),
+        None => Paths::resolve(None, None),
+    }
+}
+
+/// Whether files can be created in `dir`, or it can be created with files in it. Tries it out,
+/// since permissions alone don't tell about read-only mounts.
+pub fn is_writable(dir: &Path) -> bool {
+    let existing = match dir.ancestors().find(|d| d.exists()) {
+        Some(existing) => existing,
+        None => return false,
+    };
+    let probe = existing.join(format!(".dura-write-test-{}", std::process::id()));
+    match fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&probe)
+    {
+        Ok(_) => fs::remove_file(&probe).is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// Creates `dir` if needed, readable by us alone. False if it's a symlink, belongs to someone
+/// else, or can't be made private.
+#[cfg(unix)]
+fn is_private(dir: &Path) -> bool {
+    use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+    match fs::DirBuilder::new().mode(0o700).create(dir) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {}
+        Err(_) => return false,
+    }
+    let meta = match fs::symlink_metadata(dir) {
+        Ok(meta) => meta,
+        Err(_) => return false,
+    };
+    if !meta.is_dir() || meta.uid() != unsafe { libc::getuid() } {
+        return false;
+    }
+    // Made by an older dura, or under a loose umask
+    meta.mode() & 0o077 == 0 || fs::set_permissions(dir, fs::Permissions::from_mode(0o700)).is_ok()
+}
+
+/// The temp dir is in the user's own profile on Windows
+#[cfg(windows)]
+fn is_private(_dir: &Path) -> bool {
+    true
+}
+
+#[cfg(unix)]
+fn user_id() -> String {
+    unsafe { libc::getuid() }.to_string()
+}
+
+#[cfg(windows)]
+fn user_id() -> String {
+    env::var("USERNAME").unwrap_or_else(|_| "user".to_string())
+}
+
+fn env_dir(name: &str) -> Option<PathBuf> {
+    env::var_os(name)
+        .filter(|value| !value.is_empty())
+        .map(PathBuf::from)
+}
+
+/// Linux   :   $XDG_CONFIG_HOME/dura or $HOME/.config/dura
+/// macOS   :   $HOME/Library/Application Support
+/// Windows :   %AppData%\Roaming\dura
+fn default_config_dir() -> PathBuf {
+    dirs::config_dir()
+        .expect(
+            "Could not find your config directory. The default is ~/.config/dura but it can also \
+            be controlled with --config-dir or the DURA_CONFIG_HOME environment variable.",
+        )
+        .join("dura")
+}
+
+/// Linux   :   $XDG_CACHE_HOME/dura or $HOME/.cache/dura
+/// macOS   :   $HOME/Library/Caches
+/// Windows :   %AppData%\Local\dura
+fn default_cache_dir() -> PathBuf {
+    dirs::cache_dir()
+        .expect(
+            "Could not find your cache directory. The default is ~/.cache/dura but it can also \
+            be controlled with --cache-dir or the DURA_CACHE_HOME environment variable.",
+        )
+        .join("dura")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flags_win() {
+        let paths = Paths::resolve(Some("/a".into()), Some("/b".into()));
+        assert_eq!(paths.config_file(), PathBuf::from("/a/config.toml"));
+        assert_eq!(paths.runtime_lock(), PathBuf::from("/b/runtime.db"));
+        assert!(!paths.from_legacy_home());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn read_only_dir_isnt_writable() {
+        use std::os::unix::fs::PermissionsExt;
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().join("cache");
+        assert!(is_writable(&dir.join("dura")));
+        fs::create_dir(&dir).unwrap();
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o555)).unwrap();
+        // root can write anywhere
+        let expected = unsafe { libc::getuid() } == 0;
+        assert_eq!(is_writable(&dir.join("dura")), expected);
+        assert_eq!(is_writable(&dir), expected);
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn fallback_dir_must_be_private() {
+        use std::os::unix::fs::PermissionsExt;
+        let tmp = tempfile::tempdir().unwrap();
+        let mode = |dir: &Path| fs::metadata(dir).unwrap().permissions().mode() & 0o777;
+
+        let fresh = tmp.path().join("fresh");
+        assert!(is_private(&fresh));
+        assert_eq!(mode(&fresh), 0o700);
+
+        let loose = tmp.path().join("loose");
+        fs::create_dir(&loose).unwrap();
+        fs::set_permissions(&loose, fs::Permissions::from_mode(0o777)).unwrap();
+        assert!(is_private(&loose));
+        assert_eq!(mode(&loose), 0o700);
+
+        // Could point anywhere, e.g. at a directory someone else made
+        let link = tmp.path().join("link");
+        std::os::unix::fs::symlink(&fresh, &link).unwrap();
+        assert!(!is_private(&link));
+        let file = tmp.path().join("file");
+        fs::write(&file, "").unwrap();
+        assert!(!is_private(&file));
+    }
+}
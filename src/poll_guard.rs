@@ -1,14 +1,17 @@
-use git2::{BranchType, Commit, Repository};
+use git2::{Commit, Repository};
 use std::collections::hash_map::Entry;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fmt::{Debug, Formatter};
+use std::fs;
 use std::ops::Add;
 use std::path::{Path, PathBuf};
 use std::time::{Duration, SystemTime};
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use walkdir::{DirEntry, WalkDir};
 
+use crate::snapshots::{self, RefNamespace};
+
 /// OPTIMIZATION for checking for changes
 ///
 /// Provides a function, dir_changed, that is a much faster way to detect if any files in
@@ -17,62 +20,259 @@ use walkdir::{DirEntry, WalkDir};
 /// let Git2 make a commit, which triggered a whole lot of I/O and hashing.
 pub struct PollGuard {
     git_cache: HashMap<PathBuf, Repository>,
+    fast: bool,
+    dir_times: HashMap<PathBuf, DirTimes>,
+    precheck: bool,
+    watermarks: HashMap<PathBuf, CachedWatermark>,
+    watermark_reads: usize,
+}
+
+/// When `fast` is on, every this many scans of a repo look at every file anyway, to catch edits
+/// that don't touch any directory mtime.
+pub const FULL_WALK_EVERY: u32 = 10;
+
+/// What the fast heuristic remembers about a single repo
+#[derive(Debug, Default)]
+struct DirTimes {
+    /// mtime of every directory, as of the last scan
+    mtimes: HashMap<PathBuf, SystemTime>,
+    /// Directories where changed files were last found. People tend to keep editing the same
+    /// files, so these are always looked into.
+    hot: HashSet<PathBuf>,
+    scans_since_full: u32,
+}
+
+/// Changes to git files younger than this may not show up in their mtime yet, so a watermark
+/// read while they're this fresh isn't reused
+const RACY_WINDOW: Duration = Duration::from_secs(2);
+
+/// Where a repo keeps the git files its watermark depends on. Linked worktrees (a `.git` file)
+/// keep HEAD and the index in their own directory, and refs in the main repo's.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct GitLayout {
+    git_dir: PathBuf,
+    common_dir: PathBuf,
+    /// The branch HEAD points to, e.g. `refs/heads/main`. None when it's detached.
+    head_ref: Option<String>,
+}
+
+/// Modification time and size of a file, or None if it doesn't exist
+type Stamp = Option<(SystemTime, u64)>;
+
+impl GitLayout {
+    /// None when the repo's layout isn't one we know, e.g. it uses reftable. The watermark is
+    /// then always read through git.
+    fn find(workdir: &Path) -> Option<Self> {
+        let dot_git = workdir.join(".git");
+        let git_dir = match fs::metadata(&dot_git).ok()? {
+            metadata if metadata.is_dir() => dot_git,
+            _ => {
+                let contents = fs::read_to_string(&dot_git).ok()?;
+                workdir.join(contents.strip_prefix("gitdir:")?.trim())
+            }
+        };
+        let common_dir = match fs::read_to_string(git_dir.join("commondir")) {
+            Ok(contents) => git_dir.join(contents.trim()),
+            Err(_) => git_dir.clone(),
+        };
+        if common_dir.join("reftable").exists() || !common_dir.join("refs").is_dir() {
+            return None;
+        }
+        let head = fs::read_to_string(git_dir.join("HEAD")).ok()?;
+        let head_ref = head
+            .strip_prefix("ref:")
+            .map(|target| target.trim().to_string());
+        Some(Self {
+            git_dir,
+            common_dir,
+            head_ref,
+        })
+    }
+
+    /// Stats everything that changes along with HEAD, the branch it points to or dura's
+    /// snapshot refs. Six or seven stats, rather than opening the repo and resolving refs.
+    fn fingerprint(&self) -> Vec<Stamp> {
+        let mut paths = vec![
+            self.git_dir.join("HEAD"),
+            self.git_dir.join("index"),
+            self.common_dir.join("packed-refs"),
+            self.common_dir.join("refs/heads"),
+            self.common_dir.join("refs/heads/dura"),
+            self.common_dir.join("refs/dura"),
+        ];
+        if let Some(head_ref) = &self.head_ref {
+            paths.push(self.common_dir.join(head_ref));
+        }
+        paths
+            .iter()
+            .map(|path| {
+                let metadata = fs::metadata(path).ok()?;
+                Some((metadata.modified().ok()?, metadata.len()))
+            })
+            .collect()
+    }
+}
+
+/// A watermark, with the fingerprint of the git files it was read from
+#[derive(Debug)]
+struct CachedWatermark {
+    layout: GitLayout,
+    fingerprint: Vec<Stamp>,
+    watermark: SystemTime,
+}
+
+fn is_racy(fingerprint: &[Stamp], now: SystemTime) -> bool {
+    fingerprint.iter().flatten().any(|(modified, _)| {
+        now.duration_since(*modified)
+            .map_or(true, |age| age < RACY_WINDOW)
+    })
 }
 
 impl PollGuard {
     pub fn new() -> Self {
         Self {
             git_cache: Default::default(),
+            fast: false,
+            dir_times: Default::default(),
+            precheck: true,
+            watermarks: Default::default(),
+            watermark_reads: 0,
+        }
+    }
+
+    /// The precheck (on by default) only reads the watermark through git when HEAD, the index or
+    /// refs changed on disk since it was last read. Turning it off is only useful for comparing,
+    /// e.g. in `dura bench`.
+    pub fn set_precheck(&mut self, precheck: bool) {
+        if !precheck {
+            self.watermarks.clear();
+        }
+        self.precheck = precheck;
+    }
+
+    /// How many times the watermark was read through git rather than taken from the precheck
+    pub fn watermark_reads(&self) -> usize {
+        self.watermark_reads
+    }
+
+    /// Turns on a cheaper, heuristic scan. A directory's mtime changes when entries are added,
+    /// removed or renamed in it (which is how many editors save), but not when a file's contents
+    /// are edited in place. So files are only stat'ed in directories whose mtime changed since the
+    /// last scan, or which held the last changed file. Edits the heuristic can't see are picked
+    /// up by a full walk every `FULL_WALK_EVERY` scans.
+    pub fn set_fast(&mut self, fast: bool) {
+        if !fast {
+            self.dir_times.clear();
         }
+        self.fast = fast;
     }
 
     pub fn dir_changed(&mut self, dir: &Path) -> bool {
+        self.scan(dir, false).is_some()
+    }
+
+    /// Like `dir_changed`, but keeps walking to find the most recent modification time of
+    /// anything changed since the last snapshot. Returns `None` when nothing changed.
+    ///
+    /// If the watermark can't be determined, this returns the current time, since the
+    /// optimization is off and we can't tell how fresh changes are.
+    pub fn last_change(&mut self, dir: &Path) -> Option<SystemTime> {
+        self.scan(dir, true)
+    }
+
+    fn scan(&mut self, dir: &Path, full: bool) -> Option<SystemTime> {
         let watermark = match self.get_watermark(dir) {
             Ok(watermark) => watermark,
-            // True because we want to turn off this optimization
-            Err(_) => return true,
+            // Some because we want to turn off this optimization
+            Err(_) => return Some(SystemTime::now()),
         };
+        let worktree = self.worktree(dir);
 
-        fn compare_times(modified: SystemTime, watermark: SystemTime) -> Result<bool> {
-            let duration = modified.duration_since(watermark)?;
-            Ok(duration.as_secs_f32() > 1.0)
+        if self.fast {
+            let times = self.dir_times.entry(dir.into()).or_default();
+            return scan_fast(&worktree, watermark, full, times);
         }
 
-        fn get_file_time(entry: walkdir::Result<DirEntry>) -> Result<SystemTime> {
-            Ok(entry?.metadata()?.modified()?)
-        }
-
-        for entry in WalkDir::new(dir) {
-            if let Ok(modified) = get_file_time(entry) {
-                if compare_times(modified, watermark).unwrap_or(false) {
-                    dbg!(modified, watermark);
-                    return true;
+        let mut newest: Option<SystemTime> = None;
+        for entry in WalkDir::new(&worktree) {
+            if let Ok(modified) = get_file_time(&entry) {
+                if is_newer(modified, watermark) {
+                    if !full {
+                        return Some(modified);
+                    }
+                    newest = newest.max(Some(modified));
                 }
             }
         }
-        false
+        newest
     }
 
-    /// Find the last known commit timestamp
+    /// Find the last known commit timestamp, reusing the last one found when the precheck says
+    /// nothing in git changed since
     fn get_watermark(&mut self, path: &Path) -> Result<SystemTime> {
-        // Get git repo, create if necessary
-        let repo: &Repository = match self.git_cache.entry(path.into()) {
-            Entry::Occupied(entry) => entry.into_mut(),
-            Entry::Vacant(entry) => {
-                let new = Repository::open(path)?;
-                entry.insert(new)
+        if !self.precheck {
+            return self.read_watermark(path);
+        }
+        if let Some(cached) = self.watermarks.get(path) {
+            if cached.layout.fingerprint() == cached.fingerprint {
+                return Ok(cached.watermark);
             }
-        };
+        }
+
+        // Stat before reading, so anything that changes while git is read shows up next time
+        let layout = GitLayout::find(path);
+        let fingerprint = layout.as_ref().map(GitLayout::fingerprint);
+        let watermark = self.read_watermark(path)?;
+        match (layout, fingerprint) {
+            (Some(layout), Some(fingerprint)) if !is_racy(&fingerprint, SystemTime::now()) => {
+                self.watermarks.insert(
+                    path.into(),
+                    CachedWatermark {
+                        layout,
+                        fingerprint,
+                        watermark,
+                    },
+                );
+            }
+            _ => {
+                self.watermarks.remove(path);
+            }
+        }
+        Ok(watermark)
+    }
+
+    /// The repo at `path`, opened on first use
+    fn repo(&mut self, path: &Path) -> Result<&Repository> {
+        Ok(match self.git_cache.entry(path.into()) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(snapshots::open_repo(path)?),
+        })
+    }
+
+    /// Where the files of the repo at `path` are. That's `path` itself, unless the repo says
+    /// otherwise, e.g. with `core.worktree`.
+    fn worktree(&mut self, path: &Path) -> PathBuf {
+        match self
+            .repo(path)
+            .map(|repo| repo.workdir().map(Path::to_path_buf))
+        {
+            Ok(Some(workdir)) => workdir,
+            _ => path.to_path_buf(),
+        }
+    }
+
+    fn read_watermark(&mut self, path: &Path) -> Result<SystemTime> {
+        self.watermark_reads += 1;
+        let repo = self.repo(path)?;
 
         fn get_time(commit: &Commit) -> SystemTime {
             SystemTime::UNIX_EPOCH.add(Duration::from_secs(commit.time().seconds() as u64))
         }
 
         fn get_dura_time(head: &Commit, repo: &Repository) -> Result<SystemTime> {
-            let branch_name = format!("dura/{}", head.id());
-            let ret = repo
-                .find_branch(&branch_name, BranchType::Local)?
-                .get()
+            // Both namespaces are checked, so it doesn't matter which one is configured
+            let ret = snapshots::find_snapshot_ref(repo, RefNamespace::default(), head.id())
+                .ok_or_else(|| anyhow!("no snapshot ref"))?
                 .peel_to_commit()?;
             Ok(get_time(&ret))
         }
@@ -83,6 +283,97 @@ impl PollGuard {
     }
 }
 
+fn is_newer(modified: SystemTime, watermark: SystemTime) -> bool {
+    match modified.duration_since(watermark) {
+        Ok(duration) => duration.as_secs_f32() > 1.0,
+        Err(_) => false,
+    }
+}
+
+/// When the entry last changed. Symlinks aren't followed, so repointing one counts as a change.
+fn get_file_time(entry: &walkdir::Result<DirEntry>) -> Result<SystemTime> {
+    match entry {
+        Ok(entry) => change_time(&entry.metadata()?),
+        Err(e) => Err(anyhow!("{e}")),
+    }
+}
+
+/// On unix, a chmod only updates ctime, so mode changes would go unnoticed with mtime alone
+#[cfg(unix)]
+fn change_time(metadata: &std::fs::Metadata) -> Result<SystemTime> {
+    use std::os::unix::fs::MetadataExt;
+    let ctime = SystemTime::UNIX_EPOCH
+        + std::time::Duration::new(metadata.ctime().max(0) as u64, metadata.ctime_nsec() as u32);
+    Ok(metadata.modified()?.max(ctime))
+}
+
+#[cfg(not(unix))]
+fn change_time(metadata: &std::fs::Metadata) -> Result<SystemTime> {
+    Ok(metadata.modified()?)
+}
+
+/// `PollGuard::scan` with the directory mtime heuristic, see `PollGuard::set_fast`. Directories
+/// are still all visited, but most files are skipped.
+fn scan_fast(
+    dir: &Path,
+    watermark: SystemTime,
+    full: bool,
+    times: &mut DirTimes,
+) -> Option<SystemTime> {
+    times.scans_since_full += 1;
+    let exhaustive = times.mtimes.is_empty() || times.scans_since_full >= FULL_WALK_EVERY;
+    if exhaustive {
+        times.mtimes.clear();
+        times.scans_since_full = 0;
+    }
+
+    let mut newest: Option<SystemTime> = None;
+    let mut dirty: HashSet<PathBuf> = HashSet::new();
+    let mut hot: HashSet<PathBuf> = HashSet::new();
+    for entry in WalkDir::new(dir) {
+        let is_dir = matches!(&entry, Ok(e) if e.file_type().is_dir());
+        let path = match &entry {
+            Ok(e) => e.path().to_path_buf(),
+            Err(_) => continue,
+        };
+        if !is_dir && !exhaustive {
+            let parent_dirty = path.parent().map(|p| dirty.contains(p)).unwrap_or(true);
+            if !parent_dirty {
+                continue;
+            }
+        }
+
+        let modified = match get_file_time(&entry) {
+            Ok(modified) => modified,
+            Err(_) => continue,
+        };
+        if is_dir {
+            let previous = times.mtimes.insert(path.clone(), modified);
+            if previous != Some(modified) || times.hot.contains(&path) {
+                dirty.insert(path.clone());
+            }
+        }
+        if is_newer(modified, watermark) {
+            let changed_dir = match is_dir {
+                true => Some(path.as_path()),
+                false => path.parent(),
+            };
+            if let Some(changed_dir) = changed_dir {
+                hot.insert(changed_dir.to_path_buf());
+            }
+            newest = newest.max(Some(modified));
+            // Exhaustive scans keep going so every directory mtime gets recorded
+            if !full && !exhaustive {
+                break;
+            }
+        }
+    }
+    if !hot.is_empty() {
+        times.hot = hot;
+    }
+    newest
+}
+
 /// Implemented manually because Repository doesn't implement it
 impl Debug for PollGuard {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
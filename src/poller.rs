@@ -1,48 +1,109 @@
-use std::path::Path;
-use std::process;
-use std::time::Instant;
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::{Duration, Instant, SystemTime};
+use std::{panic, process};
 
-use tokio::time;
-use tracing::{debug, error, info, trace};
+use chrono::{Local, Utc};
+use tokio::{task, time};
+use tracing::{debug, error, info, trace, warn};
 
-use crate::config::Config;
-use crate::database::RuntimeLock;
-use crate::log::{Operation, StatCollector};
+use crate::build_info::BuildInfo;
+use crate::config::{Config, ConfigError};
+use crate::database::{
+    self, Boost, Boosts, LowDisk, MissingRoots, PollerStatus, RepoTimeout, RuntimeLock,
+};
+use crate::disk_space::DiskGuard;
+use crate::effective_config::{EffectiveConfig, Layers, DEFAULT_POLL_INTERVAL_SECS};
+use crate::events::{EventKind, EventSink, EventTarget};
+use crate::git_repo_iter::GitRepoIter;
+use crate::history::History;
+use crate::log::{CaptureOutcome, LifecycleEvent, MaintenanceAction, Operation, StatCollector};
+use crate::maintenance::{self, GcOutcome};
+use crate::notify::{DesktopNotifier, NotifyState};
 use crate::poll_guard::PollGuard;
-use crate::snapshots;
+use crate::redact;
+use crate::snapshots::{self, CaptureConfig, CaptureStatus};
+use crate::trigger::{self, CaptureResponse};
 
 /// If the directory is a repo, attempts to create a snapshot.
 /// Otherwise, recurses into each child directory.
-#[tracing::instrument]
-fn process_directory(current_path: &Path, guard: &mut PollGuard) {
+///
+/// With `force`, PollGuard is skipped and a capture is always attempted. With `debounce`, the
+/// capture is deferred if anything was written more recently than that. Snapshots are added to
+/// the history, which keeps `history_size` of them, and returned along with the outcome.
+#[tracing::instrument(skip(capture_config, capture))]
+fn process_directory(
+    current_path: &Path,
+    capture_config: &CaptureConfig,
+    guard: &mut PollGuard,
+    force: bool,
+    debounce: Option<Duration>,
+    capture: CaptureFn,
+    history_size: usize,
+) -> (CaptureOutcome, Option<CaptureStatus>) {
     let mut op: Option<snapshots::CaptureStatus> = None;
     let mut error: Option<String> = None;
     let start_time = Instant::now();
 
-    if guard.dir_changed(current_path) {
+    // Checked before PollGuard, so changes made meanwhile are still picked up once it's over
+    if let Some(reason) = snapshots::skipped_on_branch(current_path, capture_config) {
+        debug!(
+            "Not snapshotting {reason}: path = {path}",
+            path = redact::path(current_path)
+        );
+        return (CaptureOutcome::Skipped(reason), None);
+    }
+
+    let changed = match (force, debounce) {
+        (true, _) => true,
+        (false, None) => guard.dir_changed(current_path),
+        (false, Some(debounce)) => match guard.last_change(current_path) {
+            Some(last_change) if is_fresh(last_change, SystemTime::now(), debounce) => {
+                debug!(
+                    "Deferring capture, files are still being written: path = {path}",
+                    path = redact::path(current_path)
+                );
+                return (CaptureOutcome::Deferred, None);
+            }
+            Some(_) => true,
+            None => false,
+        },
+    };
+
+    if changed {
         debug!(
             "Potential change detected in repo: path = {path}",
-            path = current_path.to_str().unwrap_or("")
+            path = redact::path(current_path)
         );
-        match snapshots::capture(current_path) {
-            Ok(Some(status)) => op = Some(status),
+        match capture(current_path, capture_config) {
+            Ok(Some(status)) => {
+                if history_size > 0 {
+                    record_history(current_path, &status, history_size);
+                }
+                op = Some(status);
+            }
             Ok(None) => (),
             Err(err) => {
-                error = Some(format!("{err}"));
+                error = Some(redact::message(&err.to_string(), current_path));
             }
         }
     } else {
         trace!(
             "No files in repo have changed: path = {path}",
-            path = current_path.to_str().unwrap_or("")
+            path = redact::path(current_path)
         );
     }
 
     let latency = (Instant::now() - start_time).as_secs_f32();
-    let repo = current_path
-        .to_str()
-        .unwrap_or("<invalid path>")
-        .to_string();
+    let repo = redact::path(current_path);
+    let outcome = match (&op, &error) {
+        (_, Some(error)) => CaptureOutcome::Failed(error.clone()),
+        (Some(_), None) => CaptureOutcome::Succeeded,
+        (None, None) => CaptureOutcome::NoChange,
+    };
+    let status = op.clone();
     let mut operation = Operation::Snapshot {
         repo,
         op,
@@ -52,44 +113,1733 @@ fn process_directory(current_path: &Path, guard: &mut PollGuard) {
     if operation.should_log() {
         info!(operation = operation.log_str().as_str(), "info_operation")
     }
+    (outcome, status)
 }
 
-#[tracing::instrument]
-fn do_task(stats: &mut StatCollector, guard: &mut PollGuard) {
-    let runtime_lock = RuntimeLock::load();
-    if runtime_lock.pid != Some(process::id()) {
-        error!(
-            "Shutting down because other poller took lock: {:?}",
-            runtime_lock.pid
+/// The history is a convenience, so failing to write it is only logged
+fn record_history(path: &Path, status: &CaptureStatus, keep: usize) {
+    let db = History::default_path();
+    let result = match Config::create_dir(&db) {
+        Ok(()) => History::open(&db)
+            .and_then(|mut history| history.record(path, status, keep))
+            .map_err(|e| e.to_string()),
+        Err(e) => Err(e.to_string()),
+    };
+    if let Err(e) = result {
+        warn!(
+            repo = redact::path(path).as_str(),
+            "Couldn't add snapshot to history: {e}"
         );
-        process::exit(1);
     }
+}
 
-    let config = Config::load();
+/// Deletes the repo's snapshot refs that have since been committed for real, logging each one.
+/// With `run_gc`, git's gc gets a chance to reclaim the space afterwards.
+fn clean_merged(path: &Path, run_gc: bool) {
+    let start_time = Instant::now();
+    let repo = redact::path(path);
+    let result = snapshots::clean_merged(path);
+    let latency = (Instant::now() - start_time).as_secs_f32();
+    match result {
+        Ok(cleaned) => {
+            if run_gc && !cleaned.is_empty() {
+                gc(path);
+            }
+            for merged in cleaned {
+                let mut operation = Operation::Maintenance {
+                    repo: repo.clone(),
+                    action: MaintenanceAction::CleanMerged,
+                    details: BTreeMap::from([
+                        ("ref".to_string(), merged.reference),
+                        ("snapshot".to_string(), merged.snapshot.to_string()),
+                        ("matched".to_string(), merged.matched.to_string()),
+                    ]),
+                    error: None,
+                    latency,
+                };
+                info!(operation = operation.log_str().as_str(), "info_operation");
+            }
+        }
+        Err(e) => {
+            let mut operation = Operation::Maintenance {
+                repo,
+                action: MaintenanceAction::CleanMerged,
+                details: BTreeMap::new(),
+                error: Some(redact::message(&e.to_string(), path)),
+                latency,
+            };
+            info!(operation = operation.log_str().as_str(), "info_operation");
+        }
+    }
+}
+
+fn gc(path: &Path) {
+    let start_time = Instant::now();
+    let result = maintenance::gc(path);
+    let mut details = BTreeMap::new();
+    match &result {
+        Ok(outcome @ GcOutcome::Ran { before, after }) => {
+            details.insert("objects_bytes_before".to_string(), before.to_string());
+            details.insert("objects_bytes_after".to_string(), after.to_string());
+            details.insert(
+                "reclaimed_bytes".to_string(),
+                outcome.reclaimed().to_string(),
+            );
+        }
+        Ok(GcOutcome::Skipped(reason)) => {
+            details.insert("skipped".to_string(), reason.to_string());
+        }
+        Err(_) => (),
+    }
+    let mut operation = Operation::Maintenance {
+        repo: redact::path(path),
+        action: MaintenanceAction::Gc,
+        details,
+        error: result.err().map(|e| redact::message(&e, path)),
+        latency: (Instant::now() - start_time).as_secs_f32(),
+    };
+    info!(operation = operation.log_str().as_str(), "info_operation");
+}
+
+/// Runs blocking git and filesystem work on tokio's blocking pool, so the runtime thread stays
+/// free for anything else that's going on. A panic in `f` carries on as if it happened here.
+async fn off_runtime<T, F>(f: F) -> T
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    match task::spawn_blocking(f).await {
+        Ok(value) => value,
+        Err(e) if e.is_panic() => panic::resume_unwind(e.into_panic()),
+        Err(e) => panic!("Blocking task didn't finish: {e}"),
+    }
+}
+
+/// Like `off_runtime`, but stops waiting after `timeout`. A blocking task can't be cancelled, so
+/// when that happens its thread keeps going until `f` returns by itself. The task's handle is
+/// handed back so the caller can tell when it has.
+async fn off_runtime_timeout<T, F>(f: F, timeout: Duration) -> Result<T, task::JoinHandle<T>>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let mut handle = task::spawn_blocking(f);
+    match time::timeout(timeout, &mut handle).await {
+        Ok(Ok(value)) => Ok(value),
+        Ok(Err(e)) if e.is_panic() => panic::resume_unwind(e.into_panic()),
+        Ok(Err(e)) => panic!("Blocking task didn't finish: {e}"),
+        Err(_) => Err(handle),
+    }
+}
+
+/// Longest a repo that keeps timing out is left alone
+const MAX_TIMEOUT_BACKOFF: Duration = Duration::from_secs(3600);
+
+/// A repo whose processing was abandoned because it took too long
+#[derive(Debug)]
+struct TimedOutRepo {
+    /// Timeouts in a row
+    count: u32,
+    retry_after: Instant,
+    /// The abandoned work. The repo isn't tried again while it's still running.
+    task: task::JoinHandle<Processed>,
+}
+
+/// What comes back from looking at a repo on a blocking thread
+#[derive(Debug)]
+struct Processed {
+    guard: PollGuard,
+    disk: DiskGuard,
+    outcome: CaptureOutcome,
+    status: Option<CaptureStatus>,
+    /// MB free where the repo lives, when it was below `min_free_space_mb`
+    low_disk_mb: Option<u64>,
+}
+
+impl TimedOutRepo {
+    fn should_skip(&self, now: Instant) -> bool {
+        !self.task.is_finished() || now < self.retry_after
+    }
+}
+
+/// Waits twice as long after each timeout in a row, starting at the timeout itself
+fn timeout_backoff(timeout: Duration, count: u32) -> Duration {
+    timeout
+        .saturating_mul(2u32.saturating_pow(count.saturating_sub(1)))
+        .min(MAX_TIMEOUT_BACKOFF)
+}
+
+/// Was something written within the `debounce` window? A modification time in the future (e.g.
+/// clock skew) counts as fresh; `debounce_max_defer_loops` keeps that from deferring forever.
+fn is_fresh(last_change: SystemTime, now: SystemTime, debounce: Duration) -> bool {
+    match now.duration_since(last_change) {
+        Ok(age) => age < debounce,
+        Err(_) => true,
+    }
+}
+
+/// Remembers which repos were found on the previous loop, mapped to their watch root, so that
+/// discovery changes are logged once per transition rather than every loop.
+#[derive(Debug, Default)]
+struct KnownRepos {
+    repos: HashMap<PathBuf, PathBuf>,
+    denied: HashMap<PathBuf, PathBuf>,
+}
+
+impl KnownRepos {
+    fn update(&mut self, found: HashMap<PathBuf, PathBuf>, events: &mut EventSink) {
+        for (repo, watch_root) in found.iter() {
+            if !self.repos.contains_key(repo) {
+                info!(
+                    repo = redact::path(repo).as_str(),
+                    watch_root = redact::path(watch_root).as_str(),
+                    "repo_discovered"
+                );
+                events.emit(EventKind::RepoDiscovered { repo: repo.clone() });
+            }
+        }
+        for (repo, watch_root) in self.repos.iter() {
+            if !found.contains_key(repo) {
+                warn!(
+                    repo = redact::path(repo).as_str(),
+                    watch_root = redact::path(watch_root).as_str(),
+                    "repo_lost"
+                );
+            }
+        }
+        self.repos = found;
+    }
+
+    /// Logs repos newly kept out by `deny_repos`, so each one is only mentioned once
+    fn update_denied(&mut self, denied: &HashMap<PathBuf, PathBuf>) {
+        for (repo, watch_root) in denied.iter() {
+            if !self.denied.contains_key(repo) {
+                info!(
+                    repo = redact::path(repo).as_str(),
+                    watch_root = redact::path(watch_root).as_str(),
+                    "repo_denied"
+                );
+            }
+        }
+        self.denied = denied.clone();
+    }
+}
+
+/// Everything the poller carries from one loop to the next
+#[derive(Debug, Default)]
+struct PollerState {
+    options: PollerOptions,
+    stats: StatCollector,
+    guard: PollGuard,
+    known: KnownRepos,
+    clock: ClockWatch,
+    /// How long the poller meant to sleep before this loop
+    expected_gap: Duration,
+    /// Skip PollGuard for the next loop, because it can't be trusted
+    force_capture: bool,
+    /// When each repo last got a snapshot, for rate limiting
+    last_snapshot: HashMap<PathBuf, Instant>,
+    /// How many loops in a row each repo's capture has been deferred by debouncing
+    deferrals: HashMap<PathBuf, u32>,
+    disk: DiskGuard,
+    /// When `auto_clean_merged` last ran
+    last_clean: Option<Instant>,
+    /// When changes were last seen in each repo, so active repos go first
+    last_activity: HashMap<PathBuf, Instant>,
+    /// Repos that took longer than `per_repo_timeout_secs`, and are being backed off from
+    timeouts: HashMap<PathBuf, TimedOutRepo>,
+    notify: NotifyState,
+    /// When the runtime lock was last read, see `LOCK_CHECK_EVERY`
+    lock_checked: Option<Instant>,
+    /// Loaded on the first loop
+    missing_roots: Option<MissingRoots>,
+    /// When each missing watch root was last warned about
+    missing_warned: HashMap<PathBuf, Instant>,
+    /// For `--events-fd`
+    events: EventSink,
+    /// Repos not being snapshotted because of `skip_on_branches`, with the reason
+    skipped: HashMap<PathBuf, String>,
+    /// Repos already warned about being found twice
+    duplicates_warned: HashSet<PathBuf>,
+    /// As of `boosts_checked`
+    boosts: Boosts,
+    boosts_checked: Option<Instant>,
+    /// Repos being boosted, and when each was last looked at for its boost
+    boosted: HashMap<PathBuf, Option<Instant>>,
+    /// What `dura doctor` is told, saved whenever it changes
+    status: PollerStatus,
+    /// The config as of the last loop, so work between loops doesn't parse it again
+    config: Option<Rc<Config>>,
+}
+
+/// How far the wall clock may drift from the monotonic clock between two observations before it
+/// counts as a jump.
+const MAX_CLOCK_SKEW: Duration = Duration::from_secs(30);
+
+/// A gap this many times longer than expected means we weren't running, even if the monotonic
+/// clock kept ticking (some platforms count time asleep).
+const STALL_FACTOR: u32 = 10;
+
+/// Gaps shorter than this are never stalls, no matter how short the interval is.
+const MIN_STALL: Duration = Duration::from_secs(60);
+
+/// How often to look for snapshot refs that have been committed for real. Users don't commit
+/// every few seconds, so there's no point doing this every loop.
+const CLEAN_MERGED_INTERVAL: Duration = Duration::from_secs(600);
+
+/// How many times to try writing one of dura's files before giving up
+const SAVE_ATTEMPTS: u32 = 3;
 
+/// Delay before the first retry of a failed write. Each retry waits a little longer.
+const SAVE_RETRY_DELAY: Duration = Duration::from_millis(200);
+
+/// Notices when time doesn't add up between loops, e.g. the laptop was suspended overnight or
+/// the wall clock was changed. Clock values are passed in so it can be tested.
+#[derive(Debug, Default)]
+struct ClockWatch {
+    last: Option<(Instant, SystemTime)>,
+}
+
+impl ClockWatch {
+    /// Returns the size of the discontinuity, if there was one since the last observation.
+    /// `expected` is how much time should have passed; use `Duration::MAX` to only check for
+    /// clock jumps.
+    fn observe(&mut self, now: Instant, wall: SystemTime, expected: Duration) -> Option<Duration> {
+        let (last_now, last_wall) = self.last.replace((now, wall))?;
+        let elapsed = now.saturating_duration_since(last_now);
+        let wall_elapsed = match wall.duration_since(last_wall) {
+            Ok(d) => d,
+            // the wall clock went backwards
+            Err(e) if e.duration() > MAX_CLOCK_SKEW => return Some(e.duration()),
+            Err(_) => Duration::ZERO,
+        };
+
+        let skew = match wall_elapsed > elapsed {
+            true => wall_elapsed - elapsed,
+            false => elapsed - wall_elapsed,
+        };
+        if skew > MAX_CLOCK_SKEW {
+            return Some(skew);
+        }
+
+        let stall = expected.saturating_mul(STALL_FACTOR).max(MIN_STALL);
+        if elapsed > stall {
+            return Some(elapsed - expected);
+        }
+        None
+    }
+}
+
+/// Sleep between loops during quiet hours. Nothing is captured, so there's no reason to wake up
+/// often, but we still want to notice promptly-ish when quiet hours end.
+const QUIET_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long to pause between repos so that a loop's work is spread across the interval instead
+/// of happening in one burst. Based on how long each repo took last loop, so the whole loop still
+/// fits in the interval.
+fn stagger_delay(interval: Duration, repo_count: usize, dir_latency: Duration) -> Duration {
+    let repo_count: u32 = match repo_count.try_into() {
+        Ok(n) if n > 1 => n,
+        _ => return Duration::ZERO,
+    };
+    let work = dir_latency.saturating_mul(repo_count);
+    interval.saturating_sub(work) / repo_count
+}
+
+/// Puts the repos with the most recently seen changes first, so the one being worked on doesn't
+/// wait behind every idle repo. The rest keep their discovery order.
+fn order_by_activity<T>(
+    queue: &mut [T],
+    path: impl Fn(&T) -> &Path,
+    last_activity: &HashMap<PathBuf, Instant>,
+) {
+    queue.sort_by_key(|item| std::cmp::Reverse(last_activity.get(path(item)).copied()));
+}
+
+/// A repo to look at this loop, with the watch root it was found under, that watch's
+/// `min_snapshot_interval`, and the settings to capture it with. Resolved here, since the repo
+/// was canonicalized anyway, so nothing in the loop touches a filesystem that may hang outside
+/// `per_repo_timeout_secs`.
+type Queued = (PathBuf, Option<PathBuf>, Option<Duration>, CaptureConfig);
+
+/// A repo that was found again under another path or watch, and left out
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Duplicate {
+    path: PathBuf,
+    watch_root: Option<PathBuf>,
+    /// The path the repo is queued under instead
+    kept: PathBuf,
+}
+
+/// Everything the watches turn up this loop. A repo found more than once, under nested watches or
+/// through a symlink, is only queued once (compared by canonical path, see `Config::path_case`),
+/// under the most specific watch root so that watch's settings apply. It keeps the place it was
+/// first found in.
+fn discover(config: &Config, repos: &mut GitRepoIter) -> (Vec<Queued>, Vec<Duplicate>) {
+    let mut queue: Vec<Queued> = vec![];
+    let mut duplicates = vec![];
+    let mut seen: HashMap<PathBuf, usize> = HashMap::new();
+    let depth = |root: &Option<PathBuf>| root.as_ref().map_or(0, |r| r.components().count());
+    while let Some(repo) = repos.next() {
+        let layers = Layers::new(config, repos.watch_root().zip(repos.watch_config()));
+        let min_interval = EffectiveConfig::resolve(&layers).min_snapshot_interval();
+        let canonical = std::fs::canonicalize(&repo).unwrap_or_else(|_| repo.clone());
+        let capture_config = CaptureConfig::from(&EffectiveConfig::resolve(
+            &Layers::for_canonical_repo(config, &canonical),
+        ));
+        let found = (
+            repo,
+            repos.watch_root().map(Path::to_path_buf),
+            min_interval,
+            capture_config,
+        );
+        let canonical = config.path_case.key(&canonical);
+        let i = match seen.get(&canonical) {
+            Some(&i) => i,
+            None => {
+                seen.insert(canonical, queue.len());
+                queue.push(found);
+                continue;
+            }
+        };
+        let dropped = match depth(&found.1) > depth(&queue[i].1) {
+            true => std::mem::replace(&mut queue[i], found),
+            false => found,
+        };
+        duplicates.push(Duplicate {
+            path: dropped.0,
+            watch_root: dropped.1,
+            kept: queue[i].0.clone(),
+        });
+    }
+    (queue, duplicates)
+}
+
+/// Randomize the interval by ±10% so that many machines (or log pipelines) don't fall into lockstep.
+fn jitter(interval: Duration) -> Duration {
+    interval.mul_f64(0.9 + 0.2 * fastrand::f64())
+}
+
+/// How often the runtime lock is read again in the middle of a loop or between loops, so a new
+/// `dura serve` taking over doesn't have to wait for this one to get around to it
+const LOCK_CHECK_EVERY: Duration = Duration::from_secs(1);
+
+/// Exits when the runtime lock was taken by another poller, or cleared by `dura kill`. Unless
+/// `now`, at most once every `LOCK_CHECK_EVERY`.
+fn check_lock(state: &mut PollerState, now: bool) {
+    if !now
+        && state
+            .lock_checked
+            .is_some_and(|at| at.elapsed() < LOCK_CHECK_EVERY)
+    {
+        return;
+    }
+    state.lock_checked = Some(Instant::now());
+    match RuntimeLock::load() {
+        Ok(runtime_lock) if runtime_lock.pid.is_none() => {
+            log_lifecycle(LifecycleEvent::KillReceived, BTreeMap::new());
+            log_lifecycle(LifecycleEvent::Stopped, reason("kill"));
+            process::exit(1);
+        }
+        Ok(runtime_lock) if runtime_lock.pid != Some(process::id()) => {
+            error!(
+                "Shutting down because other poller took lock: {:?}",
+                runtime_lock.pid
+            );
+            let new_pid = runtime_lock
+                .pid
+                .map(|pid| pid.to_string())
+                .unwrap_or_default();
+            log_lifecycle(
+                LifecycleEvent::TakeoverCompleted,
+                BTreeMap::from([("new_pid".to_string(), new_pid)]),
+            );
+            log_lifecycle(LifecycleEvent::Stopped, reason("takeover"));
+            process::exit(1);
+        }
+        Ok(_) => (),
+        // Shutting down over a disk hiccup would be worse than briefly running alongside another
+        // poller
+        Err(e) => warn!("Couldn't check the runtime lock, assuming it's still ours: {e}"),
+    }
+}
+
+/// A watch root that's still missing is warned about again after this long
+const MISSING_WARN_EVERY: Duration = Duration::from_secs(24 * 3600);
+
+/// Notices watch roots that were deleted or moved away. Each one is warned about when it goes
+/// missing and then daily, and unwatched once it's been gone for `auto_unwatch_missing_after_days`.
+fn check_missing_roots(state: &mut PollerState, config: &mut Config) {
+    let watched: Vec<PathBuf> = config.repos.keys().map(PathBuf::from).collect();
+    let missing: Vec<PathBuf> = watched
+        .iter()
+        // An error means it might still be there, e.g. a permission problem
+        .filter(|root| matches!(root.try_exists(), Ok(false)))
+        .cloned()
+        .collect();
+    let record = state
+        .missing_roots
+        .get_or_insert_with(MissingRoots::load_or_default);
+    let now = Utc::now();
+    let forgotten = record.forget_unwatched(&watched);
+    state
+        .missing_warned
+        .retain(|root, _| watched.contains(root));
+    let (gone, back) = record.update(&missing, now);
+    let mut changed = forgotten || !gone.is_empty() || !back.is_empty();
+    for root in &back {
+        info!(
+            watch_root = redact::path(root).as_str(),
+            "watch_root_restored"
+        );
+        state.missing_warned.remove(root);
+    }
+    for root in &missing {
+        let due = state
+            .missing_warned
+            .get(root)
+            .is_none_or(|warned| warned.elapsed() >= MISSING_WARN_EVERY);
+        if due {
+            warn!(
+                watch_root = redact::path(root).as_str(),
+                missing_since = record.since[root].to_rfc3339().as_str(),
+                "watch_root_missing"
+            );
+            state.missing_warned.insert(root.clone(), Instant::now());
+        }
+    }
+
+    let expired = match config.auto_unwatch_missing_after_days {
+        Some(days) => record.expired(days, now),
+        None => vec![],
+    };
+    if !expired.is_empty() {
+        // Reloaded, so this changes as little as possible of what's on disk
+        let saved = Config::load().and_then(|mut fresh| {
+            for root in &expired {
+                fresh.repos.remove(&root.display().to_string());
+            }
+            fresh.save()
+        });
+        match saved {
+            Ok(()) => {
+                for root in &expired {
+                    config.repos.remove(&root.display().to_string());
+                    record.since.remove(root);
+                    state.missing_warned.remove(root);
+                    warn!(
+                        watch_root = redact::path(root).as_str(),
+                        "watch_root_unwatched"
+                    );
+                }
+                changed = true;
+            }
+            Err(e) => warn!("Couldn't unwatch missing watch roots: {e}"),
+        }
+    }
+    if changed {
+        if let Err(e) = record.save() {
+            warn!("Couldn't save missing watch roots: {e}");
+        }
+    }
+}
+
+/// Runs one pass over all repos and returns how long was spent sleeping between them.
+#[tracing::instrument]
+async fn do_task(state: &mut PollerState) -> Duration {
+    check_lock(state, true);
+
+    let resumed = state
+        .clock
+        .observe(Instant::now(), SystemTime::now(), state.expected_gap);
+    if let Some(gap) = resumed {
+        warn!(gap_secs = gap.as_secs(), "system_resume");
+        // Things probably changed while we were out, e.g. via syncthing or dropbox
+        state.force_capture = true;
+    }
+
+    let mut staggered = Duration::ZERO;
+    let mut config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            // Don't fall back to an empty config, that would quietly stop watching everything
+            error!("Couldn't load config, skipping this loop: {e}");
+            state.stats.record_skipped_loop();
+            return staggered;
+        }
+    };
+    for warning in Config::take_warnings() {
+        warn!("{warning}");
+    }
+    config.log_redact_paths.set_global();
+    check_missing_roots(state, &mut config);
+    state.config = Some(Rc::new(config.clone()));
+
+    let quiet_until = config.schedule.quiet_until(Local::now().time());
+    match (state.status.quiet_until, quiet_until) {
+        (None, Some(until)) => info!(
+            quiet_until = until.format("%H:%M").to_string().as_str(),
+            "quiet_hours_started"
+        ),
+        (Some(_), None) => info!("quiet_hours_ended"),
+        _ => (),
+    }
+    if state.status.quiet_until != quiet_until {
+        state.status.quiet_until = quiet_until;
+        save_status(&state.status);
+    }
+    if quiet_until.is_some() {
+        state.stats.record_skipped_loop();
+        return staggered;
+    }
+
+    let stagger = match state.options.once {
+        true => Duration::ZERO,
+        false => stagger_delay(
+            state.options.interval,
+            state.stats.repo_count(),
+            state.stats.last_loop_dir_latency(),
+        ),
+    };
+    trace!(stagger_ms = stagger.as_millis() as u64, "Pacing repos");
+
+    let timeout = config.per_repo_timeout();
+    let history_size = config.history_size;
+    state.disk.new_loop();
+    state.guard.set_fast(config.fast_poll_guard);
     let loop_start = Instant::now();
-    for repo in config.git_repos() {
+    let mut found = HashMap::new();
+    let mut status_changed = false;
+    let mut repos = config.git_repos();
+    let (mut queue, duplicates) = discover(&config, &mut repos);
+    for duplicate in duplicates {
+        if state.duplicates_warned.insert(duplicate.path.clone()) {
+            warn!(
+                repo = redact::path(&duplicate.path).as_str(),
+                watch_root = duplicate
+                    .watch_root
+                    .as_deref()
+                    .map(redact::path)
+                    .unwrap_or_default()
+                    .as_str(),
+                same_as = redact::path(&duplicate.kept).as_str(),
+                "repo_found_twice"
+            );
+        }
+    }
+    order_by_activity(&mut queue, |(repo, _, _, _)| repo, &state.last_activity);
+    trace!(
+        first = queue
+            .first()
+            .map(|(repo, _, _, _)| redact::path(repo))
+            .unwrap_or_default()
+            .as_str(),
+        active = queue
+            .iter()
+            .filter(|(repo, _, _, _)| state.last_activity.contains_key(repo))
+            .count(),
+        "loop_order"
+    );
+
+    for (repo, watch_root, min_interval, capture_config) in queue {
+        if !found.is_empty() && !stagger.is_zero() {
+            sleep_serving_requests(state, stagger).await;
+            staggered += stagger;
+        }
+        // Yield to a new poller between repos rather than at the end of a long loop
+        check_lock(state, false);
+        if let Some(watch_root) = watch_root {
+            found.insert(repo.clone(), watch_root);
+        }
+
+        let last_snapshot = state.last_snapshot.get(&repo);
+        if let (Some(min_interval), Some(last_snapshot)) = (min_interval, last_snapshot) {
+            if last_snapshot.elapsed() < min_interval {
+                // Anything changed in the meantime is newer than the last snapshot, so PollGuard
+                // will still pick it up once the interval is over.
+                trace!(
+                    repo = redact::path(&repo).as_str(),
+                    "Skipping, snapshot taken too recently"
+                );
+                continue;
+            }
+        }
+
+        if let Some(timed_out) = state.timeouts.get(&repo) {
+            if timed_out.should_skip(Instant::now()) {
+                trace!(
+                    repo = redact::path(&repo).as_str(),
+                    "Skipping, repo timed out recently"
+                );
+                continue;
+            }
+        }
+
+        let deferrals = state.deferrals.get(&repo).copied().unwrap_or(0);
+        let debounce = match config.debounce_ms {
+            // A repo that never settles down still needs to be captured eventually
+            Some(_) if deferrals >= config.debounce_max_defer_loops => None,
+            Some(ms) => Some(Duration::from_millis(ms)),
+            None => None,
+        };
+
         let dir_start = Instant::now();
-        process_directory(repo.as_path(), guard);
-        stats.record_dir(Instant::now() - dir_start);
+        // The guards go along to the blocking thread and come back with the outcome
+        let mut guard = std::mem::take(&mut state.guard);
+        let mut disk = std::mem::take(&mut state.disk);
+        let min_free_space_mb = config.min_free_space_mb;
+        let force = state.force_capture;
+        let path = repo.clone();
+        let capture = state.options.capture;
+        let result = off_runtime_timeout(
+            move || {
+                // Asking a stalled mount for its free space hangs just like capturing it does
+                let min_free_bytes = min_free_space_mb.map(|mb| mb.saturating_mul(1024 * 1024));
+                if let Some(min_free_bytes) = min_free_bytes {
+                    if !disk.has_room(&path, min_free_bytes) {
+                        let available = disk.available_bytes(&path).unwrap_or(0);
+                        return Processed {
+                            guard,
+                            disk,
+                            outcome: CaptureOutcome::LowDisk,
+                            status: None,
+                            low_disk_mb: Some(available / 1024 / 1024),
+                        };
+                    }
+                }
+                let (outcome, status) = process_directory(
+                    path.as_path(),
+                    &capture_config,
+                    &mut guard,
+                    force,
+                    debounce,
+                    capture,
+                    history_size,
+                );
+                Processed {
+                    guard,
+                    disk,
+                    outcome,
+                    status,
+                    low_disk_mb: None,
+                }
+            },
+            timeout,
+        )
+        .await;
+        let outcome = match result {
+            Ok(processed) => {
+                state.guard = processed.guard;
+                state.disk = processed.disk;
+                status_changed |= state.status.timeouts.remove(&repo).is_some();
+                if let Some(timed_out) = state.timeouts.remove(&repo) {
+                    info!(
+                        repo = redact::path(&repo).as_str(),
+                        timeouts = timed_out.count,
+                        "repo_timeout_recovered"
+                    );
+                }
+                if let (Some(available_mb), Some(min_free_space_mb)) =
+                    (processed.low_disk_mb, min_free_space_mb)
+                {
+                    let low_disk = state.status.low_disk.entry(repo.clone());
+                    status_changed |= matches!(low_disk, Entry::Vacant(_));
+                    let low_disk = low_disk.or_insert(LowDisk {
+                        since: Utc::now(),
+                        available_mb,
+                        min_free_space_mb,
+                    });
+                    low_disk.available_mb = available_mb;
+                    low_disk.min_free_space_mb = min_free_space_mb;
+                    state.stats.record_outcome(&CaptureOutcome::LowDisk);
+                    continue;
+                }
+                status_changed |= state.status.low_disk.remove(&repo).is_some();
+                let (outcome, status) = (processed.outcome, processed.status);
+                emit_capture(&mut state.events, &repo, &outcome, status.as_ref());
+                outcome
+            }
+            Err(task) => {
+                // The guards went with the abandoned task, so start over with fresh ones. It only
+                // costs a full scan of each repo, and free space being looked up again.
+                state.guard.set_fast(config.fast_poll_guard);
+                let count = state.timeouts.get(&repo).map_or(0, |t| t.count) + 1;
+                let backoff = timeout_backoff(timeout, count);
+                warn!(
+                    repo = redact::path(&repo).as_str(),
+                    timeout_secs = timeout.as_secs(),
+                    timeouts = count,
+                    retry_in_secs = backoff.as_secs(),
+                    "repo_timeout"
+                );
+                state.status.timeouts.insert(
+                    repo.clone(),
+                    RepoTimeout {
+                        count,
+                        retry_after: Utc::now()
+                            + chrono::Duration::from_std(backoff).unwrap_or_default(),
+                    },
+                );
+                status_changed = true;
+                state.timeouts.insert(
+                    repo.clone(),
+                    TimedOutRepo {
+                        count,
+                        retry_after: Instant::now() + backoff,
+                        task,
+                    },
+                );
+                state.events.emit(EventKind::SnapshotError {
+                    repo: repo.clone(),
+                    error: format!("timed out after {}s", timeout.as_secs()),
+                });
+                CaptureOutcome::TimedOut
+            }
+        };
+        state.stats.record_dir(Instant::now() - dir_start);
+        state.stats.record_outcome(&outcome);
+        state
+            .notify
+            .update(&config.notifications, &mut DesktopNotifier, &repo, &outcome);
+        match &outcome {
+            CaptureOutcome::Skipped(reason) => {
+                if state.skipped.insert(repo.clone(), reason.clone()).is_none() {
+                    info!(
+                        repo = redact::path(&repo).as_str(),
+                        reason = reason.as_str(),
+                        "snapshots_paused"
+                    );
+                }
+            }
+            _ => {
+                if state.skipped.remove(&repo).is_some() {
+                    info!(repo = redact::path(&repo).as_str(), "snapshots_resumed");
+                }
+            }
+        }
+        match outcome {
+            CaptureOutcome::Deferred => {
+                state.last_activity.insert(repo.clone(), Instant::now());
+                state.deferrals.insert(repo, deferrals + 1);
+            }
+            CaptureOutcome::Succeeded => {
+                state.deferrals.remove(&repo);
+                state.last_activity.insert(repo.clone(), Instant::now());
+                state.last_snapshot.insert(repo, Instant::now());
+            }
+            _ => {
+                state.deferrals.remove(&repo);
+            }
+        }
+    }
+
+    let resumed = state
+        .clock
+        .observe(Instant::now(), SystemTime::now(), Duration::MAX);
+    match resumed {
+        Some(gap) => {
+            warn!(gap_secs = gap.as_secs(), "system_resume");
+            // This loop's latency is meaningless
+            state.stats.discard_loop();
+        }
+        None => state
+            .stats
+            .record_loop((Instant::now() - loop_start).saturating_sub(staggered)),
+    }
+    state.force_capture = resumed.is_some();
+    state.stats.record_repo_count(found.len());
+    state.stats.record_discovery(repos.stats());
+    let clean_due = match state.last_clean {
+        Some(last_clean) => last_clean.elapsed() >= CLEAN_MERGED_INTERVAL,
+        None => true,
+    };
+    if config.auto_clean_merged && clean_due {
+        let run_gc = config.run_gc_after_cleanup;
+        for repo in found.keys() {
+            let path = repo.clone();
+            off_runtime(move || clean_merged(&path, run_gc)).await;
+        }
+        state.last_clean = Some(Instant::now());
+    }
+    state
+        .last_activity
+        .retain(|repo, _| found.contains_key(repo));
+    state.timeouts.retain(|repo, _| found.contains_key(repo));
+    state.notify.retain(|repo| found.contains_key(repo));
+    state.skipped.retain(|repo, _| found.contains_key(repo));
+    let held_back = state.status.low_disk.len() + state.status.timeouts.len();
+    state
+        .status
+        .low_disk
+        .retain(|repo, _| found.contains_key(repo));
+    state
+        .status
+        .timeouts
+        .retain(|repo, _| found.contains_key(repo));
+    if status_changed || state.status.low_disk.len() + state.status.timeouts.len() != held_back {
+        save_status(&state.status);
+    }
+    state.known.update(found, &mut state.events);
+    state.known.update_denied(repos.denied());
+
+    if state.stats.should_log() {
+        info!(operation = state.stats.log_str().as_str(), "poller_stats");
+    }
+    staggered
+}
+
+/// How often `dura serve` looks for `dura capture --via-daemon` requests while it's sleeping
+const REQUEST_POLL: Duration = Duration::from_millis(200);
+
+/// Sleeps for `gap`, answering capture requests in the meantime
+async fn sleep_serving_requests(state: &mut PollerState, gap: Duration) {
+    let until = Instant::now() + gap;
+    loop {
+        check_lock(state, false);
+        serve_capture_requests(state).await;
+        serve_boosts(state).await;
+        let left = until.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return;
+        }
+        time::sleep(left.min(REQUEST_POLL)).await;
+    }
+}
+
+async fn serve_capture_requests(state: &mut PollerState) {
+    let pending = trigger::take_requests(&trigger::default_dir());
+    if pending.is_empty() {
+        return;
+    }
+    let config = Config::load();
+    for pending in pending {
+        if pending.withdrawn() {
+            pending.abandon();
+            continue;
+        }
+        let response = match &config {
+            Ok(config) => requested_capture(state, config, &pending.request.path).await,
+            Err(e) => CaptureResponse {
+                status: None,
+                error: Some(format!("Couldn't load config: {e}")),
+            },
+        };
+        if let Err(e) = pending.respond(&response) {
+            warn!("Couldn't answer capture request: {e}");
+        }
+    }
+}
+
+/// Captures a repo ahead of schedule, for `dura capture --via-daemon`. Only repos under a watch
+/// are captured, the same as the poller would.
+async fn requested_capture(
+    state: &mut PollerState,
+    config: &Config,
+    path: &Path,
+) -> CaptureResponse {
+    let failed = |error: String| CaptureResponse {
+        status: None,
+        error: Some(error),
+    };
+    let repo = match std::fs::canonicalize(path) {
+        Ok(repo) => repo,
+        Err(e) => return failed(format!("{}: {e}", path.display())),
+    };
+    if config.watch_root_of(&repo).is_none() {
+        return failed(format!(
+            "{} isn't under a watched directory",
+            repo.display()
+        ));
+    }
+    info!(repo = redact::path(&repo).as_str(), "capture_requested");
+
+    match capture_now(state, config, &repo, true).await {
+        Err(error) => failed(error),
+        Ok((CaptureOutcome::Failed(error), _)) => failed(error),
+        Ok((CaptureOutcome::Succeeded, status)) => CaptureResponse {
+            status,
+            error: None,
+        },
+        Ok(_) => CaptureResponse {
+            status: None,
+            error: None,
+        },
+    }
+}
+
+/// Captures `repo` outside of the loop, with the same timeout and bookkeeping. Skips
+/// `min_snapshot_interval_secs`, debouncing and timeout backoff. Fails only when it timed out.
+async fn capture_now(
+    state: &mut PollerState,
+    config: &Config,
+    repo: &Path,
+    force: bool,
+) -> Result<(CaptureOutcome, Option<CaptureStatus>), String> {
+    let timeout = config.per_repo_timeout();
+    let history_size = config.history_size;
+    let capture_config = CaptureConfig::for_repo(config, repo);
+    let capture = state.options.capture;
+    let mut guard = std::mem::take(&mut state.guard);
+    let path = repo.to_path_buf();
+    let result = off_runtime_timeout(
+        move || {
+            let result = process_directory(
+                &path,
+                &capture_config,
+                &mut guard,
+                force,
+                None,
+                capture,
+                history_size,
+            );
+            (guard, result)
+        },
+        timeout,
+    )
+    .await;
+    let (outcome, status) = match result {
+        Ok((guard, result)) => {
+            state.guard = guard;
+            result
+        }
+        Err(_) => {
+            state.guard.set_fast(config.fast_poll_guard);
+            return Err(format!(
+                "{} took longer than {}s",
+                repo.display(),
+                timeout.as_secs()
+            ));
+        }
+    };
+    state.stats.record_outcome(&outcome);
+    emit_capture(&mut state.events, repo, &outcome, status.as_ref());
+    if outcome == CaptureOutcome::Succeeded {
+        state
+            .last_activity
+            .insert(repo.to_path_buf(), Instant::now());
+        state
+            .last_snapshot
+            .insert(repo.to_path_buf(), Instant::now());
+    }
+    Ok((outcome, status))
+}
+
+/// How often `dura serve` rereads the boosts, see `serve_boosts`
+const BOOST_CHECK_EVERY: Duration = Duration::from_secs(1);
+
+/// Looks at boosted repos whose interval is up, see `dura boost`. This runs between loops, so
+/// boosted repos keep being looked at through quiet hours and however long the loop interval is.
+/// Boosts that ran out or were cleared are logged, and left for `dura boost` to tidy up.
+async fn serve_boosts(state: &mut PollerState) {
+    if state
+        .boosts_checked
+        .is_none_or(|at| at.elapsed() >= BOOST_CHECK_EVERY)
+    {
+        state.boosts_checked = Some(Instant::now());
+        state.boosts = Boosts::load_or_default();
+        let now = Utc::now();
+        let active: HashMap<&PathBuf, &Boost> = state.boosts.active(now).collect();
+        let ended: Vec<PathBuf> = state
+            .boosted
+            .keys()
+            .filter(|repo| !active.contains_key(repo))
+            .cloned()
+            .collect();
+        for (repo, boost) in &active {
+            if !state.boosted.contains_key(*repo) {
+                info!(
+                    repo = redact::path(repo).as_str(),
+                    until = boost.until.to_rfc3339().as_str(),
+                    interval_secs = boost.interval_secs,
+                    "boost_started"
+                );
+                state.boosted.insert((*repo).clone(), None);
+            }
+        }
+        for repo in ended {
+            let reason = match state.boosts.repos.contains_key(&repo) {
+                true => "expired",
+                false => "cleared",
+            };
+            info!(repo = redact::path(&repo).as_str(), reason, "boost_ended");
+            state.boosted.remove(&repo);
+        }
+    }
+
+    let due: Vec<PathBuf> = state
+        .boosted
+        .iter()
+        .filter(|(repo, last)| {
+            let interval = state
+                .boosts
+                .repos
+                .get(*repo)
+                .map_or(Duration::MAX, |boost| {
+                    Duration::from_secs(boost.interval_secs)
+                });
+            last.is_none_or(|last| last.elapsed() >= interval)
+        })
+        .map(|(repo, _)| repo.clone())
+        .collect();
+    if due.is_empty() {
+        return;
+    }
+    // Only before the first loop, after that it's whatever the loop loaded
+    let config = match &state.config {
+        Some(config) => Rc::clone(config),
+        None => match Config::load() {
+            Ok(config) => Rc::clone(state.config.insert(Rc::new(config))),
+            Err(e) => {
+                warn!("Couldn't load config, skipping boosted repos: {e}");
+                return;
+            }
+        },
+    };
+    for repo in due {
+        state.boosted.insert(repo.clone(), Some(Instant::now()));
+        if let Err(error) = capture_now(state, &config, &repo, false).await {
+            warn!(repo = redact::path(&repo).as_str(), "{error}");
+        }
+    }
+}
+
+/// Tells `--events-fd` about a snapshot that was taken or failed. Anything else isn't an event.
+fn emit_capture(
+    events: &mut EventSink,
+    repo: &Path,
+    outcome: &CaptureOutcome,
+    status: Option<&CaptureStatus>,
+) {
+    match (outcome, status) {
+        (CaptureOutcome::Succeeded, Some(status)) => {
+            events.emit(EventKind::snapshot_created(repo.to_path_buf(), status))
+        }
+        (CaptureOutcome::Failed(error), _) => events.emit(EventKind::SnapshotError {
+            repo: repo.to_path_buf(),
+            error: error.clone(),
+        }),
+        _ => (),
+    }
+}
+
+/// Knobs for `dura serve` that come from the command line rather than the config file.
+#[derive(Debug, Clone)]
+pub struct PollerOptions {
+    /// How long to sleep between loops
+    pub interval: Duration,
+    /// Run a single loop, flush stats and return rather than looping forever
+    pub once: bool,
+    /// Takes the snapshot. Only ever replaced in tests.
+    pub capture: CaptureFn,
+    /// Pid of the `dura serve --supervise` process that started this one
+    pub supervisor: Option<u32>,
+    /// Where to write events for other tools, see `events`
+    pub events: Option<EventTarget>,
+}
+
+pub type CaptureFn = fn(&Path, &CaptureConfig) -> Result<Option<CaptureStatus>, git2::Error>;
+
+impl Default for PollerOptions {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(DEFAULT_POLL_INTERVAL_SECS),
+            once: false,
+            capture: snapshots::capture_with,
+            supervisor: None,
+            events: None,
+        }
+    }
+}
+
+/// Writes to dura's own files sometimes fail for a moment, e.g. while the disk is busy. Each
+/// failed attempt is logged.
+async fn save_with_retry(
+    mut save: impl FnMut() -> Result<(), ConfigError>,
+) -> Result<(), ConfigError> {
+    let mut attempt = 1;
+    loop {
+        match save() {
+            Ok(()) => return Ok(()),
+            Err(e) if attempt < SAVE_ATTEMPTS => {
+                warn!(attempt = attempt, "Save failed, retrying: {e}");
+                time::sleep(SAVE_RETRY_DELAY * attempt).await;
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn save_status(status: &PollerStatus) {
+    if let Err(e) = status.save() {
+        warn!("Couldn't save the poller status: {e}");
+    }
+}
+
+fn log_lifecycle(event: LifecycleEvent, details: BTreeMap<String, String>) {
+    let mut operation = Operation::Lifecycle {
+        event,
+        pid: process::id(),
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        details,
+    };
+    info!(operation = operation.log_str().as_str(), "info_operation");
+}
+
+fn reason(reason: &str) -> BTreeMap<String, String> {
+    BTreeMap::from([("reason".to_string(), reason.to_string())])
+}
+
+/// What `dura serve` started out with, for `LifecycleEvent::Started`
+fn startup_details(options: &PollerOptions) -> BTreeMap<String, String> {
+    let mut details = BTreeMap::from([
+        (
+            "interval_secs".to_string(),
+            options.interval.as_secs_f32().to_string(),
+        ),
+        ("once".to_string(), options.once.to_string()),
+    ]);
+    match Config::load() {
+        Ok(config) => {
+            let enabled = config.repos.values().filter(|watch| watch.enabled).count();
+            details.insert("watches".to_string(), config.repos.len().to_string());
+            details.insert("watches_enabled".to_string(), enabled.to_string());
+        }
+        Err(e) => {
+            details.insert("config_error".to_string(), e.to_string());
+        }
     }
-    stats.record_loop(Instant::now() - loop_start);
+    details
+}
+
+/// How long a new `dura serve` waits for the one it replaces to stop. That one checks the lock
+/// between repos, so this only runs out when a single repo takes longer than that.
+const TAKEOVER_TIMEOUT: Duration = Duration::from_secs(30);
 
-    if stats.should_log() {
-        info!(operation = stats.log_str().as_str(), "poller_stats");
+/// Waits for the poller that held the lock before us to notice and exit, so the two never
+/// snapshot the same repo at once. Then clears `taking_over_from`, unless the lock changed hands
+/// again in the meantime.
+async fn wait_for_takeover(previous: u32) {
+    let deadline = Instant::now() + TAKEOVER_TIMEOUT;
+    while database::pid_alive(previous) {
+        if Instant::now() >= deadline {
+            warn!(
+                old_pid = previous,
+                timeout_secs = TAKEOVER_TIMEOUT.as_secs(),
+                "Previous poller didn't stop in time, starting anyway"
+            );
+            break;
+        }
+        time::sleep(REQUEST_POLL).await;
+    }
+    match RuntimeLock::load() {
+        Ok(mut lock) if lock.pid == Some(process::id()) => {
+            lock.taking_over_from = None;
+            if let Err(e) = save_with_retry(|| lock.save()).await {
+                warn!("Couldn't update the runtime lock: {e}");
+            }
+        }
+        _ => (),
     }
 }
 
-pub async fn start() {
-    let mut runtime_lock = RuntimeLock::load();
+pub async fn start(options: PollerOptions) {
+    let mut runtime_lock = RuntimeLock::load().unwrap_or_else(|e| {
+        warn!("Replacing unreadable runtime lock: {e}");
+        RuntimeLock::empty()
+    });
+    let previous = runtime_lock
+        .pid
+        .filter(|&pid| pid != process::id() && database::pid_alive(pid));
     runtime_lock.pid = Some(process::id());
-    runtime_lock.save();
+    runtime_lock.build = Some(BuildInfo::current());
+    runtime_lock.supervisor_pid = options.supervisor;
+    runtime_lock.config_path = Some(Config::default_path());
+    runtime_lock.taking_over_from = previous;
+    if let Err(e) = save_with_retry(|| runtime_lock.save()).await {
+        error!("Couldn't take the runtime lock: {e}");
+        process::exit(1);
+    }
+    let events = match &options.events {
+        Some(target) => EventSink::open(target).unwrap_or_else(|e| {
+            error!("Couldn't open {target} for events: {e}");
+            process::exit(1);
+        }),
+        None => EventSink::default(),
+    };
     info!(pid = std::process::id());
+    log_lifecycle(LifecycleEvent::Started, startup_details(&options));
+    if let Some(previous) = previous {
+        log_lifecycle(
+            LifecycleEvent::TakeoverStarted,
+            BTreeMap::from([("old_pid".to_string(), previous.to_string())]),
+        );
+        wait_for_takeover(previous).await;
+    }
 
-    let mut stats = StatCollector::new();
-    let mut guard = PollGuard::new();
+    let mut state = PollerState {
+        options: options.clone(),
+        events,
+        ..Default::default()
+    };
+    // Whatever the last poller was holding back on is out of date
+    save_status(&state.status);
+    state.events.emit(EventKind::DaemonStarted {
+        pid: process::id(),
+        version: env!("CARGO_PKG_VERSION").to_string(),
+    });
+    if options.once {
+        do_task(&mut state).await;
+        info!(operation = state.stats.log_str().as_str(), "poller_stats");
+        log_lifecycle(LifecycleEvent::Stopped, reason("once"));
+        return;
+    }
+    let mut staggered = Duration::ZERO;
     loop {
-        time::sleep(time::Duration::from_secs(5)).await;
-        do_task(&mut stats, &mut guard);
+        let interval = match state.status.quiet_until {
+            Some(_) => options.interval.max(QUIET_INTERVAL),
+            None => options.interval,
+        };
+        // Time spent pacing repos last loop was already part of the interval
+        state.expected_gap = jitter(interval).saturating_sub(staggered);
+        let gap = state.expected_gap;
+        sleep_serving_requests(&mut state, gap).await;
+        staggered = do_task(&mut state).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::WatchConfig;
+    use crate::paths::{self, Paths};
+
+    #[test]
+    fn active_repos_first() {
+        let now = Instant::now();
+        let mut activity = HashMap::new();
+        activity.insert(PathBuf::from("/c"), now - Duration::from_secs(60));
+        activity.insert(PathBuf::from("/d"), now);
+        let mut queue: Vec<PathBuf> = ["/a", "/b", "/c", "/d"].iter().map(PathBuf::from).collect();
+        order_by_activity(&mut queue, |p| p, &activity);
+        let expected: Vec<PathBuf> = ["/d", "/c", "/a", "/b"].iter().map(PathBuf::from).collect();
+        assert_eq!(queue, expected);
+    }
+
+    fn watch(config: &mut Config, root: &Path, min_snapshot_interval_secs: Option<u64>) {
+        let watch = WatchConfig {
+            min_snapshot_interval_secs,
+            ..WatchConfig::new()
+        };
+        let key = root.to_str().unwrap().to_string();
+        config.repos.insert(key, Rc::new(watch));
+    }
+
+    #[test]
+    fn nested_watches_queue_repo_once() {
+        let tmp = tempfile::tempdir().unwrap();
+        let code = tmp.path().canonicalize().unwrap();
+        let client = code.join("client");
+        for repo in [code.join("a"), client.join("b")] {
+            std::fs::create_dir_all(&repo).unwrap();
+            git2::Repository::init(&repo).unwrap();
+        }
+        let mut config = Config::empty();
+        watch(&mut config, &code, None);
+        watch(&mut config, &client, Some(60));
+
+        let (queue, duplicates) = discover(&config, &mut config.git_repos());
+
+        let mut repos: Vec<_> = queue.iter().map(|(repo, _, _, _)| repo.clone()).collect();
+        repos.sort();
+        assert_eq!(repos, vec![code.join("a"), client.join("b")]);
+        let b = queue
+            .iter()
+            .find(|(repo, _, _, _)| repo.ends_with("b"))
+            .unwrap();
+        // Attributed to the inner watch, along with its settings
+        assert_eq!(b.1.as_deref(), Some(client.as_path()));
+        assert_eq!(b.2, Some(Duration::from_secs(60)));
+        assert_eq!(
+            duplicates,
+            vec![Duplicate {
+                path: client.join("b"),
+                watch_root: Some(code.clone()),
+                kept: client.join("b"),
+            }]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinked_repo_queued_once() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().canonicalize().unwrap();
+        let real = root.join("code");
+        std::fs::create_dir_all(real.join("a")).unwrap();
+        git2::Repository::init(real.join("a")).unwrap();
+        let linked = root.join("linked");
+        std::fs::create_dir(&linked).unwrap();
+        std::os::unix::fs::symlink(real.join("a"), linked.join("a")).unwrap();
+        let mut config = Config::empty();
+        watch(&mut config, &real, None);
+        watch(&mut config, &linked, None);
+
+        let (queue, duplicates) = discover(&config, &mut config.git_repos());
+
+        assert_eq!(queue.len(), 1, "{queue:?}");
+        assert_eq!(duplicates.len(), 1, "{duplicates:?}");
+        assert_eq!(duplicates[0].kept, queue[0].0);
+        assert_ne!(duplicates[0].path, queue[0].0);
+    }
+
+    #[test]
+    fn stagger_spreads_spare_time() {
+        let delay = stagger_delay(Duration::from_secs(5), 10, Duration::from_millis(100));
+        // 5s - 10 * 100ms = 4s spare, spread over 10 repos
+        assert_eq!(delay, Duration::from_millis(400));
+    }
+
+    #[test]
+    fn stagger_gives_up_when_work_exceeds_interval() {
+        let delay = stagger_delay(Duration::from_secs(5), 10, Duration::from_secs(1));
+        assert_eq!(delay, Duration::ZERO);
+    }
+
+    #[test]
+    fn stagger_needs_several_repos() {
+        assert_eq!(
+            stagger_delay(Duration::from_secs(5), 1, Duration::ZERO),
+            Duration::ZERO
+        );
+        assert_eq!(
+            stagger_delay(Duration::from_secs(5), 0, Duration::ZERO),
+            Duration::ZERO
+        );
+    }
+
+    #[test]
+    fn clock_watch_steady() {
+        let mut watch = ClockWatch::default();
+        let now = Instant::now();
+        let wall = SystemTime::now();
+        let interval = Duration::from_secs(5);
+        assert_eq!(watch.observe(now, wall, interval), None);
+        for i in 1..10 {
+            let elapsed = interval * i;
+            assert_eq!(watch.observe(now + elapsed, wall + elapsed, interval), None);
+        }
+    }
+
+    #[test]
+    fn clock_watch_suspend() {
+        let mut watch = ClockWatch::default();
+        let now = Instant::now();
+        let wall = SystemTime::now();
+        let interval = Duration::from_secs(5);
+        watch.observe(now, wall, interval);
+        // monotonic clock paused while asleep, wall clock didn't
+        let gap = watch.observe(
+            now + interval,
+            wall + Duration::from_secs(8 * 3600),
+            interval,
+        );
+        assert!(gap.unwrap() > Duration::from_secs(7 * 3600));
+    }
+
+    #[test]
+    fn clock_watch_stall() {
+        let mut watch = ClockWatch::default();
+        let now = Instant::now();
+        let wall = SystemTime::now();
+        let interval = Duration::from_secs(5);
+        watch.observe(now, wall, interval);
+        // both clocks counted the time asleep
+        let elapsed = Duration::from_secs(3600);
+        assert!(watch
+            .observe(now + elapsed, wall + elapsed, interval)
+            .is_some());
+        // ...but a long loop isn't a stall when we didn't know how long to expect
+        let later = elapsed * 2;
+        assert_eq!(
+            watch.observe(now + later, wall + later, Duration::MAX),
+            None
+        );
+    }
+
+    #[test]
+    fn clock_watch_backwards() {
+        let mut watch = ClockWatch::default();
+        let now = Instant::now();
+        let wall = SystemTime::now();
+        let interval = Duration::from_secs(5);
+        watch.observe(now, wall, interval);
+        let gap = watch.observe(now + interval, wall - Duration::from_secs(3600), interval);
+        assert_eq!(gap, Some(Duration::from_secs(3600)));
+    }
+
+    #[test]
+    fn debounce_freshness() {
+        let now = SystemTime::now();
+        let debounce = Duration::from_millis(500);
+        assert!(is_fresh(now - Duration::from_millis(100), now, debounce));
+        assert!(!is_fresh(now - Duration::from_millis(600), now, debounce));
+        assert!(is_fresh(now + Duration::from_secs(10), now, debounce));
+    }
+
+    #[test]
+    fn jitter_stays_within_ten_percent() {
+        let interval = Duration::from_secs(5);
+        for _ in 0..1000 {
+            let jittered = jitter(interval);
+            assert!(jittered >= Duration::from_millis(4500));
+            assert!(jittered <= Duration::from_millis(5500));
+        }
+    }
+
+    fn write_error() -> ConfigError {
+        ConfigError::Write {
+            path: PathBuf::from("runtime.db"),
+            source: std::io::Error::other("disk hiccup"),
+        }
+    }
+
+    #[tokio::test]
+    async fn save_retry_recovers() {
+        let mut calls = 0;
+        let res = save_with_retry(|| {
+            calls += 1;
+            match calls {
+                1 => Err(write_error()),
+                _ => Ok(()),
+            }
+        })
+        .await;
+        assert!(res.is_ok());
+        assert_eq!(calls, 2);
+    }
+
+    #[tokio::test]
+    async fn save_retry_gives_up() {
+        let mut calls = 0;
+        let res = save_with_retry(|| {
+            calls += 1;
+            Err(write_error())
+        })
+        .await;
+        assert!(res.is_err());
+        assert_eq!(calls, SAVE_ATTEMPTS);
+    }
+
+    #[tokio::test]
+    async fn blocking_work_leaves_runtime_free() {
+        // The default test runtime has a single thread, so ticks only happen while it's free
+        let ticks = std::sync::Arc::new(std::sync::atomic::AtomicU32::new(0));
+        let ticker = {
+            let ticks = ticks.clone();
+            tokio::spawn(async move {
+                loop {
+                    time::sleep(Duration::from_millis(10)).await;
+                    ticks.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                }
+            })
+        };
+
+        let value = off_runtime(|| {
+            std::thread::sleep(Duration::from_millis(300));
+            42
+        })
+        .await;
+        ticker.abort();
+
+        assert_eq!(value, 42);
+        assert!(ticks.load(std::sync::atomic::Ordering::SeqCst) >= 10);
+    }
+
+    #[tokio::test]
+    async fn timeout_stops_waiting() {
+        let start = Instant::now();
+        let res = off_runtime_timeout(
+            || std::thread::sleep(Duration::from_secs(2)),
+            Duration::from_millis(100),
+        )
+        .await;
+        assert!(res.is_err());
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn timeout_backoff_doubles() {
+        let timeout = Duration::from_secs(120);
+        assert_eq!(timeout_backoff(timeout, 1), timeout);
+        assert_eq!(timeout_backoff(timeout, 3), timeout * 4);
+        assert_eq!(timeout_backoff(timeout, 40), MAX_TIMEOUT_BACKOFF);
+    }
+
+    /// Makes a repo with one commit and an uncommitted change
+    fn dirty_repo(dir: &Path) {
+        let repo = git2::Repository::init(dir).unwrap();
+        std::fs::write(dir.join("foo.txt"), "one").unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new("foo.txt")).unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let sig = git2::Signature::now("test", "test@example.com").unwrap();
+        repo.commit(Some("HEAD"), &sig, &sig, "init", &tree, &[])
+            .unwrap();
+        std::fs::write(dir.join("foo.txt"), "two").unwrap();
+    }
+
+    /// Tests that point `paths` somewhere else can't run at the same time
+    static ENV_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+    /// Config and cache homes of a test's own, see `poller_env`. Deleted when dropped, and the next
+    /// test gets `ENV_LOCK`.
+    struct TestEnv {
+        _lock: tokio::sync::MutexGuard<'static, ()>,
+        config_home: tempfile::TempDir,
+        cache_home: tempfile::TempDir,
+        watched: tempfile::TempDir,
+    }
+
+    impl TestEnv {
+        fn repo(&self, name: &str) -> PathBuf {
+            self.watched.path().join(name)
+        }
+    }
+
+    /// Saves `config`, watching a directory with a dirty repo for each of `repos`, in homes of
+    /// its own. This process holds the runtime lock, so `do_task` doesn't exit.
+    async fn poller_env(mut config: Config, repos: &[&str]) -> TestEnv {
+        let lock = ENV_LOCK.lock().await;
+        let env = TestEnv {
+            _lock: lock,
+            config_home: tempfile::tempdir().unwrap(),
+            cache_home: tempfile::tempdir().unwrap(),
+            watched: tempfile::tempdir().unwrap(),
+        };
+        paths::set(Paths::resolve(
+            Some(env.config_home.path().to_path_buf()),
+            Some(env.cache_home.path().to_path_buf()),
+        ));
+        RuntimeLock {
+            pid: Some(process::id()),
+            ..RuntimeLock::empty()
+        }
+        .save()
+        .unwrap();
+
+        for name in repos {
+            dirty_repo(&env.repo(name));
+        }
+        config
+            .set_watch(
+                env.watched.path().to_str().unwrap().to_string(),
+                &Default::default(),
+            )
+            .unwrap();
+        config.save().unwrap();
+        env
+    }
+
+    #[tokio::test]
+    async fn config_loaded_once_per_loop() {
+        let _env = poller_env(Config::empty(), &["a", "b", "c"]).await;
+
+        let mut state = PollerState {
+            options: PollerOptions {
+                once: true,
+                ..Default::default()
+            },
+            // The change is too close to the commit for PollGuard to see it
+            force_capture: true,
+            ..Default::default()
+        };
+        let config_file = Config::default_path();
+        let loads = Config::load_count(&config_file);
+        do_task(&mut state).await;
+
+        assert_eq!(Config::load_count(&config_file) - loads, 1);
+        assert_eq!(state.last_snapshot.len(), 3);
+    }
+
+    /// Hangs on any repo named `slow`, like a capture stuck on a dead network mount
+    fn capture_or_hang(
+        path: &Path,
+        config: &CaptureConfig,
+    ) -> Result<Option<CaptureStatus>, git2::Error> {
+        if path.ends_with("slow") {
+            std::thread::sleep(Duration::from_secs(3));
+        }
+        snapshots::capture_with(path, config)
+    }
+
+    #[tokio::test]
+    async fn slow_repo_doesnt_hold_up_loop() {
+        let mut config = Config::empty();
+        config.per_repo_timeout_secs = 1;
+        let env = poller_env(config, &["fast", "slow"]).await;
+
+        let mut state = PollerState {
+            options: PollerOptions {
+                once: true,
+                capture: capture_or_hang,
+                ..Default::default()
+            },
+            force_capture: true,
+            ..Default::default()
+        };
+        let start = Instant::now();
+        do_task(&mut state).await;
+
+        assert!(start.elapsed() < Duration::from_millis(2500));
+        let snapshotted: Vec<_> = state.last_snapshot.keys().collect();
+        assert_eq!(snapshotted, vec![&env.repo("fast")]);
+        let timed_out = &state.timeouts[&env.repo("slow")];
+        assert_eq!(timed_out.count, 1);
+        assert!(timed_out.should_skip(Instant::now()));
+        let status = PollerStatus::load_or_default();
+        let saved: Vec<_> = status.timeouts.keys().collect();
+        assert_eq!(saved, vec![&env.repo("slow")]);
+        let saved = status.timeouts[&env.repo("slow")];
+        assert_eq!(saved.count, 1);
+        assert!(saved.retry_after > Utc::now());
+    }
+
+    /// Like a network mount that stopped answering
+    struct HangingSpace;
+
+    impl crate::disk_space::SpaceProvider for HangingSpace {
+        fn volume(&self, _path: &Path) -> std::io::Result<u64> {
+            std::thread::sleep(Duration::from_secs(3));
+            Ok(1)
+        }
+
+        fn available(&self, _path: &Path) -> std::io::Result<u64> {
+            Ok(u64::MAX)
+        }
+    }
+
+    #[tokio::test]
+    async fn hanging_free_space_check_times_out() {
+        let mut config = Config::empty();
+        config.per_repo_timeout_secs = 1;
+        config.min_free_space_mb = Some(1);
+        let env = poller_env(config, &["repo"]).await;
+
+        let mut state = PollerState {
+            options: PollerOptions {
+                once: true,
+                ..Default::default()
+            },
+            disk: DiskGuard::new(Box::new(HangingSpace)),
+            force_capture: true,
+            ..Default::default()
+        };
+        let start = Instant::now();
+        do_task(&mut state).await;
+
+        assert!(start.elapsed() < Duration::from_millis(2500));
+        assert!(state.last_snapshot.is_empty());
+        assert_eq!(state.timeouts[&env.repo("repo")].count, 1);
     }
 }
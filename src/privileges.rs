@@ -0,0 +1,145 @@
+//! Dura refuses to run as root (or as Administrator on Windows), since git objects it writes into
+//! a user's repo would then belong to root and break later git commands. Containers often run
+//! everything as root though, where that can't happen, so it can be allowed explicitly.
+use std::path::Path;
+
+/// Environment variable that does the same as the flag, e.g. for a container image
+pub const ALLOW_ENV: &str = "DURA_ALLOW_ROOT";
+
+/// The flag that lets dura run with elevated privileges anyway
+#[cfg(unix)]
+pub const ALLOW_FLAG: &str = "allow-root";
+#[cfg(windows)]
+pub const ALLOW_FLAG: &str = "allow-elevated";
+
+#[cfg(unix)]
+pub const REFUSAL: &str =
+    "Dura cannot be run as root, to avoid data corruption. Pass --allow-root (or set DURA_ALLOW_ROOT=1) if everything runs as root anyway, e.g. in a container";
+#[cfg(windows)]
+pub const REFUSAL: &str =
+    "Dura cannot be run as Administrator, to avoid data corruption. Pass --allow-elevated (or set DURA_ALLOW_ROOT=1) if that's really what you want";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Check {
+    /// Running as an ordinary user
+    User,
+    /// Privileged, but allowed by the flag or environment variable
+    Allowed,
+    Refused,
+}
+
+impl Check {
+    pub fn new(privileged: bool, flag: bool, env: Option<&str>) -> Self {
+        match (privileged, flag || env_allows(env)) {
+            (false, _) => Check::User,
+            (true, true) => Check::Allowed,
+            (true, false) => Check::Refused,
+        }
+    }
+
+    /// Checks the current process
+    pub fn current(flag: bool) -> Self {
+        Self::new(
+            is_privileged(),
+            flag,
+            std::env::var(ALLOW_ENV).ok().as_deref(),
+        )
+    }
+}
+
+fn env_allows(value: Option<&str>) -> bool {
+    matches!(
+        value.map(|v| v.trim().to_ascii_lowercase()).as_deref(),
+        Some("1" | "true" | "yes")
+    )
+}
+
+#[cfg(unix)]
+pub fn is_privileged() -> bool {
+    sudo::check() == sudo::RunningAs::Root
+}
+
+/// Files written from an elevated shell end up owned by Administrators, which causes the same
+/// permission trouble in repos as running as root does elsewhere.
+#[cfg(windows)]
+pub fn is_privileged() -> bool {
+    use windows_sys::Win32::Foundation::{CloseHandle, HANDLE};
+    use windows_sys::Win32::Security::{
+        GetTokenInformation, TokenElevation, TOKEN_ELEVATION, TOKEN_QUERY,
+    };
+    use windows_sys::Win32::System::Threading::{GetCurrentProcess, OpenProcessToken};
+
+    let mut token: HANDLE = std::ptr::null_mut();
+    // If the token can't be read, don't lock people out over it
+    if unsafe { OpenProcessToken(GetCurrentProcess(), TOKEN_QUERY, &mut token) } == 0 {
+        return false;
+    }
+    let mut elevation = TOKEN_ELEVATION::default();
+    let mut size = 0u32;
+    let ok = unsafe {
+        GetTokenInformation(
+            token,
+            TokenElevation,
+            &mut elevation as *mut TOKEN_ELEVATION as *mut std::ffi::c_void,
+            std::mem::size_of::<TOKEN_ELEVATION>() as u32,
+            &mut size,
+        )
+    };
+    unsafe { CloseHandle(token) };
+    ok != 0 && elevation.TokenIsElevated != 0
+}
+
+/// Warns (once per repo) when the repo's objects belong to someone other than this process. New
+/// snapshot objects would belong to this process, and that mix is what breaks git for the owner.
+#[cfg(unix)]
+pub fn warn_if_foreign_owner(git_dir: &Path) {
+    use std::collections::BTreeSet;
+    use std::os::unix::fs::MetadataExt;
+    use std::path::PathBuf;
+    use std::sync::Mutex;
+
+    use tracing::warn;
+
+    use crate::redact;
+
+    /// Repos we've already warned about, so it's only logged once per process
+    static FOREIGN_REPOS_SEEN: Mutex<BTreeSet<PathBuf>> = Mutex::new(BTreeSet::new());
+
+    let owner = match std::fs::metadata(git_dir.join("objects")) {
+        Ok(metadata) => metadata.uid(),
+        Err(_) => return,
+    };
+    let uid = unsafe { libc::geteuid() };
+    if owner == uid {
+        return;
+    }
+    let mut seen = FOREIGN_REPOS_SEEN.lock().unwrap_or_else(|e| e.into_inner());
+    if seen.insert(git_dir.to_path_buf()) {
+        warn!(
+            repo = redact::path(git_dir).as_str(),
+            owner_uid = owner,
+            dura_uid = uid,
+            "Repo belongs to another user, snapshot objects will be owned by dura's user"
+        );
+    }
+}
+
+#[cfg(windows)]
+pub fn warn_if_foreign_owner(_git_dir: &Path) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flag_and_env_combinations() {
+        assert_eq!(Check::new(false, false, None), Check::User);
+        assert_eq!(Check::new(false, true, Some("1")), Check::User);
+        assert_eq!(Check::new(true, false, None), Check::Refused);
+        assert_eq!(Check::new(true, true, None), Check::Allowed);
+        assert_eq!(Check::new(true, false, Some("1")), Check::Allowed);
+        assert_eq!(Check::new(true, false, Some("TRUE")), Check::Allowed);
+        assert_eq!(Check::new(true, false, Some("0")), Check::Refused);
+        assert_eq!(Check::new(true, false, Some("")), Check::Refused);
+    }
+}
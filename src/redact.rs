@@ -0,0 +1,95 @@
+//! Keeps repo paths out of logs that are going to be shared. Paths often contain usernames and
+//! client names, so `log_redact_paths` can swap them for a stable hash or just the last component.
+//!
+//! The mode is process-wide, because paths are logged from deep inside the poller and snapshot
+//! code. The poller sets it from the config on every loop.
+use std::path::Path;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RedactPaths {
+    #[default]
+    Off,
+    /// `repo-` followed by a short hash of the whole path, see `hash`
+    Hash,
+    /// Only the last component, e.g. `dura` for `/home/me/code/dura`
+    Basename,
+}
+
+static MODE: AtomicU8 = AtomicU8::new(0);
+
+impl RedactPaths {
+    pub fn apply(&self, path: &Path) -> String {
+        match self {
+            RedactPaths::Off => path.to_str().unwrap_or("<invalid path>").to_string(),
+            RedactPaths::Hash => hash(path),
+            RedactPaths::Basename => path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .unwrap_or("<invalid path>")
+                .to_string(),
+        }
+    }
+
+    /// Makes this the mode used by `path` and `message`
+    pub fn set_global(self) {
+        let value = match self {
+            RedactPaths::Off => 0,
+            RedactPaths::Hash => 1,
+            RedactPaths::Basename => 2,
+        };
+        MODE.store(value, Ordering::Relaxed);
+    }
+
+    pub fn global() -> Self {
+        match MODE.load(Ordering::Relaxed) {
+            1 => RedactPaths::Hash,
+            2 => RedactPaths::Basename,
+            _ => RedactPaths::Off,
+        }
+    }
+}
+
+/// `path` the way it should appear in logs
+pub fn path(path: &Path) -> String {
+    RedactPaths::global().apply(path)
+}
+
+/// `message` with any mention of `path` redacted, e.g. for git errors that name the repo
+pub fn message(message: &str, path: &Path) -> String {
+    match (RedactPaths::global(), path.to_str()) {
+        (RedactPaths::Off, _) | (_, None) => message.to_string(),
+        (mode, Some(raw)) => message.replace(raw, &mode.apply(path)),
+    }
+}
+
+/// Stable across runs and machines (FNV-1a of the path), so redacted logs can still be grouped
+/// by repo and looked up with `dura redact-map`.
+pub fn hash(path: &Path) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in path.to_string_lossy().bytes() {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    format!("repo-{:012x}", hash >> 16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn modes() {
+        let path = Path::new("/home/alice/clients/acme");
+        assert_eq!(RedactPaths::Off.apply(path), "/home/alice/clients/acme");
+        assert_eq!(RedactPaths::Basename.apply(path), "acme");
+        let hashed = RedactPaths::Hash.apply(path);
+        assert!(hashed.starts_with("repo-"));
+        assert_eq!(hashed.len(), "repo-".len() + 12);
+        assert_eq!(hashed, hash(Path::new("/home/alice/clients/acme")));
+        assert_ne!(hashed, hash(Path::new("/home/alice/clients/other")));
+    }
+}
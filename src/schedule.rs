@@ -0,0 +1,180 @@
+//! Quiet hours: windows of local time during which the poller doesn't capture anything.
+//!
+//! Windows are written as `"HH:MM-HH:MM"` in local wall-clock time, e.g. `"22:00-06:00"`. A window
+//! whose end is before its start wraps around midnight. The start is inclusive and the end is
+//! exclusive.
+//!
+//! This is deliberately DST-naive. Only the time of day is compared, so on the night clocks change
+//! a window like `"01:00-03:00"` lasts one hour shorter or longer in real time, and times that
+//! don't exist (or happen twice) are treated like any other wall-clock time.
+use std::fmt;
+
+use chrono::NaiveTime;
+use serde::{Deserialize, Serialize};
+
+const TIME_FORMAT: &str = "%H:%M";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct QuietWindow {
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl QuietWindow {
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            // crosses midnight
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+impl TryFrom<String> for QuietWindow {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let (start, end) = value
+            .split_once('-')
+            .ok_or_else(|| format!("Quiet window '{value}' should look like 22:00-06:00"))?;
+        let parse = |s: &str| {
+            NaiveTime::parse_from_str(s.trim(), TIME_FORMAT)
+                .map_err(|e| format!("Invalid time '{s}' in quiet window '{value}': {e}"))
+        };
+        let window = Self {
+            start: parse(start)?,
+            end: parse(end)?,
+        };
+        if window.start == window.end {
+            return Err(format!("Quiet window '{value}' has no duration"));
+        }
+        Ok(window)
+    }
+}
+
+impl From<QuietWindow> for String {
+    fn from(value: QuietWindow) -> Self {
+        value.to_string()
+    }
+}
+
+impl fmt::Display for QuietWindow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}-{}",
+            self.start.format(TIME_FORMAT),
+            self.end.format(TIME_FORMAT)
+        )
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Schedule {
+    #[serde(default)]
+    pub quiet: Vec<QuietWindow>,
+}
+
+impl Schedule {
+    pub fn is_quiet(&self, time: NaiveTime) -> bool {
+        self.quiet.iter().any(|w| w.contains(time))
+    }
+
+    /// If `time` falls in quiet hours, when do they end? Overlapping and back-to-back windows are
+    /// treated as a single quiet period.
+    pub fn quiet_until(&self, time: NaiveTime) -> Option<NaiveTime> {
+        let mut until = self.quiet.iter().find(|w| w.contains(time))?.end;
+        // Each pass can only hop to another window, so this terminates even if the windows
+        // cover the whole day.
+        for _ in 0..self.quiet.len() {
+            match self.quiet.iter().find(|w| w.contains(until)) {
+                Some(w) if w.end != until => until = w.end,
+                _ => break,
+            }
+        }
+        Some(until)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn t(s: &str) -> NaiveTime {
+        NaiveTime::parse_from_str(s, TIME_FORMAT).unwrap()
+    }
+
+    fn schedule(windows: &[&str]) -> Schedule {
+        Schedule {
+            quiet: windows
+                .iter()
+                .map(|w| QuietWindow::try_from(w.to_string()).unwrap())
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn parse_round_trip() {
+        let window = QuietWindow::try_from("22:00-06:00".to_string()).unwrap();
+        assert_eq!(window.to_string(), "22:00-06:00");
+    }
+
+    #[test]
+    fn parse_rejects_garbage() {
+        assert!(QuietWindow::try_from("22:00".to_string()).is_err());
+        assert!(QuietWindow::try_from("25:00-06:00".to_string()).is_err());
+        assert!(QuietWindow::try_from("noon-1pm".to_string()).is_err());
+        assert!(QuietWindow::try_from("06:00-06:00".to_string()).is_err());
+    }
+
+    #[test]
+    fn same_day_window() {
+        let s = schedule(&["12:00-13:00"]);
+        assert!(!s.is_quiet(t("11:59")));
+        assert!(s.is_quiet(t("12:00")));
+        assert!(s.is_quiet(t("12:59")));
+        assert!(!s.is_quiet(t("13:00")));
+    }
+
+    #[test]
+    fn crossing_midnight() {
+        let s = schedule(&["22:00-06:00"]);
+        assert!(s.is_quiet(t("23:30")));
+        assert!(s.is_quiet(t("00:00")));
+        assert!(s.is_quiet(t("05:59")));
+        assert!(!s.is_quiet(t("06:00")));
+        assert!(!s.is_quiet(t("21:59")));
+        assert_eq!(s.quiet_until(t("23:30")), Some(t("06:00")));
+    }
+
+    #[test]
+    fn overlapping_windows_merge() {
+        let s = schedule(&["22:00-02:00", "01:00-06:00", "12:00-13:00"]);
+        assert_eq!(s.quiet_until(t("23:00")), Some(t("06:00")));
+        assert_eq!(s.quiet_until(t("03:00")), Some(t("06:00")));
+        assert_eq!(s.quiet_until(t("12:30")), Some(t("13:00")));
+        assert_eq!(s.quiet_until(t("10:00")), None);
+    }
+
+    #[test]
+    fn adjacent_windows_merge() {
+        let s = schedule(&["12:00-13:00", "13:00-14:00"]);
+        assert_eq!(s.quiet_until(t("12:15")), Some(t("14:00")));
+    }
+
+    #[test]
+    fn whole_day_terminates() {
+        let s = schedule(&["00:00-12:00", "12:00-00:00"]);
+        assert!(s.is_quiet(t("18:00")));
+        assert!(s.quiet_until(t("18:00")).is_some());
+    }
+
+    #[test]
+    fn empty_schedule_is_never_quiet() {
+        let s = Schedule::default();
+        assert!(!s.is_quiet(t("03:00")));
+        assert_eq!(s.quiet_until(t("03:00")), None);
+    }
+}
@@ -0,0 +1,117 @@
+//! How much of a repo's object database only exists because of dura.
+//!
+//! Objects are counted when they're reachable from snapshot refs (and pins) but not from the
+//! user's own refs. Like `git rev-list --objects dura --not <user refs>`, "not reachable" is
+//! judged against the trees of the commits snapshots hang off, rather than the user's entire
+//! history, so content that was later reverted in a real commit is still counted.
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+use git2::{Error, ObjectType, Oid, TreeWalkMode, TreeWalkResult};
+
+use crate::snapshots;
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SizeReport {
+    /// Uncompressed bytes of the blobs and trees only dura refers to
+    pub total_bytes: u64,
+    pub objects: usize,
+    pub commits: usize,
+    /// Paths by cumulative size of their dura-only versions, largest first
+    pub files: Vec<(String, u64)>,
+}
+
+pub fn dura_size(path: &Path) -> Result<SizeReport, Error> {
+    dura_size_without(path, &HashSet::new())
+}
+
+/// The size as if the refs named in `excluded` were already deleted
+pub fn dura_size_without(path: &Path, excluded: &HashSet<String>) -> Result<SizeReport, Error> {
+    let repo = snapshots::open_repo(path)?;
+    let odb = repo.odb()?;
+
+    let mut walk = repo.revwalk()?;
+    if let Ok(head) = repo.head() {
+        if let Some(oid) = head.target() {
+            walk.hide(oid)?;
+        }
+    }
+    for reference in repo.references()? {
+        let reference = reference?;
+        let target = match reference.peel_to_commit() {
+            Ok(commit) => commit.id(),
+            Err(_) => continue,
+        };
+        match reference.name() {
+            Some(name) if excluded.contains(name) => (),
+            Some(name) if snapshots::is_snapshot_ref(name) => walk.push(target)?,
+            _ => walk.hide(target)?,
+        }
+    }
+    let dura_commits = walk.collect::<Result<HashSet<Oid>, Error>>()?;
+
+    // Everything in the trees that snapshots were taken on top of belongs to the user
+    let mut uninteresting = HashSet::new();
+    for oid in &dura_commits {
+        for parent in repo.find_commit(*oid)?.parents() {
+            if !dura_commits.contains(&parent.id()) {
+                let tree = parent.tree()?;
+                uninteresting.insert(tree.id());
+                tree.walk(TreeWalkMode::PreOrder, |_, entry| {
+                    uninteresting.insert(entry.id());
+                    TreeWalkResult::Ok
+                })?;
+            }
+        }
+    }
+
+    let mut report = SizeReport {
+        commits: dura_commits.len(),
+        ..Default::default()
+    };
+    let mut seen = HashSet::new();
+    let mut files: HashMap<String, u64> = HashMap::new();
+    let mut count = |oid: Oid| -> Result<u64, Error> {
+        let (size, _) = odb.read_header(oid)?;
+        report.objects += 1;
+        report.total_bytes += size as u64;
+        Ok(size as u64)
+    };
+    for oid in &dura_commits {
+        let tree = repo.find_commit(*oid)?.tree()?;
+        if uninteresting.contains(&tree.id()) || !seen.insert(tree.id()) {
+            continue;
+        }
+        count(tree.id())?;
+        let mut error = None;
+        tree.walk(TreeWalkMode::PreOrder, |root, entry| {
+            // Nothing below an already counted (or user owned) tree needs looking at
+            if uninteresting.contains(&entry.id()) || !seen.insert(entry.id()) {
+                return TreeWalkResult::Skip;
+            }
+            match count(entry.id()) {
+                Ok(size) if entry.kind() == Some(ObjectType::Blob) => {
+                    let name = format!("{root}{}", entry.name().unwrap_or("<invalid path>"));
+                    *files.entry(name).or_default() += size;
+                }
+                Ok(_) => (),
+                // e.g. a gitlink to a submodule commit that isn't in this repo
+                Err(e) if e.code() == git2::ErrorCode::NotFound => (),
+                Err(e) => {
+                    error = Some(e);
+                    return TreeWalkResult::Abort;
+                }
+            }
+            TreeWalkResult::Ok
+        })?;
+        if let Some(e) = error {
+            return Err(e);
+        }
+    }
+
+    report.files = files.into_iter().collect();
+    report
+        .files
+        .sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    Ok(report)
+}
@@ -1,124 +1,1646 @@
-use git2::{BranchType, DiffOptions, Error, IndexAddOption, Repository, Signature};
+use chrono::{DateTime, FixedOffset, SecondsFormat, TimeZone, Utc};
+use git2::{
+    AttrCheckFlags, Commit, DiffOptions, Error, Index, IndexAddOption, IndexEntry,
+    IndexEntryExtendedFlag, IndexTime, Oid, Reference, Repository, RepositoryOpenFlags, Revwalk,
+    Signature, Sort, StatusOptions,
+};
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashSet};
+use std::ffi::OsStr;
 use std::fmt;
-use std::path::Path;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
 
 use crate::config::Config;
+use crate::effective_config::{EffectiveConfig, Layers};
+use crate::privileges;
+use crate::redact;
 
-#[derive(Debug, Serialize, Deserialize, Eq, PartialEq)]
+/// File mode of a submodule entry in the index
+const GITLINK_MODE: u32 = 0o160000;
+
+/// Nested repos we've already told the user about, so it's only logged once per process
+static NESTED_REPOS_SEEN: Mutex<BTreeSet<PathBuf>> = Mutex::new(BTreeSet::new());
+
+/// Same for files left out because they were reached through a symlink leading out of the repo
+static EXTERNAL_SYMLINKS_SEEN: Mutex<BTreeSet<PathBuf>> = Mutex::new(BTreeSet::new());
+
+/// And repos whose snapshots fell back to dura's own name or email
+static FALLBACK_IDENTITY_SEEN: Mutex<BTreeSet<PathBuf>> = Mutex::new(BTreeSet::new());
+
+/// Mask and value of `IndexEntry::mode` for regular files, executable or not
+const FILE_TYPE_MASK: u32 = 0o170000;
+const REGULAR_FILE_TYPE: u32 = 0o100000;
+
+/// Where snapshot refs live. `Heads` makes them ordinary branches (`refs/heads/dura/<sha>`),
+/// which is what dura has always done. `Dura` keeps them in `refs/dura/<sha>` so they don't show
+/// up in `git branch` and branch pickers.
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RefNamespace {
+    #[default]
+    Heads,
+    Dura,
+}
+
+impl RefNamespace {
+    pub fn prefix(&self) -> &'static str {
+        match self {
+            RefNamespace::Heads => "refs/heads/dura/",
+            RefNamespace::Dura => "refs/dura/",
+        }
+    }
+
+    /// Full ref name of the snapshot ref for commits on top of `base`
+    pub fn snapshot_ref(&self, base: Oid) -> String {
+        format!("{}{}", self.prefix(), base)
+    }
+
+    /// What `CaptureStatus::dura_branch` calls the snapshot ref for `base`: short for a branch,
+    /// in full otherwise, so git resolves it to the same ref either way
+    pub fn snapshot_branch(&self, base: Oid) -> String {
+        match self {
+            RefNamespace::Heads => format!("dura/{base}"),
+            RefNamespace::Dura => self.snapshot_ref(base),
+        }
+    }
+
+    /// Full ref name of the ref that keeps the stash commit `stash` around
+    pub fn stash_ref(&self, stash: Oid) -> String {
+        format!("{}{STASH_DIR}{stash}", self.prefix())
+    }
+
+    fn other(&self) -> Self {
+        match self {
+            RefNamespace::Heads => RefNamespace::Dura,
+            RefNamespace::Dura => RefNamespace::Heads,
+        }
+    }
+}
+
+/// Stash refs go under this in either namespace, see [`keep_stashes`]
+const STASH_DIR: &str = "stash/";
+
+/// Whether `name` is a ref made by [`keep_stashes`]
+pub fn is_stash_ref(name: &str) -> bool {
+    [RefNamespace::Heads, RefNamespace::Dura]
+        .iter()
+        .any(|namespace| name.starts_with(&format!("{}{STASH_DIR}", namespace.prefix())))
+}
+
+/// What to do with files tracked by Git LFS. libgit2 doesn't run LFS's filter, so by default
+/// (`Full`) snapshots contain the whole file, unlike real commits which contain a pointer.
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LfsMode {
+    /// Store the LFS pointer, like `git add` does. Needs git-lfs installed.
+    Pointer,
+    /// Leave LFS files as they are in the index
+    Skip,
+    #[default]
+    Full,
+}
+
+/// Whether snapshots pick up files git doesn't track yet, see `WatchConfig::snapshot_untracked`
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SnapshotUntracked {
+    #[default]
+    Always,
+    /// Only tracked files are snapshotted
+    Never,
+    /// Untracked files go along with changes to tracked ones, but don't trigger a snapshot alone
+    OnlyWithTrackedChanges,
+}
+
+/// Finds the snapshot ref for `base`, looking in `namespace` first and then the other one, so
+/// snapshots aren't lost track of while refs are being migrated.
+pub fn find_snapshot_ref(
+    repo: &Repository,
+    namespace: RefNamespace,
+    base: Oid,
+) -> Option<Reference<'_>> {
+    repo.find_reference(&namespace.snapshot_ref(base))
+        .or_else(|_| repo.find_reference(&namespace.other().snapshot_ref(base)))
+        .ok()
+}
+
+/// Moves every snapshot ref in the repo at `path` into `to`. Only refs named after a commit hash
+/// are moved, so a user's own branch that happens to be called `dura/something` is left alone.
+/// Returns how many refs were moved.
+pub fn migrate_refs(path: &Path, to: RefNamespace) -> Result<usize, Error> {
+    let repo = open_repo(path)?;
+    let from = to.other();
+    let mut moved = 0;
+    for reference in repo.references_glob(&format!("{}*", from.prefix()))? {
+        let mut reference = reference?;
+        let base = match reference
+            .name()
+            .and_then(|name| name.strip_prefix(from.prefix()))
+            .and_then(|sha| Oid::from_str(sha).ok())
+        {
+            Some(base) => base,
+            None => continue,
+        };
+        let target = to.snapshot_ref(base);
+        if repo.find_reference(&target).is_ok() {
+            // Both exist, most likely because dura ran with the new setting before migrating.
+            // Keep whichever one is there already rather than throwing snapshots away.
+            info!(
+                repo = redact::path(path).as_str(),
+                from = reference.name().unwrap_or_default(),
+                to = target.as_str(),
+                "Not migrating snapshot ref, target already exists"
+            );
+            continue;
+        }
+        reference.rename(&target, false, "dura: migrate snapshot ref")?;
+        moved += 1;
+    }
+    Ok(moved)
+}
+
+/// Commit message of every snapshot
+pub const SNAPSHOT_MESSAGE: &str = "dura auto-backup";
+
+/// Message of snapshots taken with `dura capture --force`. The first line is the same, so they
+/// count as snapshots everywhere else.
+pub const FORCED_SNAPSHOT_MESSAGE: &str =
+    "dura auto-backup\n\nForced with `dura capture --force`, may not change anything\n";
+
+/// Whether `commit` is a snapshot rather than one of the user's commits
+pub fn is_snapshot(commit: &git2::Commit) -> bool {
+    commit.summary() == Some(SNAPSHOT_MESSAGE)
+}
+
+/// Pins are lightweight tags. Snapshots they point at are never cleaned up.
+pub const PIN_PREFIX: &str = "refs/tags/dura/pin/";
+
+/// Whether `name` is a ref that snapshots sit on: a dura branch in either namespace, or a pin
+pub fn is_snapshot_ref(name: &str) -> bool {
+    [
+        RefNamespace::Heads.prefix(),
+        RefNamespace::Dura.prefix(),
+        PIN_PREFIX,
+    ]
+    .iter()
+    .any(|prefix| name.starts_with(prefix))
+}
+
+/// Every snapshot in a repo, newest first, see [`snapshot_walk`]
+pub struct SnapshotWalk<'r> {
+    repo: &'r Repository,
+    walk: Revwalk<'r>,
+    /// Snapshot refs that don't point at a commit, with their target and why
+    pub broken: Vec<(String, Option<Oid>, Error)>,
+}
+
+impl<'r> Iterator for SnapshotWalk<'r> {
+    /// A commit that can't be read comes with its id and why
+    type Item = Result<(Oid, Result<Commit<'r>, Error>), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for oid in self.walk.by_ref() {
+            let oid = match oid {
+                Ok(oid) => oid,
+                Err(e) => return Some(Err(e)),
+            };
+            match self.repo.find_commit(oid) {
+                // Base commits whose branch has since moved on aren't hidden by the walk
+                Ok(commit) if !is_snapshot(&commit) => continue,
+                commit => return Some(Ok((oid, commit))),
+            }
+        }
+        None
+    }
+}
+
+/// Walks the commits on snapshot refs (see [`is_snapshot_ref`]) in `repo`, skipping the user's
+/// own commits
+pub fn snapshot_walk(repo: &Repository) -> Result<SnapshotWalk<'_>, Error> {
+    let mut walk = repo.revwalk()?;
+    walk.set_sorting(Sort::TOPOLOGICAL | Sort::TIME)?;
+    let mut broken = vec![];
+    for reference in repo.references()? {
+        let reference = reference?;
+        let name = reference.name().unwrap_or_default().to_string();
+        match (reference.peel_to_commit(), is_snapshot_ref(&name)) {
+            (Ok(commit), true) => walk.push(commit.id())?,
+            // Snapshots sit on top of these, so this keeps the walk to snapshots
+            (Ok(commit), false) => walk.hide(commit.id())?,
+            (Err(e), true) => broken.push((name, reference.target(), e)),
+            (Err(_), false) => (),
+        }
+    }
+    Ok(SnapshotWalk { repo, walk, broken })
+}
+
+/// Pins the commit that `target` (a commit hash, branch or anything else `git rev-parse`
+/// understands) resolves to as `name`. Fails if a pin called `name` already exists.
+pub fn pin(path: &Path, target: &str, name: &str) -> Result<Oid, Error> {
+    let repo = open_repo(path)?;
+    let commit = repo.revparse_single(target)?.peel_to_commit()?;
+    repo.reference(
+        &format!("{PIN_PREFIX}{name}"),
+        commit.id(),
+        false,
+        "dura: pin snapshot",
+    )?;
+    Ok(commit.id())
+}
+
+pub fn unpin(path: &Path, name: &str) -> Result<(), Error> {
+    let repo = open_repo(path)?;
+    let mut reference = repo.find_reference(&format!("{PIN_PREFIX}{name}"))?;
+    reference.delete()
+}
+
+/// Every pin in the repo, by name
+pub fn list_pins(path: &Path) -> Result<Vec<(String, Oid)>, Error> {
+    let repo = open_repo(path)?;
+    let mut pins = vec![];
+    for reference in repo.references_glob(&format!("{PIN_PREFIX}*"))? {
+        let reference = reference?;
+        let name = reference.name().and_then(|n| n.strip_prefix(PIN_PREFIX));
+        if let (Some(name), Some(oid)) = (name, reference.target()) {
+            pins.push((name.to_string(), oid));
+        }
+    }
+    Ok(pins)
+}
+
+pub(crate) fn pinned_commits(repo: &Repository) -> Result<HashSet<Oid>, Error> {
+    let mut pinned = HashSet::new();
+    for reference in repo.references_glob(&format!("{PIN_PREFIX}*"))? {
+        if let Ok(commit) = reference?.peel_to_commit() {
+            pinned.insert(commit.id());
+        }
+    }
+    Ok(pinned)
+}
+
+/// How many commits on user branches to compare against each snapshot before giving up. Only
+/// commits made since the snapshot's base are looked at, so this is rarely reached.
+const MERGED_WALK_LIMIT: usize = 1000;
+
+/// A snapshot ref whose tree is exactly the tree of a commit on a user branch
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MergedSnapshot {
+    pub reference: String,
+    pub snapshot: Oid,
+    /// The user's commit with the same tree
+    pub matched: Oid,
+}
+
+/// Deletes snapshot refs (in either namespace) that are made redundant by a real commit, i.e. a
+/// commit reachable from a branch or HEAD has exactly the same tree as the snapshot. Snapshots
+/// that only partially made it into a commit are kept, and so are ones whose tip is pinned or
+/// isn't a snapshot.
+/// Returns the refs that were deleted.
+pub fn clean_merged(path: &Path) -> Result<Vec<MergedSnapshot>, Error> {
+    let repo = open_repo(path)?;
+    let merged = find_merged(&repo)?;
+    for snapshot in &merged {
+        repo.find_reference(&snapshot.reference)?.delete()?;
+    }
+    Ok(merged)
+}
+
+/// The refs [`clean_merged`] would delete, without deleting them
+pub fn merged_snapshots(path: &Path) -> Result<Vec<MergedSnapshot>, Error> {
+    find_merged(&open_repo(path)?)
+}
+
+fn find_merged(repo: &Repository) -> Result<Vec<MergedSnapshot>, Error> {
+    let mut user_tips = vec![];
+    if let Ok(head) = repo.head() {
+        if let Some(oid) = head.target() {
+            user_tips.push(oid);
+        }
+    }
+    for reference in repo.references_glob("refs/heads/*")? {
+        let reference = reference?;
+        let is_snapshot = reference
+            .name()
+            .map(|name| name.starts_with(RefNamespace::Heads.prefix()))
+            .unwrap_or(true);
+        if let (false, Some(oid)) = (is_snapshot, reference.target()) {
+            user_tips.push(oid);
+        }
+    }
+
+    let pinned = pinned_commits(repo)?;
+    let mut merged = vec![];
+    for namespace in [RefNamespace::Heads, RefNamespace::Dura] {
+        for reference in repo.references_glob(&format!("{}*", namespace.prefix()))? {
+            let reference = reference?;
+            let name = reference.name().unwrap_or_default().to_string();
+            let base = match name
+                .strip_prefix(namespace.prefix())
+                .and_then(|sha| Oid::from_str(sha).ok())
+                .and_then(|sha| repo.find_commit(sha).ok())
+            {
+                Some(base) => base,
+                None => continue,
+            };
+            // Points at a missing object, nothing to compare
+            let snapshot = match reference.peel_to_commit() {
+                Ok(snapshot) => snapshot,
+                Err(_) => continue,
+            };
+            // Somebody committed on top of the ref by hand, so it's theirs now
+            if pinned.contains(&snapshot.id()) || !is_snapshot(&snapshot) {
+                continue;
+            }
+            let tree = snapshot.tree_id();
+
+            let mut walk = repo.revwalk()?;
+            for tip in &user_tips {
+                walk.push(*tip)?;
+            }
+            for parent in base.parent_ids() {
+                walk.hide(parent)?;
+            }
+            let matched = walk
+                .take(MERGED_WALK_LIMIT)
+                .filter_map(|oid| oid.ok())
+                .find(|oid| {
+                    repo.find_commit(*oid)
+                        .map(|c| c.tree_id() == tree)
+                        .unwrap_or(false)
+                });
+            if let Some(matched) = matched {
+                merged.push(MergedSnapshot {
+                    reference: name,
+                    snapshot: snapshot.id(),
+                    matched,
+                });
+            }
+        }
+    }
+    Ok(merged)
+}
+
+/// Points a ref at each stash that no dura ref reaches yet, so it's still there after
+/// `git stash drop`. Returns the refs created.
+pub fn keep_stashes(repo: &mut Repository, namespace: RefNamespace) -> Result<Vec<String>, Error> {
+    let mut stashes = vec![];
+    repo.stash_foreach(|_, _, oid| {
+        stashes.push(*oid);
+        true
+    })?;
+    if stashes.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let mut tips = vec![];
+    for prefix in [
+        RefNamespace::Heads.prefix(),
+        RefNamespace::Dura.prefix(),
+        PIN_PREFIX,
+    ] {
+        for reference in repo.references_glob(&format!("{prefix}*"))? {
+            if let Some(oid) = reference?.target() {
+                tips.push(oid);
+            }
+        }
+    }
+    let mut kept = vec![];
+    for stash in stashes {
+        let reachable = tips
+            .iter()
+            .any(|&tip| tip == stash || repo.graph_descendant_of(tip, stash).unwrap_or(false));
+        if reachable {
+            continue;
+        }
+        let name = namespace.stash_ref(stash);
+        repo.reference(&name, stash, false, "dura: keep stash")?;
+        tips.push(stash);
+        kept.push(name);
+    }
+    Ok(kept)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct CaptureStatus {
     pub dura_branch: String,
     pub commit_hash: String,
     pub base_hash: String,
+    /// Missing from logs written by older versions
+    #[serde(default)]
+    pub phases: CapturePhases,
+    /// Size of the change since the previous snapshot (or the base commit, for the first one).
+    /// Older logs don't have these.
+    #[serde(default)]
+    pub files_changed: usize,
+    #[serde(default)]
+    pub insertions: usize,
+    #[serde(default)]
+    pub deletions: usize,
+    /// When the snapshot was committed. The Unix epoch in entries from older logs.
+    #[serde(default)]
+    pub timestamp: DateTime<Utc>,
+    /// Who the snapshot was committed as. Missing from logs written by older versions.
+    #[serde(default)]
+    pub identity: Option<Identity>,
+    /// The commit's committer time as git recorded it, with the local offset. Whole seconds, so
+    /// it can be up to a second behind `timestamp`. Missing from dry runs and older logs.
+    #[serde(default)]
+    pub commit_time: Option<DateTime<FixedOffset>>,
+    /// Untracked sensitive files (see `Config::sensitive_files`) left out of the snapshot,
+    /// relative to the repo
+    #[serde(default)]
+    pub withheld: Vec<String>,
+    /// How many times writing a ref was retried because something else had it locked
+    #[serde(default)]
+    pub lock_retries: u32,
+    /// Of the files changed, how many git already tracks and how many are new untracked files.
+    /// Older logs don't have these.
+    #[serde(default)]
+    pub tracked_changes: usize,
+    #[serde(default)]
+    pub untracked_changes: usize,
+}
+
+/// Where the time went during a capture, in milliseconds
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
+pub struct CapturePhases {
+    pub statuses_ms: f32,
+    pub index_add_ms: f32,
+    pub diff_ms: f32,
+    pub tree_write_ms: f32,
+    pub commit_ms: f32,
+}
+
+/// Milliseconds since `start`, then resets `start` so phases can be timed back-to-back
+fn lap(start: &mut Instant) -> f32 {
+    let now = Instant::now();
+    let ms = (now - *start).as_secs_f32() * 1000.0;
+    *start = now;
+    ms
 }
 
 impl fmt::Display for CaptureStatus {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
-            "dura: {}, commit_hash: {}, base: {}",
-            self.dura_branch, self.commit_hash, self.base_hash
+            "dura: {}, commit_hash: {}, base: {}, files_changed: {} (+{} -{}), time: {}",
+            self.dura_branch,
+            self.commit_hash,
+            self.base_hash,
+            self.files_changed,
+            self.insertions,
+            self.deletions,
+            self.timestamp.to_rfc3339_opts(SecondsFormat::Secs, true)
         )
     }
 }
 
+/// Variables git uses to find a repo somewhere other than the directory it's run in. Tools often
+/// set them for the processes they start, but dura works on the repos it was pointed at.
+const GIT_LOCATION_VARS: &[&str] = &[
+    "GIT_DIR",
+    "GIT_WORK_TREE",
+    "GIT_INDEX_FILE",
+    "GIT_COMMON_DIR",
+    "GIT_OBJECT_DIRECTORY",
+    "GIT_ALTERNATE_OBJECT_DIRECTORIES",
+    "GIT_NAMESPACE",
+];
+
+/// Opens the repo at `path` and nowhere else. Parent directories aren't searched and GIT_DIR and
+/// the like are ignored, but a `.git` file is followed, e.g. for `git init --separate-git-dir`
+/// or linked worktrees.
+pub fn open_repo(path: &Path) -> Result<Repository, Error> {
+    Repository::open_ext(
+        path,
+        RepositoryOpenFlags::NO_SEARCH,
+        std::iter::empty::<&OsStr>(),
+    )
+}
+
+/// `git`, without any of `GIT_LOCATION_VARS` dura may have inherited
+pub fn git_command() -> Command {
+    let mut command = Command::new("git");
+    for var in GIT_LOCATION_VARS {
+        command.env_remove(var);
+    }
+    command
+}
+
 pub fn is_repo(path: &Path) -> bool {
-    Repository::open(path).is_ok()
+    open_repo(path).is_ok()
+}
+
+/// The parts of `Config` that a capture needs. Resolved once per loop by the poller, rather than
+/// loading the config for every repo.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CaptureConfig {
+    pub commit_author: Option<String>,
+    pub commit_email: Option<String>,
+    pub commit_exclude_git_config: bool,
+    pub ref_namespace: RefNamespace,
+    pub lfs_mode: LfsMode,
+    pub forbid_external_symlink_content: bool,
+    pub require_identity: bool,
+    pub snapshot_sensitive_files: bool,
+    pub sensitive_files: Vec<String>,
+    /// From the watch the repo is under, see `WatchConfig::skip_on_branches`
+    pub skip_on_branches: Vec<String>,
+    /// From the watch the repo is under, see `WatchConfig::snapshot_stashes`
+    pub snapshot_stashes: bool,
+    /// From the watch the repo is under, see `WatchConfig::snapshot_untracked`
+    pub snapshot_untracked: SnapshotUntracked,
+}
+
+impl Default for CaptureConfig {
+    fn default() -> Self {
+        Self::from(&Config::empty())
+    }
+}
+
+/// Without any watch's settings
+impl From<&Config> for CaptureConfig {
+    fn from(config: &Config) -> Self {
+        Self::from(&EffectiveConfig::resolve(&Layers::new(config, None)))
+    }
+}
+
+impl From<&EffectiveConfig> for CaptureConfig {
+    fn from(effective: &EffectiveConfig) -> Self {
+        Self {
+            commit_author: effective.commit_author.value.clone(),
+            commit_email: effective.commit_email.value.clone(),
+            commit_exclude_git_config: effective.commit_exclude_git_config.value,
+            ref_namespace: effective.ref_namespace.value,
+            lfs_mode: effective.lfs_mode.value,
+            forbid_external_symlink_content: effective.forbid_external_symlink_content.value,
+            require_identity: effective.require_identity.value,
+            snapshot_sensitive_files: effective.snapshot_sensitive_files.value,
+            sensitive_files: effective.sensitive_files.value.clone(),
+            skip_on_branches: effective.skip_on_branches.value.clone(),
+            snapshot_stashes: effective.snapshot_stashes.value,
+            snapshot_untracked: effective.snapshot_untracked.value,
+        }
+    }
+}
+
+impl CaptureConfig {
+    /// With the settings of the watch that `repo` falls under applied
+    pub fn for_repo(config: &Config, repo: &Path) -> Self {
+        Self::from(&EffectiveConfig::resolve(&Layers::for_repo(config, repo)))
+    }
 }
 
+/// Loads the config and captures the repo at `path`. For one-off captures, e.g. from the CLI.
 pub fn capture(path: &Path) -> Result<Option<CaptureStatus>, Error> {
-    let repo = Repository::open(path)?;
+    capture_with(
+        path,
+        &CaptureConfig::for_repo(&Config::load_or_default(), path),
+    )
+}
+
+pub fn capture_with(path: &Path, dura_cfg: &CaptureConfig) -> Result<Option<CaptureStatus>, Error> {
+    capture_inner(path, dura_cfg, false, false)
+}
+
+/// Captures even when nothing changed, e.g. to mark a point in the timeline. The snapshot's tree
+/// may be the same as its parent's. Never returns `Ok(None)`.
+pub fn capture_forced(
+    path: &Path,
+    dura_cfg: &CaptureConfig,
+) -> Result<Option<CaptureStatus>, Error> {
+    capture_inner(path, dura_cfg, false, true)
+}
+
+/// Does all the work of a capture except moving any refs, e.g. to time it. The tree is still
+/// written, so a few unreferenced objects may be left for `git gc`. The result has an empty
+/// `commit_hash` since nothing was committed.
+pub fn capture_dry_run(
+    path: &Path,
+    dura_cfg: &CaptureConfig,
+) -> Result<Option<CaptureStatus>, Error> {
+    capture_inner(path, dura_cfg, true, false)
+}
+
+fn capture_inner(
+    path: &Path,
+    dura_cfg: &CaptureConfig,
+    dry_run: bool,
+    force: bool,
+) -> Result<Option<CaptureStatus>, Error> {
+    // Before opening, since libgit2 refuses to open repos owned by someone else unless they're
+    // listed in safe.directory
+    privileges::warn_if_foreign_owner(&path.join(".git"));
+    let mut repo = open_repo(path)?;
+    if let Some(reason) = skip_reason(&repo, &dura_cfg.skip_on_branches) {
+        info!(
+            repo = redact::path(path).as_str(),
+            reason = reason.as_str(),
+            "Not snapshotting"
+        );
+        return Ok(None);
+    }
+    // Stashing leaves the worktree clean, so this can't wait until there's something to snapshot
+    if dura_cfg.snapshot_stashes && !dry_run {
+        for reference in keep_stashes(&mut repo, dura_cfg.ref_namespace)? {
+            info!(
+                repo = redact::path(path).as_str(),
+                reference = reference.as_str(),
+                "Kept stash"
+            );
+        }
+    }
     let head = repo.head()?.peel_to_commit()?;
-    let message = "dura auto-backup";
+    let message = match force {
+        true => FORCED_SNAPSHOT_MESSAGE,
+        false => SNAPSHOT_MESSAGE,
+    };
+    let mut phases = CapturePhases::default();
+    let mut timer = Instant::now();
 
-    // status check
-    if repo.statuses(None)?.is_empty() {
+    // status check. Only needs to answer "is anything different?", the diff below works out
+    // exactly what. Ignored files and the contents of untracked directories don't matter for that.
+    let mut status_opts = StatusOptions::new();
+    status_opts
+        .include_untracked(true)
+        .recurse_untracked_dirs(false)
+        .include_ignored(false)
+        .include_unmodified(false)
+        .update_index(false);
+    if !force && repo.statuses(Some(&mut status_opts))?.is_empty() {
         return Ok(None);
     }
+    phases.statuses_ms = lap(&mut timer);
 
-    let branch_name = format!("dura/{}", head.id());
-    let branch_commit = match repo.find_branch(&branch_name, BranchType::Local) {
+    let branch_name = dura_cfg.ref_namespace.snapshot_branch(head.id());
+    let ref_name = dura_cfg.ref_namespace.snapshot_ref(head.id());
+    let branch_commit = match repo.find_reference(&ref_name) {
         Ok(mut branch) => {
-            match branch.get().peel_to_commit() {
+            match branch.peel_to_commit() {
                 Ok(commit) if commit.id() != head.id() => Some(commit),
-                _ => {
+                Ok(_) => {
                     // Dura branch exist but no commit is made by dura
                     // So we clean this branch
-                    branch.delete()?;
+                    if !dry_run {
+                        branch.delete()?;
+                    }
                     None
                 }
+                // Points at an object that isn't there (anymore). Nothing worth keeping, and a
+                // ref to a missing object makes git gc fail, so it goes.
+                Err(e) if e.code() == git2::ErrorCode::NotFound => {
+                    if !dry_run {
+                        delete_dangling_ref(path, branch, &e)?;
+                    }
+                    None
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Err(e) if e.code() == git2::ErrorCode::NotFound => None,
+        // The ref file exists but can't even be parsed
+        Err(e) if e.class() == git2::ErrorClass::Reference => {
+            if !dry_run {
+                set_aside_corrupt_ref(&repo, path, dura_cfg.ref_namespace, &ref_name, &e)?;
             }
+            None
         }
-        Err(_) => None,
+        Err(e) => return Err(e),
     };
     let parent_commit = branch_commit.as_ref().unwrap_or(&head);
 
     // tree
     let mut index = repo.index()?;
-    index.add_all(["*"].iter(), IndexAddOption::DEFAULT, None)?;
+    let workdir = repo.workdir().unwrap_or(path).to_path_buf();
+    let submodules: BTreeSet<Vec<u8>> = index
+        .iter()
+        .filter(|entry| entry.mode == GITLINK_MODE)
+        .map(|entry| entry.path)
+        .collect();
+    // Sparse checkout marks entries outside the cone as skip-worktree. They're missing from the
+    // worktree on purpose, so add_all mustn't record them as deleted.
+    let sparse: Vec<IndexEntry> = index
+        .iter()
+        .filter(|entry| {
+            IndexEntryExtendedFlag::from_bits_truncate(entry.flags_extended).is_skip_worktree()
+        })
+        .collect();
+    let tracked: BTreeSet<Vec<u8>> = index.iter().map(|entry| entry.path).collect();
+    let sensitive = match dura_cfg.snapshot_sensitive_files {
+        true => vec![],
+        false => sensitive_patterns(&dura_cfg.sensitive_files),
+    };
+    let mut withheld = vec![];
+    let mut nested_repos = Vec::new();
+    // A repo checked out inside this one (but not as a submodule) would be recorded as a gitlink
+    // to whatever its HEAD happens to be, which is misleading. Leave it out entirely; it'll get
+    // its own snapshots if it's watched.
+    let mut lfs_paths = Vec::new();
+    let mut skip_nested = |rel_path: &Path, _: &[u8]| -> i32 {
+        // Untracked files that are left out are skipped here, before add_all hashes them, so
+        // their content never makes it into the object database
+        if let Some(rel_path) = rel_path
+            .to_str()
+            .filter(|p| !tracked.contains(p.as_bytes()))
+        {
+            if is_sensitive(&sensitive, rel_path) {
+                withheld.push(rel_path.to_string());
+                return 1;
+            }
+            if dura_cfg.snapshot_untracked == SnapshotUntracked::Never {
+                return 1;
+            }
+        }
+        if dura_cfg.lfs_mode != LfsMode::Full && is_lfs_path(&repo, rel_path) {
+            lfs_paths.push(rel_path.to_path_buf());
+            return 1;
+        }
+        let abs_path = workdir.join(rel_path);
+        let is_submodule = rel_path
+            .to_str()
+            .map(|p| submodules.contains(p.trim_end_matches('/').as_bytes()))
+            .unwrap_or(false);
+        if !is_submodule && abs_path.join(".git").exists() {
+            nested_repos.push(abs_path);
+            1
+        } else {
+            0
+        }
+    };
+    index.add_all(
+        ["*"].iter(),
+        IndexAddOption::DEFAULT,
+        Some(&mut skip_nested),
+    )?;
+    log_nested_repos(path, nested_repos);
+    for entry in sparse {
+        let path = Path::new(std::str::from_utf8(&entry.path).unwrap_or_default());
+        if index.get_path(path, 0).is_none() {
+            index.add(&entry)?;
+        }
+    }
+    if dura_cfg.lfs_mode == LfsMode::Pointer {
+        add_lfs_pointers(&repo, &mut index, &workdir, &lfs_paths);
+    }
+    log_lfs_paths(path, dura_cfg.lfs_mode, &lfs_paths);
+    if dura_cfg.forbid_external_symlink_content {
+        let original = Index::open(&repo.path().join("index"))?;
+        let external = drop_external_content(&original, &mut index, &workdir)?;
+        log_external_symlinks(path, external);
+    }
+    phases.index_add_ms = lap(&mut timer);
 
     let dirty_diff = repo.diff_tree_to_index(
         Some(&parent_commit.tree()?),
         Some(&index),
         Some(DiffOptions::new().include_untracked(true)),
     )?;
-    if dirty_diff.deltas().len() == 0 {
+    if !force && dirty_diff.deltas().len() == 0 {
+        return Ok(None);
+    }
+    let (tracked_changes, untracked_changes) = count_untracked(&dirty_diff, &tracked);
+    if !force
+        && tracked_changes == 0
+        && dura_cfg.snapshot_untracked == SnapshotUntracked::OnlyWithTrackedChanges
+    {
+        info!(
+            repo = redact::path(path).as_str(),
+            untracked_changes, "Not snapshotting, only untracked files changed"
+        );
         return Ok(None);
     }
+    let stats = dirty_diff.stats()?;
+    phases.diff_ms = lap(&mut timer);
 
     let tree_oid = index.write_tree()?;
     let tree = repo.find_tree(tree_oid)?;
-    if repo.find_branch(&branch_name, BranchType::Local).is_err() {
-        repo.branch(branch_name.as_str(), &head, false)?;
-    }
-
-    let committer = Signature::now(&get_git_author(&repo), &get_git_email(&repo))?;
-    let oid = repo.commit(
-        Some(&format!("refs/heads/{}", &branch_name)),
-        &committer,
-        &committer,
-        message,
-        &tree,
-        &[parent_commit],
-    )?;
+    phases.tree_write_ms = lap(&mut timer);
+
+    let identity = resolve_identity(&repo, dura_cfg);
+    if identity.is_fallback() {
+        if dura_cfg.require_identity {
+            return Err(Error::from_str(&format!(
+                "no identity to snapshot as ({identity}), set user.name and user.email in git or commit_author and commit_email in dura's config"
+            )));
+        }
+        log_fallback_identity(path, &identity);
+    }
+    if dry_run {
+        return Ok(Some(CaptureStatus {
+            dura_branch: branch_name,
+            commit_hash: String::new(),
+            base_hash: head.id().to_string(),
+            phases,
+            files_changed: stats.files_changed(),
+            insertions: stats.insertions(),
+            deletions: stats.deletions(),
+            timestamp: Utc::now(),
+            identity: Some(identity),
+            commit_time: None,
+            withheld,
+            lock_retries: 0,
+            tracked_changes,
+            untracked_changes,
+        }));
+    }
+    let mut lock_retries = 0;
+    if repo.find_reference(&ref_name).is_err() {
+        retry_locked(&mut lock_retries, || {
+            repo.reference(&ref_name, head.id(), false, "dura: start snapshots")
+        })?;
+    }
+
+    let timestamp = Utc::now();
+    let committer = Signature::now(&identity.name, &identity.email)?;
+    let oid = retry_locked(&mut lock_retries, || {
+        repo.commit(
+            Some(&ref_name),
+            &committer,
+            &committer,
+            message,
+            &tree,
+            &[parent_commit],
+        )
+    })?;
+    phases.commit_ms = lap(&mut timer);
+    let when = repo.find_commit(oid)?.committer().when();
+    let commit_time = FixedOffset::east_opt(when.offset_minutes() * 60)
+        .and_then(|offset| offset.timestamp_opt(when.seconds(), 0).single());
 
     Ok(Some(CaptureStatus {
         dura_branch: branch_name,
         commit_hash: oid.to_string(),
         base_hash: head.id().to_string(),
+        phases,
+        files_changed: stats.files_changed(),
+        insertions: stats.insertions(),
+        deletions: stats.deletions(),
+        timestamp,
+        identity: Some(identity),
+        commit_time,
+        withheld,
+        lock_retries,
+        tracked_changes,
+        untracked_changes,
     }))
 }
 
-fn get_git_author(repo: &Repository) -> String {
-    let dura_cfg = Config::load();
-    if let Some(value) = dura_cfg.commit_author {
-        return value;
+/// What `skip_on_branches` matches a detached HEAD as
+pub const DETACHED_HEAD: &str = "(detached)";
+
+/// Why the repo at `path` mustn't be snapshotted right now, if `skip_on_branches` says so
+pub fn skipped_on_branch(path: &Path, dura_cfg: &CaptureConfig) -> Option<String> {
+    if dura_cfg.skip_on_branches.is_empty() {
+        return None;
     }
+    skip_reason(&open_repo(path).ok()?, &dura_cfg.skip_on_branches)
+}
 
-    if !dura_cfg.commit_exclude_git_config {
-        if let Ok(git_cfg) = repo.config() {
-            if let Ok(value) = git_cfg.get_string("user.name") {
-                return value;
+fn skip_reason(repo: &Repository, patterns: &[String]) -> Option<String> {
+    if patterns.is_empty() {
+        return None;
+    }
+    let branch = match repo.head_detached() {
+        Ok(true) => DETACHED_HEAD.to_string(),
+        // An unborn branch has nothing to snapshot on top of anyway
+        _ => repo.head().ok()?.shorthand()?.to_string(),
+    };
+    let pattern = patterns.iter().find(|pattern| {
+        pattern.as_str() == branch || glob::Pattern::new(pattern).is_ok_and(|p| p.matches(&branch))
+    })?;
+    Some(format!(
+        "on branch {branch}, which matches skip_on_branches {pattern}"
+    ))
+}
+
+/// How many times a ref write that hit someone else's lock is tried again
+const LOCK_RETRIES: u32 = 3;
+
+/// Wait before the first retry of a locked ref. Each retry waits twice as long, give or take.
+const LOCK_RETRY_DELAY: Duration = Duration::from_millis(50);
+
+/// Whether `e` means another process (e.g. an editor running git) held a lock file for a moment
+fn is_lock_contention(e: &Error) -> bool {
+    use git2::{ErrorClass, ErrorCode};
+    e.code() == ErrorCode::Locked
+        || (matches!(
+            e.class(),
+            ErrorClass::Os | ErrorClass::Reference | ErrorClass::Filesystem
+        ) && e.message().to_lowercase().contains("lock"))
+}
+
+/// Runs `write`, and again up to `LOCK_RETRIES` times while it fails on a lock, counting the
+/// retries in `retries`
+fn retry_locked<T>(
+    retries: &mut u32,
+    mut write: impl FnMut() -> Result<T, Error>,
+) -> Result<T, Error> {
+    let mut attempt = 0;
+    loop {
+        match write() {
+            Err(e) if is_lock_contention(&e) && attempt < LOCK_RETRIES => {
+                let delay = LOCK_RETRY_DELAY * 2u32.pow(attempt);
+                thread::sleep(delay.mul_f64(0.75 + 0.5 * fastrand::f64()));
+                attempt += 1;
+                *retries += 1;
+            }
+            Err(e) if attempt > 0 && is_lock_contention(&e) => {
+                return Err(Error::new(
+                    e.code(),
+                    e.class(),
+                    format!("{} (gave up after {attempt} retries)", e.message()),
+                ));
             }
+            result => return result,
         }
     }
+}
 
-    "dura".to_string()
+/// `Config::sensitive_files`, the ones that are valid patterns
+fn sensitive_patterns(patterns: &[String]) -> Vec<glob::Pattern> {
+    patterns
+        .iter()
+        .filter_map(|pattern| glob::Pattern::new(pattern).ok())
+        .collect()
 }
 
-fn get_git_email(repo: &Repository) -> String {
-    let dura_cfg = Config::load();
-    if let Some(value) = dura_cfg.commit_email {
-        return value;
+/// Whether `rel_path` matches one of `patterns`. Patterns without a `/` match the file name
+/// anywhere, the rest match the path relative to the repo. Untracked files that match are left
+/// out of snapshots, since they're usually kept untracked so they never end up in git. Tracked
+/// ones stay, git has them already.
+fn is_sensitive(patterns: &[glob::Pattern], rel_path: &str) -> bool {
+    let name = rel_path.rsplit('/').next().unwrap_or_default();
+    patterns
+        .iter()
+        .any(|pattern| match pattern.as_str().contains('/') {
+            true => pattern.matches(rel_path),
+            false => pattern.matches(name),
+        })
+}
+
+/// How many of the changes in `diff` are to files the repo's own index has (`tracked_paths`),
+/// and how many are to untracked files
+fn count_untracked(diff: &git2::Diff, tracked_paths: &BTreeSet<Vec<u8>>) -> (usize, usize) {
+    diff.deltas().fold((0, 0), |(tracked, untracked), delta| {
+        let is_tracked = [delta.new_file().path(), delta.old_file().path()]
+            .into_iter()
+            .flatten()
+            .filter_map(|path| path.to_str())
+            .any(|path| tracked_paths.contains(path.as_bytes()));
+        match is_tracked {
+            true => (tracked + 1, untracked),
+            false => (tracked, untracked + 1),
+        }
+    })
+}
+
+/// Symlinks are stored as links, but make sure of it: any file content staged from outside the
+/// worktree, because a symlink to it or to a directory above it was followed, goes back to what
+/// the repo's own index (`original`) had (or out, if it wasn't there). Only entries this capture
+/// changed are checked. Returns the paths that were dropped.
+fn drop_external_content(
+    original: &Index,
+    index: &mut Index,
+    workdir: &Path,
+) -> Result<Vec<PathBuf>, Error> {
+    let root = workdir
+        .canonicalize()
+        .unwrap_or_else(|_| workdir.to_path_buf());
+    let mut external = vec![];
+    for entry in index.iter() {
+        if entry.mode & FILE_TYPE_MASK != REGULAR_FILE_TYPE {
+            continue;
+        }
+        let rel_path = match std::str::from_utf8(&entry.path) {
+            Ok(rel_path) => PathBuf::from(rel_path),
+            Err(_) => continue,
+        };
+        let before = original.get_path(&rel_path, 0);
+        if before.as_ref().is_some_and(|before| before.id == entry.id) {
+            continue;
+        }
+        let abs_path = workdir.join(&rel_path);
+        let escapes = match fs::symlink_metadata(&abs_path) {
+            Ok(meta) if meta.file_type().is_symlink() => true,
+            _ => abs_path
+                .canonicalize()
+                .is_ok_and(|real| !real.starts_with(&root)),
+        };
+        if escapes {
+            external.push((rel_path, before));
+        }
+    }
+    for (rel_path, before) in &external {
+        match before {
+            Some(before) => index.add(before)?,
+            None => index.remove_path(rel_path)?,
+        }
     }
+    Ok(external.into_iter().map(|(rel_path, _)| rel_path).collect())
+}
 
-    if !dura_cfg.commit_exclude_git_config {
-        if let Ok(git_cfg) = repo.config() {
-            if let Ok(value) = git_cfg.get_string("user.email") {
-                return value;
-            }
+/// Deletes a snapshot ref that points at a missing object, so a fresh one can be started from
+/// HEAD. The sha it had is logged.
+fn delete_dangling_ref(path: &Path, mut reference: Reference, reason: &Error) -> Result<(), Error> {
+    let name = reference.name().unwrap_or_default().to_string();
+    let target = reference
+        .target()
+        .map(|oid| oid.to_string())
+        .unwrap_or_default();
+    reference.delete()?;
+    warn!(
+        repo = redact::path(path).as_str(),
+        reference = name.as_str(),
+        target = target.as_str(),
+        "Snapshot ref points at a missing object, deleted it and starting a fresh one: {}",
+        reason.message()
+    );
+    Ok(())
+}
+
+/// Moves a snapshot ref that can't be parsed out of the way, to `<prefix>corrupt/<timestamp>`,
+/// so a fresh one can be started from HEAD rather than every capture failing until someone
+/// notices. The loose ref file is moved as it is, so whatever it contained is kept for a look.
+/// A bad packed ref is just deleted, there's no file of its own to keep.
+fn set_aside_corrupt_ref(
+    repo: &Repository,
+    path: &Path,
+    namespace: RefNamespace,
+    ref_name: &str,
+    reason: &Error,
+) -> Result<(), Error> {
+    let git_dir = repo.path();
+    let common_dir = match fs::read_to_string(git_dir.join("commondir")) {
+        Ok(common) => git_dir.join(common.trim()),
+        Err(_) => git_dir.to_path_buf(),
+    };
+    let loose = common_dir.join(ref_name);
+    if !loose.is_file() {
+        repo.find_reference(ref_name)?.delete()?;
+        warn!(
+            repo = redact::path(path).as_str(),
+            reference = ref_name,
+            "Packed snapshot ref is corrupt, deleted it and starting a fresh one: {}",
+            reason.message()
+        );
+        return Ok(());
+    }
+    // Another one set aside within the same second mustn't replace this one
+    let stamp = Utc::now().format("%Y%m%dT%H%M%SZ");
+    let mut aside = format!("{}corrupt/{stamp}", namespace.prefix());
+    let mut n = 1;
+    while common_dir.join(&aside).exists() {
+        aside = format!("{}corrupt/{stamp}-{n}", namespace.prefix());
+        n += 1;
+    }
+    let target = common_dir.join(&aside);
+    fs::create_dir_all(target.parent().unwrap_or(&common_dir))
+        .and_then(|_| fs::rename(&loose, &target))
+        .map_err(|e| Error::from_str(&format!("couldn't move {ref_name} aside: {e}")))?;
+    warn!(
+        repo = redact::path(path).as_str(),
+        reference = ref_name,
+        moved_to = aside.as_str(),
+        "Snapshot ref is corrupt, moved it aside and starting a fresh one: {}",
+        reason.message()
+    );
+    Ok(())
+}
+
+fn log_external_symlinks(path: &Path, external: Vec<PathBuf>) {
+    let mut seen = EXTERNAL_SYMLINKS_SEEN
+        .lock()
+        .unwrap_or_else(|e| e.into_inner());
+    let new: Vec<String> = external
+        .into_iter()
+        .map(|rel_path| path.join(rel_path))
+        .filter(|abs_path| seen.insert(abs_path.clone()))
+        .map(|abs_path| redact::path(&abs_path))
+        .collect();
+    if !new.is_empty() {
+        warn!(
+            repo = redact::path(path).as_str(),
+            paths = new.join(", ").as_str(),
+            "Skipped files reached through symlinks that lead out of the repo"
+        );
+    }
+}
+
+fn is_lfs_path(repo: &Repository, rel_path: &Path) -> bool {
+    matches!(
+        repo.get_attr(rel_path, "filter", AttrCheckFlags::FILE_THEN_INDEX),
+        Ok(Some("lfs"))
+    )
+}
+
+/// Stages what `git add` would: the LFS pointer rather than the file itself. Paths that can't be
+/// cleaned (e.g. git-lfs isn't installed) are left as they were in the index, like `Skip`.
+fn add_lfs_pointers(repo: &Repository, index: &mut Index, workdir: &Path, lfs_paths: &[PathBuf]) {
+    // As add_all would, the worktree's executable bit doesn't count when git ignores it
+    let file_mode = repo
+        .config()
+        .and_then(|config| config.get_bool("core.fileMode"))
+        .unwrap_or(true);
+    for rel_path in lfs_paths {
+        let staged = index.get_path(rel_path, 0).map(|entry| entry.mode);
+        let executable = match file_mode {
+            true => is_executable(&workdir.join(rel_path)),
+            false => None,
+        };
+        let mode = match (executable, staged) {
+            (Some(true), _) => 0o100755,
+            (None, Some(staged)) => staged,
+            _ => 0o100644,
+        };
+        let result = lfs_clean(workdir, rel_path).and_then(|pointer| {
+            let entry = IndexEntry {
+                ctime: IndexTime::new(0, 0),
+                mtime: IndexTime::new(0, 0),
+                dev: 0,
+                ino: 0,
+                mode,
+                uid: 0,
+                gid: 0,
+                file_size: pointer.len() as u32,
+                id: Oid::zero(),
+                flags: 0,
+                flags_extended: 0,
+                path: rel_path.to_string_lossy().replace('\\', "/").into_bytes(),
+            };
+            index
+                .add_frombuffer(&entry, &pointer)
+                .map_err(|e| e.to_string())
+        });
+        if let Err(e) = result {
+            warn!(
+                path = rel_path.to_str().unwrap_or("<invalid path>"),
+                "Couldn't store LFS pointer, keeping the staged version: {e}"
+            );
+        }
+    }
+}
+
+/// None where there's no executable bit to look at
+#[cfg(unix)]
+fn is_executable(path: &Path) -> Option<bool> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::metadata(path)
+        .ok()
+        .map(|meta| meta.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_executable(_path: &Path) -> Option<bool> {
+    None
+}
+
+/// Runs the file through `git lfs clean`, which prints its pointer
+fn lfs_clean(workdir: &Path, rel_path: &Path) -> Result<Vec<u8>, String> {
+    let file = File::open(workdir.join(rel_path)).map_err(|e| e.to_string())?;
+    let output = git_command()
+        .current_dir(workdir)
+        .args(["lfs", "clean", "--"])
+        .arg(rel_path)
+        .stdin(file)
+        .output()
+        .map_err(|e| format!("Couldn't run git lfs: {e}"))?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+    }
+    Ok(output.stdout)
+}
+
+fn log_lfs_paths(path: &Path, mode: LfsMode, lfs_paths: &[PathBuf]) {
+    if lfs_paths.is_empty() {
+        return;
+    }
+    let paths: Vec<&str> = lfs_paths
+        .iter()
+        .map(|p| p.to_str().unwrap_or("<invalid path>"))
+        .collect();
+    info!(
+        repo = redact::path(path).as_str(),
+        lfs_mode = format!("{mode:?}").as_str(),
+        paths = paths.join(", ").as_str(),
+        "LFS files not snapshotted in full"
+    );
+}
+
+fn log_nested_repos(path: &Path, nested_repos: Vec<PathBuf>) {
+    if nested_repos.is_empty() {
+        return;
+    }
+    let mut seen = NESTED_REPOS_SEEN.lock().unwrap_or_else(|e| e.into_inner());
+    for nested in nested_repos {
+        if !seen.contains(&nested) {
+            info!(
+                repo = redact::path(path).as_str(),
+                nested = redact::path(&nested).as_str(),
+                "Excluding nested repository from snapshots"
+            );
+            seen.insert(nested);
+        }
+    }
+}
+
+/// Where the name or email on a repo's snapshots comes from, in the order they're tried
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum IdentitySource {
+    /// `commit_author` / `commit_email` in dura's config
+    DuraConfig,
+    /// `user.name` / `user.email` in the repo's git config, including the global one
+    GitConfig,
+    /// Neither was set, dura made one up
+    Fallback,
+}
+
+impl fmt::Display for IdentitySource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IdentitySource::DuraConfig => write!(f, "dura config"),
+            IdentitySource::GitConfig => write!(f, "git config"),
+            IdentitySource::Fallback => write!(f, "fallback"),
         }
     }
+}
 
-    "dura@github.io".to_string()
+/// The author and committer of a repo's snapshots
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Identity {
+    pub name: String,
+    pub name_source: IdentitySource,
+    pub email: String,
+    pub email_source: IdentitySource,
+}
+
+impl Identity {
+    pub fn is_fallback(&self) -> bool {
+        self.name_source == IdentitySource::Fallback
+            || self.email_source == IdentitySource::Fallback
+    }
+}
+
+impl fmt::Display for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.name_source == self.email_source {
+            true => write!(
+                f,
+                "{} <{}> from {}",
+                self.name, self.email, self.name_source
+            ),
+            false => write!(
+                f,
+                "{} <{}> with name from {}, email from {}",
+                self.name, self.email, self.name_source, self.email_source
+            ),
+        }
+    }
+}
+
+/// Who snapshots of `repo` get committed as
+pub fn resolve_identity(repo: &Repository, dura_cfg: &CaptureConfig) -> Identity {
+    let git_cfg = match dura_cfg.commit_exclude_git_config {
+        true => None,
+        false => repo.config().ok(),
+    };
+    let resolve = |dura_value: &Option<String>, git_key: &str, fallback: &str| {
+        if let Some(value) = dura_value {
+            return (value.clone(), IdentitySource::DuraConfig);
+        }
+        if let Some(value) = git_cfg
+            .as_ref()
+            .and_then(|cfg| cfg.get_string(git_key).ok())
+        {
+            return (value, IdentitySource::GitConfig);
+        }
+        (fallback.to_string(), IdentitySource::Fallback)
+    };
+    let (name, name_source) = resolve(&dura_cfg.commit_author, "user.name", "dura");
+    let (email, email_source) = resolve(&dura_cfg.commit_email, "user.email", "dura@github.io");
+    Identity {
+        name,
+        name_source,
+        email,
+        email_source,
+    }
+}
+
+fn log_fallback_identity(path: &Path, identity: &Identity) {
+    let mut seen = FALLBACK_IDENTITY_SEEN
+        .lock()
+        .unwrap_or_else(|e| e.into_inner());
+    if seen.insert(path.to_path_buf()) {
+        warn!(
+            repo = redact::path(path).as_str(),
+            identity = identity.to_string().as_str(),
+            "No git identity configured, snapshots use dura's own. Set require_identity to refuse instead."
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::WatchConfig;
+    use std::rc::Rc;
+
+    #[cfg(unix)]
+    #[test]
+    fn external_content_is_dropped() {
+        let tmp = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        let secret = outside.path().join("secret.txt");
+        fs::write(&secret, "hunter2").unwrap();
+        let repo = Repository::init(tmp.path()).unwrap();
+        std::os::unix::fs::symlink(&secret, tmp.path().join("secret")).unwrap();
+        fs::write(tmp.path().join("plain.txt"), "hi").unwrap();
+
+        // As if the symlink had been followed
+        let mut index = repo.index().unwrap();
+        for (path, contents) in [("secret", "hunter2"), ("plain.txt", "hi")] {
+            let entry = IndexEntry {
+                ctime: IndexTime::new(0, 0),
+                mtime: IndexTime::new(0, 0),
+                dev: 0,
+                ino: 0,
+                mode: 0o100644,
+                uid: 0,
+                gid: 0,
+                file_size: contents.len() as u32,
+                id: Oid::zero(),
+                flags: 0,
+                flags_extended: 0,
+                path: path.as_bytes().to_vec(),
+            };
+            index.add_frombuffer(&entry, contents.as_bytes()).unwrap();
+        }
+
+        let original = Index::open(&repo.path().join("index")).unwrap();
+        let dropped = drop_external_content(&original, &mut index, tmp.path()).unwrap();
+        assert_eq!(dropped, vec![PathBuf::from("secret")]);
+        assert!(index.get_path(Path::new("secret"), 0).is_none());
+        assert!(index.get_path(Path::new("plain.txt"), 0).is_some());
+    }
+
+    /// A repo with one commit and an uncommitted change
+    fn changed_repo(dir: &Path) -> Repository {
+        let repo = Repository::init(dir).unwrap();
+        let sig = Signature::now("a", "a@example.com").unwrap();
+        let tree_oid = repo.index().unwrap().write_tree().unwrap();
+        let tree = repo.find_tree(tree_oid).unwrap();
+        repo.commit(Some("HEAD"), &sig, &sig, "initial", &tree, &[])
+            .unwrap();
+        drop(tree);
+        fs::write(dir.join("notes.txt"), "hi").unwrap();
+        repo
+    }
+
+    #[test]
+    fn identity_sources() {
+        let tmp = tempfile::tempdir().unwrap();
+        let repo = changed_repo(tmp.path());
+        repo.config()
+            .unwrap()
+            .set_str("user.email", "git@example.com")
+            .unwrap();
+        let cfg = CaptureConfig {
+            commit_author: Some("Configured".to_string()),
+            ..CaptureConfig::default()
+        };
+
+        let identity = resolve_identity(&repo, &cfg);
+        assert_eq!(identity.name, "Configured");
+        assert_eq!(identity.name_source, IdentitySource::DuraConfig);
+        assert_eq!(identity.email, "git@example.com");
+        assert_eq!(identity.email_source, IdentitySource::GitConfig);
+        assert!(!identity.is_fallback());
+
+        let cfg = CaptureConfig {
+            commit_exclude_git_config: true,
+            ..cfg
+        };
+        let identity = resolve_identity(&repo, &cfg);
+        assert_eq!(identity.email, "dura@github.io");
+        assert_eq!(identity.email_source, IdentitySource::Fallback);
+        assert!(identity.is_fallback());
+        assert_eq!(
+            identity.to_string(),
+            "Configured <dura@github.io> with name from dura config, email from fallback"
+        );
+
+        let status = capture_with(tmp.path(), &cfg).unwrap().unwrap();
+        assert_eq!(status.identity, Some(identity));
+        let commit = repo
+            .find_commit(Oid::from_str(&status.commit_hash).unwrap())
+            .unwrap();
+        assert_eq!(commit.author().email(), Some("dura@github.io"));
+    }
+
+    #[test]
+    fn require_identity_refuses_fallback() {
+        let tmp = tempfile::tempdir().unwrap();
+        let repo = changed_repo(tmp.path());
+        let cfg = CaptureConfig {
+            commit_exclude_git_config: true,
+            require_identity: true,
+            ..CaptureConfig::default()
+        };
+
+        let err = capture_with(tmp.path(), &cfg).unwrap_err();
+        assert!(err.message().starts_with("no identity to snapshot as"));
+        assert!(repo
+            .references_glob("refs/heads/dura/*")
+            .unwrap()
+            .next()
+            .is_none());
+
+        let cfg = CaptureConfig {
+            commit_author: Some("Me".to_string()),
+            commit_email: Some("me@example.com".to_string()),
+            ..cfg
+        };
+        assert!(capture_with(tmp.path(), &cfg).unwrap().is_some());
+    }
+
+    #[test]
+    fn corrupt_snapshot_ref_is_moved_aside() {
+        let tmp = tempfile::tempdir().unwrap();
+        let repo = changed_repo(tmp.path());
+        let head = repo.head().unwrap().target().unwrap();
+        let ref_file = repo.path().join(format!("refs/heads/dura/{head}"));
+        let corrupt = repo.path().join("refs/heads/dura/corrupt");
+        fs::create_dir_all(ref_file.parent().unwrap()).unwrap();
+        let snapshot_is = |status: &CaptureStatus| {
+            let snapshot = repo.find_reference(&format!("refs/heads/dura/{head}"));
+            assert_eq!(
+                snapshot.unwrap().target().unwrap().to_string(),
+                status.commit_hash
+            );
+        };
+
+        // Unparseable, kept for a look. Twice in the same second doesn't lose the first one.
+        for n in 1..=2 {
+            fs::write(&ref_file, "garbage\n").unwrap();
+            fs::write(tmp.path().join("notes.txt"), format!("change {n}")).unwrap();
+            let status = capture_with(tmp.path(), &CaptureConfig::default())
+                .unwrap()
+                .unwrap();
+            snapshot_is(&status);
+            let aside: Vec<String> = fs::read_dir(&corrupt)
+                .unwrap()
+                .map(|entry| fs::read_to_string(entry.unwrap().path()).unwrap())
+                .collect();
+            assert_eq!(aside, vec!["garbage\n".to_string(); n], "{aside:?}");
+        }
+        fs::remove_dir_all(&corrupt).unwrap();
+
+        // Pointing at a missing object, deleted so git gc doesn't trip over it
+        fs::write(&ref_file, "1111111111111111111111111111111111111111\n").unwrap();
+        fs::write(tmp.path().join("notes.txt"), "change 3").unwrap();
+        let status = capture_with(tmp.path(), &CaptureConfig::default())
+            .unwrap()
+            .unwrap();
+        snapshot_is(&status);
+        assert!(!corrupt.exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unreadable_snapshot_ref_is_an_error() {
+        use std::os::unix::fs::PermissionsExt;
+        let tmp = tempfile::tempdir().unwrap();
+        let repo = changed_repo(tmp.path());
+        let first = capture_with(tmp.path(), &CaptureConfig::default())
+            .unwrap()
+            .unwrap();
+        let head = repo.head().unwrap().target().unwrap();
+        let ref_file = repo.path().join(format!("refs/heads/dura/{head}"));
+        fs::set_permissions(&ref_file, fs::Permissions::from_mode(0o000)).unwrap();
+        if fs::read(&ref_file).is_ok() {
+            // Running as root
+            return;
+        }
+        fs::write(tmp.path().join("notes.txt"), "changed").unwrap();
+
+        assert!(capture_with(tmp.path(), &CaptureConfig::default()).is_err());
+        fs::set_permissions(&ref_file, fs::Permissions::from_mode(0o644)).unwrap();
+        assert_eq!(
+            fs::read_to_string(&ref_file).unwrap().trim(),
+            first.commit_hash
+        );
+        assert!(!repo.path().join("refs/heads/dura/corrupt").exists());
+    }
+
+    #[test]
+    fn forced_capture_of_clean_repo() {
+        let tmp = tempfile::tempdir().unwrap();
+        let repo = changed_repo(tmp.path());
+        let cfg = CaptureConfig::default();
+        let first = capture_with(tmp.path(), &cfg).unwrap().unwrap();
+        assert!(capture_with(tmp.path(), &cfg).unwrap().is_none());
+
+        let forced = capture_forced(tmp.path(), &cfg).unwrap().unwrap();
+        assert_eq!(forced.files_changed, 0);
+        let commit = repo
+            .find_commit(Oid::from_str(&forced.commit_hash).unwrap())
+            .unwrap();
+        let parent = commit.parent(0).unwrap();
+        assert_eq!(parent.id().to_string(), first.commit_hash);
+        assert_eq!(commit.tree_id(), parent.tree_id());
+        assert!(is_snapshot(&commit));
+        assert_eq!(commit.message(), Some(FORCED_SNAPSHOT_MESSAGE));
+    }
+
+    #[test]
+    fn locked_ref_is_retried() {
+        let tmp = tempfile::tempdir().unwrap();
+        let repo = changed_repo(tmp.path());
+        let head = repo.head().unwrap().target().unwrap();
+        let lock = tmp.path().join(format!(".git/refs/heads/dura/{head}.lock"));
+        fs::create_dir_all(lock.parent().unwrap()).unwrap();
+        fs::write(&lock, "").unwrap();
+        let release = {
+            let lock = lock.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(60));
+                fs::remove_file(lock).unwrap();
+            })
+        };
+
+        let status = capture_with(tmp.path(), &CaptureConfig::default())
+            .unwrap()
+            .unwrap();
+        release.join().unwrap();
+        assert!(status.lock_retries > 0);
+        assert!(repo
+            .find_reference(&format!("refs/heads/dura/{head}"))
+            .is_ok());
+
+        // Never released
+        fs::write(tmp.path().join("notes.txt"), "changed").unwrap();
+        fs::write(&lock, "").unwrap();
+        let err = capture_with(tmp.path(), &CaptureConfig::default()).unwrap_err();
+        assert!(err.message().contains("gave up after 3 retries"), "{err}");
+    }
+
+    #[test]
+    fn sensitive_files_are_withheld() {
+        let tmp = tempfile::tempdir().unwrap();
+        let repo = changed_repo(tmp.path());
+        fs::write(tmp.path().join(".env"), "TOKEN=hunter2").unwrap();
+        fs::create_dir(tmp.path().join("certs")).unwrap();
+        fs::write(tmp.path().join("certs/server.pem"), "key").unwrap();
+        let in_tree = |status: &CaptureStatus, path: &str| {
+            let commit = repo
+                .find_commit(Oid::from_str(&status.commit_hash).unwrap())
+                .unwrap();
+            let found = commit.tree().unwrap().get_path(Path::new(path)).is_ok();
+            found
+        };
+
+        let status = capture_with(tmp.path(), &CaptureConfig::default())
+            .unwrap()
+            .unwrap();
+        assert!(in_tree(&status, "notes.txt"));
+        assert!(!in_tree(&status, ".env"));
+        assert!(!in_tree(&status, "certs/server.pem"));
+        assert_eq!(status.withheld, [".env", "certs/server.pem"]);
+        // Not even as a loose object nothing refers to
+        let secret = Oid::hash_object(git2::ObjectType::Blob, b"TOKEN=hunter2").unwrap();
+        assert!(!repo.odb().unwrap().exists(secret));
+
+        let mut config = Config::empty();
+        config.snapshot_sensitive_files = true;
+        fs::write(tmp.path().join("notes.txt"), "changed").unwrap();
+        let status = capture_with(tmp.path(), &CaptureConfig::from(&config))
+            .unwrap()
+            .unwrap();
+        assert!(in_tree(&status, ".env"));
+        assert!(status.withheld.is_empty());
+    }
+
+    #[test]
+    fn sensitive_files_per_watch() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().canonicalize().unwrap();
+        let mut config = Config::empty();
+        let mut watch = WatchConfig::new();
+        watch.snapshot_sensitive_files = Some(true);
+        config
+            .repos
+            .insert(root.to_str().unwrap().to_string(), Rc::new(watch));
+        changed_repo(&root.join("repo"));
+
+        assert!(CaptureConfig::for_repo(&config, &root.join("repo")).snapshot_sensitive_files);
+        assert!(
+            !CaptureConfig::for_repo(&config, Path::new("/elsewhere")).snapshot_sensitive_files
+        );
+    }
 }
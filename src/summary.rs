@@ -0,0 +1,151 @@
+//! When each watched repo was last snapshotted, read straight from the snapshot refs rather than
+//! the logs, so it also works when `dura serve` logs somewhere else or not at all.
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use chrono::{DateTime, TimeZone, Utc};
+use git2::Error;
+use serde::Serialize;
+
+use crate::poll_guard::PollGuard;
+use crate::snapshots::{self, RefNamespace};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RepoSummary {
+    pub repo: PathBuf,
+    /// Commit time of the newest snapshot in either namespace
+    pub last_snapshot: Option<DateTime<Utc>>,
+    /// Newest modification in the working tree that no snapshot has, if there are any
+    pub unsnapshotted_change: Option<DateTime<Utc>>,
+}
+
+impl RepoSummary {
+    /// Time since the last snapshot, or `None` if there never was one
+    pub fn age(&self, now: DateTime<Utc>) -> Option<Duration> {
+        self.last_snapshot
+            .map(|last| (now - last).to_std().unwrap_or(Duration::ZERO))
+    }
+
+    /// Has work gone without a snapshot for longer than `max_age`? A repo with nothing to
+    /// snapshot is never stale, no matter how old its last snapshot is.
+    pub fn is_stale(&self, now: DateTime<Utc>, max_age: Duration) -> bool {
+        self.unsnapshotted_change.is_some() && self.age(now).is_none_or(|age| age > max_age)
+    }
+}
+
+pub fn summarize(path: &Path, guard: &mut PollGuard) -> Result<RepoSummary, Error> {
+    let repo = snapshots::open_repo(path)?;
+    let mut newest: Option<i64> = None;
+    for namespace in [RefNamespace::Heads, RefNamespace::Dura] {
+        for reference in repo.references_glob(&format!("{}*", namespace.prefix()))? {
+            let reference = reference?;
+            // When something was stashed isn't when it was last snapshotted
+            if snapshots::is_stash_ref(reference.name().unwrap_or_default()) {
+                continue;
+            }
+            if let Ok(commit) = reference.peel_to_commit() {
+                newest = newest.max(Some(commit.time().seconds()));
+            }
+        }
+    }
+
+    Ok(RepoSummary {
+        repo: path.to_path_buf(),
+        last_snapshot: newest.and_then(|secs| Utc.timestamp_opt(secs, 0).single()),
+        unsnapshotted_change: guard.last_change(path).map(DateTime::<Utc>::from),
+    })
+}
+
+/// Most stale first: repos without any snapshot, then oldest snapshot
+pub fn sort_by_staleness(summaries: &mut [RepoSummary]) {
+    summaries.sort_by(|a, b| match (a.last_snapshot, b.last_snapshot) {
+        (None, None) => a.repo.cmp(&b.repo),
+        (None, Some(_)) => std::cmp::Ordering::Less,
+        (Some(_), None) => std::cmp::Ordering::Greater,
+        (Some(a_time), Some(b_time)) => a_time.cmp(&b_time).then_with(|| a.repo.cmp(&b.repo)),
+    });
+}
+
+/// Parses ages like `90s`, `30m`, `12h` or `1d`
+pub fn parse_age(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("'{value}' should look like 30m, 12h or 1d"))?;
+    let unit_secs = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(format!("Unknown unit in '{value}', use s, m, h or d")),
+    };
+    number
+        .checked_mul(unit_secs)
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("'{value}' is too long"))
+}
+
+/// Roughest sensible unit, e.g. `3d 4h` or `12m`
+pub fn format_age(age: Duration) -> String {
+    let secs = age.as_secs();
+    match secs {
+        0..=59 => format!("{secs}s"),
+        60..=3599 => format!("{}m", secs / 60),
+        3600..=86399 => format!("{}h {}m", secs / 3600, secs % 3600 / 60),
+        _ => format!("{}d {}h", secs / 86400, secs % 86400 / 3600),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn summary(name: &str, age_secs: Option<i64>, changed: bool) -> RepoSummary {
+        let now = Utc.timestamp_opt(1_000_000, 0).unwrap();
+        RepoSummary {
+            repo: PathBuf::from(name),
+            last_snapshot: age_secs.map(|secs| now - chrono::Duration::seconds(secs)),
+            unsnapshotted_change: changed.then_some(now),
+        }
+    }
+
+    #[test]
+    fn ages() {
+        assert_eq!(parse_age("1d"), Ok(Duration::from_secs(86400)));
+        assert_eq!(parse_age("30m"), Ok(Duration::from_secs(1800)));
+        assert!(parse_age("1w").is_err());
+        assert!(parse_age("d").is_err());
+        assert_eq!(
+            parse_age("99999999999999999d"),
+            Err("'99999999999999999d' is too long".to_string())
+        );
+        assert_eq!(format_age(Duration::from_secs(90061)), "1d 1h");
+        assert_eq!(format_age(Duration::from_secs(750)), "12m");
+    }
+
+    #[test]
+    fn never_snapshotted_sorts_first() {
+        let mut summaries = vec![
+            summary("fresh", Some(10), false),
+            summary("never", None, false),
+            summary("old", Some(5000), false),
+        ];
+        sort_by_staleness(&mut summaries);
+        let names: Vec<_> = summaries.iter().map(|s| s.repo.to_str().unwrap()).collect();
+        assert_eq!(names, vec!["never", "old", "fresh"]);
+    }
+
+    #[test]
+    fn stale_only_with_unsaved_work() {
+        let now = Utc.timestamp_opt(1_000_000, 0).unwrap();
+        let hour = Duration::from_secs(3600);
+        assert!(summary("a", Some(7200), true).is_stale(now, hour));
+        assert!(summary("a", None, true).is_stale(now, hour));
+        assert!(!summary("a", Some(7200), false).is_stale(now, hour));
+        assert!(!summary("a", Some(60), true).is_stale(now, hour));
+    }
+}
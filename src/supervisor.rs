@@ -0,0 +1,170 @@
+//! `dura serve --supervise`: a small parent process that runs the real poller as a child (the
+//! worker) and starts it again when it crashes, so a panic doesn't stop snapshots until someone
+//! notices.
+//!
+//! The runtime lock records both pids. The worker holds the lock as usual, and the supervisor
+//! stops for good as soon as the lock no longer names it, e.g. after `dura kill` or when another
+//! `dura serve` took over.
+use std::ffi::OsString;
+use std::path::Path;
+use std::process::{self, ExitStatus};
+use std::time::{Duration, Instant};
+
+use tokio::process::Command;
+use tokio::time;
+use tracing::{error, info, warn};
+
+use crate::database::RuntimeLock;
+
+/// A worker that dies sooner than this after it was started counts as a rapid crash
+const RAPID_CRASH_WINDOW: Duration = Duration::from_secs(60);
+
+/// Give up after this many rapid crashes in a row, rather than restarting in a tight loop
+pub const MAX_RAPID_CRASHES: u32 = 5;
+
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(60);
+
+/// Wait before restarting after `rapid_crashes` rapid crashes in a row: 1s, 2s, 4s...
+fn restart_delay(rapid_crashes: u32) -> Duration {
+    Duration::from_secs(1)
+        .saturating_mul(2u32.saturating_pow(rapid_crashes.saturating_sub(1)))
+        .min(MAX_RESTART_DELAY)
+}
+
+fn still_supervising() -> bool {
+    RuntimeLock::load_or_default().supervisor_pid == Some(process::id())
+}
+
+/// Runs `exe` with `worker_args` (plus `--supervised-by <pid>`) until it exits cleanly, the
+/// supervisor is asked to stop, or the worker crashed `MAX_RAPID_CRASHES` times in a row.
+pub async fn supervise(exe: &Path, worker_args: &[OsString]) -> Result<(), String> {
+    let mut lock = RuntimeLock::load_or_default();
+    lock.supervisor_pid = Some(process::id());
+    lock.save().map_err(|e| e.to_string())?;
+    info!(pid = process::id(), "supervisor_started");
+
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+    let mut crashes = 0u32;
+    let mut rapid_crashes = 0u32;
+    loop {
+        let started = Instant::now();
+        let mut child = Command::new(exe)
+            .args(worker_args)
+            .arg("--supervised-by")
+            .arg(process::id().to_string())
+            .spawn()
+            .map_err(|e| format!("Couldn't start the worker: {e}"))?;
+        info!(worker_pid = child.id(), "worker_started");
+
+        let status: ExitStatus = tokio::select! {
+            status = child.wait() => status.map_err(|e| format!("Lost track of the worker: {e}"))?,
+            _ = &mut shutdown => {
+                info!("Supervisor shutting down, stopping the worker");
+                if let Some(pid) = child.id() {
+                    terminate(pid);
+                }
+                let _ = child.wait().await;
+                return Ok(());
+            }
+        };
+        if status.success() || !still_supervising() {
+            info!(
+                status = status.to_string().as_str(),
+                "Worker stopped, so is the supervisor"
+            );
+            return Ok(());
+        }
+
+        crashes += 1;
+        rapid_crashes = match started.elapsed() < RAPID_CRASH_WINDOW {
+            true => rapid_crashes + 1,
+            false => 1,
+        };
+        if rapid_crashes >= MAX_RAPID_CRASHES {
+            error!(
+                crashes,
+                rapid_crashes,
+                status = status.to_string().as_str(),
+                "Worker keeps crashing, giving up"
+            );
+            return Err(format!(
+                "the worker crashed {rapid_crashes} times in a row, giving up"
+            ));
+        }
+        let delay = restart_delay(rapid_crashes);
+        warn!(
+            crashes,
+            rapid_crashes,
+            status = status.to_string().as_str(),
+            restart_in_secs = delay.as_secs(),
+            "worker_crashed"
+        );
+        tokio::select! {
+            _ = time::sleep(delay) => (),
+            _ = &mut shutdown => return Ok(()),
+        }
+        if !still_supervising() {
+            info!("Runtime lock was taken while restarting, supervisor stopping");
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn shutdown_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+    match signal(SignalKind::terminate()) {
+        Ok(mut term) => {
+            tokio::select! {
+                _ = term.recv() => (),
+                _ = tokio::signal::ctrl_c() => (),
+            }
+        }
+        Err(_) => {
+            let _ = tokio::signal::ctrl_c().await;
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn shutdown_signal() {
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Asks a process to shut down with SIGTERM. Does nothing on Windows.
+#[cfg(unix)]
+pub fn terminate(pid: u32) {
+    if let Ok(pid) = libc::pid_t::try_from(pid) {
+        unsafe { libc::kill(pid, libc::SIGTERM) };
+    }
+}
+
+#[cfg(not(unix))]
+pub fn terminate(_pid: u32) {}
+
+/// Called by the worker, so it doesn't carry on as an orphan when the supervisor is killed.
+/// Only Linux can arrange this.
+#[cfg(target_os = "linux")]
+pub fn die_with_supervisor(supervisor: u32) {
+    unsafe { libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGTERM) };
+    // The supervisor may have died before prctl was called
+    if std::os::unix::process::parent_id() != supervisor {
+        process::exit(1);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn die_with_supervisor(_supervisor: u32) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn restart_delay_backs_off() {
+        assert_eq!(restart_delay(1), Duration::from_secs(1));
+        assert_eq!(restart_delay(4), Duration::from_secs(8));
+        assert_eq!(restart_delay(30), MAX_RESTART_DELAY);
+    }
+}
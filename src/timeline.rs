@@ -0,0 +1,159 @@
+//! `dura timeline`: snapshots grouped by the commit they were taken on top of, for answering
+//! "what was I doing on top of X?" when the dura branches are only a flat list of shas.
+use std::collections::HashMap;
+use std::path::Path;
+
+use chrono::{DateTime, TimeZone, Utc};
+use git2::{Error, Oid};
+use serde::Serialize;
+
+use crate::api;
+use crate::snapshots;
+
+/// One of the user's commits, and the snapshots taken on top of it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Base {
+    pub commit: String,
+    /// First line of the commit message
+    pub subject: String,
+    pub timestamp: DateTime<Utc>,
+    /// Newest first
+    pub snapshots: Vec<TimelineSnapshot>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TimelineSnapshot {
+    pub commit: String,
+    pub timestamp: DateTime<Utc>,
+    /// Size of the change since the snapshot before it (or the base, for the first one)
+    pub files_changed: usize,
+    pub insertions: usize,
+    pub deletions: usize,
+}
+
+/// Every snapshot in the repo at `repo_path`, grouped by base, newest base first
+pub fn timeline(repo_path: &Path) -> Result<Vec<Base>, Error> {
+    let repo = snapshots::open_repo(repo_path)?;
+    let mut bases: Vec<Base> = vec![];
+    let mut index: HashMap<String, usize> = HashMap::new();
+    // Already newest first, which carries over to the snapshots within each base
+    for snapshot in api::list_snapshots(repo_path)? {
+        let i = match index.get(&snapshot.base) {
+            Some(&i) => i,
+            None => {
+                let base = repo.find_commit(Oid::from_str(&snapshot.base)?)?;
+                bases.push(Base {
+                    commit: snapshot.base.clone(),
+                    subject: base.summary().unwrap_or_default().to_string(),
+                    timestamp: commit_time(&base),
+                    snapshots: vec![],
+                });
+                index.insert(snapshot.base.clone(), bases.len() - 1);
+                bases.len() - 1
+            }
+        };
+        let commit = repo.find_commit(Oid::from_str(&snapshot.commit)?)?;
+        let parent_tree = match commit.parent(0) {
+            Ok(parent) => Some(parent.tree()?),
+            Err(_) => None,
+        };
+        let diff = repo.diff_tree_to_tree(parent_tree.as_ref(), Some(&commit.tree()?), None)?;
+        let stats = diff.stats()?;
+        bases[i].snapshots.push(TimelineSnapshot {
+            commit: snapshot.commit,
+            timestamp: snapshot.timestamp,
+            files_changed: stats.files_changed(),
+            insertions: stats.insertions(),
+            deletions: stats.deletions(),
+        });
+    }
+    bases.sort_by_key(|base| std::cmp::Reverse(base.timestamp));
+    Ok(bases)
+}
+
+fn commit_time(commit: &git2::Commit) -> DateTime<Utc> {
+    Utc.timestamp_opt(commit.time().seconds(), 0)
+        .single()
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshots::CaptureConfig;
+    use git2::{Repository, Signature};
+    use std::fs;
+
+    fn commit(repo: &Repository, message: &str, seconds: i64) -> Oid {
+        let sig = Signature::new("a", "a@example.com", &git2::Time::new(seconds, 0)).unwrap();
+        let mut index = repo.index().unwrap();
+        index
+            .add_all(["*"], git2::IndexAddOption::DEFAULT, None)
+            .unwrap();
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let parents = match repo.head() {
+            Ok(head) => vec![head.peel_to_commit().unwrap()],
+            Err(_) => vec![],
+        };
+        let parents: Vec<&git2::Commit> = parents.iter().collect();
+        repo.commit(Some("HEAD"), &sig, &sig, message, &tree, &parents)
+            .unwrap()
+    }
+
+    #[test]
+    fn grouped_by_base() {
+        let tmp = tempfile::tempdir().unwrap();
+        let repo = Repository::init(tmp.path()).unwrap();
+        let cfg = CaptureConfig::default();
+        fs::write(tmp.path().join("a.txt"), "one\n").unwrap();
+        let first = commit(&repo, "First\n\nDetails", 1_000);
+        fs::write(tmp.path().join("a.txt"), "one\ntwo\n").unwrap();
+        let on_first = snapshots::capture_with(tmp.path(), &cfg).unwrap().unwrap();
+
+        let second = commit(&repo, "Second", 2_000);
+        fs::write(tmp.path().join("a.txt"), "three\n").unwrap();
+        let older = snapshots::capture_with(tmp.path(), &cfg).unwrap().unwrap();
+        fs::write(tmp.path().join("b.txt"), "new\n").unwrap();
+        let newer = snapshots::capture_with(tmp.path(), &cfg).unwrap().unwrap();
+
+        let bases = timeline(tmp.path()).unwrap();
+        let commits: Vec<(&str, Vec<&str>)> = bases
+            .iter()
+            .map(|base| {
+                let snapshots = base.snapshots.iter().map(|s| s.commit.as_str()).collect();
+                (base.commit.as_str(), snapshots)
+            })
+            .collect();
+        let second = second.to_string();
+        let first = first.to_string();
+        // Snapshots on the same base can share a second, so don't rely on their order
+        assert_eq!(commits.len(), 2);
+        assert_eq!(commits[0].0, second);
+        assert_eq!(
+            commits[1],
+            (first.as_str(), vec![on_first.commit_hash.as_str()])
+        );
+        let mut on_second = commits[0].1.clone();
+        on_second.sort();
+        let mut expected = vec![older.commit_hash.as_str(), newer.commit_hash.as_str()];
+        expected.sort();
+        assert_eq!(on_second, expected);
+
+        assert_eq!(bases[1].subject, "First");
+        let stats = |hash: &str| {
+            let snapshot = bases[0]
+                .snapshots
+                .iter()
+                .find(|s| s.commit == hash)
+                .unwrap();
+            (
+                snapshot.files_changed,
+                snapshot.insertions,
+                snapshot.deletions,
+            )
+        };
+        assert_eq!(stats(&older.commit_hash), (1, 1, 2));
+        assert_eq!(stats(&newer.commit_hash), (1, 1, 0));
+    }
+}
@@ -0,0 +1,284 @@
+//! `dura capture --via-daemon`: asks the running `dura serve` to snapshot a repo right away,
+//! rather than opening everything again in a separate process.
+//!
+//! Requests are files in the `requests` directory next to the runtime lock. The client writes
+//! `<id>.request`, the daemon renames it to `<id>.taken`, captures the repo, writes
+//! `<id>.response` with the result and then deletes `<id>.taken`. A client that gives up deletes
+//! `<id>.taken` itself, so whichever side deletes it first decides whether the response counts.
+//! Files are written to a temporary name first and renamed, so neither side ever reads a half
+//! written file.
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::database::{self, RuntimeLock};
+use crate::snapshots::CaptureStatus;
+
+/// How often the client looks for its response
+const RESPONSE_POLL: Duration = Duration::from_millis(50);
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CaptureRequest {
+    pub path: PathBuf,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct CaptureResponse {
+    /// None when nothing changed since the last snapshot, or the capture failed
+    pub status: Option<CaptureStatus>,
+    pub error: Option<String>,
+}
+
+/// A request the daemon has taken, to be answered with `respond`
+#[derive(Debug)]
+pub struct PendingRequest {
+    pub request: CaptureRequest,
+    taken_path: PathBuf,
+    response_path: PathBuf,
+}
+
+impl PendingRequest {
+    /// Whether the client stopped waiting, or exited, so there's no point capturing
+    pub fn withdrawn(&self) -> bool {
+        let requester = self
+            .taken_path
+            .file_stem()
+            .and_then(|id| id.to_str()?.split('-').next()?.parse().ok());
+        !self.taken_path.exists() || requester.is_some_and(|pid| !database::pid_alive(pid))
+    }
+
+    /// Lets go of a withdrawn request without answering it
+    pub fn abandon(&self) {
+        let _ = fs::remove_file(&self.taken_path);
+    }
+
+    pub fn respond(&self, response: &CaptureResponse) -> io::Result<()> {
+        answer(&self.taken_path, &self.response_path, response)
+    }
+}
+
+/// Nothing is left behind when the client withdrew in the meantime
+fn answer(taken_path: &Path, response_path: &Path, response: &CaptureResponse) -> io::Result<()> {
+    write_atomic(response_path, response)?;
+    match fs::remove_file(taken_path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let _ = fs::remove_file(response_path);
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+pub fn default_dir() -> PathBuf {
+    RuntimeLock::default_path().with_file_name("requests")
+}
+
+fn write_atomic<T: Serialize>(path: &Path, value: &T) -> io::Result<()> {
+    let json = serde_json::to_vec(value)?;
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, json)?;
+    fs::rename(&tmp, path)
+}
+
+/// Asks the daemon to capture `path`. Gives up, and withdraws the request, if the daemon hasn't
+/// taken it within `pickup_timeout`, or hasn't answered within `capture_timeout` after that. In
+/// both cases the result is `Ok(None)`, and the daemon won't capture for it or answer it after.
+pub fn request_capture(
+    dir: &Path,
+    path: &Path,
+    pickup_timeout: Duration,
+    capture_timeout: Duration,
+) -> io::Result<Option<CaptureResponse>> {
+    fs::create_dir_all(dir)?;
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let id = format!("{}-{nanos}", std::process::id());
+    let request_path = dir.join(format!("{id}.request"));
+    let taken_path = dir.join(format!("{id}.taken"));
+    let response_path = dir.join(format!("{id}.response"));
+    let read_response = || -> io::Result<Option<CaptureResponse>> {
+        let json = match fs::read(&response_path) {
+            Ok(json) => json,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let _ = fs::remove_file(&response_path);
+        serde_json::from_slice(&json)
+            .map(Some)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    };
+    write_atomic(
+        &request_path,
+        &CaptureRequest {
+            path: path.to_path_buf(),
+        },
+    )?;
+
+    let started = Instant::now();
+    let mut timeout = pickup_timeout;
+    loop {
+        if let Some(response) = read_response()? {
+            return Ok(Some(response));
+        }
+        if started.elapsed() >= timeout {
+            if timeout == pickup_timeout {
+                match fs::remove_file(&request_path) {
+                    // The daemon took it and is still capturing, so give it longer
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                        timeout = pickup_timeout + capture_timeout;
+                    }
+                    _ => return Ok(None),
+                }
+            } else {
+                return match fs::remove_file(&taken_path) {
+                    // The daemon finished after all, its response is there
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => read_response(),
+                    // Withdrawn. It may have answered just now, before noticing.
+                    _ => {
+                        let _ = fs::remove_file(&response_path);
+                        Ok(None)
+                    }
+                };
+            }
+        }
+        std::thread::sleep(RESPONSE_POLL);
+    }
+}
+
+/// Takes every waiting request out of `dir`, oldest first. Unreadable requests are answered with
+/// an error right away.
+pub fn take_requests(dir: &Path) -> Vec<PendingRequest> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return vec![],
+    };
+    let mut paths: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "request"))
+        .collect();
+    paths.sort();
+
+    let mut pending = vec![];
+    for path in paths {
+        let taken_path = path.with_extension("taken");
+        // Failing to rename it means the client withdrew it in the meantime
+        if fs::rename(&path, &taken_path).is_err() {
+            continue;
+        }
+        let request = fs::read(&taken_path)
+            .map_err(|e| e.to_string())
+            .and_then(|json| serde_json::from_slice(&json).map_err(|e| e.to_string()));
+        let response_path = path.with_extension("response");
+        match request {
+            Ok(request) => pending.push(PendingRequest {
+                request,
+                taken_path,
+                response_path,
+            }),
+            Err(e) => {
+                let _ = answer(
+                    &taken_path,
+                    &response_path,
+                    &CaptureResponse {
+                        status: None,
+                        error: Some(format!("unreadable request: {e}")),
+                    },
+                );
+            }
+        }
+    }
+    pending
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_path_buf();
+        let daemon_dir = dir.clone();
+        let daemon = std::thread::spawn(move || loop {
+            if let Some(pending) = take_requests(&daemon_dir).pop() {
+                pending
+                    .respond(&CaptureResponse {
+                        status: None,
+                        error: Some(pending.request.path.display().to_string()),
+                    })
+                    .unwrap();
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        });
+
+        let response = request_capture(
+            &dir,
+            Path::new("/code/dura"),
+            Duration::from_secs(10),
+            Duration::from_secs(10),
+        )
+        .unwrap()
+        .unwrap();
+        daemon.join().unwrap();
+        assert_eq!(response.error.as_deref(), Some("/code/dura"));
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn withdrawn_when_nobody_answers() {
+        let tmp = tempfile::tempdir().unwrap();
+        let response = request_capture(
+            tmp.path(),
+            Path::new("/code/dura"),
+            Duration::from_millis(100),
+            Duration::from_secs(10),
+        )
+        .unwrap();
+        assert_eq!(response, None);
+        assert!(take_requests(tmp.path()).is_empty());
+    }
+
+    #[test]
+    fn withdrawn_after_pickup() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_path_buf();
+        let (taken_tx, taken_rx) = std::sync::mpsc::channel();
+        let (gave_up_tx, gave_up_rx) = std::sync::mpsc::channel::<()>();
+        let daemon_dir = dir.clone();
+        let daemon = std::thread::spawn(move || loop {
+            if let Some(pending) = take_requests(&daemon_dir).pop() {
+                taken_tx.send(()).unwrap();
+                // Still capturing when the client gives up
+                gave_up_rx.recv().unwrap();
+                assert!(pending.withdrawn());
+                pending
+                    .respond(&CaptureResponse {
+                        status: None,
+                        error: None,
+                    })
+                    .unwrap();
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        });
+
+        let response = request_capture(
+            &dir,
+            Path::new("/code/dura"),
+            Duration::from_millis(500),
+            Duration::from_millis(200),
+        )
+        .unwrap();
+        taken_rx.recv().unwrap();
+        gave_up_tx.send(()).unwrap();
+        daemon.join().unwrap();
+        assert_eq!(response, None);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+    }
+}
@@ -0,0 +1,539 @@
+mod util;
+
+use crate::util::dura::Dura;
+use dura::config::Config;
+use std::fs;
+use std::process::Output;
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8(output.stderr.clone()).unwrap()
+}
+
+#[test]
+fn metrics_missing_input() {
+    let dura = Dura::new();
+    let output = dura.run_output(&["metrics", "-i", "/nonexistent/dura.log"]);
+
+    assert_eq!(output.status.code(), Some(1));
+    let err = stderr(&output);
+    assert!(err.starts_with("dura: Couldn't open '/nonexistent/dura.log'"));
+    assert_eq!(err.lines().count(), 1);
+}
+
+#[test]
+fn watch_invalid_max_depth() {
+    let tmp = tempfile::tempdir().unwrap();
+    let dura = Dura::new();
+    let output = dura.run_output(&["watch", tmp.path().to_str().unwrap(), "-d", "300"]);
+
+    assert_eq!(output.status.code(), Some(2));
+    assert!(dura.get_config().is_none());
+}
+
+#[test]
+fn unwatch_broken_config() {
+    let tmp = tempfile::tempdir().unwrap();
+    let dura = Dura::new();
+    let config_path = dura.config_path();
+    Config::create_dir(config_path.as_path()).unwrap();
+    fs::write(&config_path, "repos = [not toml").unwrap();
+
+    let output = dura.run_output(&["unwatch", tmp.path().to_str().unwrap()]);
+
+    assert_eq!(output.status.code(), Some(3));
+    assert!(stderr(&output).starts_with("dura: Couldn't parse"));
+    // Left alone so it can be fixed by hand
+    assert_eq!(
+        fs::read_to_string(config_path).unwrap(),
+        "repos = [not toml"
+    );
+}
+
+#[test]
+fn config_check_finds_typos() {
+    let dura = Dura::new();
+    let config_path = dura.config_path();
+    Config::create_dir(config_path.as_path()).unwrap();
+    fs::write(
+        &config_path,
+        "debounce_ms = 500\nmin_snapshot_intreval_secs = 60\n[repos]\n",
+    )
+    .unwrap();
+
+    let output = dura.run_output(&["config", "check"]);
+    assert_eq!(output.status.code(), Some(3));
+    assert!(stderr(&output).contains("unknown settings: min_snapshot_intreval_secs"));
+
+    // Everything else only warns, once
+    let output = dura.run_output(&["unwatch", "/nowhere"]);
+    assert_eq!(
+        stderr(&output).matches("ignoring unknown settings").count(),
+        1
+    );
+
+    fs::write(&config_path, "debounce_ms = 500\n[repos]\n").unwrap();
+    let output = dura.run_output(&["config", "check"]);
+    assert_eq!(output.status.code(), Some(0));
+
+    // Hand-edited entries that could never match
+    fs::write(
+        &config_path,
+        "[repos.\"/code\"]\ninclude = [\"/abs\"]\nexclude = [\"target\", \"../other\"]\nmax_depth = 255\n",
+    )
+    .unwrap();
+    let output = dura.run_output(&["config", "check"]);
+    assert_eq!(output.status.code(), Some(3));
+    assert!(
+        stderr(&output).contains("/code: '/abs' must be relative to the watch directory"),
+        "{}",
+        stderr(&output)
+    );
+    assert!(stderr(&output).contains("/code: '../other' '..' would leave the watch directory"));
+    assert!(!stderr(&output).contains("'target'"));
+}
+
+#[test]
+fn diagnostics_go_to_stderr() {
+    let tmp = tempfile::tempdir().unwrap();
+    let dir = tmp.path().to_str().unwrap();
+    let dura = Dura::new();
+    let config_path = dura.config_path();
+    Config::create_dir(config_path.as_path()).unwrap();
+    fs::write(&config_path, "min_snapshot_intreval_secs = 60\n[repos]\n").unwrap();
+
+    let output = dura.run_output(&["watch", dir, "--include", "missing"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let err = stderr(&output);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.starts_with("Started watching"), "{stdout}");
+    assert!(!stdout.contains("warning"), "{stdout}");
+    assert!(
+        err.contains("dura: warning: ignoring unknown settings"),
+        "{err}"
+    );
+    assert!(
+        err.contains("dura: warning: 'missing' doesn't exist"),
+        "{err}"
+    );
+
+    // Results still come through, errors too, but nothing else
+    let output = dura.run_output(&["--quiet", "unwatch", dir]);
+    assert!(output.status.success());
+    assert_eq!(stderr(&output), "");
+    assert!(String::from_utf8(output.stdout)
+        .unwrap()
+        .starts_with("Stopped watching"));
+    let output = dura.run_output(&["unwatch", "-q", "--no-color", "--bogus"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(stderr(&output).contains("--bogus"));
+    assert!(!stderr(&output).contains('\x1b'));
+}
+
+#[test]
+fn guard_snapshots_before_and_after() {
+    let tmp = tempfile::tempdir().unwrap();
+    let mut repo = util::git_repo::GitRepo::new(tmp.path().to_path_buf());
+    repo.init();
+    repo.write_file("foo.txt");
+    repo.commit_all();
+    repo.change_file("foo.txt");
+    let dura = Dura::new();
+
+    let output = dura.run_output_in_dir(
+        &["guard", "--", "sh", "-c", "echo more >> foo.txt; exit 3"],
+        &repo.dir,
+    );
+    assert_eq!(output.status.code(), Some(3));
+    let err = stderr(&output);
+    let hash = |label: &str| {
+        err.lines()
+            .find_map(|line| line.strip_prefix(&format!("dura: {label}: ")))
+            .unwrap_or_else(|| panic!("no {label} in {err}"))
+            .to_string()
+    };
+    let (before, after) = (hash("before"), hash("after"));
+    assert_ne!(before, after);
+    assert_eq!(
+        repo.git(&["rev-parse", &format!("{after}^")])
+            .unwrap()
+            .trim(),
+        before
+    );
+    assert_eq!(
+        repo.git(&["show", &format!("{after}:foo.txt")]).unwrap(),
+        fs::read_to_string(repo.dir.join("foo.txt")).unwrap()
+    );
+}
+
+#[test]
+fn guard_snapshots_on_skipped_branches() {
+    let tmp = tempfile::tempdir().unwrap();
+    let mut repo = util::git_repo::GitRepo::new(tmp.path().to_path_buf());
+    repo.init();
+    repo.write_file("foo.txt");
+    repo.commit_all();
+    repo.change_file("foo.txt");
+    let dura = Dura::new();
+    dura.run_in_dir(&["watch"], &repo.dir);
+    let mut config = dura.get_config().unwrap();
+    for watch in config.repos.values_mut() {
+        std::rc::Rc::make_mut(watch).skip_on_branches = vec!["*".to_string()];
+    }
+    dura.save_config(&config);
+
+    let output = dura.run_output_in_dir(&["guard", "--", "true"], &repo.dir);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let err = stderr(&output);
+    let before = err
+        .lines()
+        .find_map(|line| line.strip_prefix("dura: before: "))
+        .unwrap_or_else(|| panic!("no before in {err}"));
+    // The uncommitted change is in it, not just HEAD
+    assert_eq!(
+        repo.git(&["show", &format!("{before}:foo.txt")]).unwrap(),
+        fs::read_to_string(repo.dir.join("foo.txt")).unwrap()
+    );
+}
+
+#[test]
+fn init_twice() {
+    let tmp = tempfile::tempdir().unwrap();
+    let mut repo = util::git_repo::GitRepo::new(tmp.path().to_path_buf());
+    repo.init();
+    repo.write_file("foo.txt");
+    repo.commit_all();
+    repo.change_file("foo.txt");
+    let dura = Dura::new();
+    let dir = tmp.path().to_str().unwrap();
+
+    for _ in 0..2 {
+        let output = dura.run_output(&["init", dir, "--no-service"]);
+        assert!(output.status.success(), "{}", stderr(&output));
+    }
+
+    assert_eq!(dura.get_config().unwrap().repos.len(), 1);
+    assert!(!repo
+        .git(&["branch", "--list", "dura/*"])
+        .unwrap()
+        .is_empty());
+}
+
+#[test]
+fn version_verbose() {
+    let dura = Dura::new();
+    let output = dura.run_output(&["--version", "--verbose"]);
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    for field in ["commit:", "build date:", "target:", "rustc:"] {
+        let line = stdout
+            .lines()
+            .find(|line| line.starts_with(field))
+            .unwrap_or_else(|| panic!("no {field} in {stdout}"));
+        assert!(!line[field.len()..].trim().is_empty(), "{line}");
+    }
+}
+
+#[test]
+fn config_export_import_across_homes() {
+    let home1 = tempfile::tempdir().unwrap();
+    let home2 = tempfile::tempdir().unwrap();
+    for home in [&home1, &home2] {
+        fs::create_dir_all(home.path().join("code/app")).unwrap();
+    }
+    let app1 = home1.path().join("code/app").canonicalize().unwrap();
+    let app2 = home2.path().join("code/app").canonicalize().unwrap();
+    let home1_path = home1.path().canonicalize().unwrap();
+    let home2_path = home2.path().canonicalize().unwrap();
+
+    let dura1 = Dura::new();
+    dura1.run(&["watch", app1.to_str().unwrap(), "-e", "target"]);
+    let output = dura1.run_output_with_env(&["config", "export"], &[("HOME", &home1_path)]);
+    assert!(output.status.success());
+    let exported = String::from_utf8(output.stdout).unwrap();
+    assert!(exported.contains("\"~/code/app\""), "{exported}");
+    let bundle = home1.path().join("dura.toml");
+    fs::write(&bundle, exported).unwrap();
+
+    // The other machine already watches the same repo, but differently
+    let dura2 = Dura::new();
+    dura2.run(&["watch", app2.to_str().unwrap(), "-e", "build"]);
+    let import = ["config", "import", bundle.to_str().unwrap()];
+    let output = dura2.run_output_with_env(&import, &[("HOME", &home2_path)]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("- exclude: build"), "{stdout}");
+    assert!(stdout.contains("+ exclude: target"), "{stdout}");
+
+    let cfg = dura2.get_config().unwrap();
+    assert_eq!(cfg.repos.len(), 1);
+    let watch = cfg.repos.get(app2.to_str().unwrap()).unwrap();
+    assert_eq!(watch.exclude, vec!["target".to_string()]);
+
+    // Importing again is a no-op
+    let output = dura2.run_output_with_env(&import, &[("HOME", &home2_path)]);
+    assert!(String::from_utf8(output.stdout)
+        .unwrap()
+        .contains("Nothing changed"));
+}
+
+#[test]
+fn summary_flags_unsnapshotted_repo() {
+    let tmp = tempfile::tempdir().unwrap();
+    let mut saved = util::git_repo::GitRepo::new(tmp.path().join("saved"));
+    let mut unsaved = util::git_repo::GitRepo::new(tmp.path().join("unsaved"));
+    for repo in [&mut saved, &mut unsaved] {
+        repo.init();
+        repo.write_file("foo.txt");
+        repo.commit_all();
+    }
+    std::thread::sleep(std::time::Duration::from_secs_f64(1.5));
+    saved.change_file("foo.txt");
+    let dura = Dura::new();
+    dura.run(&["capture", saved.dir.to_str().unwrap()]);
+    unsaved.change_file("foo.txt");
+    dura.run(&["watch", tmp.path().to_str().unwrap()]);
+
+    let output = dura.run_output(&["summary", "--json", "--max-age", "1h"]);
+    assert_eq!(output.status.code(), Some(1));
+    let json: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let repos = json.as_array().unwrap();
+    assert_eq!(repos.len(), 2);
+    // Never snapshotted, so it comes first
+    assert!(repos[0]["repo"].as_str().unwrap().ends_with("unsaved"));
+    assert_eq!(repos[0]["last_snapshot"], serde_json::Value::Null);
+    assert_eq!(repos[0]["stale"], true);
+    assert!(repos[1]["repo"].as_str().unwrap().ends_with("saved"));
+    assert!(repos[1]["last_snapshot"].is_string());
+    assert_eq!(repos[1]["stale"], false);
+
+    // Without --max-age it's only a report
+    let output = dura.run_output(&["summary"]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.lines().next().unwrap().starts_with("   never"));
+}
+
+#[test]
+fn identity_shows_where_it_came_from() {
+    let tmp = tempfile::tempdir().unwrap();
+    let repo = util::git_repo::GitRepo::new(tmp.path().join("repo"));
+    repo.init();
+    repo.write_file("foo.txt");
+    repo.commit_all();
+    let dura = Dura::new();
+    dura.run(&["watch", tmp.path().to_str().unwrap()]);
+
+    let output = dura.run_output(&["identity", "--json"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let json: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(json[0]["name"], "duratest");
+    assert_eq!(json[0]["name_source"], "git_config");
+
+    let mut config = dura.get_config().unwrap();
+    config.commit_exclude_git_config = true;
+    config.require_identity = true;
+    dura.save_config(&config);
+    let output = dura.run_output(&["identity"]);
+    assert_eq!(output.status.code(), Some(1));
+    let stdout = String::from_utf8(output.stdout.clone()).unwrap();
+    assert!(
+        stdout.ends_with("  dura <dura@github.io> from fallback\n"),
+        "{stdout}"
+    );
+    assert!(stderr(&output).contains("won't be snapshotted"));
+}
+
+#[test]
+fn bench_doesnt_snapshot() {
+    let tmp = tempfile::tempdir().unwrap();
+    let mut repo = util::git_repo::GitRepo::new(tmp.path().join("repo"));
+    repo.init();
+    repo.write_file("foo.txt");
+    repo.commit_all();
+    repo.change_file("foo.txt");
+    let dura = Dura::new();
+    dura.run(&["watch", tmp.path().to_str().unwrap()]);
+
+    let output = dura.run_output(&[
+        "bench",
+        repo.dir.to_str().unwrap(),
+        "--iterations",
+        "3",
+        "--json",
+    ]);
+    assert!(output.status.success());
+    let json: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let names: Vec<&str> = json
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|r| r["name"].as_str().unwrap())
+        .collect();
+    assert_eq!(
+        names,
+        vec![
+            "discovery_cold",
+            "discovery_warm",
+            "poll_guard_cold",
+            "poll_guard_warm",
+            "poll_guard_warm_no_precheck",
+            "capture_dry_run"
+        ]
+    );
+    assert_eq!(json[5]["samples"], 3);
+    assert_eq!(repo.git(&["for-each-ref", "refs/heads/dura"]).unwrap(), "");
+}
+
+#[test]
+fn redacted_logs_still_give_metrics() {
+    let tmp = tempfile::tempdir().unwrap();
+    let mut repo = util::git_repo::GitRepo::new(tmp.path().join("acme-client"));
+    repo.init();
+    repo.write_file("foo.txt");
+    repo.commit_all();
+    let dura = Dura::new();
+    dura.run(&["watch", repo.dir.to_str().unwrap()]);
+    let mut cfg = dura.get_config().unwrap();
+    cfg.log_redact_paths = dura::redact::RedactPaths::Hash;
+    dura.save_config(&cfg);
+    std::thread::sleep(std::time::Duration::from_secs_f64(1.5));
+    repo.change_file("foo.txt");
+
+    let log = tmp.path().join("dura.log");
+    dura.run(&["serve", "--once", "--logfile", log.to_str().unwrap()]);
+    let logged = fs::read_to_string(&log).unwrap();
+    let repo_path = repo.dir.canonicalize().unwrap();
+    let token = dura::redact::hash(&repo_path);
+    assert!(logged.contains(&token), "{logged}");
+    assert!(!logged.contains("acme-client"), "{logged}");
+
+    let output = dura.run_output(&["metrics", "-i", log.to_str().unwrap()]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let metrics: serde_json::Value =
+        serde_json::from_str(String::from_utf8(output.stdout).unwrap().trim()).unwrap();
+    assert_eq!(metrics["repo"], token.as_str());
+    assert!(metrics["time_unix_ms"].is_i64(), "{metrics}");
+    // Logged right after the commit, on the same clock
+    let skew = metrics["clock_skew_ms"].as_i64().unwrap();
+    assert!((0..5000).contains(&skew), "{metrics}");
+
+    let output = dura.run_output(&["redact-map"]);
+    let map = String::from_utf8(output.stdout).unwrap();
+    assert!(
+        map.contains(&format!("{token}\t{}", repo_path.display())),
+        "{map}"
+    );
+}
+
+#[test]
+fn history_keeps_latest_snapshots() {
+    let tmp = tempfile::tempdir().unwrap();
+    let mut repo = util::git_repo::GitRepo::new(tmp.path().join("repo"));
+    repo.init();
+    repo.write_file("foo.txt");
+    repo.commit_all();
+    let dura = Dura::new();
+    dura.run(&["watch", tmp.path().to_str().unwrap()]);
+    let mut config = dura.get_config().unwrap();
+    config.history_size = 2;
+    dura.save_config(&config);
+
+    for _ in 0..3 {
+        std::thread::sleep(std::time::Duration::from_secs_f64(1.1));
+        repo.change_file("foo.txt");
+        dura.run(&["serve", "--once"]);
+    }
+
+    let output = dura.run_output(&["history", repo.dir.to_str().unwrap(), "--json"]);
+    assert!(output.status.success());
+    let json: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let entries = json.as_array().unwrap();
+    assert_eq!(entries.len(), 2);
+    let head = repo.git(&["rev-parse", "HEAD"]).unwrap();
+    let newest = repo
+        .git(&["rev-parse", &format!("dura/{}", head.trim())])
+        .unwrap();
+    assert_eq!(entries[0]["commit_hash"], newest.trim());
+    let previous = repo
+        .git(&["rev-parse", &format!("{}^", newest.trim())])
+        .unwrap();
+    assert_eq!(entries[1]["commit_hash"], previous.trim());
+}
+
+#[test]
+fn find_file_in_snapshots() {
+    let tmp = tempfile::tempdir().unwrap();
+    let mut repo = util::git_repo::GitRepo::new(tmp.path().to_path_buf());
+    repo.init();
+    repo.write_file("foo.txt");
+    repo.commit_all();
+    fs::create_dir(repo.dir.join("notes")).unwrap();
+    fs::write(repo.dir.join("notes/todo.txt"), "never committed").unwrap();
+    let dura = Dura::new();
+    let path = repo.dir.clone();
+    let dir = path.to_str().unwrap();
+    dura.run(&["capture", dir]);
+    repo.change_file("foo.txt");
+    dura.run(&["capture", dir]);
+    fs::remove_dir_all(repo.dir.join("notes")).unwrap();
+    repo.change_file("foo.txt");
+    dura.run(&["capture", dir]);
+
+    let output = dura.run_output(&["find-file", "todo.txt", dir, "--json"]);
+    assert!(output.status.success());
+    let json: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let found = json.as_array().unwrap();
+    assert_eq!(found.len(), 2);
+    assert!(found
+        .iter()
+        .all(|f| f["path"] == "notes/todo.txt" && f["size"] == 15));
+    let head = repo.git(&["rev-parse", "HEAD"]).unwrap();
+    let snapshots = repo
+        .git(&["log", "--format=%H", &format!("dura/{}", head.trim())])
+        .unwrap();
+    let snapshots: Vec<&str> = snapshots.lines().collect();
+    // The newest snapshot no longer has it
+    assert_eq!(found[0]["commit"], snapshots[1]);
+    assert_eq!(found[1]["commit"], snapshots[2]);
+
+    let output = dura.run_output(&["find-file", "[", dir]);
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn config_show_says_where_settings_come_from() {
+    let tmp = tempfile::tempdir().unwrap();
+    let repo = util::git_repo::GitRepo::new(tmp.path().join("repo"));
+    repo.init();
+    let dura = Dura::new();
+    dura.run(&["watch", tmp.path().to_str().unwrap()]);
+    let mut config = dura.get_config().unwrap();
+    config.commit_author = Some("Me".to_string());
+    config.min_snapshot_interval_secs = Some(60);
+    let root = config.repos.keys().next().unwrap().clone();
+    let mut watch = (*config.repos[&root]).clone();
+    watch.min_snapshot_interval_secs = Some(300);
+    config.repos.insert(root.clone(), std::rc::Rc::new(watch));
+    dura.save_config(&config);
+
+    let dir = repo.dir.to_str().unwrap();
+    let output = dura.run_output(&["config", "show", dir, "--interval", "2", "--json"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let json: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(json["watch_root"], root.as_str());
+    assert_eq!(json["commit_author"]["value"], "Me");
+    assert_eq!(json["commit_author"]["source"], "global");
+    assert_eq!(json["commit_email"]["source"], "default");
+    assert_eq!(json["min_snapshot_interval_secs"]["value"], 300);
+    assert_eq!(json["min_snapshot_interval_secs"]["source"], "watch");
+    assert_eq!(json["poll_interval_secs"]["value"], 2);
+    assert_eq!(json["poll_interval_secs"]["source"], "cli");
+    assert_eq!(json["sensitive_files"]["source"], "default");
+
+    let output = dura.run_output(&["config", "show", dir]);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains(&format!("watch: {root}\n")), "{stdout}");
+    assert!(stdout.contains(" = Me  (config file)\n"), "{stdout}");
+    assert!(stdout.contains(" = 5  (default)\n"), "{stdout}");
+}
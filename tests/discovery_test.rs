@@ -0,0 +1,201 @@
+mod util;
+
+use crate::util::daemon;
+use crate::util::dura::Dura;
+use crate::util::git_repo::GitRepo;
+
+/// How many lines to read from the daemon before giving up on an event
+const MAX_LINES: usize = 20;
+
+#[test]
+fn repo_discovered_mid_run() {
+    let tmp = tempfile::tempdir().unwrap();
+    let repo1 = GitRepo::new(tmp.path().join("repo1"));
+    repo1.init();
+
+    let mut dura = Dura::new();
+    dura.run_in_dir(&["watch"], tmp.path());
+    dura.start_async(&["serve"], true);
+    let daemon = dura.primary.as_ref().unwrap();
+
+    // wait for the first loop to finish before adding another repo
+    let discovered = |repo: &GitRepo| {
+        let path = repo.dir.canonicalize().unwrap();
+        let path = path.to_str().unwrap().to_string();
+        daemon.wait_for_event(
+            |e| daemon::message(e) == "repo_discovered" && e["fields"]["repo"] == path,
+            15,
+        )
+    };
+    assert!(
+        discovered(&repo1).is_some(),
+        "no repo_discovered event for repo1"
+    );
+
+    let repo2 = GitRepo::new(tmp.path().join("repo2"));
+    repo2.init();
+    assert!(
+        discovered(&repo2).is_some(),
+        "no repo_discovered event for repo2"
+    );
+}
+
+/// Pulls `fields.<name>` out of a log line, if it's there
+fn field(line: &str, name: &str) -> Option<String> {
+    let json: serde_json::Value = serde_json::from_str(line).ok()?;
+    match &json["fields"][name] {
+        serde_json::Value::String(s) => Some(s.clone()),
+        _ => None,
+    }
+}
+
+fn time(line: &str) -> chrono::DateTime<chrono::FixedOffset> {
+    let json: serde_json::Value = serde_json::from_str(line).unwrap();
+    chrono::DateTime::parse_from_rfc3339(json["time"].as_str().unwrap()).unwrap()
+}
+
+#[test]
+fn active_repo_processed_first() {
+    let tmp = tempfile::tempdir().unwrap();
+    let mut repos = vec![];
+    for name in ["a", "b", "c"] {
+        let repo = GitRepo::new(tmp.path().join(name));
+        repo.init();
+        repo.write_file("foo.txt");
+        repo.commit_all();
+        repos.push(repo);
+    }
+    let mut dura = Dura::new();
+    dura.run_in_dir(&["watch"], tmp.path());
+
+    // Edit whichever repo would otherwise be visited last
+    let discovered: Vec<_> = dura.get_config().unwrap().git_repos().collect();
+    let active_path = discovered.last().unwrap().clone();
+    let active_str = active_path.to_str().unwrap().to_string();
+    let active = repos
+        .iter_mut()
+        .find(|repo| repo.dir.canonicalize().unwrap() == active_path)
+        .unwrap();
+    std::thread::sleep(std::time::Duration::from_secs_f64(1.1));
+    active.change_file("foo.txt");
+
+    // Long enough that the stagger between repos is easy to tell apart
+    dura.start_async_with_env(
+        &["serve", "--interval", "6"],
+        true,
+        &[("RUST_LOG", "dura::poller=trace")],
+    );
+    let daemon = dura.primary.as_ref().unwrap();
+
+    let mut snapshots = 0;
+    let mut loop_start = None;
+    for _ in 0..500 {
+        let line = match daemon.read_line(20) {
+            Some(line) => line,
+            None => break,
+        };
+        if line.contains("\"loop_order\"") {
+            loop_start = Some(line);
+            continue;
+        }
+        if !line.contains("\"Snapshot\"") || !line.contains(&active_str) {
+            continue;
+        }
+        snapshots += 1;
+        if snapshots > 1 {
+            // Once it has been seen changing, it's first and gets captured right away
+            let start = loop_start.as_ref().unwrap();
+            assert_eq!(field(start, "first").as_deref(), Some(active_str.as_str()));
+            let delay = time(&line) - time(start);
+            assert!(delay < chrono::Duration::seconds(1), "took {delay}");
+        }
+        if snapshots == 3 {
+            return;
+        }
+        std::thread::sleep(std::time::Duration::from_secs_f64(1.1));
+        active.change_file("foo.txt");
+    }
+    panic!("only saw {snapshots} snapshots of {active_str}");
+}
+
+#[test]
+fn missing_watch_root_is_flagged() {
+    let tmp = tempfile::tempdir().unwrap();
+    let kept = GitRepo::new(tmp.path().join("kept"));
+    kept.init();
+    let doomed = GitRepo::new(tmp.path().join("doomed"));
+    doomed.init();
+
+    let mut dura = Dura::new();
+    dura.run(&[
+        "watch",
+        kept.dir.to_str().unwrap(),
+        doomed.dir.to_str().unwrap(),
+    ]);
+    dura.start_async(&["serve", "--interval", "1"], true);
+    let daemon = dura.primary.as_ref().unwrap();
+    let doomed_str = doomed.dir.canonicalize().unwrap().display().to_string();
+    let found = (0..MAX_LINES)
+        .map_while(|_| daemon.read_line(15))
+        .any(|line| line.contains("repo_discovered") && line.contains(&doomed_str));
+    assert!(found, "no repo_discovered event for {doomed_str}");
+
+    std::fs::remove_dir_all(&doomed.dir).unwrap();
+    let found = (0..MAX_LINES)
+        .map_while(|_| daemon.read_line(15))
+        .any(|line| line.contains("watch_root_missing") && line.contains(&doomed_str));
+    assert!(found, "no watch_root_missing event for {doomed_str}");
+
+    // Warned once, and it stays watched without auto_unwatch_missing_after_days
+    let repeated = (0..3)
+        .map_while(|_| daemon.read_line(15))
+        .any(|line| line.contains("watch_root_missing"));
+    assert!(!repeated);
+    assert!(dura.get_config().unwrap().repos.contains_key(&doomed_str));
+    let output = dura.run_output(&["summary"]);
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains(&format!("dura: {doomed_str} doesn't exist anymore")),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn long_missing_watch_root_is_unwatched() {
+    let tmp = tempfile::tempdir().unwrap();
+    let kept = GitRepo::new(tmp.path().join("kept"));
+    kept.init();
+    let doomed = tmp.path().join("doomed");
+    std::fs::create_dir(&doomed).unwrap();
+
+    let mut dura = Dura::new();
+    dura.run(&[
+        "watch",
+        kept.dir.to_str().unwrap(),
+        doomed.to_str().unwrap(),
+    ]);
+    let doomed_str = doomed.canonicalize().unwrap().display().to_string();
+    std::fs::remove_dir(&doomed).unwrap();
+    let mut config = dura.get_config().unwrap();
+    config.auto_unwatch_missing_after_days = Some(7);
+    dura.save_config(&config);
+    // As if an earlier run had noticed it ten days ago
+    let since = chrono::Utc::now() - chrono::Duration::days(10);
+    let record = serde_json::json!({ "since": { &doomed_str: since.to_rfc3339() } });
+    std::fs::write(
+        dura.runtime_lock_path()
+            .with_file_name("missing_roots.json"),
+        record.to_string(),
+    )
+    .unwrap();
+
+    dura.start_async(&["serve", "--interval", "1"], true);
+    let daemon = dura.primary.as_ref().unwrap();
+    let found = (0..MAX_LINES)
+        .map_while(|_| daemon.read_line(15))
+        .any(|line| line.contains("watch_root_unwatched") && line.contains(&doomed_str));
+    assert!(found, "no watch_root_unwatched event for {doomed_str}");
+    let repos = dura.get_config().unwrap().repos;
+    assert!(!repos.contains_key(&doomed_str));
+    assert_eq!(repos.len(), 1);
+}
@@ -0,0 +1,209 @@
+mod util;
+
+use dura::compact::{self, CompactOptions, Stage, StageOutcome};
+use dura::maintenance::{self, GcOutcome};
+use dura::snapshots;
+use git2::{Oid, Repository, Signature, Time};
+use std::fs;
+use std::path::Path;
+
+#[test]
+fn gc_removes_cleaned_snapshots() {
+    let tmp = tempfile::tempdir().unwrap();
+    let mut repo = repo_and_file!(tmp, "foo.txt");
+    // Prune unreachable objects straight away rather than after two weeks
+    repo.set_config("gc.pruneExpire", "now");
+
+    repo.change_file("foo.txt");
+    let status = snapshots::capture(repo.dir.as_path()).unwrap().unwrap();
+    repo.commit_all();
+    assert_eq!(
+        snapshots::clean_merged(repo.dir.as_path()).unwrap().len(),
+        1
+    );
+
+    let outcome = maintenance::gc(repo.dir.as_path()).unwrap();
+
+    assert!(matches!(outcome, GcOutcome::Ran { .. }));
+    let git = Repository::open(repo.dir.as_path()).unwrap();
+    let snapshot = Oid::from_str(&status.commit_hash).unwrap();
+    assert!(!git.odb().unwrap().exists(snapshot));
+}
+
+#[test]
+fn gc_skipped_while_repo_busy() {
+    let tmp = tempfile::tempdir().unwrap();
+    let repo = repo_and_file!(tmp, "foo.txt");
+    fs::write(repo.dir.join(".git/index.lock"), "").unwrap();
+
+    let outcome = maintenance::gc(repo.dir.as_path()).unwrap();
+
+    assert_eq!(outcome, GcOutcome::Skipped("index.lock"));
+}
+
+/// Recommits the snapshot at `branch` as if it was taken `days` ago
+fn backdate(dir: &Path, branch: &str, days: i64) {
+    let git = Repository::open(dir).unwrap();
+    let reference = format!("refs/heads/{branch}");
+    let snapshot = git
+        .find_reference(&reference)
+        .unwrap()
+        .peel_to_commit()
+        .unwrap();
+    let when = Time::new(chrono::Utc::now().timestamp() - days * 24 * 60 * 60, 0);
+    let sig = Signature::new("dura", "dura@github.io", &when).unwrap();
+    let parents: Vec<_> = snapshot.parents().collect();
+    let parents: Vec<_> = parents.iter().collect();
+    let tree = snapshot.tree().unwrap();
+    let old = git
+        .commit(
+            None,
+            &sig,
+            &sig,
+            snapshots::SNAPSHOT_MESSAGE,
+            &tree,
+            &parents,
+        )
+        .unwrap();
+    git.reference(&reference, old, true, "backdate").unwrap();
+}
+
+#[test]
+fn compact_skips_refs_to_missing_objects() {
+    let tmp = tempfile::tempdir().unwrap();
+    let mut repo = repo_and_file!(tmp, "foo.txt");
+    let dir = repo.dir.clone();
+    repo.change_file("foo.txt");
+    let old = snapshots::capture(&dir).unwrap().unwrap().dura_branch;
+    backdate(&dir, &old, 60);
+    let dangling = "refs/heads/dura/1111111111111111111111111111111111111111";
+    fs::write(
+        dir.join(".git").join(dangling),
+        "2222222222222222222222222222222222222222\n",
+    )
+    .unwrap();
+    let options = CompactOptions {
+        keep_days: 30,
+        keep_stash_days: 30,
+        dry_run: false,
+        repack: false,
+    };
+
+    let report = compact::compact(&dir, &options).unwrap();
+
+    assert_eq!(report.stages[0].result, Ok(StageOutcome::Deleted(1)));
+    assert_eq!(report.unreadable, vec![dangling.to_string()]);
+    assert!(repo.git(&["rev-parse", "--verify", &old]).is_none());
+}
+
+#[test]
+fn compact_leaves_user_dura_branches_alone() {
+    let tmp = tempfile::tempdir().unwrap();
+    let repo = repo_and_file!(tmp, "foo.txt");
+    let dir = repo.dir.clone();
+    // A user's branch that just happens to live under dura/, with an old commit on it
+    repo.git(&["branch", "dura/experiment"]).unwrap();
+    let git = Repository::open(&dir).unwrap();
+    let head = git.head().unwrap().peel_to_commit().unwrap();
+    let when = Time::new(chrono::Utc::now().timestamp() - 2000 * 24 * 60 * 60, 0);
+    let sig = Signature::new("me", "me@example.com", &when).unwrap();
+    let old = git
+        .commit(
+            None,
+            &sig,
+            &sig,
+            "experiment",
+            &head.tree().unwrap(),
+            &[&head],
+        )
+        .unwrap();
+    git.reference("refs/heads/dura/experiment", old, true, "test")
+        .unwrap();
+    // Named like a snapshot branch, but the user committed on top of it
+    let lookalike = format!("dura/{}", head.id());
+    git.reference(&format!("refs/heads/{lookalike}"), old, false, "test")
+        .unwrap();
+    let options = CompactOptions {
+        keep_days: 30,
+        keep_stash_days: 30,
+        dry_run: false,
+        repack: false,
+    };
+
+    let report = compact::compact(&dir, &options).unwrap();
+
+    assert_eq!(report.stages[0].result, Ok(StageOutcome::Deleted(0)));
+    assert_eq!(report.before.branches, 1);
+    assert!(repo
+        .git(&["rev-parse", "--verify", "dura/experiment"])
+        .is_some());
+    assert!(repo.git(&["rev-parse", "--verify", &lookalike]).is_some());
+}
+
+#[test]
+fn compact_prunes_old_and_merged_snapshots() {
+    let tmp = tempfile::tempdir().unwrap();
+    let mut repo = repo_and_file!(tmp, "foo.txt");
+    let dir = repo.dir.clone();
+    let mut branches = vec![];
+    // Old, so pruned
+    repo.change_file("foo.txt");
+    branches.push(snapshots::capture(&dir).unwrap().unwrap().dura_branch);
+    backdate(&dir, &branches[0], 60);
+    repo.commit_all();
+    // Old but pinned, so kept
+    repo.change_file("foo.txt");
+    let status = snapshots::capture(&dir).unwrap().unwrap();
+    branches.push(status.dura_branch);
+    backdate(&dir, &branches[1], 60);
+    snapshots::pin(&dir, &branches[1], "keep").unwrap();
+    repo.write_file("bar.txt");
+    repo.commit_all();
+    // New, but then committed for real
+    repo.change_file("foo.txt");
+    branches.push(snapshots::capture(&dir).unwrap().unwrap().dura_branch);
+    repo.commit_all();
+    // New, so kept
+    repo.change_file("foo.txt");
+    branches.push(snapshots::capture(&dir).unwrap().unwrap().dura_branch);
+    let exists = |branch: &String| repo.git(&["rev-parse", "--verify", branch]).is_some();
+
+    let mut options = CompactOptions {
+        keep_days: 30,
+        keep_stash_days: 30,
+        dry_run: true,
+        repack: true,
+    };
+    let dry_run = compact::compact(&dir, &options).unwrap();
+
+    assert!(branches.iter().all(exists));
+    assert_eq!(dry_run.before.branches, 4);
+    assert_eq!(dry_run.after.as_ref().unwrap().branches, 2);
+    let outcomes: Vec<_> = dry_run
+        .stages
+        .iter()
+        .map(|stage| (stage.stage, stage.result.clone()))
+        .collect();
+    assert_eq!(
+        outcomes,
+        vec![
+            (Stage::Prune, Ok(StageOutcome::Deleted(1))),
+            (Stage::CleanMerged, Ok(StageOutcome::Deleted(1))),
+            (Stage::Gc, Ok(StageOutcome::DryRun)),
+            (Stage::Repack, Ok(StageOutcome::DryRun)),
+        ]
+    );
+
+    options.dry_run = false;
+    let report = compact::compact(&dir, &options).unwrap();
+
+    assert!(!report.failed(), "{report:?}");
+    assert_eq!(report.before, dry_run.before);
+    assert_eq!(report.after, dry_run.after);
+    let kept: Vec<bool> = branches.iter().map(exists).collect();
+    assert_eq!(kept, vec![false, true, false, true]);
+    assert!(matches!(
+        report.stages[2].result,
+        Ok(StageOutcome::Ran(GcOutcome::Ran { .. }))
+    ));
+}
@@ -1,4 +1,4 @@
-use dura::poll_guard::PollGuard;
+use dura::poll_guard::{PollGuard, FULL_WALK_EVERY};
 use dura::snapshots;
 use std::thread::sleep;
 use std::time::Duration;
@@ -17,6 +17,22 @@ fn changed_file() {
     assert!(pg.dir_changed(repo.dir.as_path()));
 }
 
+#[cfg(unix)]
+#[test]
+fn mode_changed() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let repo = repo_and_file!(tmp, "foo.txt");
+    let mut pg = PollGuard::new();
+    assert!(!pg.dir_changed(repo.dir.as_path()));
+
+    sleep(Duration::from_secs_f64(1.5));
+    let path = repo.dir.join("foo.txt");
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755)).unwrap();
+    assert!(pg.dir_changed(repo.dir.as_path()));
+}
+
 /// Changing a branch still looks like a file change.
 ///
 /// The reason is because `Repository::is_path_ignored` takes a ton of time,
@@ -57,3 +73,131 @@ fn file_changed_after_snapshot() {
     repo.change_file("foo.txt");
     assert!(pg.dir_changed(repo.dir.as_path()));
 }
+
+#[test]
+fn fast_branch_changed() {
+    let tmp = tempfile::tempdir().unwrap();
+    let repo = repo_and_file!(tmp, "foo.txt");
+    let mut pg = PollGuard::new();
+    pg.set_fast(true);
+    assert!(!pg.dir_changed(repo.dir.as_path()));
+
+    sleep(Duration::from_secs_f64(1.5));
+    repo.git(&["checkout", "-b", "new-branch"])
+        .expect("checkout failed");
+    assert!(pg.dir_changed(repo.dir.as_path()));
+}
+
+/// Adding a file changes the directory's mtime, so it's seen right away
+#[test]
+fn fast_file_added() {
+    let tmp = tempfile::tempdir().unwrap();
+    let repo = repo_and_file!(tmp, "foo.txt");
+    let mut pg = PollGuard::new();
+    pg.set_fast(true);
+    assert!(!pg.dir_changed(repo.dir.as_path()));
+    assert!(!pg.dir_changed(repo.dir.as_path()));
+
+    sleep(Duration::from_secs_f64(1.5));
+    repo.write_file("bar.txt");
+    assert!(pg.dir_changed(repo.dir.as_path()));
+}
+
+/// Once a file changed, its directory is watched closely, even for in-place edits
+#[test]
+fn fast_hot_dir_edits() {
+    let tmp = tempfile::tempdir().unwrap();
+    let mut repo = repo_and_file!(tmp, "foo.txt");
+    let mut pg = PollGuard::new();
+    pg.set_fast(true);
+    assert!(!pg.dir_changed(repo.dir.as_path()));
+
+    sleep(Duration::from_secs_f64(1.5));
+    repo.write_file("bar.txt");
+    assert!(pg.dir_changed(repo.dir.as_path()));
+
+    sleep(Duration::from_secs_f64(1.5));
+    snapshots::capture(repo.dir.as_path()).expect("snapshot failed");
+    assert!(!pg.dir_changed(repo.dir.as_path()));
+
+    sleep(Duration::from_secs_f64(1.5));
+    repo.change_file("bar.txt");
+    assert!(pg.dir_changed(repo.dir.as_path()));
+}
+
+/// An in-place edit doesn't change any directory mtime, so only the periodic full walk finds it
+#[test]
+fn fast_content_change_caught_by_full_walk() {
+    let tmp = tempfile::tempdir().unwrap();
+    let mut repo = repo_and_file!(tmp, "foo.txt");
+    let mut pg = PollGuard::new();
+    pg.set_fast(true);
+    assert!(!pg.dir_changed(repo.dir.as_path()));
+
+    sleep(Duration::from_secs_f64(1.5));
+    repo.change_file("foo.txt");
+    let scans = (1..=FULL_WALK_EVERY + 1)
+        .find(|_| pg.dir_changed(repo.dir.as_path()))
+        .expect("full walk never found the change");
+    assert_eq!(scans, FULL_WALK_EVERY);
+
+    // Back to normal once it's off
+    pg.set_fast(false);
+    assert!(pg.dir_changed(repo.dir.as_path()));
+}
+
+/// Once git's files have settled, idle scans don't read the watermark through git again
+#[test]
+fn precheck_skips_git_when_idle() {
+    let tmp = tempfile::tempdir().unwrap();
+    let repo = repo_and_file!(tmp, "foo.txt");
+    sleep(Duration::from_secs_f64(2.5));
+    let mut pg = PollGuard::new();
+    for _ in 0..3 {
+        assert!(!pg.dir_changed(repo.dir.as_path()));
+    }
+    assert_eq!(pg.watermark_reads(), 1);
+
+    repo.git(&["commit", "--allow-empty", "-m", "empty"])
+        .expect("commit failed");
+    pg.dir_changed(repo.dir.as_path());
+    assert_eq!(pg.watermark_reads(), 2);
+}
+
+#[test]
+fn precheck_in_linked_worktree() {
+    let tmp = tempfile::tempdir().unwrap();
+    let repo = repo_and_file!(tmp, "foo.txt");
+    let other = tempfile::tempdir().unwrap();
+    let worktree = other.path().join("worktree");
+    repo.git(&["worktree", "add", "-b", "other", worktree.to_str().unwrap()])
+        .expect("worktree add failed");
+    sleep(Duration::from_secs_f64(2.5));
+    let mut pg = PollGuard::new();
+    assert!(!pg.dir_changed(&worktree));
+    assert!(!pg.dir_changed(&worktree));
+    assert_eq!(pg.watermark_reads(), 1);
+
+    std::fs::write(worktree.join("foo.txt"), "changed").unwrap();
+    assert!(pg.dir_changed(&worktree));
+    snapshots::capture(&worktree).expect("snapshot failed");
+    assert!(!pg.dir_changed(&worktree));
+    assert_eq!(pg.watermark_reads(), 2);
+}
+
+#[test]
+fn separate_git_dir() {
+    let tmp = tempfile::tempdir().unwrap();
+    let mut repo = util::git_repo::GitRepo::new(tmp.path().join("repo"));
+    repo.init_separate(&tmp.path().join("repo.git"));
+    repo.write_file("foo.txt");
+    repo.commit_all();
+    let mut pg = PollGuard::new();
+    assert!(!pg.dir_changed(repo.dir.as_path()));
+
+    sleep(Duration::from_secs_f64(1.5));
+    repo.change_file("foo.txt");
+    assert!(pg.dir_changed(repo.dir.as_path()));
+    snapshots::capture(repo.dir.as_path()).unwrap().unwrap();
+    assert!(!pg.dir_changed(repo.dir.as_path()));
+}
@@ -0,0 +1,39 @@
+mod util;
+
+use dura::size;
+use dura::snapshots;
+use std::fs;
+
+#[test]
+fn only_snapshot_objects_counted() {
+    let tmp = tempfile::tempdir().unwrap();
+    let mut repo = repo_and_file!(tmp, "foo.txt");
+    // A big file the user committed themselves doesn't count
+    fs::write(repo.dir.join("user.bin"), vec![b'u'; 200_000]).unwrap();
+    repo.commit_all();
+
+    // ...but one that only ever existed in snapshots does
+    fs::write(repo.dir.join("big.bin"), vec![b'x'; 500_000]).unwrap();
+    snapshots::capture(repo.dir.as_path()).unwrap().unwrap();
+    fs::write(repo.dir.join("big.bin"), vec![b'y'; 300_000]).unwrap();
+    repo.change_file("foo.txt");
+    snapshots::capture(repo.dir.as_path()).unwrap().unwrap();
+
+    let report = size::dura_size(repo.dir.as_path()).unwrap();
+
+    assert_eq!(report.commits, 2);
+    assert_eq!(report.files[0], ("big.bin".to_string(), 800_000));
+    assert!(report.files.iter().all(|(file, _)| file != "user.bin"));
+    assert!(report.total_bytes >= 800_000);
+    assert!(report.total_bytes < 900_000);
+}
+
+#[test]
+fn no_snapshots() {
+    let tmp = tempfile::tempdir().unwrap();
+    let repo = repo_and_file!(tmp, "foo.txt");
+
+    let report = size::dura_size(repo.dir.as_path()).unwrap();
+
+    assert_eq!(report, size::SizeReport::default());
+}
@@ -1,6 +1,8 @@
-use dura::{config::Config, snapshots};
+use dura::config::Config;
+use dura::snapshots::{self, CaptureConfig, LfsMode, RefNamespace, SnapshotUntracked};
 
 use std::env;
+use std::fs;
 
 mod util;
 
@@ -17,6 +19,9 @@ fn change_single_file() {
     assert_ne!(status.commit_hash, status.base_hash);
     assert_eq!(status.dura_branch, format!("dura/{}", status.base_hash));
     assert_eq!(status.dura_branch, format!("dura/{}", status.base_hash));
+    assert_eq!(status.files_changed, 1);
+    assert!(status.insertions > 0);
+    assert!((chrono::Utc::now() - status.timestamp).num_seconds() < 60);
 }
 
 #[test]
@@ -60,7 +65,6 @@ fn during_merge_conflicts() {
 }
 
 #[test]
-#[serial]
 fn test_commit_signature_using_dura_config() {
     let tmp = tempfile::tempdir().unwrap();
     let mut repo = util::git_repo::GitRepo::new(tmp.path().to_path_buf());
@@ -68,17 +72,19 @@ fn test_commit_signature_using_dura_config() {
     repo.set_config("user.name", "git-author");
     repo.set_config("user.email", "git@someemail.com");
 
-    env::set_var("DURA_CONFIG_HOME", tmp.path());
-    let mut dura_config = Config::empty();
-    dura_config.commit_author = Some("dura-config".to_string());
-    dura_config.commit_email = Some("dura-config@email.com".to_string());
-    dura_config.save();
+    let dura_config = CaptureConfig {
+        commit_author: Some("dura-config".to_string()),
+        commit_email: Some("dura-config@email.com".to_string()),
+        ..Default::default()
+    };
 
     repo.write_file("foo.txt");
     repo.commit_all();
 
     repo.change_file("foo.txt");
-    let status = snapshots::capture(repo.dir.as_path()).unwrap().unwrap();
+    let status = snapshots::capture_with(repo.dir.as_path(), &dura_config)
+        .unwrap()
+        .unwrap();
 
     let commit_author = repo.git(&["show", "-s", "--format=format:%an", &status.commit_hash]);
     assert_eq!(commit_author, dura_config.commit_author);
@@ -88,7 +94,6 @@ fn test_commit_signature_using_dura_config() {
 }
 
 #[test]
-#[serial]
 fn test_commit_signature_using_git_config() {
     let tmp = tempfile::tempdir().unwrap();
     let mut repo = util::git_repo::GitRepo::new(tmp.path().to_path_buf());
@@ -96,15 +101,13 @@ fn test_commit_signature_using_git_config() {
     repo.set_config("user.name", "git-author");
     repo.set_config("user.email", "git@someemail.com");
 
-    env::set_var("DURA_CONFIG_HOME", tmp.path());
-    let dura_config = Config::empty();
-    dura_config.save();
-
     repo.write_file("foo.txt");
     repo.commit_all();
 
     repo.change_file("foo.txt");
-    let status = snapshots::capture(repo.dir.as_path()).unwrap().unwrap();
+    let status = snapshots::capture_with(repo.dir.as_path(), &CaptureConfig::default())
+        .unwrap()
+        .unwrap();
 
     let commit_author = repo
         .git(&["show", "-s", "--format=format:%an", &status.commit_hash])
@@ -118,7 +121,6 @@ fn test_commit_signature_using_git_config() {
 }
 
 #[test]
-#[serial]
 fn test_commit_signature_exclude_git_config() {
     let tmp = tempfile::tempdir().unwrap();
     let mut repo = util::git_repo::GitRepo::new(tmp.path().to_path_buf());
@@ -126,15 +128,17 @@ fn test_commit_signature_exclude_git_config() {
     repo.set_config("user.name", "git-author");
     repo.set_config("user.email", "git@someemail.com");
 
-    env::set_var("DURA_CONFIG_HOME", tmp.path());
-    let mut dura_config = Config::empty();
-    dura_config.commit_exclude_git_config = true;
-    dura_config.save();
+    let dura_config = CaptureConfig {
+        commit_exclude_git_config: true,
+        ..Default::default()
+    };
 
     repo.write_file("foo.txt");
     repo.commit_all();
     repo.change_file("foo.txt");
-    let status = snapshots::capture(repo.dir.as_path()).unwrap().unwrap();
+    let status = snapshots::capture_with(repo.dir.as_path(), &dura_config)
+        .unwrap()
+        .unwrap();
 
     let commit_author = repo
         .git(&["show", "-s", "--format=format:%an", &status.commit_hash])
@@ -146,3 +150,543 @@ fn test_commit_signature_exclude_git_config() {
         .unwrap();
     assert_eq!(commit_email, "dura@github.io");
 }
+
+/// A repo cloned inside another (not a submodule) isn't recorded as a gitlink
+#[test]
+fn nested_repo_excluded() {
+    let tmp = tempfile::tempdir().unwrap();
+    let mut repo = repo_and_file!(tmp, "foo.txt");
+    let inner = util::git_repo::GitRepo::new(tmp.path().join("vendor/inner"));
+    inner.init();
+    inner.write_file("bar.txt");
+    inner.commit_all();
+
+    repo.change_file("foo.txt");
+    repo.write_file("vendor/notes.txt");
+    let status = snapshots::capture(repo.dir.as_path()).unwrap().unwrap();
+
+    let tree = repo
+        .git(&["ls-tree", "-r", "--full-tree", &status.commit_hash])
+        .unwrap();
+    assert!(tree.contains("vendor/notes.txt"));
+    assert!(!tree.contains("vendor/inner"));
+    assert!(!tree.contains("160000"));
+}
+
+#[test]
+#[serial]
+fn dura_ref_namespace() {
+    let tmp = tempfile::tempdir().unwrap();
+    let mut repo = repo_and_file!(tmp, "foo.txt");
+
+    env::set_var("DURA_CONFIG_HOME", tmp.path());
+    let mut dura_config = Config::empty();
+    dura_config.ref_namespace = RefNamespace::Dura;
+    dura_config.save().unwrap();
+
+    repo.change_file("foo.txt");
+    let first = snapshots::capture(repo.dir.as_path()).unwrap().unwrap();
+    repo.change_file("foo.txt");
+    let second = snapshots::capture(repo.dir.as_path()).unwrap().unwrap();
+
+    let refs = repo.git(&["show-ref"]).unwrap();
+    assert!(refs.contains(&format!(
+        "{} refs/dura/{}",
+        second.commit_hash, second.base_hash
+    )));
+    assert!(!refs.contains("refs/heads/dura/"));
+    assert_eq!(
+        second.dura_branch,
+        format!("refs/dura/{}", second.base_hash)
+    );
+    let logged = repo
+        .git(&["log", "-1", "--format=%H", &second.dura_branch])
+        .unwrap();
+    assert_eq!(logged.trim(), second.commit_hash);
+    let parent = repo
+        .git(&["rev-parse", &format!("{}^", second.commit_hash)])
+        .unwrap();
+    assert_eq!(parent.trim(), first.commit_hash);
+}
+
+#[test]
+#[serial]
+fn migrate_refs_between_namespaces() {
+    let tmp = tempfile::tempdir().unwrap();
+    let mut repo = repo_and_file!(tmp, "foo.txt");
+    repo.git(&["branch", "dura/not-a-snapshot"]).unwrap();
+
+    env::set_var("DURA_CONFIG_HOME", tmp.path());
+    let mut dura_config = Config::empty();
+    dura_config.save().unwrap();
+
+    repo.change_file("foo.txt");
+    let first = snapshots::capture(repo.dir.as_path()).unwrap().unwrap();
+
+    let moved = snapshots::migrate_refs(repo.dir.as_path(), RefNamespace::Dura).unwrap();
+    assert_eq!(moved, 1);
+    let refs = repo.git(&["show-ref"]).unwrap();
+    assert!(refs.contains(&format!("refs/dura/{}", first.base_hash)));
+    assert!(!refs.contains(&format!("refs/heads/dura/{}", first.base_hash)));
+    assert!(refs.contains("refs/heads/dura/not-a-snapshot"));
+
+    // Snapshots carry on from the migrated ref
+    dura_config.ref_namespace = RefNamespace::Dura;
+    dura_config.save().unwrap();
+    repo.change_file("foo.txt");
+    let second = snapshots::capture(repo.dir.as_path()).unwrap().unwrap();
+    let parent = repo
+        .git(&["rev-parse", &format!("{}^", second.commit_hash)])
+        .unwrap();
+    assert_eq!(parent.trim(), first.commit_hash);
+}
+
+#[test]
+fn clean_merged_only_exact_matches() {
+    let tmp = tempfile::tempdir().unwrap();
+    let mut repo = repo_and_file!(tmp, "foo.txt");
+
+    // A snapshot, then the same content committed for real
+    repo.change_file("foo.txt");
+    let merged = snapshots::capture(repo.dir.as_path()).unwrap().unwrap();
+    repo.commit_all();
+
+    // A snapshot that never got committed as-is
+    repo.change_file("foo.txt");
+    let kept = snapshots::capture(repo.dir.as_path()).unwrap().unwrap();
+    repo.git(&["checkout", "--", "foo.txt"]).unwrap();
+    repo.change_file("foo.txt");
+    repo.commit_all();
+
+    let cleaned = snapshots::clean_merged(repo.dir.as_path()).unwrap();
+
+    assert_eq!(cleaned.len(), 1);
+    assert_eq!(
+        cleaned[0].reference,
+        format!("refs/heads/{}", merged.dura_branch)
+    );
+    let branches = repo.git(&["branch", "--list", "dura/*"]).unwrap();
+    assert!(!branches.contains(&merged.dura_branch));
+    assert!(branches.contains(&kept.dura_branch));
+}
+
+#[test]
+fn pinned_snapshot_survives_cleanup() {
+    let tmp = tempfile::tempdir().unwrap();
+    let mut repo = repo_and_file!(tmp, "foo.txt");
+
+    repo.change_file("foo.txt");
+    let status = snapshots::capture(repo.dir.as_path()).unwrap().unwrap();
+    repo.commit_all();
+    let pinned =
+        snapshots::pin(repo.dir.as_path(), &status.dura_branch, "before-refactor").unwrap();
+
+    let cleaned = snapshots::clean_merged(repo.dir.as_path()).unwrap();
+
+    assert!(cleaned.is_empty());
+    assert_eq!(pinned.to_string(), status.commit_hash);
+    let branches = repo.git(&["branch", "--list", "dura/*"]).unwrap();
+    assert!(branches.contains(&status.dura_branch));
+    assert_eq!(
+        snapshots::list_pins(repo.dir.as_path()).unwrap(),
+        vec![("before-refactor".to_string(), pinned)]
+    );
+
+    // Once unpinned it's fair game
+    snapshots::unpin(repo.dir.as_path(), "before-refactor").unwrap();
+    assert_eq!(
+        snapshots::clean_merged(repo.dir.as_path()).unwrap().len(),
+        1
+    );
+    assert!(snapshots::list_pins(repo.dir.as_path()).unwrap().is_empty());
+}
+
+/// Snapshots go through the same clean filters as `git add`, so line endings match real commits
+#[test]
+fn line_endings_normalized_by_gitattributes() {
+    let tmp = tempfile::tempdir().unwrap();
+    let repo = util::git_repo::GitRepo::new(tmp.path().to_path_buf());
+    repo.init();
+    fs::write(repo.dir.join(".gitattributes"), "* text eol=lf\n").unwrap();
+    fs::write(repo.dir.join("foo.txt"), "one\ntwo\n").unwrap();
+    repo.commit_all();
+
+    // Same content with CRLF isn't a change at all
+    fs::write(repo.dir.join("foo.txt"), "one\r\ntwo\r\n").unwrap();
+    assert_eq!(snapshots::capture(repo.dir.as_path()).unwrap(), None);
+
+    // A real change is stored with LF
+    fs::write(repo.dir.join("foo.txt"), "one\r\ntwo\r\nthree\r\n").unwrap();
+    let status = snapshots::capture(repo.dir.as_path()).unwrap().unwrap();
+    assert_eq!(status.insertions, 1);
+    assert_eq!(status.deletions, 0);
+    let blob = repo
+        .git(&["show", &format!("{}:foo.txt", status.commit_hash)])
+        .unwrap();
+    assert_eq!(blob, "one\ntwo\nthree\n");
+}
+
+fn lfs_repo(tmp: &tempfile::TempDir) -> util::git_repo::GitRepo {
+    let repo = util::git_repo::GitRepo::new(tmp.path().to_path_buf());
+    repo.init();
+    fs::write(
+        repo.dir.join(".gitattributes"),
+        "*.bin filter=lfs diff=lfs merge=lfs -text\n",
+    )
+    .unwrap();
+    fs::write(repo.dir.join("big.bin"), "committed").unwrap();
+    repo.write_file("foo.txt");
+    repo.commit_all();
+    repo
+}
+
+/// The lfs filter doesn't need to be installed to know which paths are LFS paths
+#[test]
+fn lfs_skip_keeps_staged_version() {
+    let tmp = tempfile::tempdir().unwrap();
+    let mut repo = lfs_repo(&tmp);
+    fs::write(repo.dir.join("big.bin"), vec![b'x'; 100_000]).unwrap();
+    fs::write(repo.dir.join("new.bin"), vec![b'y'; 100_000]).unwrap();
+    repo.change_file("foo.txt");
+
+    let config = CaptureConfig {
+        lfs_mode: LfsMode::Skip,
+        ..Default::default()
+    };
+    let status = snapshots::capture_with(repo.dir.as_path(), &config)
+        .unwrap()
+        .unwrap();
+
+    assert_eq!(status.files_changed, 1);
+    let big = repo
+        .git(&["show", &format!("{}:big.bin", status.commit_hash)])
+        .unwrap();
+    assert_eq!(big, "committed");
+    let files = repo
+        .git(&["ls-tree", "--name-only", &status.commit_hash])
+        .unwrap();
+    assert!(!files.contains("new.bin"));
+}
+
+#[test]
+fn lfs_full_by_default() {
+    let tmp = tempfile::tempdir().unwrap();
+    let repo = lfs_repo(&tmp);
+    fs::write(repo.dir.join("big.bin"), "changed").unwrap();
+
+    let status = snapshots::capture(repo.dir.as_path()).unwrap().unwrap();
+
+    let big = repo
+        .git(&["show", &format!("{}:big.bin", status.commit_hash)])
+        .unwrap();
+    assert_eq!(big, "changed");
+}
+
+#[test]
+fn lfs_pointer() {
+    let tmp = tempfile::tempdir().unwrap();
+    let repo = lfs_repo(&tmp);
+    // git-lfs isn't installed
+    if repo.git(&["lfs", "version"]).is_none() {
+        return;
+    }
+    fs::write(repo.dir.join("big.bin"), vec![b'x'; 100_000]).unwrap();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let path = repo.dir.join("big.bin");
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    let config = CaptureConfig {
+        lfs_mode: LfsMode::Pointer,
+        ..Default::default()
+    };
+    let status = snapshots::capture_with(repo.dir.as_path(), &config)
+        .unwrap()
+        .unwrap();
+
+    let big = repo
+        .git(&["show", &format!("{}:big.bin", status.commit_hash)])
+        .unwrap();
+    assert!(big.starts_with("version https://git-lfs.github.com/spec/v1"));
+    #[cfg(unix)]
+    {
+        let tree = repo.git(&["ls-tree", &status.commit_hash, "big.bin"]);
+        assert!(tree.unwrap().starts_with("100755"));
+    }
+}
+
+#[test]
+fn sparse_checkout_keeps_files_outside_cone() {
+    let tmp = tempfile::tempdir().unwrap();
+    let mut repo = util::git_repo::GitRepo::new(tmp.path().to_path_buf());
+    repo.init();
+    for dir in ["inside", "outside"] {
+        fs::create_dir(repo.dir.join(dir)).unwrap();
+        repo.write_file(&format!("{dir}/file.txt"));
+    }
+    repo.write_file("foo.txt");
+    repo.commit_all();
+    repo.git(&["sparse-checkout", "set", "inside"]).unwrap();
+    assert!(!repo.dir.join("outside/file.txt").exists());
+
+    repo.change_file("inside/file.txt");
+    let status = snapshots::capture(repo.dir.as_path()).unwrap().unwrap();
+
+    assert_eq!(status.files_changed, 1);
+    let outside = repo
+        .git(&["show", &format!("{}:outside/file.txt", status.commit_hash)])
+        .unwrap();
+    assert_eq!(outside, "initial rev");
+}
+
+#[cfg(unix)]
+#[test]
+fn mode_change() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let repo = repo_and_file!(tmp, "foo.txt");
+    let path = repo.dir.join("foo.txt");
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+
+    let status = snapshots::capture(repo.dir.as_path()).unwrap().unwrap();
+
+    let tree = repo.git(&["ls-tree", &status.commit_hash]).unwrap();
+    assert!(tree.starts_with("100755"), "{tree}");
+}
+
+#[cfg(unix)]
+#[test]
+fn mode_change_ignored_without_file_mode() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let repo = repo_and_file!(tmp, "foo.txt");
+    repo.set_config("core.fileMode", "false");
+    let path = repo.dir.join("foo.txt");
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+
+    assert_eq!(snapshots::capture(repo.dir.as_path()).unwrap(), None);
+}
+
+#[cfg(unix)]
+#[test]
+fn symlink_retargeted() {
+    let tmp = tempfile::tempdir().unwrap();
+    let repo = util::git_repo::GitRepo::new(tmp.path().to_path_buf());
+    repo.init();
+    repo.write_file("a.txt");
+    repo.write_file("b.txt");
+    std::os::unix::fs::symlink("a.txt", repo.dir.join("link")).unwrap();
+    repo.commit_all();
+
+    fs::remove_file(repo.dir.join("link")).unwrap();
+    std::os::unix::fs::symlink("b.txt", repo.dir.join("link")).unwrap();
+    let status = snapshots::capture(repo.dir.as_path()).unwrap().unwrap();
+
+    let tree = repo.git(&["ls-tree", &status.commit_hash, "link"]).unwrap();
+    assert!(tree.starts_with("120000"), "{tree}");
+    let target = repo
+        .git(&["show", &format!("{}:link", status.commit_hash)])
+        .unwrap();
+    assert_eq!(target, "b.txt");
+}
+
+#[cfg(unix)]
+#[test]
+fn symlinks_out_of_repo_stay_links() {
+    let tmp = tempfile::tempdir().unwrap();
+    let outside = tempfile::tempdir().unwrap();
+    let secret = outside.path().join("secret.txt");
+    fs::write(&secret, "hunter2").unwrap();
+    let mut repo = repo_and_file!(tmp, "foo.txt");
+    std::os::unix::fs::symlink(&secret, repo.dir.join("secret")).unwrap();
+    std::os::unix::fs::symlink(outside.path(), repo.dir.join("config")).unwrap();
+
+    for symlinks in ["true", "false"] {
+        repo.set_config("core.symlinks", symlinks);
+        repo.change_file("foo.txt");
+        let status = snapshots::capture(repo.dir.as_path()).unwrap().unwrap();
+
+        let tree = repo.git(&["ls-tree", "-r", &status.commit_hash]).unwrap();
+        assert!(!tree.contains("secret.txt"), "{tree}");
+        for link in ["secret", "config"] {
+            let entry = repo.git(&["ls-tree", &status.commit_hash, link]).unwrap();
+            assert!(entry.starts_with("120000"), "{entry}");
+        }
+        let target = repo
+            .git(&["show", &format!("{}:secret", status.commit_hash)])
+            .unwrap();
+        assert_eq!(target, secret.to_str().unwrap());
+    }
+}
+
+#[test]
+fn dry_run_leaves_refs_alone() {
+    let tmp = tempfile::tempdir().unwrap();
+    let mut repo = repo_and_file!(tmp, "foo.txt");
+    repo.change_file("foo.txt");
+    let refs_before = repo.git(&["for-each-ref"]).unwrap();
+
+    let status = snapshots::capture_dry_run(repo.dir.as_path(), &CaptureConfig::default())
+        .unwrap()
+        .unwrap();
+
+    assert_eq!(status.commit_hash, "");
+    assert_eq!(status.files_changed, 1);
+    assert_eq!(repo.git(&["for-each-ref"]).unwrap(), refs_before);
+    // ...and the real thing still snapshots the change
+    assert!(snapshots::capture(repo.dir.as_path()).unwrap().is_some());
+}
+
+#[test]
+fn dropped_stash_is_kept() {
+    let tmp = tempfile::tempdir().unwrap();
+    let mut repo = repo_and_file!(tmp, "foo.txt");
+    let cfg = CaptureConfig {
+        snapshot_stashes: true,
+        ..CaptureConfig::default()
+    };
+    repo.change_file("foo.txt");
+    repo.git(&["stash"]).unwrap();
+    let stash = repo.git(&["rev-parse", "stash@{0}"]).unwrap();
+    let stash = stash.trim();
+
+    // Nothing left to snapshot, but the stash is kept all the same
+    assert_eq!(
+        snapshots::capture_with(repo.dir.as_path(), &cfg).unwrap(),
+        None
+    );
+    repo.git(&["stash", "drop"]).unwrap();
+    assert!(repo.git(&["rev-parse", "--verify", "stash@{0}"]).is_none());
+    let kept = format!("dura/stash/{stash}");
+    assert_eq!(
+        repo.git(&["show", &format!("{kept}:foo.txt")]).unwrap(),
+        "change 1"
+    );
+
+    // Another stash is kept too, but only once
+    repo.change_file("foo.txt");
+    repo.git(&["stash"]).unwrap();
+    snapshots::capture_with(repo.dir.as_path(), &cfg).unwrap();
+    snapshots::capture_with(repo.dir.as_path(), &cfg).unwrap();
+    let refs = repo
+        .git(&[
+            "for-each-ref",
+            "--format=%(refname)",
+            "refs/heads/dura/stash/",
+        ])
+        .unwrap();
+    assert_eq!(refs.lines().count(), 2, "{refs}");
+}
+
+#[test]
+fn stashes_left_alone_by_default() {
+    let tmp = tempfile::tempdir().unwrap();
+    let mut repo = repo_and_file!(tmp, "foo.txt");
+    repo.change_file("foo.txt");
+    repo.git(&["stash"]).unwrap();
+
+    snapshots::capture_with(repo.dir.as_path(), &CaptureConfig::default()).unwrap();
+
+    let refs = repo
+        .git(&["for-each-ref", "--format=%(refname)", "refs/heads/dura/"])
+        .unwrap();
+    assert_eq!(refs, "");
+}
+
+/// A repo with one tracked edit and one new untracked file, captured with `mode`
+fn capture_untracked(
+    tmp: &tempfile::TempDir,
+    mode: SnapshotUntracked,
+) -> (util::git_repo::GitRepo, Option<snapshots::CaptureStatus>) {
+    let mut repo = repo_and_file!(tmp, "foo.txt");
+    repo.change_file("foo.txt");
+    fs::write(repo.dir.join("new.txt"), "new file").unwrap();
+    let cfg = CaptureConfig {
+        snapshot_untracked: mode,
+        ..CaptureConfig::default()
+    };
+    let status = snapshots::capture_with(repo.dir.as_path(), &cfg).unwrap();
+    (repo, status)
+}
+
+fn snapshotted_files(repo: &util::git_repo::GitRepo, commit: &str) -> String {
+    repo.git(&["ls-tree", "--name-only", commit]).unwrap()
+}
+
+#[test]
+fn untracked_always_by_default() {
+    assert_eq!(
+        CaptureConfig::default().snapshot_untracked,
+        SnapshotUntracked::Always
+    );
+    let tmp = tempfile::tempdir().unwrap();
+    let (repo, status) = capture_untracked(&tmp, SnapshotUntracked::Always);
+    let status = status.unwrap();
+
+    assert_eq!((status.tracked_changes, status.untracked_changes), (1, 1));
+    assert_eq!(
+        snapshotted_files(&repo, &status.commit_hash),
+        "foo.txt\nnew.txt\n"
+    );
+}
+
+#[test]
+fn untracked_never() {
+    let tmp = tempfile::tempdir().unwrap();
+    let (repo, status) = capture_untracked(&tmp, SnapshotUntracked::Never);
+    let status = status.unwrap();
+
+    assert_eq!((status.tracked_changes, status.untracked_changes), (1, 0));
+    assert_eq!(snapshotted_files(&repo, &status.commit_hash), "foo.txt\n");
+    // Not even as a loose object nothing refers to
+    let new_file = repo.dir.join("new.txt");
+    let blob = repo
+        .git(&["hash-object", new_file.to_str().unwrap()])
+        .unwrap();
+    assert!(repo.git(&["cat-file", "-e", blob.trim()]).is_none());
+
+    // A new file on its own is nothing to snapshot
+    repo.write_file("other.txt");
+    let cfg = CaptureConfig {
+        snapshot_untracked: SnapshotUntracked::Never,
+        ..CaptureConfig::default()
+    };
+    assert_eq!(
+        snapshots::capture_with(repo.dir.as_path(), &cfg).unwrap(),
+        None
+    );
+}
+
+#[test]
+fn untracked_only_with_tracked_changes() {
+    let tmp = tempfile::tempdir().unwrap();
+    let (mut repo, status) = capture_untracked(&tmp, SnapshotUntracked::OnlyWithTrackedChanges);
+    let status = status.unwrap();
+
+    assert_eq!((status.tracked_changes, status.untracked_changes), (1, 1));
+    assert_eq!(
+        snapshotted_files(&repo, &status.commit_hash),
+        "foo.txt\nnew.txt\n"
+    );
+
+    // Another new file waits for the next tracked change
+    repo.write_file("other.txt");
+    let cfg = CaptureConfig {
+        snapshot_untracked: SnapshotUntracked::OnlyWithTrackedChanges,
+        ..CaptureConfig::default()
+    };
+    assert_eq!(
+        snapshots::capture_with(repo.dir.as_path(), &cfg).unwrap(),
+        None
+    );
+    repo.change_file("foo.txt");
+    let status = snapshots::capture_with(repo.dir.as_path(), &cfg)
+        .unwrap()
+        .unwrap();
+    assert_eq!((status.tracked_changes, status.untracked_changes), (1, 1));
+    assert!(snapshotted_files(&repo, &status.commit_hash).contains("other.txt"));
+}
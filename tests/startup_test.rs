@@ -1,8 +1,14 @@
 mod util;
 
+use crate::util::daemon::{self, Daemon};
+use crate::util::git_repo::GitRepo;
+use dura::build_info::BuildInfo;
 use dura::config::Config;
 use dura::database::RuntimeLock;
+use dura::events::{Event, EventKind};
+use serde_json::Value;
 use std::fs;
+use std::path::Path;
 
 /// How many seconds to wait, at most, for dura to start?
 const START_TIMEOUT: u64 = 8;
@@ -14,14 +20,188 @@ fn start_serve() {
     assert_eq!(None, dura.get_runtime_lock());
 
     dura.start_async(&["serve"], true);
-    dura.primary
-        .as_ref()
-        .map(|d| d.read_line(START_TIMEOUT).unwrap());
+    dura.wait_for_startup();
 
     assert_ne!(None, dura.pid(true));
     let runtime_lock = dura.get_runtime_lock();
     assert_ne!(None, runtime_lock);
-    assert_eq!(dura.pid(true), runtime_lock.unwrap().pid);
+    let runtime_lock = runtime_lock.unwrap();
+    assert_eq!(dura.pid(true), runtime_lock.pid);
+    assert_eq!(Some(BuildInfo::current()), runtime_lock.build);
+}
+
+#[test]
+fn watch_warns_about_other_config_home() {
+    let mut dura = util::dura::Dura::new();
+    dura.start_async(&["serve"], true);
+    dura.wait_for_startup();
+    assert_eq!(
+        dura.get_runtime_lock().unwrap().config_path,
+        Some(dura.config_path())
+    );
+
+    let tmp = tempfile::tempdir().unwrap();
+    let other_home = tempfile::tempdir().unwrap();
+    let output = dura.run_output_with_env(
+        &["watch", tmp.path().to_str().unwrap()],
+        &[("DURA_CONFIG_HOME", other_home.path())],
+    );
+    let err = String::from_utf8(output.stderr).unwrap();
+    assert!(err.contains("WARNING: the running dura serve"), "{err}");
+    assert!(err.contains(dura.config_path().to_str().unwrap()), "{err}");
+    assert!(err.contains(other_home.path().to_str().unwrap()), "{err}");
+
+    // Same home, no warning
+    let output = dura.run_output(&["watch", tmp.path().to_str().unwrap()]);
+    assert!(!String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("WARNING"));
+}
+
+/// Waits for `daemon` to log the lifecycle event `name`, e.g. "TakeoverStarted"
+fn wait_for_lifecycle(daemon: &Daemon, name: &str) -> Value {
+    daemon
+        .wait_for_event(|e| daemon::is_lifecycle(e, name), START_TIMEOUT)
+        .unwrap_or_else(|| panic!("exited before {name}"))
+}
+
+#[test]
+fn takeover_is_logged() {
+    let mut dura = util::dura::Dura::new();
+    dura.start_async(&["serve", "--interval", "1"], true);
+    dura.wait_for_startup();
+    let first_pid = dura.pid(true).unwrap();
+
+    dura.start_async(&["serve", "--interval", "1"], false);
+    let second = wait_for_lifecycle(dura.secondary.as_ref().unwrap(), "TakeoverStarted");
+    let details = &daemon::operation(&second, "Lifecycle")["details"];
+    assert_eq!(details["old_pid"], first_pid.to_string(), "{second}");
+
+    // The first one notices at the start of its next loop, and says so before stopping
+    let first = dura.primary.as_ref().unwrap();
+    wait_for_lifecycle(first, "TakeoverCompleted");
+    wait_for_lifecycle(first, "Stopped");
+}
+
+#[test]
+fn separate_config_dirs_run_side_by_side() {
+    let tmp = tempfile::tempdir().unwrap();
+    let mut repos = vec![];
+    for name in ["work", "personal"] {
+        let repo = GitRepo::new(tmp.path().join(name));
+        repo.init();
+        repo.write_file("foo.txt");
+        repo.commit_all();
+        repos.push(repo);
+    }
+    std::thread::sleep(std::time::Duration::from_secs_f64(1.5));
+
+    let dirs: Vec<[String; 4]> = ["work", "personal"]
+        .iter()
+        .map(|name| {
+            let dir = |kind: &str| tmp.path().join(format!("{name}-{kind}"));
+            [
+                "--config-dir".to_string(),
+                dir("config").to_str().unwrap().to_string(),
+                "--cache-dir".to_string(),
+                dir("cache").to_str().unwrap().to_string(),
+            ]
+        })
+        .collect();
+    let args = |i: usize, rest: &[&str]| -> Vec<String> {
+        let mut args = dirs[i].to_vec();
+        args.extend(rest.iter().map(|arg| arg.to_string()));
+        args
+    };
+    let mut dura = util::dura::Dura::new();
+    for (i, repo) in repos.iter().enumerate() {
+        let watch = args(i, &["watch", repo.dir.to_str().unwrap()]);
+        dura.run(&watch.iter().map(String::as_str).collect::<Vec<_>>());
+        let serve = args(i, &["serve", "--interval", "1"]);
+        dura.start_async(
+            &serve.iter().map(String::as_str).collect::<Vec<_>>(),
+            i == 0,
+        );
+    }
+    let daemons = [
+        dura.primary.as_ref().unwrap(),
+        dura.secondary.as_ref().unwrap(),
+    ];
+    for daemon in daemons {
+        wait_for_lifecycle(daemon, "Started");
+    }
+
+    for repo in &mut repos {
+        repo.change_file("foo.txt");
+    }
+    for (i, daemon) in daemons.into_iter().enumerate() {
+        let repo = repos[i].dir.canonicalize().unwrap();
+        let other = repos[1 - i].dir.canonicalize().unwrap();
+        let snapshot = daemon
+            .wait_for_event(
+                |e| daemon::is_snapshot_of(e, &repo) || daemon::is_snapshot_of(e, &other),
+                START_TIMEOUT,
+            )
+            .unwrap();
+        assert!(daemon::is_snapshot_of(&snapshot, &repo), "{snapshot}");
+    }
+    // Neither took the other's lock
+    for (i, daemon) in daemons.into_iter().enumerate() {
+        let lock = RuntimeLock::load_file(&Path::new(&dirs[i][3]).join("runtime.db")).unwrap();
+        assert_eq!(lock.pid, Some(daemon.child.id()));
+    }
+    // Nothing went to the harness' own dirs
+    assert_eq!(None, dura.get_runtime_lock());
+    assert!(!dura.config_path().exists());
+}
+
+/// When a logged event happened
+fn logged_at(event: &Value) -> chrono::DateTime<chrono::FixedOffset> {
+    chrono::DateTime::parse_from_rfc3339(event["time"].as_str().unwrap()).unwrap()
+}
+
+#[test]
+fn takeover_waits_for_old_poller() {
+    let tmp = tempfile::tempdir().unwrap();
+    let mut repos: Vec<GitRepo> = (0..3)
+        .map(|i| GitRepo::new(tmp.path().join(format!("repo{i}"))))
+        .collect();
+    for repo in &mut repos {
+        repo.init();
+        // Enough files that a loop takes a while
+        for i in 0..1000 {
+            fs::write(repo.dir.join(format!("file{i}.txt")), "initial rev").unwrap();
+        }
+        repo.commit_all();
+    }
+    std::thread::sleep(std::time::Duration::from_secs_f64(1.5));
+    let mut dura = util::dura::Dura::new();
+    dura.run(&["watch", tmp.path().to_str().unwrap()]);
+    for repo in &mut repos {
+        repo.change_file("file0.txt");
+    }
+
+    dura.start_async(&["serve", "--interval", "1"], true);
+    dura.wait_for_startup();
+    dura.start_async(&["serve", "--interval", "1"], false);
+    let stopped = wait_for_lifecycle(dura.primary.as_ref().unwrap(), "Stopped");
+
+    for repo in &mut repos {
+        repo.change_file("file1.txt");
+    }
+    let snapshot = dura
+        .secondary
+        .as_ref()
+        .unwrap()
+        .wait_for_event(
+            |e| !daemon::operation(e, "Snapshot")["op"].is_null(),
+            START_TIMEOUT,
+        )
+        .unwrap();
+    assert!(
+        logged_at(&snapshot) >= logged_at(&stopped),
+        "{stopped}\n{snapshot}"
+    );
 }
 
 #[test]
@@ -35,9 +215,7 @@ fn start_serve_with_null_pid_in_config() {
     assert_ne!(None, dura.get_runtime_lock());
 
     dura.start_async(&["serve"], true);
-    dura.primary
-        .as_ref()
-        .map(|d| d.read_line(START_TIMEOUT).unwrap());
+    dura.wait_for_startup();
 
     assert_ne!(None, dura.pid(true));
     let runtime_lock = dura.get_runtime_lock();
@@ -58,9 +236,7 @@ fn start_serve_with_other_pid_in_config() {
     assert_ne!(None, dura.get_runtime_lock());
 
     dura.start_async(&["serve"], true);
-    dura.primary
-        .as_ref()
-        .map(|d| d.read_line(START_TIMEOUT).unwrap());
+    dura.wait_for_startup();
 
     assert_ne!(None, dura.pid(true));
     let runtime_lock = dura.get_runtime_lock();
@@ -72,19 +248,617 @@ fn start_serve_with_other_pid_in_config() {
 fn start_serve_with_invalid_json() {
     let mut dura = util::dura::Dura::new();
     let runtime_lock_path = dura.runtime_lock_path();
-    Config::create_dir(runtime_lock_path.as_path());
+    Config::create_dir(runtime_lock_path.as_path()).unwrap();
     fs::write(runtime_lock_path, "{\"pid\":34725").unwrap();
 
     assert_eq!(None, dura.pid(true));
     assert_eq!(None, dura.get_runtime_lock());
 
     dura.start_async(&["serve"], true);
-    dura.primary
+    let line = dura
+        .primary
         .as_ref()
-        .map(|d| d.read_line(START_TIMEOUT).unwrap());
+        .and_then(|d| d.read_line(START_TIMEOUT))
+        .unwrap();
+    assert!(line.contains("Replacing unreadable runtime lock"), "{line}");
+    dura.wait_for_startup();
 
     assert_ne!(None, dura.pid(true));
     let runtime_lock = dura.get_runtime_lock();
     assert_ne!(None, runtime_lock);
     assert_eq!(dura.pid(true), runtime_lock.unwrap().pid);
 }
+
+#[test]
+fn serve_refuses_invalid_config() {
+    let mut dura = util::dura::Dura::new();
+    let config_path = dura.config_path();
+    Config::create_dir(config_path.as_path()).unwrap();
+    fs::write(config_path, "repos = [not toml").unwrap();
+
+    dura.start_async(&["serve"], true);
+    let daemon = dura.primary.as_mut().unwrap();
+    assert_eq!(None, daemon.read_line(START_TIMEOUT));
+    assert!(!daemon.child.wait().unwrap().success());
+    assert_eq!(None, dura.get_runtime_lock());
+}
+
+#[test]
+fn serve_once_exits_after_snapshot() {
+    let tmp = tempfile::tempdir().unwrap();
+    let mut repo = GitRepo::new(tmp.path().join("repo"));
+    repo.init();
+    repo.write_file("foo.txt");
+    repo.commit_all();
+    // PollGuard ignores changes within a second of the last commit
+    std::thread::sleep(std::time::Duration::from_secs_f64(1.5));
+    repo.change_file("foo.txt");
+
+    let mut dura = util::dura::Dura::new();
+    dura.run_in_dir(&["watch"], repo.dir.as_path());
+    dura.start_async(&["serve", "--once"], true);
+
+    let daemon = dura.primary.as_mut().unwrap();
+    let lines: Vec<String> = std::iter::from_fn(|| daemon.read_line(START_TIMEOUT)).collect();
+    assert!(lines.iter().any(|l| l.contains("info_operation")));
+    assert!(lines.iter().any(|l| l.contains("poller_stats")));
+
+    let status = daemon.child.wait().unwrap();
+    assert!(status.success());
+
+    let branches = repo.git(&["branch", "--list", "dura/*"]).unwrap();
+    assert!(!branches.trim().is_empty());
+}
+
+#[test]
+fn min_snapshot_interval_limits_snapshots() {
+    let tmp = tempfile::tempdir().unwrap();
+    let mut repo = GitRepo::new(tmp.path().join("repo"));
+    repo.init();
+    repo.write_file("foo.txt");
+    repo.commit_all();
+    let head = repo.git(&["rev-parse", "HEAD"]).unwrap();
+    let head = head.trim();
+
+    let mut dura = util::dura::Dura::new();
+    dura.run_in_dir(&["watch"], repo.dir.as_path());
+    let mut cfg = dura.get_config().unwrap();
+    cfg.min_snapshot_interval_secs = Some(10);
+    dura.save_config(&cfg);
+
+    dura.start_async(&["serve", "--interval", "1"], true);
+    for _ in 0..6 {
+        std::thread::sleep(std::time::Duration::from_secs_f64(1.5));
+        repo.change_file("foo.txt");
+    }
+
+    let range = format!("{head}..dura/{head}");
+    let count = repo.git(&["rev-list", "--count", range.as_str()]).unwrap();
+    // Without the limit this would be closer to 6. The next snapshot isn't allowed until ~12s in.
+    assert_eq!(count.trim(), "1");
+}
+
+#[test]
+fn debounce_defers_fresh_writes() {
+    let tmp = tempfile::tempdir().unwrap();
+    let mut repo = GitRepo::new(tmp.path().join("repo"));
+    repo.init();
+    repo.write_file("foo.txt");
+    repo.commit_all();
+
+    let dura = util::dura::Dura::new();
+    dura.run_in_dir(&["watch"], repo.dir.as_path());
+    let mut cfg = dura.get_config().unwrap();
+    cfg.debounce_ms = Some(3000);
+    dura.save_config(&cfg);
+
+    std::thread::sleep(std::time::Duration::from_secs_f64(1.5));
+    repo.change_file("foo.txt");
+
+    // Too fresh, deferred
+    dura.run(&["serve", "--once"]);
+    let branches = repo.git(&["branch", "--list", "dura/*"]).unwrap();
+    assert_eq!(branches.trim(), "");
+
+    // Settled down
+    std::thread::sleep(std::time::Duration::from_secs(3));
+    dura.run(&["serve", "--once"]);
+    let branches = repo.git(&["branch", "--list", "dura/*"]).unwrap();
+    assert_ne!(branches.trim(), "");
+}
+
+#[test]
+fn disabled_watch_not_snapshotted() {
+    let tmp = tempfile::tempdir().unwrap();
+    let mut repo = GitRepo::new(tmp.path().join("repo"));
+    repo.init();
+    repo.write_file("foo.txt");
+    repo.commit_all();
+
+    let dura = util::dura::Dura::new();
+    dura.run_in_dir(&["watch", "-e", "target"], repo.dir.as_path());
+    dura.run_in_dir(&["watch", "--disable"], repo.dir.as_path());
+    std::thread::sleep(std::time::Duration::from_secs_f64(1.5));
+    repo.change_file("foo.txt");
+
+    dura.run(&["serve", "--once"]);
+    let branches = repo.git(&["branch", "--list", "dura/*"]).unwrap();
+    assert_eq!(branches.trim(), "");
+
+    // Turning it back on doesn't lose the excludes
+    dura.run_in_dir(&["watch", "--enable"], repo.dir.as_path());
+    let cfg = dura.get_config().unwrap();
+    let watch = cfg.repos.values().next().unwrap();
+    assert_eq!(watch.exclude, vec!["target".to_string()]);
+
+    dura.run(&["serve", "--once"]);
+    let branches = repo.git(&["branch", "--list", "dura/*"]).unwrap();
+    assert_ne!(branches.trim(), "");
+}
+
+#[test]
+fn skip_on_branches_pauses_snapshots() {
+    let tmp = tempfile::tempdir().unwrap();
+    let mut repo = GitRepo::new(tmp.path().join("repo"));
+    repo.init();
+    repo.write_file("foo.txt");
+    repo.commit_all();
+    let normal = repo.git(&["rev-parse", "--abbrev-ref", "HEAD"]).unwrap();
+
+    let dura = util::dura::Dura::new();
+    dura.run(&["watch", repo.dir.to_str().unwrap()]);
+    let mut cfg = dura.get_config().unwrap();
+    let watch = cfg.repos.values_mut().next().unwrap();
+    std::rc::Rc::make_mut(watch).skip_on_branches =
+        vec!["release/*".to_string(), "(detached)".to_string()];
+    dura.save_config(&cfg);
+    std::thread::sleep(std::time::Duration::from_secs_f64(1.5));
+    repo.change_file("foo.txt");
+
+    repo.git(&["checkout", "-b", "release/1.0"]).unwrap();
+    dura.run(&["serve", "--once"]);
+    assert_eq!(
+        repo.git(&["branch", "--list", "dura/*"]).unwrap().trim(),
+        ""
+    );
+
+    repo.git(&["checkout", "--detach"]).unwrap();
+    dura.run(&["serve", "--once"]);
+    assert_eq!(
+        repo.git(&["branch", "--list", "dura/*"]).unwrap().trim(),
+        ""
+    );
+
+    repo.git(&["checkout", normal.trim()]).unwrap();
+    dura.run(&["serve", "--once"]);
+    assert_ne!(
+        repo.git(&["branch", "--list", "dura/*"]).unwrap().trim(),
+        ""
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn watch_ensures_daemon() {
+    let tmp = tempfile::tempdir().unwrap();
+    let mut repo = GitRepo::new(tmp.path().to_path_buf());
+    repo.init();
+    repo.write_file("foo.txt");
+    repo.commit_all();
+    let dura = util::dura::Dura::new();
+    let dir = repo.dir.to_str().unwrap();
+
+    let output = dura.run_output(&["watch", dir, "--ensure-daemon"]);
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("started dura serve"));
+    let pid = dura.get_runtime_lock().unwrap().pid.unwrap();
+
+    // Already running, so nothing new is started
+    let output = dura.run_output(&["watch", dir, "--ensure-daemon"]);
+    assert!(!String::from_utf8_lossy(&output.stderr).contains("started dura serve"));
+    assert_eq!(dura.get_runtime_lock().unwrap().pid, Some(pid));
+
+    std::thread::sleep(std::time::Duration::from_secs_f64(1.5));
+    repo.change_file("foo.txt");
+    let snapshotted = (0..40).any(|_| {
+        std::thread::sleep(std::time::Duration::from_millis(500));
+        !repo
+            .git(&["branch", "--list", "dura/*"])
+            .unwrap()
+            .is_empty()
+    });
+
+    std::process::Command::new("kill")
+        .arg(pid.to_string())
+        .status()
+        .unwrap();
+    assert!(snapshotted, "the daemon never took a snapshot");
+}
+
+#[test]
+fn serve_verbose_logs_discovery() {
+    let tmp = tempfile::tempdir().unwrap();
+    let repo = GitRepo::new(tmp.path().join("repo"));
+    repo.init();
+    repo.write_file("foo.txt");
+    repo.commit_all();
+    let mut dura = util::dura::Dura::new();
+    dura.run(&["watch", tmp.path().to_str().unwrap()]);
+
+    // Unset, since RUST_LOG would win over -vv
+    dura.start_async_with_env(
+        &["serve", "-vv", "--interval", "1"],
+        true,
+        &[("RUST_LOG", "")],
+    );
+    let found = dura
+        .primary
+        .as_ref()
+        .unwrap()
+        .wait_for_event(|e| daemon::message(e) == "repo_found", START_TIMEOUT)
+        .expect("no discovery was logged");
+    assert_eq!(found["level"], "Level(Trace)");
+    assert_eq!(found["target"], "dura::git_repo_iter");
+}
+
+#[test]
+fn kill_stops_running_daemon() {
+    let mut dura = util::dura::Dura::new();
+    dura.start_async(&["serve", "--interval", "1"], true);
+    dura.wait_for_startup();
+    let pid = dura.pid(true).unwrap();
+
+    let output = dura.run_output(&["kill"]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(stdout.trim(), format!("stopped pid {pid}"));
+    assert_eq!(None, dura.get_runtime_lock().unwrap().pid);
+}
+
+#[test]
+fn kill_clears_stale_pid() {
+    // A pid that's certainly not running any more
+    let mut exited = std::process::Command::new("git")
+        .arg("--version")
+        .stdout(std::process::Stdio::null())
+        .spawn()
+        .unwrap();
+    exited.wait().unwrap();
+    let dura = util::dura::Dura::new();
+    dura.save_runtime_lock(&RuntimeLock {
+        pid: Some(exited.id()),
+        ..RuntimeLock::empty()
+    });
+
+    let output = dura.run_output(&["kill"]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(
+        stdout.trim(),
+        format!("no running daemon (cleared stale pid {})", exited.id())
+    );
+    assert_eq!(None, dura.get_runtime_lock().unwrap().pid);
+}
+
+#[test]
+fn kill_without_daemon() {
+    let dura = util::dura::Dura::new();
+    let output = dura.run_output(&["kill"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("no daemon recorded"));
+}
+
+#[cfg(unix)]
+#[test]
+fn supervisor_restarts_killed_worker() {
+    let tmp = tempfile::tempdir().unwrap();
+    let mut repo = GitRepo::new(tmp.path().join("repo"));
+    repo.init();
+    repo.write_file("foo.txt");
+    repo.commit_all();
+    let mut dura = util::dura::Dura::new();
+    dura.run(&["watch", tmp.path().to_str().unwrap()]);
+
+    dura.start_async(&["serve", "--supervise", "--interval", "1"], true);
+    let supervisor = dura.pid(true).unwrap();
+    let worker_pid = |not: Option<u32>| {
+        (0..80).find_map(|_| {
+            std::thread::sleep(std::time::Duration::from_millis(100));
+            let lock = dura.get_runtime_lock()?;
+            match lock.pid {
+                Some(pid) if pid != supervisor && Some(pid) != not => {
+                    assert_eq!(lock.supervisor_pid, Some(supervisor));
+                    Some(pid)
+                }
+                _ => None,
+            }
+        })
+    };
+    let first = worker_pid(None).expect("no worker started");
+
+    std::process::Command::new("kill")
+        .args(["-9", &first.to_string()])
+        .status()
+        .unwrap();
+    let second = worker_pid(Some(first)).expect("the worker wasn't restarted");
+
+    std::thread::sleep(std::time::Duration::from_secs_f64(1.5));
+    repo.change_file("foo.txt");
+    // The worker writes to the supervisor's stdout
+    dura.wait_for_snapshot(&repo.dir);
+
+    let output = dura.run_output(&["kill"]);
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout).trim(),
+        format!("stopped pid {second}")
+    );
+}
+
+#[test]
+fn capture_via_daemon() {
+    let tmp = tempfile::tempdir().unwrap();
+    let mut repo = GitRepo::new(tmp.path().join("watched"));
+    repo.init();
+    repo.write_file("foo.txt");
+    repo.commit_all();
+    let unwatched = GitRepo::new(tmp.path().join("unwatched"));
+    unwatched.init();
+    unwatched.write_file("foo.txt");
+    unwatched.commit_all();
+    let mut dura = util::dura::Dura::new();
+    dura.run(&["watch", repo.dir.to_str().unwrap()]);
+
+    // Long enough that the daemon won't capture anything by itself
+    dura.start_async(&["serve", "--interval", "60"], true);
+    dura.wait_for_startup();
+    let daemon = dura.primary.as_ref().unwrap();
+
+    repo.change_file("foo.txt");
+    let output = dura.run_output(&["capture", "--via-daemon", repo.dir.to_str().unwrap()]);
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).starts_with("dura: dura/"));
+    assert!(String::from_utf8_lossy(&output.stderr).is_empty());
+    let requested =
+        daemon.wait_for_event(|e| daemon::message(e) == "capture_requested", START_TIMEOUT);
+    assert!(requested.is_some(), "the daemon didn't take the snapshot");
+
+    let output = dura.run_output(&["capture", "--via-daemon", unwatched.dir.to_str().unwrap()]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("isn't under a watched directory"));
+    assert_eq!(
+        unwatched
+            .git(&["branch", "--list", "dura/*"])
+            .unwrap()
+            .trim(),
+        ""
+    );
+}
+
+#[test]
+fn capture_via_daemon_without_daemon() {
+    let tmp = tempfile::tempdir().unwrap();
+    let mut repo = GitRepo::new(tmp.path().to_path_buf());
+    repo.init();
+    repo.write_file("foo.txt");
+    repo.commit_all();
+    repo.change_file("foo.txt");
+    let dura = util::dura::Dura::new();
+
+    let output = dura.run_output(&["capture", "--via-daemon", repo.dir.to_str().unwrap()]);
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).starts_with("dura: dura/"));
+    assert_ne!(
+        repo.git(&["branch", "--list", "dura/*"]).unwrap().trim(),
+        ""
+    );
+}
+
+#[test]
+fn serve_writes_events_to_stdout() {
+    let tmp = tempfile::tempdir().unwrap();
+    let mut repo = GitRepo::new(tmp.path().join("repo"));
+    repo.init();
+    repo.write_file("foo.txt");
+    repo.commit_all();
+    std::thread::sleep(std::time::Duration::from_secs_f64(1.5));
+    let mut dura = util::dura::Dura::new();
+    dura.run(&["watch", repo.dir.to_str().unwrap()]);
+
+    dura.start_async(&["serve", "--interval", "1", "--events-fd", "stdout"], true);
+    let daemon = dura.primary.as_ref().unwrap();
+    // Logs went to stderr, so every line is an event
+    let next = |name: &str| -> Event {
+        let event = daemon
+            .wait_for_event(
+                |e| {
+                    assert_eq!(e["schema"], 1, "{e}");
+                    e["event"] == name
+                },
+                START_TIMEOUT,
+            )
+            .unwrap_or_else(|| panic!("exited before {name}"));
+        serde_json::from_value(event).unwrap()
+    };
+
+    let started = next("daemon_started");
+    assert_eq!(
+        started.kind,
+        EventKind::DaemonStarted {
+            pid: dura.pid(true).unwrap(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+        }
+    );
+    let repo_path = repo.dir.canonicalize().unwrap();
+    let discovered = next("repo_discovered");
+    assert_eq!(
+        discovered.kind,
+        EventKind::RepoDiscovered {
+            repo: repo_path.clone()
+        }
+    );
+
+    repo.change_file("foo.txt");
+    match next("snapshot_created").kind {
+        EventKind::SnapshotCreated {
+            repo,
+            files_changed,
+            ..
+        } => {
+            assert_eq!(repo, repo_path);
+            assert_eq!(files_changed, 1);
+        }
+        other => panic!("{other:?}"),
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn serve_with_read_only_cache_dir() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let mut repo = GitRepo::new(tmp.path().join("repo"));
+    repo.init();
+    repo.write_file("foo.txt");
+    repo.commit_all();
+    std::thread::sleep(std::time::Duration::from_secs_f64(1.5));
+    repo.change_file("foo.txt");
+
+    let mut dura = util::dura::Dura::new();
+    dura.run_in_dir(&["watch"], repo.dir.as_path());
+    let cache_dir = dura.runtime_lock_path().parent().unwrap().to_path_buf();
+    fs::set_permissions(&cache_dir, fs::Permissions::from_mode(0o555)).unwrap();
+    let runtime_dir = tmp.path().join("runtime");
+    fs::create_dir(&runtime_dir).unwrap();
+
+    dura.start_async_with_env(
+        &["serve", "--once"],
+        true,
+        &[("XDG_RUNTIME_DIR", runtime_dir.to_str().unwrap())],
+    );
+    let daemon = dura.primary.as_mut().unwrap();
+    let lines: Vec<String> = std::iter::from_fn(|| daemon.read_line(START_TIMEOUT)).collect();
+    assert!(lines.iter().any(|l| l.contains("cache_dir_fallback")));
+    assert!(daemon.child.wait().unwrap().success());
+    let branches = repo.git(&["branch", "--list", "dura/*"]).unwrap();
+    assert!(!branches.trim().is_empty());
+    assert!(runtime_dir.join("dura").join("runtime.db").exists());
+
+    let output = dura.run_output_with_env(&["doctor"], &[("XDG_RUNTIME_DIR", &runtime_dir)]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success());
+    assert!(stdout.contains("degraded"), "{stdout}");
+    assert!(String::from_utf8_lossy(&output.stderr).contains("WARNING"));
+
+    fs::set_permissions(&cache_dir, fs::Permissions::from_mode(0o755)).unwrap();
+}
+
+#[test]
+fn doctor_says_captures_are_suspended_for_low_disk() {
+    let tmp = tempfile::tempdir().unwrap();
+    let repo = GitRepo::new(tmp.path().join("repo"));
+    repo.init();
+    repo.write_file("foo.txt");
+    repo.commit_all();
+
+    let dura = util::dura::Dura::new();
+    dura.run_in_dir(&["watch"], repo.dir.as_path());
+    let mut config = dura.get_config().unwrap();
+    // No disk has this much room
+    config.min_free_space_mb = Some(u64::MAX / 2);
+    dura.save_config(&config);
+
+    let output = dura.run_output(&["serve", "--once"]);
+    assert!(output.status.success());
+    let output = dura.run_output(&["doctor"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("captures suspended: low disk"), "{stdout}");
+    let repo_path = repo.dir.canonicalize().unwrap();
+    assert!(stdout.contains(repo_path.to_str().unwrap()), "{stdout}");
+
+    // Plenty of room again
+    config.min_free_space_mb = None;
+    dura.save_config(&config);
+    dura.run_output(&["serve", "--once"]);
+    let output = dura.run_output(&["doctor"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(!stdout.contains("captures suspended"), "{stdout}");
+}
+
+#[test]
+fn doctor_says_when_quiet_hours_end() {
+    let mut dura = util::dura::Dura::new();
+    let mut config = Config::empty();
+    // The whole day, whenever the test runs
+    config.schedule.quiet = ["00:00-12:00", "12:00-00:00"]
+        .into_iter()
+        .map(|window| window.to_string().try_into().unwrap())
+        .collect();
+    dura.save_config(&config);
+
+    dura.start_async(&["serve"], true);
+    dura.wait_for_startup();
+    let quiet = (0..START_TIMEOUT * 10).find_map(|_| {
+        let output = dura.run_output(&["doctor"]);
+        let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+        if stdout.contains("quiet until ") {
+            return Some(stdout);
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        None
+    });
+    assert!(quiet.is_some(), "doctor never said the daemon is quiet");
+}
+
+#[test]
+fn boost_snapshots_more_often_until_it_runs_out() {
+    let tmp = tempfile::tempdir().unwrap();
+    let mut repo = GitRepo::new(tmp.path().join("repo"));
+    repo.init();
+    repo.write_file("foo.txt");
+    repo.commit_all();
+    let head = repo.git(&["rev-parse", "HEAD"]).unwrap();
+    let head = head.trim();
+    let snapshots = |repo: &GitRepo| {
+        let range = format!("{head}..dura/{head}");
+        repo.git(&["rev-list", "--count", range.as_str()])
+            .map_or(0, |count| count.trim().parse::<u32>().unwrap())
+    };
+
+    let mut dura = util::dura::Dura::new();
+    dura.run_in_dir(&["watch"], repo.dir.as_path());
+    // Far longer than the test, so only the boost snapshots anything
+    dura.start_async(&["serve", "--interval", "600"], true);
+    dura.wait_for_startup();
+    let dir = repo.dir.to_str().unwrap().to_string();
+    dura.run(&["boost", &dir, "--for", "8s", "--interval", "1"]);
+    let daemon = dura.primary.as_ref().unwrap();
+    daemon
+        .wait_for_event(|e| daemon::message(e) == "boost_started", START_TIMEOUT)
+        .unwrap();
+
+    for _ in 0..3 {
+        // PollGuard ignores changes within a second of the last snapshot
+        std::thread::sleep(std::time::Duration::from_secs_f64(1.2));
+        repo.change_file("foo.txt");
+        dura.wait_for_snapshot(&repo.dir);
+    }
+    assert_eq!(snapshots(&repo), 3);
+
+    let ended = daemon
+        .wait_for_event(|e| daemon::message(e) == "boost_ended", START_TIMEOUT * 2)
+        .unwrap();
+    assert_eq!(ended["fields"]["reason"], "expired");
+    repo.change_file("foo.txt");
+    std::thread::sleep(std::time::Duration::from_secs(3));
+    assert_eq!(snapshots(&repo), 3);
+
+    let output = dura.run_output(&["boost", &dir, "--clear"]);
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout).trim(),
+        format!(
+            "{} isn't boosted",
+            repo.dir.canonicalize().unwrap().display()
+        )
+    );
+}
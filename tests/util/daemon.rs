@@ -1,9 +1,12 @@
 use std::io::{BufRead, BufReader};
+use std::path::Path;
 use std::process::{Child, ChildStdout};
 use std::sync::mpsc::{channel, Receiver};
 use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+use serde_json::Value;
 
 /// Main-thread side of a process watcher. The process that's launched is exposed as messages
 /// (per-line) over a mpsc channel. This is intended to simplify, speed up, and generally make the
@@ -77,9 +80,56 @@ impl Daemon {
             .unwrap()
     }
 
+    /// Read lines until one is a JSON log line (as `NestedJsonLayer` writes them) that `matches`
+    /// and return it, parsed. Lines on the way are skipped. `None` if the process exits first,
+    /// and like `read_line`, it panics if nothing matched within `timeout_secs`.
+    pub fn wait_for_event(
+        &self,
+        matches: impl Fn(&Value) -> bool,
+        timeout_secs: u64,
+    ) -> Option<Value> {
+        let deadline = Instant::now() + Duration::from_secs(timeout_secs);
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            let line = match self.mailbox.recv_timeout(left) {
+                Ok(Some(line)) => line,
+                Ok(None) => return None,
+                Err(_) => panic!("no matching event within {timeout_secs}s"),
+            };
+            print!("{line}");
+            if let Ok(event) = serde_json::from_str::<Value>(&line) {
+                if matches(&event) {
+                    return Some(event);
+                }
+            }
+        }
+    }
+
     pub fn kill(&mut self) {
         let mut kill_sign = self.kill_sign.lock().unwrap();
         *kill_sign -= 1;
         self.child.kill().unwrap();
     }
 }
+
+/// The `Operation` logged in `event`, e.g. `operation(e, "Snapshot")["repo"]`. Null if it's some
+/// other kind of event.
+pub fn operation<'a>(event: &'a Value, kind: &str) -> &'a Value {
+    &event["fields"]["operation"][kind]
+}
+
+/// Whether `event` is the daemon starting, stopping or handing over, e.g. "TakeoverStarted"
+pub fn is_lifecycle(event: &Value, name: &str) -> bool {
+    operation(event, "Lifecycle")["event"] == name
+}
+
+/// Whether `event` is a snapshot of the repo at `repo`
+pub fn is_snapshot_of(event: &Value, repo: &Path) -> bool {
+    let snapshot = operation(event, "Snapshot");
+    snapshot["repo"].as_str().map(Path::new) == Some(repo) && !snapshot["op"].is_null()
+}
+
+/// The `message` of a plain log line like `repo_found`
+pub fn message(event: &Value) -> &str {
+    event["fields"]["message"].as_str().unwrap_or_default()
+}
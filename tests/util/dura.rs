@@ -1,14 +1,17 @@
 use std::{
     collections::HashSet,
+    io::Write,
     ops, path,
-    process::{Command, Stdio},
-    thread, time,
+    process::{Command, Output, Stdio},
 };
 
-use crate::util::daemon::Daemon;
+use crate::util::daemon::{self, Daemon};
 use dura::config::Config;
 use dura::database::RuntimeLock;
 
+/// How many seconds the `wait_for_*` helpers wait for an event before failing the test
+const EVENT_TIMEOUT: u64 = 15;
+
 /// Utility to start dura asynchronously (e.g. dura serve) and kill the process when this goes out
 /// of scope. This helps us do end-to-end tests where we invoke the executable, possibly multiple
 /// different processes.
@@ -29,13 +32,26 @@ impl Dura {
         }
     }
 
+    /// The dura executable, using this instance's config and cache dirs
+    fn command(&self) -> Command {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_dura"));
+        command
+            .env("DURA_CONFIG_HOME", self.config_dir.path())
+            .env("DURA_CACHE_HOME", self.cache_dir.path());
+        command
+    }
+
     pub fn start_async(&mut self, args: &[&str], is_primary: bool) {
+        self.start_async_with_env(args, is_primary, &[]);
+    }
+
+    /// Like `start_async`, with extra environment variables, e.g. RUST_LOG
+    pub fn start_async_with_env(&mut self, args: &[&str], is_primary: bool, env: &[(&str, &str)]) {
         println!("$ dura {} &", args.join(" "));
-        let exe = env!("CARGO_BIN_EXE_dura").to_string();
-        let child = Command::new(exe)
+        let child = self
+            .command()
             .args(args)
-            .env("DURA_CONFIG_HOME", self.config_dir.path())
-            .env("DURA_CACHE_HOME", self.cache_dir.path())
+            .envs(env.iter().copied())
             .stdout(Stdio::piped())
             .spawn()
             .unwrap();
@@ -49,12 +65,7 @@ impl Dura {
 
     pub fn run(&self, args: &[&str]) {
         println!("$ dura {}", args.join(" "));
-        let exe = env!("CARGO_BIN_EXE_dura").to_string();
-        let child_proc = Command::new(exe)
-            .args(args)
-            .env("DURA_CONFIG_HOME", self.config_dir.path())
-            .env("DURA_CACHE_HOME", self.cache_dir.path())
-            .output();
+        let child_proc = self.command().args(args).output();
 
         if let Ok(output) = child_proc {
             if !output.status.success() {
@@ -72,15 +83,60 @@ impl Dura {
         }
     }
 
-    pub fn run_in_dir(&self, args: &[&str], dir: &path::Path) {
+    /// Like `run`, but hands back everything so exit codes and stderr can be checked
+    pub fn run_output(&self, args: &[&str]) -> Output {
+        self.run_output_with_env(args, &[])
+    }
+
+    /// Like `run_output`, with extra environment variables, e.g. a different HOME
+    pub fn run_output_with_env(&self, args: &[&str], env: &[(&str, &path::Path)]) -> Output {
         println!("$ dura {}", args.join(" "));
-        let exe = env!("CARGO_BIN_EXE_dura").to_string();
-        let child_proc = Command::new(exe)
+        let output = self
+            .command()
             .args(args)
-            .env("DURA_CONFIG_HOME", self.config_dir.path())
-            .env("DURA_CACHE_HOME", self.cache_dir.path())
-            .current_dir(dir)
-            .output();
+            .envs(env.iter().copied())
+            .output()
+            .unwrap();
+        println!("{}", String::from_utf8_lossy(&output.stdout));
+        println!("{}", String::from_utf8_lossy(&output.stderr));
+        output
+    }
+
+    /// Like `run_output`, from `dir` instead of the test's working directory
+    pub fn run_output_in_dir(&self, args: &[&str], dir: &path::Path) -> Output {
+        println!("$ cd {} && dura {}", dir.display(), args.join(" "));
+        let output = self.command().args(args).current_dir(dir).output().unwrap();
+        println!("{}", String::from_utf8_lossy(&output.stdout));
+        println!("{}", String::from_utf8_lossy(&output.stderr));
+        output
+    }
+
+    /// Like `run`, but with `input` piped to stdin
+    pub fn run_with_stdin(&self, args: &[&str], input: &str) {
+        println!("$ dura {} <<EOF\n{input}EOF", args.join(" "));
+        let mut child = self
+            .command()
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(input.as_bytes())
+            .unwrap();
+
+        let output = child.wait_with_output().unwrap();
+        println!("{}", String::from_utf8_lossy(&output.stdout));
+        println!("{}", String::from_utf8_lossy(&output.stderr));
+    }
+
+    pub fn run_in_dir(&self, args: &[&str], dir: &path::Path) {
+        println!("$ dura {}", args.join(" "));
+        let child_proc = self.command().args(args).current_dir(dir).output();
 
         if let Ok(output) = child_proc {
             if !output.status.success() {
@@ -118,7 +174,7 @@ impl Dura {
     }
 
     pub fn save_config(&self, cfg: &Config) {
-        cfg.save_to_path(self.config_path().as_path());
+        cfg.save_to_path(self.config_path().as_path()).unwrap();
     }
 
     pub fn runtime_lock_path(&self) -> path::PathBuf {
@@ -132,7 +188,8 @@ impl Dura {
     }
 
     pub fn save_runtime_lock(&self, cfg: &RuntimeLock) {
-        cfg.save_to_path(self.runtime_lock_path().as_path());
+        cfg.save_to_path(self.runtime_lock_path().as_path())
+            .unwrap();
     }
 
     pub fn git_repos(&self) -> HashSet<path::PathBuf> {
@@ -142,10 +199,22 @@ impl Dura {
         }
     }
 
-    pub fn wait(&self) {
-        // This hack isn't going to work. Another idea is to read lines
-        // from stdout as a signal to proceed.
-        thread::sleep(time::Duration::from_secs(6));
+    /// Wait for the primary daemon to log that it started
+    pub fn wait_for_startup(&self) {
+        let daemon = self.primary.as_ref().expect("start_async first");
+        daemon
+            .wait_for_event(|e| daemon::is_lifecycle(e, "Started"), EVENT_TIMEOUT)
+            .expect("dura exited before starting");
+    }
+
+    /// Wait for the primary daemon to snapshot the repo at `repo`, and return the snapshot's log
+    /// line
+    pub fn wait_for_snapshot(&self, repo: &path::Path) -> serde_json::Value {
+        let daemon = self.primary.as_ref().expect("start_async first");
+        let repo = repo.canonicalize().unwrap();
+        daemon
+            .wait_for_event(|e| daemon::is_snapshot_of(e, &repo), EVENT_TIMEOUT)
+            .expect("dura exited before the snapshot")
     }
 }
 
@@ -56,6 +56,26 @@ impl GitRepo {
     pub fn init(&self) {
         fs::create_dir_all(self.dir.as_path()).unwrap();
         let _ = self.git(&["init"]).unwrap();
+        self.init_config();
+    }
+
+    /// Like `init`, but the repo lives in `git_dir` and `.git` is a file pointing there, as with
+    /// `git init --separate-git-dir`
+    pub fn init_separate(&self, git_dir: &path::Path) {
+        fs::create_dir_all(self.dir.as_path()).unwrap();
+        fs::create_dir_all(git_dir.parent().unwrap()).unwrap();
+        let output = Command::new("git")
+            .arg("init")
+            .arg("--separate-git-dir")
+            .arg(git_dir)
+            .arg(&self.dir)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{output:?}");
+        self.init_config();
+    }
+
+    fn init_config(&self) {
         let _ = self.git(&["--version"]).unwrap();
         let _ = self.git(&["checkout", "-b", "master"]).unwrap();
         // Linux & Windows will fail on `git commit` if these aren't set
@@ -3,6 +3,7 @@ mod util;
 use crate::util::dura::Dura;
 use crate::util::git_repo::GitRepo;
 use std::collections::HashSet;
+use std::path::PathBuf;
 
 #[test]
 fn watch_repo() {
@@ -51,3 +52,413 @@ fn watch_dir_with_repo_nested_3_folders_deep() {
 
     assert_eq!(dura.git_repos(), tmp_set);
 }
+
+#[test]
+fn watch_again_updates_options() {
+    let tmp = tempfile::tempdir().unwrap();
+    let repo = GitRepo::new(tmp.path().to_path_buf());
+    repo.init();
+
+    let dura = Dura::new();
+    dura.run_in_dir(&["watch", "-e", "a"], tmp.path());
+    dura.run_in_dir(&["watch", "--add-exclude", "b,c", "-d", "3"], tmp.path());
+    dura.run_in_dir(&["watch", "--remove-exclude", "c"], tmp.path());
+
+    let cfg = dura.get_config().unwrap();
+    let key = tmp.path().canonicalize().unwrap();
+    let watch = cfg.repos.get(key.to_str().unwrap()).unwrap();
+    assert_eq!(watch.exclude, vec!["a".to_string(), "b".to_string()]);
+    assert_eq!(watch.max_depth, 3);
+    assert_eq!(cfg.repos.len(), 1);
+}
+
+#[test]
+fn watch_rejects_invalid_excludes() {
+    let tmp = tempfile::tempdir().unwrap();
+    let repo = GitRepo::new(tmp.path().to_path_buf());
+    repo.init();
+
+    let dura = Dura::new();
+    dura.run_in_dir(&["watch", "-e", "../elsewhere"], tmp.path());
+    assert!(dura.get_config().is_none());
+
+    dura.run_in_dir(&["watch", "-e", "build/"], tmp.path());
+    let cfg = dura.get_config().unwrap();
+    let key = tmp.path().canonicalize().unwrap();
+    let watch = cfg.repos.get(key.to_str().unwrap()).unwrap();
+    assert_eq!(watch.exclude, vec!["build".to_string()]);
+}
+
+#[test]
+fn watch_many_dirs_skips_bad_ones() {
+    let tmp = tempfile::tempdir().unwrap();
+    let repo1 = GitRepo::new(tmp.path().join("repo1"));
+    repo1.init();
+    let repo2 = GitRepo::new(tmp.path().join("repo2"));
+    repo2.init();
+    let missing = tmp.path().join("missing");
+
+    let dura = Dura::new();
+    dura.run(&[
+        "watch",
+        repo1.dir.to_str().unwrap(),
+        missing.to_str().unwrap(),
+        repo2.dir.to_str().unwrap(),
+    ]);
+
+    let mut tmp_set = HashSet::new();
+    tmp_set.insert(repo1.dir.canonicalize().unwrap());
+    tmp_set.insert(repo2.dir.canonicalize().unwrap());
+    assert_eq!(dura.git_repos(), tmp_set);
+}
+
+#[test]
+fn watch_strict_changes_nothing_on_failure() {
+    let tmp = tempfile::tempdir().unwrap();
+    let repo1 = GitRepo::new(tmp.path().join("repo1"));
+    repo1.init();
+    let missing = tmp.path().join("missing");
+
+    let dura = Dura::new();
+    dura.run(&[
+        "watch",
+        "--strict",
+        repo1.dir.to_str().unwrap(),
+        missing.to_str().unwrap(),
+    ]);
+
+    assert!(dura.get_config().is_none());
+}
+
+#[test]
+fn watch_from_stdin() {
+    let tmp = tempfile::tempdir().unwrap();
+    let repo1 = GitRepo::new(tmp.path().join("repo1"));
+    repo1.init();
+    let repo2 = GitRepo::new(tmp.path().join("repo2"));
+    repo2.init();
+
+    let dura = Dura::new();
+    let input = format!(
+        "{}\n\n{}\n",
+        repo1.dir.to_str().unwrap(),
+        repo2.dir.to_str().unwrap()
+    );
+    dura.run_with_stdin(&["watch", "--stdin", "-e", "target"], &input);
+
+    let cfg = dura.get_config().unwrap();
+    assert_eq!(cfg.repos.len(), 2);
+    assert!(cfg
+        .repos
+        .values()
+        .all(|watch| watch.exclude == vec!["target".to_string()]));
+}
+
+#[test]
+fn disabled_watch_hides_repos_from_parent_watch() {
+    let tmp = tempfile::tempdir().unwrap();
+    let repo1 = GitRepo::new(tmp.path().join("repo1"));
+    repo1.init();
+    let repo2 = GitRepo::new(tmp.path().join("repo2"));
+    repo2.init();
+
+    let dura = Dura::new();
+    dura.run_in_dir(&["watch"], tmp.path());
+    dura.run_in_dir(&["watch", "--disable"], repo2.dir.as_path());
+
+    let mut tmp_set = HashSet::new();
+    tmp_set.insert(repo1.dir.canonicalize().unwrap());
+    assert_eq!(dura.git_repos(), tmp_set);
+}
+
+#[test]
+fn enabled_watch_inside_disabled_one() {
+    let tmp = tempfile::tempdir().unwrap();
+    let client = GitRepo::new(tmp.path().join("client/app"));
+    client.init();
+    let other = GitRepo::new(tmp.path().join("other"));
+    other.init();
+
+    let dura = Dura::new();
+    dura.run_in_dir(&["watch", "--disable"], tmp.path());
+    dura.run_in_dir(&["watch"], &tmp.path().join("client"));
+
+    let mut tmp_set = HashSet::new();
+    tmp_set.insert(client.dir.canonicalize().unwrap());
+    assert_eq!(dura.git_repos(), tmp_set);
+}
+
+#[test]
+fn denied_repo_never_found() {
+    let tmp = tempfile::tempdir().unwrap();
+    let mut keep = GitRepo::new(tmp.path().join("keep"));
+    keep.init();
+    keep.write_file("foo.txt");
+    keep.commit_all();
+    let mut secret = GitRepo::new(tmp.path().join("team/secrets-repo"));
+    secret.init();
+    secret.write_file("foo.txt");
+    secret.commit_all();
+
+    let dura = Dura::new();
+    dura.run_in_dir(&["watch"], tmp.path());
+    let mut cfg = dura.get_config().unwrap();
+    for watch in cfg.repos.values_mut() {
+        std::rc::Rc::make_mut(watch).deny_repos = vec!["*/secrets-*".to_string()];
+    }
+    dura.save_config(&cfg);
+
+    let mut tmp_set = HashSet::new();
+    tmp_set.insert(keep.dir.canonicalize().unwrap());
+    assert_eq!(dura.git_repos(), tmp_set);
+
+    std::thread::sleep(std::time::Duration::from_secs_f64(1.5));
+    keep.change_file("foo.txt");
+    secret.change_file("foo.txt");
+    dura.run(&["serve", "--once"]);
+    let branches = |repo: &GitRepo| repo.git(&["branch", "--list", "dura/*"]).unwrap();
+    assert_ne!(branches(&keep).trim(), "");
+    assert_eq!(branches(&secret).trim(), "");
+}
+
+#[test]
+fn single_repo_watch_lists_no_dirs() {
+    let tmp = tempfile::tempdir().unwrap();
+    let repo = GitRepo::new(tmp.path().join("repo"));
+    repo.init();
+    let sibling = GitRepo::new(tmp.path().join("repo-sibling"));
+    sibling.init();
+
+    let dura = Dura::new();
+    dura.run(&["watch", repo.dir.to_str().unwrap()]);
+    let cfg = dura.get_config().unwrap();
+    assert!(cfg.repos.values().all(|watch| watch.single_repo));
+
+    let mut repos = cfg.git_repos();
+    assert_eq!(repos.next(), Some(repo.dir.canonicalize().unwrap()));
+    assert_eq!(repos.next(), None);
+    assert_eq!(repos.dirs_listed(), 0);
+
+    // Excluding something means looking through directories after all
+    dura.run(&["watch", repo.dir.to_str().unwrap(), "-e", "vendor"]);
+    let cfg = dura.get_config().unwrap();
+    assert!(cfg.repos.values().all(|watch| !watch.single_repo));
+    let mut repos = cfg.git_repos();
+    assert_eq!(repos.next(), Some(repo.dir.canonicalize().unwrap()));
+    assert!(repos.dirs_listed() > 0);
+}
+
+#[test]
+fn directory_watch_isnt_single_repo() {
+    let tmp = tempfile::tempdir().unwrap();
+    let repo = GitRepo::new(tmp.path().join("repo"));
+    repo.init();
+
+    let dura = Dura::new();
+    dura.run_in_dir(&["watch"], tmp.path());
+    let cfg = dura.get_config().unwrap();
+    assert!(cfg.repos.values().all(|watch| !watch.single_repo));
+}
+
+#[test]
+fn repos_per_watch_are_capped() {
+    let tmp = tempfile::tempdir().unwrap();
+    for i in 0..5 {
+        GitRepo::new(tmp.path().join(format!("repo{i}"))).init();
+    }
+    let dura = Dura::new();
+    dura.run(&["watch", tmp.path().to_str().unwrap()]);
+    let mut cfg = dura.get_config().unwrap();
+    assert_eq!(cfg.git_repos().count(), 5);
+
+    cfg.max_repos_per_watch = 2;
+    let mut repos = cfg.git_repos();
+    assert_eq!(repos.by_ref().count(), 2);
+    assert_eq!(repos.capped(), [tmp.path().canonicalize().unwrap()]);
+
+    dura.save_config(&cfg);
+    let output = dura.run_output(&["summary"]);
+    let err = String::from_utf8(output.stderr).unwrap();
+    assert!(err.contains("has more than 2 repos"), "{err}");
+}
+
+#[test]
+fn discovery_counts_pruned_dirs() {
+    let tmp = tempfile::tempdir().unwrap();
+    let root = tmp.path().canonicalize().unwrap();
+    for dir in ["a/repo", "a/b/too_deep", "skip/keep", "skip/other/repo"] {
+        GitRepo::new(root.join(dir)).init();
+    }
+    let dura = Dura::new();
+    dura.run(&[
+        "watch",
+        root.to_str().unwrap(),
+        "--maxdepth",
+        "2",
+        "--exclude",
+        "skip",
+        "--include",
+        "skip/keep",
+    ]);
+    let cfg = dura.get_config().unwrap();
+    let mut repos = cfg.git_repos();
+    let found: HashSet<PathBuf> = repos.by_ref().collect();
+    assert_eq!(
+        found,
+        HashSet::from([root.join("a/repo"), root.join("skip/keep")])
+    );
+
+    let stats = repos.stats()[&root];
+    assert_eq!(stats.pruned_by_depth, 1);
+    assert_eq!(stats.pruned_by_exclude, 1);
+    assert_eq!(stats.readmitted_by_include, 2);
+    assert_eq!(stats.repos_found, 2);
+    assert_eq!(stats.dirs_visited, 3);
+
+    let output = dura.run_output(&["summary", "--verbose"]);
+    let err = String::from_utf8(output.stderr).unwrap();
+    assert!(err.contains("1 pruned by max_depth"), "{err}");
+}
+
+#[test]
+fn foreground_watch_prints_snapshots() {
+    let tmp = tempfile::tempdir().unwrap();
+    let mut repo = GitRepo::new(tmp.path().to_path_buf());
+    repo.init();
+    repo.write_file("foo.txt");
+    repo.commit_all();
+    // Changes in the same second as the commit look like part of it
+    std::thread::sleep(std::time::Duration::from_secs_f64(1.5));
+    let mut dura = Dura::new();
+    dura.start_async(
+        &[
+            "watch",
+            "--foreground",
+            tmp.path().to_str().unwrap(),
+            "--interval",
+            "1",
+        ],
+        true,
+    );
+
+    repo.change_file("foo.txt");
+    let line = dura.primary.as_ref().unwrap().read_line(10).unwrap();
+    assert!(line.starts_with("dura: dura/"), "{line}");
+    let commit = line.split("commit_hash: ").nth(1).unwrap();
+    let commit = commit.split(',').next().unwrap();
+    assert_eq!(
+        repo.git(&["cat-file", "-t", commit]).unwrap().trim(),
+        "commit"
+    );
+
+    // Nothing was written for it
+    assert!(dura.get_config().is_none());
+    assert!(dura.get_runtime_lock().and_then(|lock| lock.pid).is_none());
+}
+
+#[test]
+fn foreground_watch_needs_an_interval() {
+    let tmp = tempfile::tempdir().unwrap();
+    let dir = tmp.path().to_str().unwrap();
+    let dura = Dura::new();
+
+    let output = dura.run_output(&["watch", "--foreground", dir, "--interval", "0"]);
+
+    assert!(!output.status.success());
+    assert!(dura.get_config().is_none());
+}
+
+/// Every file and directory under `dir`, with what a file watcher might notice changing
+#[cfg(unix)]
+fn tree_state(dir: &std::path::Path) -> Vec<(PathBuf, std::time::SystemTime, u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+
+    let mut state = vec![];
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        for entry in std::fs::read_dir(&dir).unwrap() {
+            let path = entry.unwrap().path();
+            let meta = std::fs::symlink_metadata(&path).unwrap();
+            if meta.is_dir() {
+                pending.push(path.clone());
+            }
+            state.push((path, meta.modified().unwrap(), meta.ino(), meta.len()));
+        }
+    }
+    state.sort();
+    state
+}
+
+#[cfg(unix)]
+#[test]
+fn unchanged_repo_is_left_untouched() {
+    let tmp = tempfile::tempdir().unwrap();
+    let mut repo = GitRepo::new(tmp.path().join("repo"));
+    repo.init();
+    repo.write_file("foo.txt");
+    repo.commit_all();
+    let dura = Dura::new();
+    dura.run(&["watch", repo.dir.to_str().unwrap()]);
+    // Saved again without changes, like an editor might. Newer than the commit, so each pass
+    // goes all the way to git to find out nothing changed.
+    std::thread::sleep(std::time::Duration::from_secs_f64(1.5));
+    repo.write_file("foo.txt");
+
+    let before = tree_state(&repo.dir);
+    for _ in 0..10 {
+        dura.run(&["serve", "--once"]);
+    }
+    assert_eq!(tree_state(&repo.dir), before);
+
+    // Same for changes that were already snapshotted
+    repo.change_file("foo.txt");
+    dura.run(&["serve", "--once"]);
+    assert_ne!(
+        repo.git(&["branch", "--list", "dura/*"]).unwrap().trim(),
+        ""
+    );
+    // Long enough for git to consider the objects stale, see FRESHEN_FREQUENCY in libgit2
+    std::thread::sleep(std::time::Duration::from_secs(3));
+    let before = tree_state(&repo.dir);
+    for _ in 0..10 {
+        dura.run(&["serve", "--once"]);
+    }
+    assert_eq!(tree_state(&repo.dir), before);
+}
+
+#[test]
+fn separate_git_dir_is_snapshotted() {
+    let tmp = tempfile::tempdir().unwrap();
+    let mut repo = GitRepo::new(tmp.path().join("work/repo"));
+    repo.init_separate(&tmp.path().join("git/repo.git"));
+    repo.write_file("foo.txt");
+    repo.commit_all();
+    // What a tool that started dura might have left in its environment
+    let decoy = GitRepo::new(tmp.path().join("decoy"));
+    decoy.init();
+    decoy.write_file("foo.txt");
+    decoy.commit_all();
+    let decoy_git = decoy.dir.join(".git");
+
+    let dura = Dura::new();
+    dura.run(&["watch", tmp.path().join("work").to_str().unwrap()]);
+    let expected = HashSet::from([repo.dir.canonicalize().unwrap()]);
+    assert_eq!(dura.git_repos(), expected);
+
+    std::thread::sleep(std::time::Duration::from_secs_f64(1.5));
+    repo.change_file("foo.txt");
+    let env = [
+        ("GIT_DIR", decoy_git.as_path()),
+        ("GIT_WORK_TREE", decoy.dir.as_path()),
+    ];
+    let output = dura.run_output_with_env(&["serve", "--once"], &env);
+    assert!(output.status.success());
+    assert_ne!(
+        repo.git(&["branch", "--list", "dura/*"]).unwrap().trim(),
+        ""
+    );
+    assert_eq!(
+        decoy.git(&["branch", "--list", "dura/*"]).unwrap().trim(),
+        ""
+    );
+}